@@ -16,6 +16,6 @@ echo "Fetching content with CID: $CID"
 echo "This may take a while as MIGA connects to the IPFS network..."
 
 # Run MIGA with the example CID and verbose output
-./target/release/MIGA --cid $CID --verbose
+./target/release/MIGA get $CID --verbose
 
 echo "Example completed!"
\ No newline at end of file
@@ -15,8 +15,11 @@ set CID=QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG
 echo Fetching and sharing content with CID: %CID%
 echo This may take a while as MIGA connects to the IPFS network...
 
-REM Run MIGA with the example CID, IPFS sharing enabled, and a description
-.\target\release\MIGA.exe --cid %CID% --verbose --share --description "IPFS Welcome Page"
+REM Fetch the example CID and pin it, so the daemon shares it
+.\target\release\MIGA.exe get %CID% --verbose --pin
+
+REM Run the daemon to share all pinned content on the IPFS network
+.\target\release\MIGA.exe daemon --verbose
 
 REM Note: The script will not complete until you press Ctrl+C to stop the IPFS node
 REM Other IPFS nodes can access the content using the CID while this node is running
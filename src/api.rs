@@ -0,0 +1,848 @@
+//! Control API of the daemon
+//!
+//! `miga daemon` serves a small HTTP API that accepts work while the node
+//! keeps running, so content can be fetched, added and pinned without
+//! starting a new node for every command. Requests and responses are JSON:
+//!
+//! - `GET /v1/id`: Peer ID, public key, addresses, agent version and protocols of the node
+//! - `POST /v1/fetch` `{"cid": "...", "pin": true}`: Fetch content into the blockstore
+//! - `POST /v1/fetch/jobs` `{"cid": "...", "pin": true}`: Fetch content in the background, see [`FetchJob`]
+//! - `GET /v1/fetch/jobs`, `GET /v1/fetch/jobs/{id}`: Progress of the fetches running in the background
+//! - `GET /v1/fetch/jobs/{id}/events`: Progress of a fetch job as Server-Sent Events
+//! - `POST /v1/add` `{"path": "..."}`: Add a file on the daemon's machine
+//! - `POST /v1/pin/add` `{"cid": "...", "direct": false}`: Pin content
+//! - `POST /v1/pin/rm` `{"cid": "..."}`: Remove a pin and stop providing the content
+//! - `GET /v1/pin/ls?cid=<prefix>&page=1&per_page=1000`: List pinned content, optionally only CIDs starting with a prefix
+//! - `GET /v1/swarm/peers`: Connected peers
+//! - `POST /v1/swarm/connect` `{"address": "..."}`: Connect to a peer
+//! - `POST /v1/swarm/block` `{"entry": "..."}`: Refuse a peer ID or IP range
+//! - `POST /v1/swarm/unblock` `{"entry": "..."}`: Remove a peer ID or IP range from the blocklist
+//! - `GET /v1/swarm/blocklist`: Blocked peer IDs and IP ranges
+//! - `GET /v1/stats/bw`: Bytes sent and received, in total, per protocol and per peer
+//! - `GET /metrics`: Metrics of the node in the Prometheus text format
+//! - `GET /healthz`, `GET /readyz`: Whether the daemon is alive and ready to serve, see [`Readiness`]
+//!
+//! The same server also answers a subset of Kubo's RPC API, see [`kubo`].
+//!
+//! The API listens on a TCP address, or on a Unix domain socket for local-only
+//! control that only the socket's owner can reach. An API reachable by others
+//! can be protected with a token, which requests then have to carry in an
+//! `Authorization: Bearer <token>` header, the same way Kubo's API
+//! authorizations work. Browser applications on other origins may only call
+//! the API if a CORS policy built with [`cors`] allows their origin.
+//!
+//! Every request answered by the API, the metrics endpoint or the gateway is
+//! logged once its response has been sent, with the `miga::access` target:
+//! method, path, status, bytes sent, duration and the client's IP address. Commands that inspect the running daemon talk to it
+//! through an [`ApiClient`].
+
+use anyhow::{anyhow, Result};
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Query, Request, State},
+    http::{header, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use cid::Cid;
+use futures::StreamExt;
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+};
+use tokio::{net::TcpListener, time::Instant};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::{
+    bandwidth::BandwidthStats,
+    blocklist::BlocklistEntry,
+    error::MigaError,
+    keypair,
+    multiformats::HashFunction,
+    network::{self, Direction},
+    node::MigaNode,
+    pin::PinMode,
+    unixfs::AddOptions,
+};
+
+mod client;
+mod jobs;
+mod kubo;
+mod kubo_client;
+
+pub use client::ApiClient;
+pub use jobs::{FetchJob, JobState};
+pub use kubo_client::{ExportStats, KuboClient};
+
+/// Default address of the control API
+pub const DEFAULT_API_ADDR: &str = "/ip4/127.0.0.1/tcp/5001";
+
+/// Default number of items on a page of a list
+const DEFAULT_PER_PAGE: usize = 1000;
+
+/// Header telling the number of items of a list across all pages
+const TOTAL_COUNT_HEADER: &str = "x-total-count";
+
+/// Methods browser applications on other origins may use, unless configured otherwise
+pub const DEFAULT_CORS_METHODS: &[&str] = &["GET", "POST"];
+
+/// Address the control API listens on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiAddr {
+    /// A TCP address, given as `/ip4/<ip>/tcp/<port>` or `/ip6/<ip>/tcp/<port>`
+    Tcp(SocketAddr),
+    /// A Unix domain socket, given as `/unix/<path>`
+    Unix(PathBuf),
+}
+
+impl FromStr for ApiAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // The rest of a `/unix/` address is the socket path, slashes included
+        if let Some(path) = s.strip_prefix("/unix/") {
+            return Ok(ApiAddr::Unix(PathBuf::from("/").join(path)));
+        }
+        let addr: Multiaddr = s.parse().map_err(|e| anyhow!("Invalid API address {:?}: {}", s, e))?;
+        Ok(ApiAddr::Tcp(socket_addr(&addr)?))
+    }
+}
+
+impl ApiAddr {
+    /// Whether other machines can reach the address, i.e. it is a TCP address other than loopback
+    pub fn is_public(&self) -> bool {
+        matches!(self, ApiAddr::Tcp(addr) if !addr.ip().is_loopback())
+    }
+}
+
+impl fmt::Display for ApiAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiAddr::Tcp(addr) => write!(f, "{}", addr),
+            ApiAddr::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Serve the control API until it is shut down
+///
+/// # Arguments
+/// * `node` - The node the API controls
+/// * `addr` - Address to listen on
+/// * `token` - Token every request has to carry, if the API is protected
+/// * `cors` - Which other origins browsers let call the API
+/// * `shutdown` - Completes when the server should stop accepting requests;
+///   requests in progress are finished before this function returns
+///
+/// # Returns
+/// * `Result<()>` - Ok once the server has shut down, Err if it failed
+pub async fn serve<F>(
+    node: Arc<MigaNode>,
+    addr: &ApiAddr,
+    token: Option<&str>,
+    cors: Cors,
+    shutdown: F,
+) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let mut router = router(node.clone(), cors.origins);
+    if let Some(token) = token {
+        router = router.layer(middleware::from_fn_with_state(Arc::<str>::from(token), authorize));
+    }
+    // Health checks of orchestrators and load balancers come without the token
+    router = router.merge(health_router(node));
+    // Browsers ask before sending the token, so preflight requests are answered ahead of the authorization
+    if let Some(layer) = cors.layer {
+        router = router.layer(layer);
+    }
+    serve_router(router, addr, shutdown).await
+}
+
+/// Which other origins browsers let call the API, built with [`cors`]
+#[derive(Clone, Default)]
+pub struct Cors {
+    /// Origins allowed to call the API, `*` for any
+    origins: Arc<[String]>,
+    /// Headers allowing those origins, None if no origin is allowed
+    layer: Option<CorsLayer>,
+}
+
+/// Build the CORS policy of the API
+///
+/// Browsers only let applications call an API on another origin if its
+/// responses allow that origin. The token of a protected API is still
+/// required; the policy only tells browsers which pages may send requests.
+///
+/// # Arguments
+/// * `origins` - Origins allowed to call the API, such as `https://app.example.com`, or `*` for any
+/// * `methods` - HTTP methods they may use
+///
+/// # Returns
+/// * `Result<Cors>` - The policy, allowing no other origin if `origins` is empty
+pub fn cors(origins: &[String], methods: &[String]) -> Result<Cors> {
+    if origins.is_empty() {
+        return Ok(Cors::default());
+    }
+    let allowed = origins.iter().map(|origin| origin.trim_end_matches('/').to_string()).collect();
+    let origins = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin.trim_end_matches('/')).map_err(|_| anyhow!("Invalid CORS origin {:?}", origin))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+    let methods = methods
+        .iter()
+        .map(|method| {
+            Method::from_bytes(method.trim().to_ascii_uppercase().as_bytes())
+                .map_err(|_| anyhow!("Invalid CORS method {:?}", method))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Cors {
+        origins: allowed,
+        layer: Some(
+            CorsLayer::new()
+                .allow_origin(origins)
+                .allow_methods(methods)
+                .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+                .expose_headers([HeaderName::from_static(TOTAL_COUNT_HEADER)]),
+        ),
+    })
+}
+
+/// Serve only the metrics of a node, for a Prometheus server to scrape
+///
+/// # Arguments
+/// * `node` - The node whose metrics are served
+/// * `addr` - Address to listen on
+/// * `shutdown` - Completes when the server should stop
+///
+/// # Returns
+/// * `Result<()>` - Ok once the server has shut down, Err if it failed
+pub async fn serve_metrics<F>(node: Arc<MigaNode>, addr: &ApiAddr, shutdown: F) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let router = Router::new().route("/metrics", get(metrics)).with_state(node.clone()).merge(health_router(node));
+    serve_router(router, addr, shutdown).await
+}
+
+/// Serve routes on a TCP address or Unix domain socket
+pub(crate) async fn serve_router<F>(router: Router, addr: &ApiAddr, shutdown: F) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let router = router.layer(middleware::from_fn(access_log));
+    match addr {
+        ApiAddr::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            info!("HTTP API listening on {}", listener.local_addr()?);
+            // Handlers can tell clients apart by their address, e.g. to limit requests per client
+            let service = router.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, service).with_graceful_shutdown(shutdown).await?;
+        }
+        ApiAddr::Unix(path) => serve_unix(router, path, shutdown).await?,
+    }
+    Ok(())
+}
+
+/// Serve routes on a Unix domain socket
+#[cfg(unix)]
+async fn serve_unix<F>(router: Router, path: &std::path::Path, shutdown: F) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    use std::{fs, os::unix::fs::PermissionsExt};
+    use tokio::net::UnixListener;
+
+    // A socket left behind by a previous run would make binding fail
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    // Only the user running the daemon may connect
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    info!("HTTP API listening on {:?}", path);
+
+    axum::serve(listener, router).with_graceful_shutdown(shutdown).await?;
+    let _ = fs::remove_file(path);
+    Ok(())
+}
+
+/// Unix domain sockets are only available on Unix
+#[cfg(not(unix))]
+async fn serve_unix<F>(_router: Router, path: &std::path::Path, _shutdown: F) -> Result<()> {
+    Err(anyhow!("Unix domain sockets are not supported on this platform ({:?})", path))
+}
+
+/// Build the routes of the control API
+///
+/// # Arguments
+/// * `node` - The node the API controls
+/// * `origins` - Origins of the browser applications allowed to call the Kubo RPC API, `*` for any
+pub fn router(node: Arc<MigaNode>, origins: Arc<[String]>) -> Router {
+    Router::new()
+        .route("/v1/id", get(id))
+        .route("/v1/fetch", post(fetch))
+        .route("/v1/add", post(add))
+        .route("/v1/pin/add", post(pin_add))
+        .route("/v1/pin/rm", post(pin_rm))
+        .route("/v1/pin/ls", get(pin_ls))
+        .route("/v1/swarm/peers", get(swarm_peers))
+        .route("/v1/swarm/connect", post(swarm_connect))
+        .route("/v1/swarm/block", post(swarm_block))
+        .route("/v1/swarm/unblock", post(swarm_unblock))
+        .route("/v1/swarm/blocklist", get(swarm_blocklist))
+        .route("/v1/stats/bw", get(stats_bw))
+        .route("/metrics", get(metrics))
+        .merge(jobs::router())
+        .merge(kubo::router(origins))
+        .with_state(node)
+}
+
+/// A request being answered, logged once its response has been sent
+struct AccessLogEntry {
+    method: Method,
+    path: String,
+    client: Option<IpAddr>,
+    status: StatusCode,
+    started: Instant,
+    bytes: u64,
+}
+
+impl AccessLogEntry {
+    /// Count a chunk of the response body as sent
+    fn sent(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+}
+
+impl Drop for AccessLogEntry {
+    fn drop(&mut self) {
+        let client = self.client.map_or_else(|| "local".to_string(), |ip| ip.to_string());
+        tracing::info!(
+            target: "miga::access",
+            method = %self.method,
+            path = %self.path,
+            status = self.status.as_u16(),
+            bytes = self.bytes,
+            duration_ms = self.started.elapsed().as_millis() as u64,
+            client = %client,
+            "{} {} {} {} bytes in {:.1?} for {}",
+            self.method,
+            self.path,
+            self.status.as_u16(),
+            self.bytes,
+            self.started.elapsed(),
+            client,
+        );
+    }
+}
+
+/// Log every request once its response has been sent, or the client went away
+async fn access_log(request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    // Clients on a Unix domain socket have no IP address
+    let client = request.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| addr.ip());
+
+    let response = next.run(request).await;
+    let mut entry = AccessLogEntry {
+        method,
+        path,
+        client,
+        status: response.status(),
+        started,
+        bytes: 0,
+    };
+    // Bodies are streamed, so the bytes are counted as they are sent
+    // The stream owns the entry, which logs the request when the stream is dropped
+    response.map(|body| {
+        Body::from_stream(body.into_data_stream().map(move |chunk| {
+            if let Ok(data) = &chunk {
+                entry.sent(data.len());
+            }
+            chunk
+        }))
+    })
+}
+
+/// Build the health check routes, served by the control API, the metrics endpoint and the gateway alike
+pub(crate) fn health_router(node: Arc<MigaNode>) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(node)
+}
+
+/// Refuse requests that don't carry the API token
+async fn authorize(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !given.is_some_and(|given| same_token(given, &token)) {
+        let body = Json(ErrorResponse {
+            error: "Missing or wrong API token".to_string(),
+        });
+        return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")], body).into_response();
+    }
+    next.run(request).await
+}
+
+/// Compare two tokens in constant time, so the time taken doesn't tell how much of a guess was right
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Convert a `/ip4/.../tcp/...` or `/ip6/.../tcp/...` multiaddress to a socket address
+fn socket_addr(addr: &Multiaddr) -> Result<SocketAddr> {
+    let mut ip = None;
+    let mut port = None;
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::Ip4(addr) => ip = Some(IpAddr::V4(addr)),
+            Protocol::Ip6(addr) => ip = Some(IpAddr::V6(addr)),
+            Protocol::Tcp(p) => port = Some(p),
+            _ => return Err(anyhow!("Unsupported API address {}", addr)),
+        }
+    }
+    match (ip, port) {
+        (Some(ip), Some(port)) => Ok(SocketAddr::new(ip, port)),
+        _ => Err(anyhow!("API address {} needs an IP address and a TCP port", addr)),
+    }
+}
+
+/// Error of an API request, returned as `{"error": "..."}` with a fitting status
+struct ApiError {
+    /// Status of the response, derived from the error if None
+    status: Option<StatusCode>,
+    /// What went wrong
+    error: anyhow::Error,
+}
+
+impl ApiError {
+    /// An error answered with the given status
+    fn new(status: StatusCode, error: impl Into<anyhow::Error>) -> Self {
+        ApiError {
+            status: Some(status),
+            error: error.into(),
+        }
+    }
+
+    /// Status of the response: client errors for bad or unknown input, server errors otherwise
+    fn status(&self) -> StatusCode {
+        if let Some(status) = self.status {
+            return status;
+        }
+        match self.error.chain().find_map(|cause| cause.downcast_ref::<MigaError>()) {
+            Some(MigaError::InvalidCid { .. }) => StatusCode::BAD_REQUEST,
+            Some(MigaError::NoSuchPath { .. } | MigaError::NotStoredLocally(_)) => StatusCode::NOT_FOUND,
+            Some(MigaError::NoProviders { .. } | MigaError::Network(_) | MigaError::Timeout { .. }) => StatusCode::GATEWAY_TIMEOUT,
+            // Such as a file to add that doesn't exist
+            _ if self.error.chain().any(|cause| {
+                cause
+                    .downcast_ref::<io::Error>()
+                    .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+            }) =>
+            {
+                StatusCode::NOT_FOUND
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl<E: Into<anyhow::Error>> From<E> for ApiError {
+    fn from(e: E) -> Self {
+        ApiError {
+            status: None,
+            error: e.into(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            warn!("API request failed: {:#}", self.error);
+        }
+        let body = Json(ErrorResponse {
+            error: format!("{:#}", self.error),
+        });
+        (status, body).into_response()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Identity of the node, as returned by `GET /v1/id`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IdResponse {
+    /// Peer ID of the node
+    pub peer_id: String,
+    /// Public key of the node, base64 of its protobuf encoding
+    pub public_key: String,
+    /// Addresses the node listens on and is reachable on from the outside, ending in `/p2p/<peer ID>`
+    pub addresses: Vec<String>,
+    /// Agent version the node presents to other peers
+    pub agent_version: String,
+    /// Protocols the node speaks
+    pub protocols: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct FetchRequest {
+    cid: String,
+    #[serde(default)]
+    pin: bool,
+}
+
+#[derive(Serialize)]
+struct FetchResponse {
+    cid: String,
+    size: u64,
+}
+
+#[derive(Deserialize)]
+struct AddRequest {
+    path: PathBuf,
+    cid_version: Option<u64>,
+    raw_leaves: Option<bool>,
+    #[serde(default)]
+    hash: HashFunction,
+}
+
+#[derive(Serialize)]
+struct AddResponse {
+    cid: String,
+    size: u64,
+    blocks: usize,
+}
+
+#[derive(Deserialize)]
+struct PinRequest {
+    cid: String,
+    #[serde(default)]
+    direct: bool,
+}
+
+#[derive(Deserialize)]
+struct PinLsQuery {
+    cid: Option<String>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct PinResponse {
+    cid: String,
+    mode: PinMode,
+}
+
+/// A connection to another peer, as listed by `GET /v1/swarm/peers`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SwarmPeer {
+    /// Peer ID of the peer
+    pub peer_id: String,
+    /// Remote address of the connection
+    pub address: String,
+    /// Which side opened the connection
+    pub direction: Direction,
+    /// Transport the connection runs over, such as `tcp`
+    pub transport: String,
+    /// Software the peer runs, if it has identified itself
+    pub agent_version: Option<String>,
+}
+
+/// Request of `POST /v1/swarm/connect`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConnectRequest {
+    /// Address of the peer, ending in `/p2p/<peer ID>`
+    pub address: String,
+}
+
+/// Response of `POST /v1/swarm/connect`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConnectResponse {
+    /// Peer ID of the peer now connected
+    pub peer_id: String,
+}
+
+/// Request of `POST /v1/swarm/block` and `POST /v1/swarm/unblock`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlocklistRequest {
+    /// Peer ID, IP address or IP range
+    pub entry: BlocklistEntry,
+}
+
+/// Response of `POST /v1/swarm/block` and `POST /v1/swarm/unblock`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlocklistResponse {
+    /// The entry of the request
+    pub entry: BlocklistEntry,
+    /// Whether the blocklist changed, false if the entry already was (or wasn't) on it
+    pub changed: bool,
+}
+
+/// Readiness of the daemon, as returned by `GET /readyz`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Readiness {
+    /// Whether every check passed
+    pub ready: bool,
+    /// Result of each check
+    pub checks: Vec<ReadinessCheck>,
+}
+
+/// Result of one check of `GET /readyz`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReadinessCheck {
+    /// What was checked: `network` or `blockstore`
+    pub name: String,
+    /// Whether the check passed
+    pub ok: bool,
+    /// What was found, or why the check failed
+    pub detail: String,
+}
+
+/// Parse a CID given in a request
+fn parse_cid(cid: &str) -> Result<Cid> {
+    cid.parse().map_err(|e: cid::Error| {
+        MigaError::InvalidCid {
+            cid: cid.to_string(),
+            reason: e.to_string(),
+        }
+        .into()
+    })
+}
+
+async fn id(State(node): State<Arc<MigaNode>>) -> Result<Json<IdResponse>, ApiError> {
+    let peer_id = node.peer_id();
+    let info = node.client().local_info().await?;
+    Ok(Json(IdResponse {
+        peer_id: peer_id.to_string(),
+        public_key: keypair::encode_public_key(node.client().public_key()),
+        addresses: info
+            .listen_addrs
+            .iter()
+            .chain(&info.external_addrs)
+            .map(|addr| format!("{}/p2p/{}", addr, peer_id))
+            .collect(),
+        agent_version: network::agent_version(),
+        protocols: info.protocols,
+    }))
+}
+
+/// The daemon is alive as long as its network task answers
+async fn healthz(State(node): State<Arc<MigaNode>>) -> Response {
+    match node.client().local_info().await {
+        Ok(_) => Json(serde_json::json!({ "status": "ok" })).into_response(),
+        Err(e) => {
+            let body = Json(ErrorResponse {
+                error: format!("{:#}", e),
+            });
+            (StatusCode::SERVICE_UNAVAILABLE, body).into_response()
+        }
+    }
+}
+
+/// The daemon is ready once it is connected to the network and can store blocks
+async fn readyz(State(node): State<Arc<MigaNode>>) -> Response {
+    // Without peers, nothing can be fetched or provided
+    let network = match node.client().connected_peers().await {
+        Ok(peers) if peers.is_empty() => ReadinessCheck {
+            name: "network".to_string(),
+            ok: false,
+            detail: "Not connected to any peer".to_string(),
+        },
+        Ok(peers) => ReadinessCheck {
+            name: "network".to_string(),
+            ok: true,
+            detail: format!("Connected to {} peers", peers.len()),
+        },
+        Err(e) => ReadinessCheck {
+            name: "network".to_string(),
+            ok: false,
+            detail: format!("{:#}", e),
+        },
+    };
+    let blockstore = node.blockstore().clone();
+    let writable = tokio::task::spawn_blocking(move || blockstore.check_writable())
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|result| result);
+    let blockstore = ReadinessCheck {
+        name: "blockstore".to_string(),
+        ok: writable.is_ok(),
+        detail: match writable {
+            Ok(()) => "Writable".to_string(),
+            Err(e) => format!("{:#}", e),
+        },
+    };
+
+    let checks = vec![network, blockstore];
+    let ready = checks.iter().all(|check| check.ok);
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(Readiness { ready, checks })).into_response()
+}
+
+async fn fetch(State(node): State<Arc<MigaNode>>, Json(request): Json<FetchRequest>) -> Result<Json<FetchResponse>, ApiError> {
+    let cid = parse_cid(&request.cid)?;
+
+    // Fetching stores every block in the blockstore, so the content itself isn't needed here
+    let size = node.fetch_to(cid, &mut tokio::io::sink()).await?;
+    if request.pin {
+        node.pin(&cid, PinMode::Recursive)?;
+        node.provide_in_background(cid);
+    }
+    Ok(Json(FetchResponse {
+        cid: cid.to_string(),
+        size,
+    }))
+}
+
+async fn add(State(node): State<Arc<MigaNode>>, Json(request): Json<AddRequest>) -> Result<Json<AddResponse>, ApiError> {
+    let options = AddOptions::from_flags(request.cid_version, request.raw_leaves, request.hash)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?;
+    let added = node.add_with_options(&request.path, options)?;
+    node.provide_in_background(added.cid);
+    Ok(Json(AddResponse {
+        cid: added.cid.to_string(),
+        size: added.size,
+        blocks: added.blocks,
+    }))
+}
+
+async fn pin_add(State(node): State<Arc<MigaNode>>, Json(request): Json<PinRequest>) -> Result<Json<PinResponse>, ApiError> {
+    let cid = parse_cid(&request.cid)?;
+    let mode = if request.direct { PinMode::Direct } else { PinMode::Recursive };
+    node.pin(&cid, mode)?;
+    node.provide_in_background(cid);
+    Ok(Json(PinResponse {
+        cid: cid.to_string(),
+        mode,
+    }))
+}
+
+async fn pin_rm(State(node): State<Arc<MigaNode>>, Json(request): Json<PinRequest>) -> Result<Json<PinResponse>, ApiError> {
+    let cid = parse_cid(&request.cid)?;
+    let mode = node
+        .pins()?
+        .into_iter()
+        .find(|(pinned, _)| *pinned == cid)
+        .map(|(_, mode)| mode)
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, anyhow!("{} is not pinned", cid)))?;
+    node.unpin(&cid)?;
+    // Unpinned content may be garbage collected, so the node shouldn't be found as its provider
+    node.unprovide(cid).await?;
+    Ok(Json(PinResponse {
+        cid: cid.to_string(),
+        mode,
+    }))
+}
+
+async fn metrics(State(node): State<Arc<MigaNode>>) -> Result<Response, ApiError> {
+    let body = node.encode_metrics()?;
+    Ok(([(header::CONTENT_TYPE, "application/openmetrics-text; version=1.0.0; charset=utf-8")], body).into_response())
+}
+
+async fn pin_ls(State(node): State<Arc<MigaNode>>, Query(query): Query<PinLsQuery>) -> Result<Response, ApiError> {
+    let pins: Vec<PinResponse> = node
+        .pins()?
+        .into_iter()
+        .map(|(cid, mode)| PinResponse {
+            cid: cid.to_string(),
+            mode,
+        })
+        .filter(|pin| query.cid.as_deref().is_none_or(|prefix| pin.cid.starts_with(prefix)))
+        .collect();
+
+    // Pins are sorted by CID, so pages stay stable while nothing is pinned or unpinned
+    let total = pins.len();
+    let per_page = query.per_page.unwrap_or(DEFAULT_PER_PAGE).max(1);
+    let page = query.page.unwrap_or(1).max(1);
+    let pins: Vec<PinResponse> = pins.into_iter().skip((page - 1).saturating_mul(per_page)).take(per_page).collect();
+    Ok(([(TOTAL_COUNT_HEADER, total.to_string())], Json(pins)).into_response())
+}
+
+async fn swarm_peers(State(node): State<Arc<MigaNode>>) -> Result<Json<Vec<SwarmPeer>>, ApiError> {
+    let peers = node
+        .client()
+        .connected_peers()
+        .await?
+        .into_iter()
+        .map(|connection| SwarmPeer {
+            peer_id: connection.peer_id.to_string(),
+            address: connection.address.to_string(),
+            direction: connection.direction,
+            transport: connection.transport().to_string(),
+            agent_version: connection.agent_version,
+        })
+        .collect();
+    Ok(Json(peers))
+}
+
+async fn swarm_connect(
+    State(node): State<Arc<MigaNode>>,
+    Json(request): Json<ConnectRequest>,
+) -> Result<Json<ConnectResponse>, ApiError> {
+    let addr: Multiaddr = request
+        .address
+        .parse()
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, anyhow!("Invalid multiaddress {}: {}", request.address, e)))?;
+    let peer_id = node.client().dial(addr).await?;
+    Ok(Json(ConnectResponse {
+        peer_id: peer_id.to_string(),
+    }))
+}
+
+async fn swarm_block(
+    State(node): State<Arc<MigaNode>>,
+    Json(request): Json<BlocklistRequest>,
+) -> Result<Json<BlocklistResponse>, ApiError> {
+    let changed = node.client().block(request.entry).await?;
+    Ok(Json(BlocklistResponse {
+        entry: request.entry,
+        changed,
+    }))
+}
+
+async fn swarm_unblock(
+    State(node): State<Arc<MigaNode>>,
+    Json(request): Json<BlocklistRequest>,
+) -> Result<Json<BlocklistResponse>, ApiError> {
+    let changed = node.client().unblock(request.entry).await?;
+    Ok(Json(BlocklistResponse {
+        entry: request.entry,
+        changed,
+    }))
+}
+
+async fn swarm_blocklist(State(node): State<Arc<MigaNode>>) -> Result<Json<Vec<BlocklistEntry>>, ApiError> {
+    Ok(Json(node.client().blocklist().await?))
+}
+
+async fn stats_bw(State(node): State<Arc<MigaNode>>) -> Json<BandwidthStats> {
+    Json(node.client().bandwidth())
+}
@@ -0,0 +1,121 @@
+//! Client of the control API
+//!
+//! Commands that look into a running daemon, such as `miga swarm peers`, ask
+//! its control API instead of starting a node of their own, which would only
+//! know about its own, freshly opened connections.
+
+use anyhow::{anyhow, Result};
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Bytes, client::conn::http1, header, Method, Request};
+use hyper_util::rt::TokioIo;
+use log::debug;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+use super::{ApiAddr, ErrorResponse};
+use crate::error::MigaError;
+
+/// Client of the control API of a running daemon
+pub struct ApiClient {
+    /// Address the daemon's API listens on
+    addr: ApiAddr,
+    /// Token the daemon's API requires, if it is protected
+    token: Option<String>,
+}
+
+impl ApiClient {
+    /// Create a client for the API at an address
+    pub fn new(addr: ApiAddr) -> Self {
+        Self { addr, token: None }
+    }
+
+    /// Authorize the requests with a token, for an API protected by one
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Send a GET request and parse the JSON response
+    ///
+    /// # Arguments
+    /// * `path` - Path of the endpoint, e.g. `/v1/swarm/peers`
+    pub async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        self.request(Method::GET, path, Vec::new()).await
+    }
+
+    /// Send a POST request with a JSON body and parse the JSON response
+    ///
+    /// # Arguments
+    /// * `path` - Path of the endpoint, e.g. `/v1/pin/add`
+    /// * `body` - Request to send as the body
+    pub async fn post<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T> {
+        self.request(Method::POST, path, serde_json::to_vec(body)?).await
+    }
+
+    /// Send a request to the daemon and parse its JSON response
+    async fn request<T: DeserializeOwned>(&self, method: Method, path: &str, body: Vec<u8>) -> Result<T> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(header::HOST, "localhost")
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(token) = &self.token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let request = request.body(Full::new(Bytes::from(body)))?;
+
+        // A daemon that isn't running is the likely cause of a refused connection
+        let unreachable = |e: std::io::Error| {
+            MigaError::Network(format!("Failed to reach the daemon API at {} ({}), is `miga daemon` running?", self.addr, e))
+        };
+        let (ok, body) = match &self.addr {
+            ApiAddr::Tcp(addr) => send(TcpStream::connect(addr).await.map_err(unreachable)?, request).await?,
+            ApiAddr::Unix(path) => send(connect_unix(path).await.map_err(unreachable)?, request).await?,
+        };
+
+        // Failed requests are answered with `{"error": "..."}`
+        if !ok {
+            let error = serde_json::from_slice::<ErrorResponse>(&body)
+                .map(|response| response.error)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&body).into_owned());
+            return Err(anyhow!("The daemon failed the request: {}", error));
+        }
+        Ok(serde_json::from_slice(&body)?)
+    }
+}
+
+/// Send a request over a connection to the daemon
+///
+/// # Returns
+/// * `Result<(bool, Bytes)>` - Whether the request succeeded, and the body of the response
+async fn send<S>(stream: S, request: Request<Full<Bytes>>) -> Result<(bool, Bytes)>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Connection to the daemon API failed: {}", e);
+        }
+    });
+
+    let response = sender.send_request(request).await?;
+    let ok = response.status().is_success();
+    let body = response.into_body().collect().await?.to_bytes();
+    Ok((ok, body))
+}
+
+/// Connect to an API listening on a Unix domain socket
+#[cfg(unix)]
+pub(super) async fn connect_unix(path: &std::path::Path) -> std::io::Result<tokio::net::UnixStream> {
+    tokio::net::UnixStream::connect(path).await
+}
+
+/// Unix domain sockets are only available on Unix
+#[cfg(not(unix))]
+pub(super) async fn connect_unix(_path: &std::path::Path) -> std::io::Result<TcpStream> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform"))
+}
@@ -0,0 +1,233 @@
+//! Fetch jobs of the control API
+//!
+//! `POST /v1/fetch` answers once the content has been fetched, which can take
+//! longer than a caller on another machine wants to keep a request open. A
+//! fetch job runs in the background instead: starting one answers right away
+//! with the job and its ID, and the job can be polled while it runs, or
+//! followed as a stream of Server-Sent Events for a live progress bar.
+//!
+//! - `POST /v1/fetch/jobs` `{"cid": "...", "pin": true}`: Start fetching content in the background
+//! - `GET /v1/fetch/jobs`: All jobs, running and recently finished
+//! - `GET /v1/fetch/jobs/{id}`: State and progress of a job
+//! - `GET /v1/fetch/jobs/{id}/events`: The job as it changes, one `progress`
+//!   event after another, ending with a `done` or `failed` event
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::sse::{Event, KeepAlive, Sse},
+    routing::get,
+    Extension, Json, Router,
+};
+use cid::Cid;
+use futures::{future, stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
+
+use super::{parse_cid, ApiError, FetchRequest};
+use crate::{fetch::FetchEvent, node::MigaNode, pin::PinMode};
+
+/// Number of finished jobs kept for callers to look up, the oldest going first
+const MAX_FINISHED_JOBS: usize = 100;
+
+/// Build the routes of the fetch jobs
+pub fn router() -> Router<Arc<MigaNode>> {
+    Router::new()
+        .route("/v1/fetch/jobs", get(list).post(start))
+        .route("/v1/fetch/jobs/{id}", get(status))
+        .route("/v1/fetch/jobs/{id}/events", get(events))
+        .layer(Extension(Arc::new(FetchJobs::default())))
+}
+
+/// State of a fetch job
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    /// The content is being fetched
+    Running,
+    /// The content was fetched, and pinned if requested
+    Done,
+    /// The fetch failed, see the job's error
+    Failed,
+}
+
+/// A fetch running in the background, as returned by `/v1/fetch/jobs`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FetchJob {
+    /// ID of the job, unique while the daemon runs
+    pub id: u64,
+    /// CID of the content
+    pub cid: String,
+    /// Whether the content is pinned once fetched
+    pub pin: bool,
+    /// State of the job
+    pub state: JobState,
+    /// Number of providers found so far
+    pub providers: usize,
+    /// Number of blocks received so far
+    pub blocks: u64,
+    /// Number of bytes of blocks received so far
+    pub bytes: u64,
+    /// Size of the content, once fetched
+    pub size: Option<u64>,
+    /// Why the job failed
+    pub error: Option<String>,
+}
+
+/// The fetch jobs of the daemon
+#[derive(Default)]
+struct FetchJobs {
+    /// Jobs by ID, which increase with every job started, each telling its watchers about changes
+    jobs: Mutex<BTreeMap<u64, watch::Sender<FetchJob>>>,
+}
+
+impl FetchJobs {
+    /// Register a new job for a CID
+    fn create(&self, cid: Cid, pin: bool) -> FetchJob {
+        let mut jobs = self.jobs.lock().unwrap();
+        let id = jobs.last_key_value().map_or(1, |(id, _)| id + 1);
+        let job = FetchJob {
+            id,
+            cid: cid.to_string(),
+            pin,
+            state: JobState::Running,
+            providers: 0,
+            blocks: 0,
+            bytes: 0,
+            size: None,
+            error: None,
+        };
+        jobs.insert(id, watch::Sender::new(job.clone()));
+
+        // Forget the oldest finished jobs, so a long-running daemon doesn't collect them forever
+        let finished: Vec<u64> = jobs
+            .iter()
+            .filter(|(_, job)| job.borrow().state != JobState::Running)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in finished.iter().take(finished.len().saturating_sub(MAX_FINISHED_JOBS)) {
+            jobs.remove(id);
+        }
+        job
+    }
+
+    /// Change a job, telling its watchers
+    fn update(&self, id: u64, change: impl FnOnce(&mut FetchJob)) {
+        if let Some(job) = self.jobs.lock().unwrap().get(&id) {
+            job.send_modify(change);
+        }
+    }
+
+    /// A job, if it exists
+    fn get(&self, id: u64) -> Option<FetchJob> {
+        self.jobs.lock().unwrap().get(&id).map(|job| job.borrow().clone())
+    }
+
+    /// Watch the changes of a job, if it exists
+    fn watch(&self, id: u64) -> Option<watch::Receiver<FetchJob>> {
+        self.jobs.lock().unwrap().get(&id).map(|job| job.subscribe())
+    }
+
+    /// All jobs, oldest first
+    fn list(&self) -> Vec<FetchJob> {
+        self.jobs.lock().unwrap().values().map(|job| job.borrow().clone()).collect()
+    }
+}
+
+/// Fetch the content of a job, keeping the job up to date as blocks arrive
+async fn run(node: Arc<MigaNode>, jobs: Arc<FetchJobs>, id: u64, cid: Cid, pin: bool) {
+    // Fetching stores every block in the blockstore, so the content itself isn't needed here
+    let mut events = node.fetch_with_events(cid, tokio::io::sink());
+    while let Some(event) = events.next().await {
+        match event {
+            FetchEvent::ProviderFound { .. } => jobs.update(id, |job| job.providers += 1),
+            FetchEvent::BlockReceived { size, .. } => jobs.update(id, |job| {
+                job.blocks += 1;
+                job.bytes += size as u64;
+            }),
+            FetchEvent::Completed { size } => {
+                let pinned = if pin { pin_and_provide(&node, cid) } else { Ok(()) };
+                jobs.update(id, |job| {
+                    job.size = Some(size);
+                    match pinned {
+                        Ok(()) => job.state = JobState::Done,
+                        Err(e) => {
+                            job.state = JobState::Failed;
+                            job.error = Some(format!("{:#}", e));
+                        }
+                    }
+                });
+            }
+            FetchEvent::Failed { error } => jobs.update(id, |job| {
+                job.state = JobState::Failed;
+                job.error = Some(error);
+            }),
+        }
+    }
+}
+
+/// Pin fetched content and announce it on the network
+fn pin_and_provide(node: &Arc<MigaNode>, cid: Cid) -> anyhow::Result<()> {
+    node.pin(&cid, PinMode::Recursive)?;
+    node.provide_in_background(cid);
+    Ok(())
+}
+
+async fn start(
+    State(node): State<Arc<MigaNode>>,
+    Extension(jobs): Extension<Arc<FetchJobs>>,
+    Json(request): Json<FetchRequest>,
+) -> Result<Json<FetchJob>, ApiError> {
+    let cid = parse_cid(&request.cid)?;
+    let job = jobs.create(cid, request.pin);
+    tokio::spawn(run(node, jobs, job.id, cid, request.pin));
+    Ok(Json(job))
+}
+
+async fn list(Extension(jobs): Extension<Arc<FetchJobs>>) -> Json<Vec<FetchJob>> {
+    Json(jobs.list())
+}
+
+async fn status(Extension(jobs): Extension<Arc<FetchJobs>>, Path(id): Path<u64>) -> Result<Json<FetchJob>, ApiError> {
+    let job = jobs.get(id).ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, anyhow!("No fetch job {}", id)))?;
+    Ok(Json(job))
+}
+
+async fn events(
+    Extension(jobs): Extension<Arc<FetchJobs>>,
+    Path(id): Path<u64>,
+) -> Result<Sse<impl Stream<Item = Result<Event, axum::Error>>>, ApiError> {
+    let receiver = jobs.watch(id).ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, anyhow!("No fetch job {}", id)))?;
+
+    // The job as it is now, then after every change; changes in quick succession are sent as one
+    let updates = stream::unfold((receiver, true), |(mut receiver, first)| async move {
+        if !first && receiver.changed().await.is_err() {
+            return None;
+        }
+        let job = receiver.borrow_and_update().clone();
+        Some((job, (receiver, false)))
+    });
+    // The stream ends with the event of the finished job
+    let events = updates
+        .scan(false, |finished, job| {
+            if *finished {
+                return future::ready(None);
+            }
+            *finished = job.state != JobState::Running;
+            future::ready(Some(job))
+        })
+        .map(|job| {
+            let name = match job.state {
+                JobState::Running => "progress",
+                JobState::Done => "done",
+                JobState::Failed => "failed",
+            };
+            Event::default().event(name).json_data(&job)
+        });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
@@ -0,0 +1,411 @@
+//! Kubo-compatible RPC API
+//!
+//! A subset of Kubo's `/api/v0` RPC API, so existing IPFS tooling (such as
+//! the ipfs-http-client libraries) can drive a MIGA daemon without changes.
+//! Like Kubo, every endpoint only accepts POST requests, takes its arguments
+//! as query parameters and reports errors as `{"Message", "Code", "Type"}`.
+//!
+//! - `/api/v0/id`: Identity of the node
+//! - `/api/v0/cat?arg=<cid>`: Content of a file
+//! - `/api/v0/add?pin=<bool>&cid-version=<0|1>&raw-leaves=<bool>&hash=<function>`: Add the files of a multipart upload
+//! - `/api/v0/pin/add?arg=<cid>&recursive=<bool>`: Fetch and pin content
+//! - `/api/v0/swarm/peers`: Connected peers
+//! - `/api/v0/swarm/connect?arg=<multiaddr>`: Connect to a peer
+//! - `/api/v0/stats/bw?peer=<peer ID>&proto=<protocol>`: Bandwidth used, in total or of a peer or protocol
+//!
+//! As these requests need no preflight, any web page could make a visitor's
+//! browser send them. So, as in Kubo, requests whose `Origin` (or, without
+//! one, `Referer`) names an origin that isn't allowed to call the API are
+//! refused with `403 Forbidden`. Requests of other programs carry neither.
+
+use anyhow::{anyhow, Result};
+use axum::{
+    body::Body,
+    extract::{DefaultBodyLimit, Multipart, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use futures::{stream, StreamExt};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::SyncIoBridge;
+
+use crate::{
+    fetch::FileReader,
+    keypair,
+    multiformats::HashFunction,
+    network,
+    node::MigaNode,
+    pin::PinMode,
+    unixfs::{self, AddOptions},
+};
+
+/// Size of the pipe uploads are streamed into the blockstore through
+const UPLOAD_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Build the routes of the Kubo RPC API
+///
+/// # Arguments
+/// * `origins` - Origins of the browser applications allowed to call the API, `*` for any
+pub fn router(origins: Arc<[String]>) -> Router<Arc<MigaNode>> {
+    Router::new()
+        .route("/api/v0/id", post(id))
+        .route("/api/v0/cat", post(cat))
+        // Uploads are streamed into the blockstore, so they aren't limited to axum's default body size
+        .route("/api/v0/add", post(add).layer(DefaultBodyLimit::disable()))
+        .route("/api/v0/pin/add", post(pin_add))
+        .route("/api/v0/swarm/peers", post(swarm_peers))
+        .route("/api/v0/swarm/connect", post(swarm_connect))
+        .route("/api/v0/stats/bw", post(stats_bw))
+        .route_layer(middleware::from_fn_with_state(origins, check_origin))
+}
+
+/// Refuse requests sent by browsers on behalf of pages of origins that aren't allowed
+async fn check_origin(State(origins): State<Arc<[String]>>, request: Request, next: Next) -> Response {
+    if !origin_allowed(request.headers(), &origins) {
+        debug!("Refused an RPC request from a page on another origin");
+        return (StatusCode::FORBIDDEN, "403 - Forbidden\n").into_response();
+    }
+    next.run(request).await
+}
+
+/// Whether the page a request comes from, if any, may call the API
+fn origin_allowed(headers: &HeaderMap, origins: &[String]) -> bool {
+    let origin = match headers.get(header::ORIGIN) {
+        Some(origin) => origin.to_str().ok(),
+        None => match headers.get(header::REFERER) {
+            Some(referer) => referer.to_str().ok().and_then(referer_origin),
+            // Not sent by a browser
+            None => return true,
+        },
+    };
+    let Some(origin) = origin else {
+        return false;
+    };
+    origins.iter().any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+}
+
+/// Origin of a referring URL, e.g. `https://app.example.com` of `https://app.example.com/page`
+fn referer_origin(referer: &str) -> Option<&str> {
+    let (scheme, rest) = referer.split_once("://")?;
+    let host_len = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    Some(&referer[..scheme.len() + "://".len() + host_len])
+}
+
+/// Error of an RPC request, in Kubo's error format
+struct KuboError(anyhow::Error);
+
+impl<E: Into<anyhow::Error>> From<E> for KuboError {
+    fn from(e: E) -> Self {
+        KuboError(e.into())
+    }
+}
+
+impl IntoResponse for KuboError {
+    fn into_response(self) -> Response {
+        warn!("RPC request failed: {:#}", self.0);
+        let body = Json(ErrorResponse {
+            message: format!("{:#}", self.0),
+            code: 0,
+            kind: "error",
+        });
+        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ErrorResponse {
+    message: String,
+    code: u32,
+    #[serde(rename = "Type")]
+    kind: &'static str,
+}
+
+/// The `arg` query parameter most endpoints take
+#[derive(Deserialize)]
+struct ArgQuery {
+    arg: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct AddQuery {
+    #[serde(default = "default_true")]
+    pin: bool,
+    cid_version: Option<u64>,
+    raw_leaves: Option<bool>,
+    #[serde(default)]
+    hash: HashFunction,
+}
+
+impl AddQuery {
+    /// Options of the DAG, with CIDv1 for other hash functions and raw leaves for CIDv1 like Kubo
+    fn options(&self) -> Result<AddOptions> {
+        AddOptions::from_flags(self.cid_version, self.raw_leaves, self.hash)
+    }
+}
+
+#[derive(Deserialize)]
+struct PinAddQuery {
+    arg: String,
+    #[serde(default = "default_true")]
+    recursive: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct IdResponse {
+    #[serde(rename = "ID")]
+    id: String,
+    public_key: String,
+    addresses: Vec<String>,
+    agent_version: String,
+    protocol_version: &'static str,
+    protocols: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct AddResponse {
+    name: String,
+    hash: String,
+    size: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct PinAddResponse {
+    pins: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SwarmPeersResponse {
+    peers: Vec<SwarmPeer>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SwarmPeer {
+    addr: String,
+    peer: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct StringsResponse {
+    strings: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct BandwidthQuery {
+    peer: Option<String>,
+    proto: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct BandwidthResponse {
+    total_in: u64,
+    total_out: u64,
+    rate_in: f64,
+    rate_out: f64,
+}
+
+/// Parse the CID argument of a request, accepting `/ipfs/<cid>` paths as well
+fn parse_cid(arg: &str) -> Result<cid::Cid> {
+    let cid = arg.strip_prefix("/ipfs/").unwrap_or(arg);
+    cid.parse().map_err(|e| anyhow!("invalid path {:?}: {}", arg, e))
+}
+
+async fn id(State(node): State<Arc<MigaNode>>) -> Result<Json<IdResponse>, KuboError> {
+    let peer_id = node.peer_id();
+    let info = node.client().local_info().await?;
+    Ok(Json(IdResponse {
+        id: peer_id.to_string(),
+        public_key: keypair::encode_public_key(node.client().public_key()),
+        addresses: info
+            .listen_addrs
+            .iter()
+            .chain(&info.external_addrs)
+            .map(|addr| format!("{}/p2p/{}", addr, peer_id))
+            .collect(),
+        agent_version: network::agent_version(),
+        protocol_version: network::PROTOCOL_VERSION,
+        protocols: info.protocols,
+    }))
+}
+
+async fn cat(State(node): State<Arc<MigaNode>>, Query(query): Query<ArgQuery>) -> Result<Response, KuboError> {
+    let cid = parse_cid(&query.arg)?;
+
+    // Fetch the first chunk before answering, so an unreadable CID is
+    // reported as an error instead of an empty response
+    let mut reader = node.reader(cid);
+    let first = reader.next_chunk().await?;
+
+    // Stream the rest of the content as it arrives
+    let rest = stream::unfold(Some(reader), |reader: Option<FileReader>| async move {
+        let mut reader = reader?;
+        match reader.next_chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), Some(reader))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    let body = stream::iter(first.map(Ok)).chain(rest);
+    Ok(([(header::CONTENT_TYPE, "text/plain")], Body::from_stream(body)).into_response())
+}
+
+async fn add(
+    State(node): State<Arc<MigaNode>>,
+    Query(query): Query<AddQuery>,
+    mut multipart: Multipart,
+) -> Result<Response, KuboError> {
+    // Add every file of the upload, answering with one JSON object per file
+    let mut lines = String::new();
+    while let Some(mut field) = multipart.next_field().await? {
+        let name = field.file_name().or(field.name()).unwrap_or_default().to_string();
+
+        // Adding reads and writes files, so it runs on a blocking thread, reading the upload through a pipe
+        let (mut upload, content) = tokio::io::duplex(UPLOAD_BUFFER_SIZE);
+        let blockstore = node.blockstore().clone();
+        let options = query.options()?;
+        let content = SyncIoBridge::new(content);
+        let adding = tokio::task::spawn_blocking(move || unixfs::add_reader(&blockstore, content, options));
+        let uploaded = async {
+            while let Some(chunk) = field.chunk().await? {
+                upload.write_all(&chunk).await?;
+            }
+            upload.shutdown().await?;
+            anyhow::Ok(())
+        }
+        .await;
+        drop(upload);
+        // If adding failed, the upload fails too as nothing reads it anymore; the reason is adding's error
+        let added = adding.await??;
+        uploaded?;
+        if query.pin {
+            node.pin(&added.cid, PinMode::Recursive)?;
+        }
+        node.provide_in_background(added.cid);
+
+        let line = AddResponse {
+            name: if name.is_empty() { added.cid.to_string() } else { name },
+            hash: added.cid.to_string(),
+            size: added.size.to_string(),
+        };
+        lines.push_str(&serde_json::to_string(&line)?);
+        lines.push('\n');
+    }
+    Ok(([(header::CONTENT_TYPE, "application/json")], lines).into_response())
+}
+
+async fn pin_add(State(node): State<Arc<MigaNode>>, Query(query): Query<PinAddQuery>) -> Result<Json<PinAddResponse>, KuboError> {
+    let cid = parse_cid(&query.arg)?;
+
+    // Like Kubo, fetch content that isn't stored locally yet before pinning it
+    node.fetch_to(cid, &mut tokio::io::sink()).await?;
+    let mode = if query.recursive { PinMode::Recursive } else { PinMode::Direct };
+    node.pin(&cid, mode)?;
+    node.provide_in_background(cid);
+    Ok(Json(PinAddResponse {
+        pins: vec![cid.to_string()],
+    }))
+}
+
+async fn swarm_peers(State(node): State<Arc<MigaNode>>) -> Result<Json<SwarmPeersResponse>, KuboError> {
+    let peers = node
+        .client()
+        .connected_peers()
+        .await?
+        .into_iter()
+        .map(|connection| SwarmPeer {
+            addr: connection.address.to_string(),
+            peer: connection.peer_id.to_string(),
+        })
+        .collect();
+    Ok(Json(SwarmPeersResponse { peers }))
+}
+
+async fn swarm_connect(State(node): State<Arc<MigaNode>>, Query(query): Query<ArgQuery>) -> Result<Json<StringsResponse>, KuboError> {
+    let addr: libp2p::Multiaddr = query.arg.parse().map_err(|e| anyhow!("invalid multiaddr {:?}: {}", query.arg, e))?;
+    let peer_id = node.client().dial(addr).await?;
+    Ok(Json(StringsResponse {
+        strings: vec![format!("connect {} success", peer_id)],
+    }))
+}
+
+async fn stats_bw(State(node): State<Arc<MigaNode>>, Query(query): Query<BandwidthQuery>) -> Result<Json<BandwidthResponse>, KuboError> {
+    let stats = node.client().bandwidth();
+    // Like Kubo, peers and protocols without traffic report zeros
+    let traffic = match (&query.peer, &query.proto) {
+        (Some(_), Some(_)) => return Err(anyhow!("please only specify peer OR protocol").into()),
+        (Some(peer), None) => stats.peers.get(peer).copied().unwrap_or_default(),
+        (None, Some(proto)) => stats.protocols.get(proto).copied().unwrap_or_default(),
+        (None, None) => stats.total,
+    };
+    Ok(Json(BandwidthResponse {
+        total_in: traffic.total_in,
+        total_out: traffic.total_out,
+        rate_in: traffic.rate_in,
+        rate_out: traffic.rate_out,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn headers(name: header::HeaderName, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    fn origins(origins: &[&str]) -> Vec<String> {
+        origins.iter().map(|origin| origin.to_string()).collect()
+    }
+
+    #[test]
+    fn allows_requests_without_origin() {
+        assert!(origin_allowed(&HeaderMap::new(), &[]));
+    }
+
+    #[test]
+    fn allows_configured_origins() {
+        let allowed = origins(&["https://app.example.com"]);
+        assert!(origin_allowed(&headers(header::ORIGIN, "https://app.example.com"), &allowed));
+        assert!(origin_allowed(&headers(header::REFERER, "https://app.example.com/page?q=1"), &allowed));
+        assert!(origin_allowed(&headers(header::ORIGIN, "https://evil.example"), &origins(&["*"])));
+    }
+
+    #[test]
+    fn refuses_other_origins() {
+        let allowed = origins(&["https://app.example.com"]);
+        assert!(!origin_allowed(&headers(header::ORIGIN, "https://evil.example"), &allowed));
+        assert!(!origin_allowed(&headers(header::ORIGIN, "https://evil.example"), &[]));
+        assert!(!origin_allowed(&headers(header::ORIGIN, "null"), &allowed));
+        assert!(!origin_allowed(&headers(header::REFERER, "https://app.example.com.evil.example/"), &allowed));
+        assert!(!origin_allowed(&headers(header::REFERER, "not a url"), &allowed));
+    }
+
+    #[test]
+    fn origin_of_referer() {
+        assert_eq!(referer_origin("http://127.0.0.1:5001/webui"), Some("http://127.0.0.1:5001"));
+        assert_eq!(referer_origin("https://app.example.com"), Some("https://app.example.com"));
+        assert_eq!(referer_origin("https://app.example.com?q=1"), Some("https://app.example.com"));
+        assert_eq!(referer_origin("app.example.com/page"), None);
+    }
+}
@@ -0,0 +1,166 @@
+//! Client of Kubo's RPC API
+//!
+//! `miga export --to-kubo` pushes blocks into a running Kubo node through its
+//! `/api/v0` RPC API, so MIGA can fetch content for an existing Kubo pinning
+//! setup. All requests go over one kept-alive connection, as a DAG is pushed
+//! one block at a time.
+
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Bytes, client::conn::http1, header, Method, Request};
+use hyper_util::rt::TokioIo;
+use log::debug;
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+use super::{client::connect_unix, ApiAddr};
+use crate::{error::MigaError, multiformats};
+
+/// Outcome of pushing a DAG to Kubo
+#[derive(Debug, Default)]
+pub struct ExportStats {
+    /// Number of blocks pushed
+    pub blocks: usize,
+    /// Number of bytes pushed
+    pub bytes: u64,
+}
+
+/// Client of the RPC API of a running Kubo node
+pub struct KuboClient {
+    /// Address Kubo's API listens on
+    addr: ApiAddr,
+    /// Connection the requests are sent over
+    sender: http1::SendRequest<Full<Bytes>>,
+}
+
+/// Response of `/api/v0/block/put`
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BlockPutResponse {
+    /// CID Kubo stored the block under
+    key: String,
+}
+
+/// Error response of Kubo's RPC API
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ErrorResponse {
+    message: String,
+}
+
+impl KuboClient {
+    /// Connect to the RPC API of a Kubo node
+    ///
+    /// # Arguments
+    /// * `addr` - Address of Kubo's API, e.g. `/ip4/127.0.0.1/tcp/5001`
+    ///
+    /// # Returns
+    /// * `Result<KuboClient>` - A client connected to the API
+    pub async fn connect(addr: ApiAddr) -> Result<Self> {
+        let unreachable = |e: std::io::Error| {
+            MigaError::Network(format!("Failed to reach the Kubo API at {} ({}), is the Kubo daemon running?", addr, e))
+        };
+        let sender = match &addr {
+            ApiAddr::Tcp(socket) => handshake(TcpStream::connect(socket).await.map_err(unreachable)?).await?,
+            ApiAddr::Unix(path) => handshake(connect_unix(path).await.map_err(unreachable)?).await?,
+        };
+        Ok(Self { addr, sender })
+    }
+
+    /// Store a block in Kubo, like `ipfs block put`
+    ///
+    /// Kubo is told the codec and hash function of the CID, and the CID it
+    /// stores the block under is checked to have the same multihash.
+    ///
+    /// # Arguments
+    /// * `cid` - CID of the block
+    /// * `data` - The block data
+    pub async fn block_put(&mut self, cid: &Cid, data: &[u8]) -> Result<()> {
+        let codec = multiformats::codec_name(cid.codec())
+            .ok_or_else(|| anyhow!("Kubo can't be told the codec 0x{:x} of {}", cid.codec(), cid))?;
+        let hash = cid.hash();
+        let hash_name = multiformats::hash_name(hash.code())
+            .ok_or_else(|| anyhow!("Kubo can't be told the hash function 0x{:x} of {}", hash.code(), cid))?;
+        let path = format!(
+            "/api/v0/block/put?cid-codec={}&mhtype={}&mhlen={}&pin=false",
+            codec,
+            hash_name,
+            hash.size()
+        );
+
+        // The block is uploaded as the only file of a multipart form
+        let boundary = format!("miga-{:032x}", rand::random::<u128>());
+        let mut body = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"block\"\r\nContent-Type: application/octet-stream\r\n\r\n"
+        )
+        .into_bytes();
+        body.extend_from_slice(data);
+        body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+        let response = self.request(&path, Some(&format!("multipart/form-data; boundary={boundary}")), body).await?;
+        let response: BlockPutResponse = serde_json::from_slice(&response)?;
+        let stored = Cid::try_from(response.key.as_str())?;
+        if stored.hash() != hash {
+            return Err(anyhow!("Kubo stored block {} as {}, which has a different hash", cid, stored));
+        }
+        Ok(())
+    }
+
+    /// Pin a CID and the DAG below it in Kubo, like `ipfs pin add`
+    ///
+    /// # Arguments
+    /// * `cid` - The CID to pin
+    pub async fn pin_add(&mut self, cid: &Cid) -> Result<()> {
+        self.request(&format!("/api/v0/pin/add?arg={}&recursive=true", cid), None, Vec::new()).await?;
+        Ok(())
+    }
+
+    /// Send a POST request to Kubo and return the body of its response
+    async fn request(&mut self, path: &str, content_type: Option<&str>, body: Vec<u8>) -> Result<Bytes> {
+        let mut request = Request::builder().method(Method::POST).uri(path).header(header::HOST, self.host());
+        if let Some(content_type) = content_type {
+            request = request.header(header::CONTENT_TYPE, content_type);
+        }
+        let request = request.body(Full::new(Bytes::from(body)))?;
+
+        self.sender.ready().await?;
+        let response = self.sender.send_request(request).await?;
+        let ok = response.status().is_success();
+        let body = response.into_body().collect().await?.to_bytes();
+
+        // Failed requests are answered with `{"Message": "...", "Code": 0, "Type": "error"}`
+        if !ok {
+            let error = serde_json::from_slice::<ErrorResponse>(&body)
+                .map(|response| response.message)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&body).into_owned());
+            return Err(anyhow!("Kubo failed the request: {}", error));
+        }
+        Ok(body)
+    }
+
+    /// Host header of the requests
+    fn host(&self) -> String {
+        match &self.addr {
+            ApiAddr::Tcp(addr) => addr.to_string(),
+            ApiAddr::Unix(_) => "localhost".to_string(),
+        }
+    }
+}
+
+/// Set up an HTTP/1 connection to Kubo
+async fn handshake<S>(stream: S) -> Result<http1::SendRequest<Full<Bytes>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, connection) = http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Connection to the Kubo API failed: {}", e);
+        }
+    });
+    Ok(sender)
+}
@@ -0,0 +1,352 @@
+//! Bandwidth accounting
+//!
+//! Every connection of the node is wrapped so the bytes read from and written
+//! to its streams are counted, in total, per peer and per protocol. The
+//! protocol of a stream is learned from its multistream-select negotiation;
+//! bytes sent before the negotiation completes are counted towards the
+//! protocol once it is known.
+//!
+//! The counts cover the data of the streams, not the framing and encryption
+//! added by the transport.
+
+use futures::{ready, AsyncRead, AsyncWrite};
+use libp2p::{
+    core::muxing::{StreamMuxer, StreamMuxerBox, StreamMuxerEvent, SubstreamBox},
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use crate::protobuf;
+
+/// Header of the multistream-select protocol, which precedes the protocol negotiated on a stream
+const MULTISTREAM_HEADER: &str = "/multistream/1.0.0";
+
+/// Name under which the bytes of streams whose protocol couldn't be learned are counted
+pub const UNKNOWN_PROTOCOL: &str = "unknown";
+
+/// Largest negotiation looked at to learn the protocol of a stream
+const MAX_NEGOTIATION_SIZE: usize = 1024;
+
+/// Time between two updates of the rates
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Bytes transferred in each direction, and the current rates
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Traffic {
+    /// Bytes received
+    pub total_in: u64,
+    /// Bytes sent
+    pub total_out: u64,
+    /// Bytes received per second, over the last second
+    pub rate_in: f64,
+    /// Bytes sent per second, over the last second
+    pub rate_out: f64,
+}
+
+/// Snapshot of the bandwidth used by a node since it started
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct BandwidthStats {
+    /// All traffic of the node
+    pub total: Traffic,
+    /// Traffic per protocol, such as `/ipfs/bitswap/1.2.0`
+    pub protocols: BTreeMap<String, Traffic>,
+    /// Traffic per peer ID
+    pub peers: BTreeMap<String, Traffic>,
+}
+
+/// Byte counts of one kind of traffic, with the counts at the last rate update
+#[derive(Debug, Default)]
+struct Counter {
+    /// The counts and rates
+    traffic: Traffic,
+    /// Bytes received at the last rate update
+    last_in: u64,
+    /// Bytes sent at the last rate update
+    last_out: u64,
+}
+
+impl Counter {
+    /// Count bytes received and sent
+    fn add(&mut self, bytes_in: u64, bytes_out: u64) {
+        self.traffic.total_in += bytes_in;
+        self.traffic.total_out += bytes_out;
+    }
+
+    /// Update the rates from the bytes transferred since the last update
+    fn update_rates(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        self.traffic.rate_in = (self.traffic.total_in - self.last_in) as f64 / secs;
+        self.traffic.rate_out = (self.traffic.total_out - self.last_out) as f64 / secs;
+        self.last_in = self.traffic.total_in;
+        self.last_out = self.traffic.total_out;
+    }
+}
+
+/// All byte counts of a node
+#[derive(Debug, Default)]
+struct Counters {
+    /// All traffic
+    total: Counter,
+    /// Traffic per protocol
+    protocols: HashMap<String, Counter>,
+    /// Traffic per peer
+    peers: HashMap<PeerId, Counter>,
+}
+
+/// Bandwidth accounting of a node
+///
+/// Cloning is cheap; all clones share the same counts.
+#[derive(Debug, Clone, Default)]
+pub struct Bandwidth {
+    /// The counts, shared by all connections
+    counters: Arc<Mutex<Counters>>,
+}
+
+impl Bandwidth {
+    /// Start counting, updating the rates every second in the background
+    pub(crate) fn new() -> Self {
+        let bandwidth = Self::default();
+        tokio::spawn(update_rates(Arc::downgrade(&bandwidth.counters)));
+        bandwidth
+    }
+
+    /// Snapshot of the counts
+    pub fn stats(&self) -> BandwidthStats {
+        let counters = self.counters.lock().unwrap();
+        BandwidthStats {
+            total: counters.total.traffic,
+            protocols: counters
+                .protocols
+                .iter()
+                .map(|(protocol, counter)| (protocol.clone(), counter.traffic))
+                .collect(),
+            peers: counters
+                .peers
+                .iter()
+                .map(|(peer, counter)| (peer.to_string(), counter.traffic))
+                .collect(),
+        }
+    }
+
+    /// Count bytes of a peer in the total and for the peer
+    fn record_peer(&self, peer: PeerId, bytes_in: u64, bytes_out: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.total.add(bytes_in, bytes_out);
+        counters.peers.entry(peer).or_default().add(bytes_in, bytes_out);
+    }
+
+    /// Count bytes for a protocol
+    fn record_protocol(&self, protocol: &str, bytes_in: u64, bytes_out: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        match counters.protocols.get_mut(protocol) {
+            Some(counter) => counter.add(bytes_in, bytes_out),
+            None => counters.protocols.entry(protocol.to_string()).or_default().add(bytes_in, bytes_out),
+        }
+    }
+}
+
+/// Update the rates every second, until the accounting is dropped
+async fn update_rates(counters: Weak<Mutex<Counters>>) {
+    let mut interval = tokio::time::interval(RATE_INTERVAL);
+    let mut last = Instant::now();
+    loop {
+        interval.tick().await;
+        let Some(counters) = counters.upgrade() else {
+            return;
+        };
+        let elapsed = last.elapsed();
+        last = Instant::now();
+        if elapsed.is_zero() {
+            continue;
+        }
+        let mut counters = counters.lock().unwrap();
+        let Counters { total, protocols, peers } = &mut *counters;
+        for counter in std::iter::once(total).chain(protocols.values_mut()).chain(peers.values_mut()) {
+            counter.update_rates(elapsed);
+        }
+    }
+}
+
+/// A connection whose streams are counted
+pub(crate) struct Muxer {
+    /// The connection
+    inner: StreamMuxerBox,
+    /// The peer at the other end
+    peer: PeerId,
+    /// Where the bytes are counted
+    bandwidth: Bandwidth,
+}
+
+impl Muxer {
+    /// Count the streams of a connection to a peer
+    pub(crate) fn new(inner: StreamMuxerBox, peer: PeerId, bandwidth: Bandwidth) -> Self {
+        Self { inner, peer, bandwidth }
+    }
+
+    /// Wrap a new stream
+    fn counted(&self, inner: SubstreamBox, listener: bool) -> CountedStream {
+        CountedStream {
+            inner,
+            peer: self.peer,
+            bandwidth: self.bandwidth.clone(),
+            listener,
+            protocol: Negotiation::Pending {
+                sniffed: Vec::new(),
+                bytes_in: 0,
+                bytes_out: 0,
+            },
+        }
+    }
+}
+
+impl StreamMuxer for Muxer {
+    type Substream = CountedStream;
+    type Error = io::Error;
+
+    fn poll_inbound(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Self::Substream, Self::Error>> {
+        let stream = ready!(Pin::new(&mut self.inner).poll_inbound(cx))?;
+        Poll::Ready(Ok(self.counted(stream, true)))
+    }
+
+    fn poll_outbound(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<Self::Substream, Self::Error>> {
+        let stream = ready!(Pin::new(&mut self.inner).poll_outbound(cx))?;
+        Poll::Ready(Ok(self.counted(stream, false)))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        Pin::new(&mut self.inner).poll(cx)
+    }
+}
+
+/// How far the protocol of a stream is known
+enum Negotiation {
+    /// The negotiation is still going on
+    Pending {
+        /// The messages of the listening side so far
+        sniffed: Vec<u8>,
+        /// Bytes received before the protocol was known
+        bytes_in: u64,
+        /// Bytes sent before the protocol was known
+        bytes_out: u64,
+    },
+    /// The protocol was negotiated
+    Done(String),
+}
+
+/// A stream whose bytes are counted
+pub(crate) struct CountedStream {
+    /// The stream
+    inner: SubstreamBox,
+    /// The peer at the other end
+    peer: PeerId,
+    /// Where the bytes are counted
+    bandwidth: Bandwidth,
+    /// Whether the peer opened the stream, so we confirm the protocol
+    listener: bool,
+    /// The protocol of the stream, once known
+    protocol: Negotiation,
+}
+
+impl CountedStream {
+    /// Count bytes transferred on the stream
+    ///
+    /// # Arguments
+    /// * `bytes_in` - Number of bytes received
+    /// * `bytes_out` - Number of bytes sent
+    /// * `from_listener` - The bytes the listening side sent, which confirm the protocol
+    fn record(&mut self, bytes_in: u64, bytes_out: u64, from_listener: &[u8]) {
+        self.bandwidth.record_peer(self.peer, bytes_in, bytes_out);
+
+        match &mut self.protocol {
+            Negotiation::Done(protocol) => self.bandwidth.record_protocol(protocol, bytes_in, bytes_out),
+            Negotiation::Pending {
+                sniffed,
+                bytes_in: pending_in,
+                bytes_out: pending_out,
+            } => {
+                *pending_in += bytes_in;
+                *pending_out += bytes_out;
+                sniffed.extend_from_slice(from_listener);
+
+                // Streams that don't look like a negotiation are counted without a protocol
+                let protocol = match negotiated_protocol(sniffed) {
+                    Some(protocol) => protocol,
+                    None if sniffed.len() > MAX_NEGOTIATION_SIZE => UNKNOWN_PROTOCOL.to_string(),
+                    None => return,
+                };
+                self.bandwidth.record_protocol(&protocol, *pending_in, *pending_out);
+                self.protocol = Negotiation::Done(protocol);
+            }
+        }
+    }
+}
+
+impl AsyncRead for CountedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        // On streams we opened, the peer is the one confirming the protocol
+        let from_listener = if self.listener { &[][..] } else { &buf[..n] };
+        self.record(n as u64, 0, from_listener);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for CountedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        let from_listener = if self.listener { &buf[..n] } else { &[][..] };
+        self.record(0, n as u64, from_listener);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl Drop for CountedStream {
+    fn drop(&mut self) {
+        // Bytes of a stream closed during the negotiation still count
+        if let Negotiation::Pending { bytes_in, bytes_out, .. } = self.protocol
+            && (bytes_in > 0 || bytes_out > 0)
+        {
+            self.bandwidth.record_protocol(UNKNOWN_PROTOCOL, bytes_in, bytes_out);
+        }
+    }
+}
+
+/// The protocol the listening side of a stream confirmed, if it did yet
+///
+/// The listener answers with the multistream-select header, then with "na"
+/// for every protocol it doesn't support and with the name of the one it
+/// accepts, each as a varint-prefixed line.
+fn negotiated_protocol(mut messages: &[u8]) -> Option<String> {
+    loop {
+        let len = usize::try_from(protobuf::read_varint(&mut messages).ok()?).ok()?;
+        if messages.len() < len {
+            return None;
+        }
+        let (message, rest) = messages.split_at(len);
+        messages = rest;
+        let line = std::str::from_utf8(message).ok()?.strip_suffix('\n')?;
+        if line != MULTISTREAM_HEADER && line != "na" {
+            return Some(line.to_string());
+        }
+    }
+}
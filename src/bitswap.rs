@@ -0,0 +1,637 @@
+//! Bitswap block exchange
+//!
+//! Bitswap is the protocol IPFS nodes use to trade blocks. A node asks peers
+//! for blocks by sending them a wantlist, and peers that have the blocks send
+//! them back on a stream of their own. Messages on both kinds of streams are
+//! length-prefixed protobufs.
+//!
+//! When several providers of a block are known, the block is requested from a
+//! few of them at once and the first copy to arrive wins. The wants sent to
+//! the other providers are cancelled, and the response times of peers are
+//! remembered so the fastest providers are asked first next time.
+//!
+//! The other way round, wants of peers are answered from the local blockstore:
+//! blocks we have are sent back, and peers that asked to be told are sent a
+//! DONT_HAVE for the blocks we don't have. With an allowlist, only the peers
+//! on it are sent blocks; everyone else is answered as if we had none.
+//!
+//! Blocks sent and received count against the node's [`RateLimits`]: a block
+//! is only sent once the upload limiter lets it pass, and the next message of
+//! a peer is only read once the blocks of the last one have passed the
+//! download limiter.
+
+use anyhow::{anyhow, Result};
+use cid::{Cid, Version};
+use futures::{AsyncReadExt, AsyncWriteExt, StreamExt};
+use libp2p::{PeerId, Stream, StreamProtocol};
+use libp2p_stream as stream;
+use log::debug;
+use multihash_codetable::{Code, MultihashDigest};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{sync::mpsc, time::Instant};
+use tracing::{info_span, Instrument};
+
+use crate::{blockstore::Blockstore, fetch::FetchEvent, metrics::Metrics, protobuf, throttle::RateLimits};
+
+/// Protocol name of Bitswap 1.2.0
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/ipfs/bitswap/1.2.0");
+
+/// Largest Bitswap message accepted
+const MAX_MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+
+/// Number of providers asked for a block at the same time
+const RACE_WIDTH: usize = 3;
+
+/// Time a provider gets to send a block before the next provider is asked
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bitswap client and server of a node
+///
+/// Cloning is cheap; all clones share the same wants and peer statistics.
+#[derive(Clone)]
+pub struct Bitswap {
+    /// Control for opening streams to peers
+    control: stream::Control,
+    /// Blocks currently being waited for
+    wants: Arc<Mutex<Wants>>,
+    /// Smoothed response times of peers that sent us blocks
+    response_times: Arc<Mutex<HashMap<PeerId, Duration>>>,
+}
+
+impl Bitswap {
+    /// Create the Bitswap client and start accepting Bitswap streams
+    ///
+    /// # Arguments
+    /// * `control` - Control of the swarm's stream behaviour
+    /// * `blockstore` - The local blockstore, whose blocks are sent to peers that want them
+    /// * `metrics` - Metrics to count the blocks sent to peers in
+    /// * `allowlist` - The only peers that are sent blocks, or None to send them to everyone
+    /// * `rates` - Limits on the rates blocks are sent and received at
+    ///
+    /// # Returns
+    /// * `Result<Bitswap>` - The Bitswap client
+    pub fn new(
+        mut control: stream::Control,
+        blockstore: Blockstore,
+        metrics: Metrics,
+        allowlist: Option<HashSet<PeerId>>,
+        rates: RateLimits,
+    ) -> Result<Self> {
+        let incoming = control
+            .accept(PROTOCOL)
+            .map_err(|e| anyhow!("Failed to register the Bitswap protocol: {}", e))?;
+        let bitswap = Self {
+            control,
+            wants: Arc::new(Mutex::new(Wants::default())),
+            response_times: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        // Peers send the blocks we asked for, and their own wants, on streams they open themselves
+        let inbound = Inbound {
+            wants: bitswap.wants.clone(),
+            control: bitswap.control.clone(),
+            blockstore,
+            metrics,
+            allowlist: allowlist.map(Arc::new),
+            rates,
+        };
+        tokio::spawn(handle_inbound(inbound, incoming));
+        Ok(bitswap)
+    }
+
+    /// Get a block from the providers of its CID
+    ///
+    /// Up to [`RACE_WIDTH`] providers are asked at the same time, fastest known
+    /// providers first. A provider that says it doesn't have the block, can't be
+    /// reached or doesn't answer within [`PROVIDER_TIMEOUT`] is replaced by the
+    /// next one. When the block arrives, the wants sent to the other providers
+    /// are cancelled.
+    ///
+    /// # Arguments
+    /// * `cid` - CID of the block
+    /// * `providers` - Providers of the block, as they are discovered
+    /// * `events` - Where to report newly found providers, if anywhere
+    ///
+    /// # Returns
+    /// * `Result<Vec<u8>>` - The block data, which matches the CID
+    pub async fn get_block(
+        &self,
+        cid: Cid,
+        mut providers: mpsc::UnboundedReceiver<PeerId>,
+        events: Option<&mpsc::UnboundedSender<FetchEvent>>,
+    ) -> Result<Vec<u8>> {
+        let mut want = self.want(cid);
+        let mut candidates: Vec<PeerId> = Vec::new();
+        let mut asked: HashMap<PeerId, Instant> = HashMap::new();
+        let mut racing: HashSet<PeerId> = HashSet::new();
+        let mut providers_done = false;
+        // Covers the time until the DHT comes up with the first provider
+        let mut lookup = Some(info_span!("provider_lookup", cid = %cid));
+
+        loop {
+            // Keep the fastest known providers racing for the block
+            while racing.len() < RACE_WIDTH {
+                let Some(peer) = self.take_fastest(&mut candidates) else {
+                    break;
+                };
+                debug!("Asking {} for block {}", peer, cid);
+                self.send_want(peer, cid, false, Some(want.sender.clone()));
+                asked.insert(peer, Instant::now());
+                racing.insert(peer);
+            }
+            if racing.is_empty() && candidates.is_empty() && providers_done {
+                return Err(anyhow!("No provider sent block {}", cid));
+            }
+
+            let deadline = racing.iter().map(|peer| asked[peer] + PROVIDER_TIMEOUT).min();
+            tokio::select! {
+                response = want.responses.recv() => match response {
+                    Some(Response::Block(peer, data)) => {
+                        if let Some(start) = asked.get(&peer) {
+                            self.record_response_time(peer, start.elapsed());
+                        }
+                        // We have the block, so the other providers can stop sending it
+                        for other in asked.keys().filter(|other| **other != peer) {
+                            self.send_want(*other, cid, true, None);
+                        }
+                        debug!("Received block {} from {}", cid, peer);
+                        return Ok(data);
+                    }
+                    Some(Response::Missing(peer)) => {
+                        racing.remove(&peer);
+                    }
+                    None => return Err(anyhow!("Stopped waiting for block {}", cid)),
+                },
+                peer = providers.recv(), if !providers_done => match peer {
+                    Some(peer) => {
+                        lookup.take();
+                        if !asked.contains_key(&peer) && !candidates.contains(&peer) {
+                            if let Some(events) = events {
+                                let _ = events.send(FetchEvent::ProviderFound { cid, peer });
+                            }
+                            candidates.push(peer);
+                        }
+                    }
+                    None => {
+                        lookup.take();
+                        providers_done = true;
+                    }
+                },
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    // Make room for other providers; a late block is still accepted
+                    let now = Instant::now();
+                    racing.retain(|peer| asked[peer] + PROVIDER_TIMEOUT > now);
+                }
+            }
+        }
+    }
+
+    /// Number of peers that have sent us blocks
+    pub fn provider_count(&self) -> usize {
+        self.response_times.lock().unwrap().len()
+    }
+
+    /// Register interest in a block, so the inbound handler forwards it to us
+    fn want(&self, cid: Cid) -> Want {
+        let (sender, responses) = mpsc::unbounded_channel();
+        let mut wants = self.wants.lock().unwrap();
+        let id = wants.next_id;
+        wants.next_id += 1;
+        wants.waiting.entry(cid).or_default().push((id, sender.clone()));
+        Want {
+            cid,
+            id,
+            wants: self.wants.clone(),
+            sender,
+            responses,
+        }
+    }
+
+    /// Remove the candidate with the lowest known response time
+    ///
+    /// Peers that never sent us a block come after all known peers, in the
+    /// order they were discovered.
+    fn take_fastest(&self, candidates: &mut Vec<PeerId>) -> Option<PeerId> {
+        let response_times = self.response_times.lock().unwrap();
+        let (index, _) = candidates
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, peer)| response_times.get(*peer).copied().unwrap_or(Duration::MAX))?;
+        Some(candidates.remove(index))
+    }
+
+    /// Fold a new response time of a peer into its smoothed response time
+    fn record_response_time(&self, peer: PeerId, elapsed: Duration) {
+        let mut response_times = self.response_times.lock().unwrap();
+        let smoothed = match response_times.get(&peer) {
+            Some(previous) => (*previous * 3 + elapsed) / 4,
+            None => elapsed,
+        };
+        response_times.insert(peer, smoothed);
+    }
+
+    /// Send a want (or the cancellation of one) to a peer in the background
+    ///
+    /// # Arguments
+    /// * `peer` - Peer to send the want to
+    /// * `cid` - CID of the wanted block
+    /// * `cancel` - Cancel an earlier want instead of wanting the block
+    /// * `on_failure` - Where to report the peer as missing if the want can't be sent
+    fn send_want(&self, peer: PeerId, cid: Cid, cancel: bool, on_failure: Option<mpsc::UnboundedSender<Response>>) {
+        let mut control = self.control.clone();
+        let message = Message {
+            wantlist: vec![WantEntry {
+                cid,
+                cancel,
+                have: false,
+                send_dont_have: !cancel,
+            }],
+            ..Default::default()
+        };
+        tokio::spawn(
+            async move {
+                if let Err(e) = send_message(&mut control, peer, &message).await {
+                    debug!("Failed to send Bitswap message to {}: {}", peer, e);
+                    if let Some(sender) = on_failure {
+                        let _ = sender.send(Response::Missing(peer));
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+/// A response from a peer to one of our wants
+enum Response {
+    /// The peer sent the block
+    Block(PeerId, Vec<u8>),
+    /// The peer doesn't have the block or couldn't be asked for it
+    Missing(PeerId),
+}
+
+/// Blocks being waited for, with the channels of everyone waiting
+#[derive(Default)]
+struct Wants {
+    /// Identifier of the next registered want
+    next_id: u64,
+    /// Waiting requests by CID
+    waiting: HashMap<Cid, Vec<(u64, mpsc::UnboundedSender<Response>)>>,
+}
+
+/// Registered interest in a block, withdrawn when dropped
+struct Want {
+    /// CID of the wanted block
+    cid: Cid,
+    /// Identifier of this want among the wants for the same CID
+    id: u64,
+    /// Registry the want is part of
+    wants: Arc<Mutex<Wants>>,
+    /// Sender of the responses, for reporting failures of our own
+    sender: mpsc::UnboundedSender<Response>,
+    /// Responses from peers
+    responses: mpsc::UnboundedReceiver<Response>,
+}
+
+impl Drop for Want {
+    fn drop(&mut self) {
+        let mut wants = self.wants.lock().unwrap();
+        if let Some(waiting) = wants.waiting.get_mut(&self.cid) {
+            waiting.retain(|(id, _)| *id != self.id);
+            if waiting.is_empty() {
+                wants.waiting.remove(&self.cid);
+            }
+        }
+    }
+}
+
+/// An entry of a Bitswap wantlist
+#[derive(Debug, Clone)]
+struct WantEntry {
+    /// CID of the wanted block
+    cid: Cid,
+    /// Whether the entry cancels an earlier want
+    cancel: bool,
+    /// Whether only a HAVE is wanted instead of the block itself
+    have: bool,
+    /// Whether the sender wants to be told if the block is missing
+    send_dont_have: bool,
+}
+
+/// A Bitswap message
+#[derive(Debug, Default)]
+struct Message {
+    /// Changes to the sender's wantlist
+    wantlist: Vec<WantEntry>,
+    /// Blocks, with the CIDs computed from their data
+    blocks: Vec<(Cid, Vec<u8>)>,
+    /// CIDs of blocks the sender has
+    have: Vec<Cid>,
+    /// CIDs of blocks the sender doesn't have
+    dont_have: Vec<Cid>,
+}
+
+impl Message {
+    /// Encode the message
+    fn encode(&self) -> Vec<u8> {
+        let mut message = protobuf::Writer::new();
+        if !self.wantlist.is_empty() {
+            let mut wantlist = protobuf::Writer::new();
+            for entry in &self.wantlist {
+                let mut writer = protobuf::Writer::new();
+                writer.bytes(1, &entry.cid.to_bytes()).varint(2, 1);
+                if entry.cancel {
+                    writer.varint(3, 1);
+                }
+                if entry.have {
+                    writer.varint(4, 1);
+                }
+                if entry.send_dont_have {
+                    writer.varint(5, 1);
+                }
+                wantlist.bytes(1, &writer.finish());
+            }
+            message.bytes(1, &wantlist.finish());
+        }
+        for (cid, data) in &self.blocks {
+            message.bytes(3, &encode_payload(cid, data));
+        }
+        for (cid, dont_have) in self.have.iter().map(|cid| (cid, false)).chain(self.dont_have.iter().map(|cid| (cid, true))) {
+            let presence = protobuf::Writer::new().bytes(1, &cid.to_bytes()).varint(2, dont_have as u64).finish();
+            message.bytes(4, &presence);
+        }
+        message.finish()
+    }
+
+    /// Decode a message
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut message = Message::default();
+        let mut reader = protobuf::Reader::new(bytes);
+        while let Some((number, field)) = reader.next_field()? {
+            match number {
+                1 => message.wantlist = decode_wantlist(field.as_bytes()?)?,
+                // Bitswap 1.0.0 blocks, which are always CIDv0
+                2 => {
+                    let data = field.as_bytes()?;
+                    let cid = Cid::new_v0(Code::Sha2_256.digest(data))?;
+                    message.blocks.push((cid, data.to_vec()));
+                }
+                3 => message.blocks.push(decode_payload(field.as_bytes()?)?),
+                4 => {
+                    if let Some(cid) = decode_dont_have(field.as_bytes()?)? {
+                        message.dont_have.push(cid);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(message)
+    }
+}
+
+/// Decode the entries of a wantlist
+fn decode_wantlist(bytes: &[u8]) -> Result<Vec<WantEntry>> {
+    let mut entries = Vec::new();
+    let mut reader = protobuf::Reader::new(bytes);
+    while let Some((number, field)) = reader.next_field()? {
+        if number != 1 {
+            continue;
+        }
+        let (mut cid, mut cancel, mut have, mut send_dont_have) = (None, false, false, false);
+        let mut fields = protobuf::Reader::new(field.as_bytes()?);
+        while let Some((number, field)) = fields.next_field()? {
+            match number {
+                1 => cid = Some(Cid::try_from(field.as_bytes()?)?),
+                3 => cancel = field.as_varint()? == 1,
+                4 => have = field.as_varint()? == 1,
+                5 => send_dont_have = field.as_varint()? == 1,
+                _ => {}
+            }
+        }
+        if let Some(cid) = cid {
+            entries.push(WantEntry {
+                cid,
+                cancel,
+                have,
+                send_dont_have,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Encode a block as a Bitswap 1.1+ payload, prefixed with its CID's version, codec and hash function
+fn encode_payload(cid: &Cid, data: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::new();
+    protobuf::write_varint(&mut prefix, u64::from(cid.version()));
+    protobuf::write_varint(&mut prefix, cid.codec());
+    protobuf::write_varint(&mut prefix, cid.hash().code());
+    protobuf::write_varint(&mut prefix, u64::from(cid.hash().size()));
+    protobuf::Writer::new().bytes(1, &prefix).bytes(2, data).finish()
+}
+
+/// Decode a block of a Bitswap 1.1+ payload
+///
+/// The block comes with a CID prefix (version, codec and hash function); the
+/// CID is completed by hashing the data, so the block always matches its CID.
+fn decode_payload(bytes: &[u8]) -> Result<(Cid, Vec<u8>)> {
+    let mut prefix: &[u8] = &[];
+    let mut data: &[u8] = &[];
+    let mut reader = protobuf::Reader::new(bytes);
+    while let Some((number, field)) = reader.next_field()? {
+        match number {
+            1 => prefix = field.as_bytes()?,
+            2 => data = field.as_bytes()?,
+            _ => {}
+        }
+    }
+
+    let version = Version::try_from(protobuf::read_varint(&mut prefix)?)?;
+    let codec = protobuf::read_varint(&mut prefix)?;
+    let hash_code = protobuf::read_varint(&mut prefix)?;
+    let code = Code::try_from(hash_code).map_err(|_| anyhow!("Unsupported hash function 0x{:x}", hash_code))?;
+    let _verify = info_span!("verify", bytes = data.len()).entered();
+    let cid = Cid::new(version, codec, code.digest(data))?;
+    Ok((cid, data.to_vec()))
+}
+
+/// Decode a block presence, returning the CID if the sender doesn't have the block
+fn decode_dont_have(bytes: &[u8]) -> Result<Option<Cid>> {
+    let mut cid = None;
+    let mut dont_have = false;
+    let mut reader = protobuf::Reader::new(bytes);
+    while let Some((number, field)) = reader.next_field()? {
+        match number {
+            1 => cid = Some(Cid::try_from(field.as_bytes()?)?),
+            2 => dont_have = field.as_varint()? == 1,
+            _ => {}
+        }
+    }
+    Ok(cid.filter(|_| dont_have))
+}
+
+/// Everything needed to handle the messages peers send us
+#[derive(Clone)]
+struct Inbound {
+    /// Blocks we are waiting for
+    wants: Arc<Mutex<Wants>>,
+    /// Control for opening streams to send responses on
+    control: stream::Control,
+    /// The local blockstore, to answer wants from
+    blockstore: Blockstore,
+    /// Metrics of the node
+    metrics: Metrics,
+    /// The only peers that are sent blocks, if not everyone
+    allowlist: Option<Arc<HashSet<PeerId>>>,
+    /// Limits on the rates blocks are sent and received at
+    rates: RateLimits,
+}
+
+/// Accept Bitswap streams from peers and handle their messages
+async fn handle_inbound(inbound: Inbound, mut incoming: stream::IncomingStreams) {
+    while let Some((peer, stream)) = incoming.next().await {
+        tokio::spawn(handle_stream(inbound.clone(), peer, stream));
+    }
+}
+
+/// Read the messages a peer sends on a stream until it is closed
+async fn handle_stream(mut inbound: Inbound, peer: PeerId, mut stream: Stream) {
+    loop {
+        match read_message(&mut stream).await {
+            Ok(Some(mut message)) => {
+                // Not reading on holds the peer back once the stream's window is full
+                if let Some(download) = &inbound.rates.download {
+                    download.acquire(message.blocks.iter().map(|(_, data)| data.len()).sum()).await;
+                }
+                let wantlist = std::mem::take(&mut message.wantlist);
+                dispatch(&inbound.wants, peer, message);
+                if let Err(e) = answer_wants(&mut inbound, peer, wantlist).await {
+                    debug!("Failed to answer the wants of {}: {}", peer, e);
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                debug!("Bitswap stream from {} failed: {}", peer, e);
+                break;
+            }
+        }
+    }
+}
+
+/// Forward the blocks and presences of a message to everyone waiting for them
+fn dispatch(wants: &Mutex<Wants>, peer: PeerId, message: Message) {
+    let wants = wants.lock().unwrap();
+    for (cid, data) in message.blocks {
+        for (_, sender) in wants.waiting.get(&cid).into_iter().flatten() {
+            let _ = sender.send(Response::Block(peer, data.clone()));
+        }
+    }
+    for cid in message.dont_have {
+        for (_, sender) in wants.waiting.get(&cid).into_iter().flatten() {
+            let _ = sender.send(Response::Missing(peer));
+        }
+    }
+}
+
+/// Answer the wants of a peer from the local blockstore
+///
+/// Blocks are sent one message at a time, so a long wantlist doesn't build a
+/// message larger than the peer accepts. Cancelled wants need no answer, as
+/// every want is answered right away.
+async fn answer_wants(inbound: &mut Inbound, peer: PeerId, wantlist: Vec<WantEntry>) -> Result<()> {
+    // Peers that aren't allowed are told we don't have anything, so they move on to other providers
+    let allowed = inbound.allowlist.as_ref().is_none_or(|allowlist| allowlist.contains(&peer));
+    if !allowed && !wantlist.is_empty() {
+        debug!("Refusing the wants of {}, which is not on the allowlist", peer);
+    }
+
+    let mut presences = Message::default();
+    for entry in wantlist.into_iter().filter(|entry| !entry.cancel) {
+        let data = match inbound.blockstore.get(&entry.cid) {
+            Ok(data) if allowed => data,
+            Ok(_) => None,
+            Err(e) => {
+                debug!("Failed to read block {} wanted by {}: {}", entry.cid, peer, e);
+                None
+            }
+        };
+        match data {
+            Some(_) if entry.have => presences.have.push(entry.cid),
+            Some(data) => {
+                if let Some(upload) = &inbound.rates.upload {
+                    upload.acquire(data.len()).await;
+                }
+                debug!("Sending block {} to {}", entry.cid, peer);
+                inbound.metrics.blocks_served.inc();
+                inbound.metrics.block_bytes_served.inc_by(data.len() as u64);
+                let message = Message {
+                    blocks: vec![(entry.cid, data)],
+                    ..Default::default()
+                };
+                send_message(&mut inbound.control, peer, &message).await?;
+            }
+            None if entry.send_dont_have => presences.dont_have.push(entry.cid),
+            None => {}
+        }
+    }
+    if !presences.have.is_empty() || !presences.dont_have.is_empty() {
+        send_message(&mut inbound.control, peer, &presences).await?;
+    }
+    Ok(())
+}
+
+/// Open a stream to a peer and send a single message on it
+async fn send_message(control: &mut stream::Control, peer: PeerId, message: &Message) -> Result<()> {
+    let mut stream = control
+        .open_stream(peer, PROTOCOL)
+        .instrument(info_span!("dial", peer = %peer))
+        .await?;
+    write_message(&mut stream, message).await?;
+    stream.close().await?;
+    Ok(())
+}
+
+/// Write a length-prefixed message to a stream
+async fn write_message(stream: &mut Stream, message: &Message) -> Result<()> {
+    let body = message.encode();
+    let mut frame = Vec::with_capacity(body.len() + 10);
+    protobuf::write_varint(&mut frame, body.len() as u64);
+    frame.extend_from_slice(&body);
+    stream.write_all(&frame).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Read a length-prefixed message from a stream
+///
+/// # Returns
+/// * `Result<Option<Message>>` - The message, or None if the stream was closed
+async fn read_message(stream: &mut Stream) -> Result<Option<Message>> {
+    // Read the length prefix one byte at a time, so nothing after it is consumed
+    let mut len = 0u64;
+    for i in 0..10 {
+        let mut byte = [0u8];
+        if stream.read(&mut byte).await? == 0 {
+            if i == 0 {
+                return Ok(None);
+            }
+            return Err(anyhow!("Truncated Bitswap message length"));
+        }
+        len |= u64::from(byte[0] & 0x7f) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let len = usize::try_from(len)?;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(anyhow!("Bitswap message of {} bytes is too large", len));
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+    Ok(Some(Message::decode(&body)?))
+}
@@ -0,0 +1,78 @@
+//! In-memory cache of recently used blocks
+//!
+//! The blockstore keeps the blocks it reads and writes in a least recently
+//! used cache bounded by their total size, so the blocks a DAG traversal, the
+//! gateway or Bitswap requests of other peers come back to are served from
+//! memory instead of the filesystem.
+
+use cid::multihash::Multihash;
+use std::collections::{BTreeMap, HashMap};
+
+/// A cache of blocks bounded by their total size, evicting the least recently used first
+#[derive(Debug)]
+pub struct BlockCache {
+    /// Maximum total size of the cached blocks, in bytes
+    capacity: u64,
+    /// Total size of the cached blocks, in bytes
+    used: u64,
+    /// Cached blocks with the tick they were last used at
+    blocks: HashMap<Multihash<64>, (Vec<u8>, u64)>,
+    /// Cached blocks by the tick they were last used at, least recently used first
+    recency: BTreeMap<u64, Multihash<64>>,
+    /// Counter ordering the uses of blocks
+    tick: u64,
+}
+
+impl BlockCache {
+    /// Create an empty cache holding up to `capacity` bytes of blocks
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            used: 0,
+            blocks: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Get a cached block, marking it as the most recently used
+    pub fn get(&mut self, hash: &Multihash<64>) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let (data, last_used) = self.blocks.get_mut(hash)?;
+        self.recency.remove(last_used);
+        self.recency.insert(self.tick, *hash);
+        *last_used = self.tick;
+        Some(data.clone())
+    }
+
+    /// Cache a block, evicting the least recently used blocks to make room
+    ///
+    /// Blocks larger than the whole cache aren't cached.
+    pub fn insert(&mut self, hash: &Multihash<64>, data: &[u8]) {
+        let size = data.len() as u64;
+        if size > self.capacity {
+            return;
+        }
+        self.remove(hash);
+        while self.used + size > self.capacity {
+            let Some((_, evicted)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some((data, _)) = self.blocks.remove(&evicted) {
+                self.used -= data.len() as u64;
+            }
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, *hash);
+        self.blocks.insert(*hash, (data.to_vec(), self.tick));
+        self.used += size;
+    }
+
+    /// Drop a block from the cache
+    pub fn remove(&mut self, hash: &Multihash<64>) {
+        if let Some((data, last_used)) = self.blocks.remove(hash) {
+            self.recency.remove(&last_used);
+            self.used -= data.len() as u64;
+        }
+    }
+}
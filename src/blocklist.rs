@@ -0,0 +1,266 @@
+//! Blocklist of peers and IP ranges
+//!
+//! Operators of public nodes can exclude abusive or broken peers, either by
+//! their peer ID or by the IP addresses they connect from. Connections to and
+//! from blocked peers are refused as they are established, and peers that
+//! become blocked while connected are disconnected.
+
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+use libp2p::{
+    core::{transport::PortUse, Endpoint},
+    multiaddr::Protocol,
+    swarm::{
+        dummy, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent,
+        THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::Infallible,
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+/// A peer or range of IP addresses that may not connect
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BlocklistEntry {
+    /// A peer, by its peer ID
+    Peer(PeerId),
+    /// A range of IP addresses, such as `192.0.2.0/24`; a single address is a range of one
+    Ip(IpNet),
+}
+
+impl BlocklistEntry {
+    /// Whether the entry covers an address
+    fn matches_addr(&self, addr: &Multiaddr) -> bool {
+        let BlocklistEntry::Ip(range) = self else {
+            return false;
+        };
+        addr.iter().any(|protocol| match protocol {
+            Protocol::Ip4(ip) => range.contains(&IpAddr::V4(ip)),
+            Protocol::Ip6(ip) => range.contains(&IpAddr::V6(ip)),
+            _ => false,
+        })
+    }
+}
+
+impl FromStr for BlocklistEntry {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(range) = s.parse::<IpNet>() {
+            return Ok(BlocklistEntry::Ip(range));
+        }
+        if let Ok(ip) = s.parse::<IpAddr>() {
+            return Ok(BlocklistEntry::Ip(ip.into()));
+        }
+        s.parse()
+            .map(BlocklistEntry::Peer)
+            .map_err(|_| anyhow!("{:?} is neither a peer ID nor an IP address or range", s))
+    }
+}
+
+impl fmt::Display for BlocklistEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlocklistEntry::Peer(peer) => write!(f, "{}", peer),
+            BlocklistEntry::Ip(range) => write!(f, "{}", range),
+        }
+    }
+}
+
+impl Serialize for BlocklistEntry {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for BlocklistEntry {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Why a connection was refused
+#[derive(Debug)]
+struct Blocked(BlocklistEntry);
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is blocked", self.0)
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+/// Behaviour refusing connections covered by the blocklist
+#[derive(Debug, Default)]
+pub struct Behaviour {
+    /// The blocked peers and IP ranges
+    entries: Vec<BlocklistEntry>,
+}
+
+impl Behaviour {
+    /// Create the behaviour with an initial blocklist
+    pub(crate) fn new(entries: impl IntoIterator<Item = BlocklistEntry>) -> Self {
+        let mut behaviour = Self::default();
+        for entry in entries {
+            behaviour.block(entry);
+        }
+        behaviour
+    }
+
+    /// Add an entry, returning false if it was already on the list
+    pub(crate) fn block(&mut self, entry: BlocklistEntry) -> bool {
+        if self.entries.contains(&entry) {
+            return false;
+        }
+        self.entries.push(entry);
+        true
+    }
+
+    /// Remove an entry, returning false if it wasn't on the list
+    pub(crate) fn unblock(&mut self, entry: &BlocklistEntry) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|blocked| blocked != entry);
+        self.entries.len() != len
+    }
+
+    /// The blocked peers and IP ranges, in the order they were added
+    pub(crate) fn entries(&self) -> &[BlocklistEntry] {
+        &self.entries
+    }
+
+    /// Whether a connection to a peer at an address is refused
+    pub(crate) fn is_blocked(&self, peer: Option<&PeerId>, addr: &Multiaddr) -> bool {
+        self.check(peer, addr).is_err()
+    }
+
+    /// Refuse a connection if the peer or its address is on the list
+    fn check(&self, peer: Option<&PeerId>, addr: &Multiaddr) -> Result<(), ConnectionDenied> {
+        let blocked = self.entries.iter().find(|entry| match entry {
+            BlocklistEntry::Peer(blocked) => Some(blocked) == peer,
+            BlocklistEntry::Ip(_) => entry.matches_addr(addr),
+        });
+        match blocked {
+            Some(entry) => Err(ConnectionDenied::new(Blocked(*entry))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Infallible;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        _local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        // Refuse blocked IP ranges before the handshake is even started
+        self.check(None, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        peer: PeerId,
+        _local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.check(Some(&peer), remote_addr)?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        peer: Option<PeerId>,
+        _addresses: &[Multiaddr],
+        _effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        // Addresses may still be DNS names here, so IP ranges are checked once connected
+        if let Some(peer) = peer {
+            self.check(Some(&peer), &Multiaddr::empty())?;
+        }
+        Ok(Vec::new())
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        _role_override: Endpoint,
+        _port_use: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.check(Some(&peer), addr)?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, _event: FromSwarm) {}
+
+    fn on_connection_handler_event(&mut self, _peer: PeerId, _connection_id: ConnectionId, event: THandlerOutEvent<Self>) {
+        match event {}
+    }
+
+    fn poll(&mut self, _cx: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "12D3KooWHguJ3iYADpCL53mkn4fSH2H3qQBJ5RSp44pTKPxLqySm";
+
+    fn entry(s: &str) -> BlocklistEntry {
+        s.parse().unwrap_or_else(|e| panic!("{:?} didn't parse: {}", s, e))
+    }
+
+    #[test]
+    fn parses_peers_addresses_and_ranges() {
+        assert_eq!(entry(PEER), BlocklistEntry::Peer(PEER.parse().unwrap()));
+        assert_eq!(entry("192.0.2.0/24"), BlocklistEntry::Ip("192.0.2.0/24".parse().unwrap()));
+        assert_eq!(entry("192.0.2.7"), BlocklistEntry::Ip("192.0.2.7/32".parse().unwrap()));
+        assert_eq!(entry("2001:db8::1"), BlocklistEntry::Ip("2001:db8::1/128".parse().unwrap()));
+        assert_eq!(entry("2001:db8::/32"), BlocklistEntry::Ip("2001:db8::/32".parse().unwrap()));
+    }
+
+    #[test]
+    fn rejects_other_text() {
+        for invalid in ["", "example.com", "192.0.2.0/33", "300.0.0.1", "12D3KooW", "/ip4/192.0.2.7"] {
+            assert!(invalid.parse::<BlocklistEntry>().is_err(), "{:?} parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_text() {
+        for s in [PEER, "192.0.2.0/24", "2001:db8::/32"] {
+            assert_eq!(entry(s).to_string(), s);
+            let json = serde_json::to_string(&entry(s)).unwrap();
+            assert_eq!(serde_json::from_str::<BlocklistEntry>(&json).unwrap(), entry(s));
+        }
+    }
+
+    #[test]
+    fn blocks_peers_and_addresses_in_ranges() {
+        let blocklist = Behaviour::new([entry(PEER), entry("192.0.2.0/24")]);
+        let peer = PEER.parse().unwrap();
+        let other = PeerId::random();
+        let inside = "/ip4/192.0.2.7/tcp/4001".parse().unwrap();
+        let outside = "/ip4/198.51.100.7/tcp/4001".parse().unwrap();
+        assert!(blocklist.is_blocked(Some(&peer), &outside));
+        assert!(blocklist.is_blocked(Some(&other), &inside));
+        assert!(blocklist.is_blocked(None, &inside));
+        assert!(!blocklist.is_blocked(Some(&other), &outside));
+    }
+}
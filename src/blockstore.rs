@@ -0,0 +1,521 @@
+//! Persistent on-disk blockstore
+//!
+//! Blocks are stored one file per block in a flatfs-style layout compatible with
+//! Kubo's default `next-to-last/2` sharding: each block is keyed by the base32
+//! encoding of its multihash and placed in a directory named after the next-to-last
+//! two characters of that key. Keying by multihash means the same bytes are stored
+//! only once, regardless of the CID version or codec used to refer to them.
+//!
+//! A bloom filter of the stored blocks sits in front of the files, so looking
+//! up a block that isn't stored, as DAG traversals and Bitswap requests of
+//! other peers often do, doesn't touch the filesystem. It is built from the
+//! files in a background thread on first use and once it holds more blocks
+//! than it was sized for, lookups going to the filesystem meanwhile, and after
+//! garbage collection. Every stored block is also appended to a journal next
+//! to the shards, which processes sharing the repository read from where they
+//! left off whenever a block is missing from their filter, so blocks written
+//! by the others show up right away. The journal only grows, by the size of a
+//! multihash per block stored.
+//!
+//! Optionally, recently read and written blocks are also kept in memory (see
+//! [`Blockstore::with_cache`]), so popular blocks are served without reading
+//! their files again.
+
+use anyhow::{anyhow, Context, Result};
+use cid::{multibase::Base, multihash::Multihash, Cid};
+use multihash_codetable::{Code, MultihashDigest};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    thread,
+    time::Duration,
+};
+use tempfile::NamedTempFile;
+
+use crate::{block_cache::BlockCache, bloom::Bloom, error::MigaError};
+
+/// Multicodec code of raw binary blocks
+pub const RAW_CODEC: u64 = 0x55;
+
+/// Version of the on-disk layout of the repository, bumped whenever it changes incompatibly
+pub const DATASTORE_VERSION: u32 = 1;
+
+/// Sharding function identifier, written to the `SHARDING` file like flatfs does
+const SHARDING: &str = "/repo/flatfs/shard/v1/next-to-last/2";
+
+/// Name of the journal of stored blocks, in the blockstore directory
+const JOURNAL: &str = "journal";
+
+/// Smallest number of blocks the bloom filter is sized for
+const MIN_FILTER_CAPACITY: usize = 1024;
+
+/// A content-addressed block store backed by the local filesystem
+///
+/// Clones share the bloom filter of the stored blocks, and the cache of recent blocks.
+#[derive(Debug, Clone)]
+pub struct Blockstore {
+    /// Root directory of the blockstore
+    root: PathBuf,
+    /// Bloom filter of the stored blocks
+    filter: Arc<RwLock<FilterState>>,
+    /// Journal of the blocks stored by every process, None if it couldn't be opened
+    journal: Option<Arc<File>>,
+    /// Recently read and written blocks, if cached
+    cache: Option<Arc<Mutex<BlockCache>>>,
+}
+
+/// The bloom filter of a blockstore and its rebuild
+#[derive(Debug, Default)]
+struct FilterState {
+    /// The filter, None until first built
+    bloom: Option<Bloom>,
+    /// Whether a rebuild is running, so only one runs at a time
+    rebuilding: bool,
+    /// Whether the last rebuild failed, so lookups don't retry it over and over
+    failed: bool,
+    /// Blocks stored while the rebuild runs, which it may have missed
+    stored_during_rebuild: Vec<Multihash<64>>,
+    /// Length of the journal whose blocks are in the filter
+    journal_read: u64,
+}
+
+impl FilterState {
+    /// Add a stored block to the filter, and to the rebuild if one is running
+    fn insert(&mut self, hash: &Multihash<64>) {
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(hash);
+        }
+        if self.rebuilding {
+            self.stored_during_rebuild.push(*hash);
+        }
+    }
+}
+
+impl Blockstore {
+    /// Open the blockstore at the given directory, creating it if needed
+    ///
+    /// # Arguments
+    /// * `root` - Directory holding the blocks
+    ///
+    /// # Returns
+    /// * `Result<Blockstore>` - The opened blockstore
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)
+            .with_context(|| format!("Failed to create blockstore directory {:?}", root))?;
+
+        let sharding_file = root.join("SHARDING");
+        if !sharding_file.exists() {
+            fs::write(&sharding_file, format!("{SHARDING}\n"))?;
+        }
+
+        // Without the journal, e.g. on a read-only repository, the filesystem
+        // decides for the blocks missing from the filter
+        let journal = match OpenOptions::new().create(true).append(true).open(root.join(JOURNAL)) {
+            Ok(journal) => Some(Arc::new(journal)),
+            Err(e) => {
+                log::debug!("Failed to open the journal of the blockstore: {}", e);
+                None
+            }
+        };
+
+        Ok(Self {
+            root,
+            filter: Arc::new(RwLock::new(FilterState::default())),
+            journal,
+            cache: None,
+        })
+    }
+
+    /// Keep recently read and written blocks in memory, up to `capacity` bytes of them
+    ///
+    /// The least recently used blocks are dropped to make room for new ones.
+    /// Blocks removed by another process on the same repository may still be
+    /// served from the cache until they are dropped. A capacity of 0 disables
+    /// the cache.
+    pub fn with_cache(mut self, capacity: u64) -> Self {
+        self.cache = (capacity > 0).then(|| Arc::new(Mutex::new(BlockCache::new(capacity))));
+        self
+    }
+
+    /// Root directory of the blockstore
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Check whether a block is present
+    pub fn has(&self, cid: &Cid) -> bool {
+        self.may_have(cid.hash()) && self.block_path(cid.hash()).exists()
+    }
+
+    /// Read a block
+    ///
+    /// # Returns
+    /// * `Result<Option<Vec<u8>>>` - The block data, or None if the block isn't stored
+    pub fn get(&self, cid: &Cid) -> Result<Option<Vec<u8>>> {
+        if let Some(data) = self.cache.as_ref().and_then(|cache| cache.lock().unwrap().get(cid.hash())) {
+            return Ok(Some(data));
+        }
+        if !self.may_have(cid.hash()) {
+            return Ok(None);
+        }
+        let path = self.block_path(cid.hash());
+        match fs::read(&path) {
+            Ok(data) => {
+                if let Some(cache) = &self.cache {
+                    cache.lock().unwrap().insert(cid.hash(), &data);
+                }
+                Ok(Some(data))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read block {}", cid)),
+        }
+    }
+
+    /// Store a block
+    ///
+    /// Storing a block that is already present is a no-op. Callers are expected
+    /// to have verified the data against the CID (see [`verify`]).
+    pub fn put(&self, cid: &Cid, data: &[u8]) -> Result<()> {
+        let path = self.block_path(cid.hash());
+        if path.exists() {
+            // The block may have been stored by another process, unknown to the filter
+            if self.filter.read().unwrap().bloom.as_ref().is_some_and(|bloom| !bloom.contains(cid.hash())) {
+                self.filter.write().unwrap().insert(cid.hash());
+            }
+            return Ok(());
+        }
+
+        let dir = path.parent().expect("block paths always have a shard directory");
+        fs::create_dir_all(dir)?;
+
+        // Write to a uniquely named temporary file and rename it into place, so
+        // an interrupted write never leaves a truncated block behind and
+        // concurrent writes of the same block don't trip over each other
+        let mut tmp = NamedTempFile::new_in(dir).with_context(|| format!("Failed to write block {}", cid))?;
+        tmp.write_all(data).with_context(|| format!("Failed to write block {}", cid))?;
+        match tmp.persist_noclobber(&path) {
+            Ok(_) => self.journal_block(cid)?,
+            // Another writer stored the same block first, and journals it
+            Err(e) if e.error.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => {
+                return Err(e.error).with_context(|| format!("Failed to move block {} into place at {:?}", cid, path));
+            }
+        }
+        self.filter.write().unwrap().insert(cid.hash());
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().insert(cid.hash(), data);
+        }
+        Ok(())
+    }
+
+    /// Check that blocks can be written, by writing and removing a file next to them
+    pub fn check_writable(&self) -> Result<()> {
+        let probe = self.root.join(".write-check");
+        fs::write(&probe, b"")
+            .and_then(|()| fs::remove_file(&probe))
+            .with_context(|| format!("Blockstore {:?} is not writable", self.root))
+    }
+
+    /// Remove a block
+    ///
+    /// # Returns
+    /// * `Result<bool>` - Whether the block was present
+    pub fn remove(&self, cid: &Cid) -> Result<bool> {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().remove(cid.hash());
+        }
+        match fs::remove_file(self.block_path(cid.hash())) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("Failed to remove block {}", cid)),
+        }
+    }
+
+    /// Size of a stored block in bytes, or None if the block isn't stored
+    pub fn size(&self, cid: &Cid) -> Result<Option<u64>> {
+        match fs::metadata(self.block_path(cid.hash())) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Time since a block was stored, or None if the block isn't stored
+    ///
+    /// Storing a block again leaves it untouched, so this is the time since it
+    /// was first stored, e.g. fetched.
+    pub fn age(&self, cid: &Cid) -> Result<Option<Duration>> {
+        match fs::metadata(self.block_path(cid.hash())) {
+            // A clock set back makes blocks from the future, which count as new
+            Ok(metadata) => Ok(Some(metadata.modified()?.elapsed().unwrap_or_default())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// List all stored blocks
+    ///
+    /// Blocks are keyed by multihash only, so the returned CIDs use the raw codec.
+    pub fn list(&self) -> Result<Vec<Cid>> {
+        let mut cids = Vec::new();
+        for shard in fs::read_dir(&self.root)? {
+            let shard = shard?;
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(shard.path())? {
+                let name = entry?.file_name();
+                let Some(key) = name.to_str().and_then(|name| name.strip_suffix(".data")) else {
+                    continue;
+                };
+                match parse_block_key(key) {
+                    Ok(hash) => cids.push(Cid::new_v1(RAW_CODEC, hash)),
+                    Err(e) => log::warn!("Skipping unrecognized file in blockstore: {}: {}", key, e),
+                }
+            }
+        }
+        Ok(cids)
+    }
+
+    /// Rebuild the bloom filter from the stored blocks
+    ///
+    /// Removed blocks stay in the filter, where they only cost a filesystem
+    /// lookup, until it is rebuilt; garbage collection does so after removing
+    /// blocks. Nothing happens if a rebuild is already running.
+    pub fn rebuild_filter(&self) -> Result<()> {
+        {
+            let mut filter = self.filter.write().unwrap();
+            if filter.rebuilding {
+                return Ok(());
+            }
+            filter.rebuilding = true;
+        }
+
+        // Blocks are journaled once stored, so the ones journaled so far are listed
+        let journal_len = self.journal_len();
+        let result = self.list();
+        let mut filter = self.filter.write().unwrap();
+        filter.rebuilding = false;
+        let stored_during_rebuild = std::mem::take(&mut filter.stored_during_rebuild);
+        filter.failed = result.is_err();
+        let hashes = result?;
+
+        let mut bloom = Bloom::with_capacity((hashes.len() * 2).max(MIN_FILTER_CAPACITY));
+        for hash in hashes.iter().map(Cid::hash).chain(&stored_during_rebuild) {
+            bloom.insert(hash);
+        }
+        log::debug!("Built the bloom filter of {} blocks", hashes.len());
+        filter.bloom = Some(bloom);
+        filter.journal_read = journal_len;
+        Ok(())
+    }
+
+    /// Rebuild the bloom filter in a background thread, unless a rebuild is already running
+    fn refresh_filter(&self) {
+        if self.filter.read().unwrap().rebuilding {
+            return;
+        }
+        let blockstore = self.clone();
+        let spawned = thread::Builder::new().name("blockstore-filter".to_string()).spawn(move || {
+            if let Err(e) = blockstore.rebuild_filter() {
+                log::warn!("Failed to build the bloom filter of the blockstore: {}", e);
+            }
+        });
+        if let Err(e) = spawned {
+            log::warn!("Failed to start building the bloom filter of the blockstore: {}", e);
+        }
+    }
+
+    /// Whether a block may be stored, false if it certainly isn't
+    ///
+    /// Blocks missing from the filter are looked up again once the blocks
+    /// journaled since it was last updated are added to it. Until the filter
+    /// is built, and once it holds more blocks than it was sized for, every
+    /// block may be stored, so the filesystem decides while it is rebuilt in
+    /// the background. After a failed rebuild, the filesystem decides until
+    /// the next explicit rebuild.
+    fn may_have(&self, hash: &Multihash<64>) -> bool {
+        let (built, failed) = {
+            let filter = self.filter.read().unwrap();
+            let bloom = filter.bloom.as_ref().filter(|bloom| !bloom.is_full());
+            if bloom.is_some_and(|bloom| bloom.contains(hash)) {
+                return true;
+            }
+            (bloom.is_some(), filter.failed)
+        };
+        if built {
+            return self.catch_up(hash);
+        }
+        if !failed {
+            self.refresh_filter();
+        }
+        true
+    }
+
+    /// Add the blocks journaled since the filter was last updated, and look a block up again
+    fn catch_up(&self, hash: &Multihash<64>) -> bool {
+        let journal_len = self.journal_len();
+        let mut filter = self.filter.write().unwrap();
+        if journal_len == filter.journal_read {
+            return filter.bloom.as_ref().is_none_or(|bloom| bloom.contains(hash));
+        }
+
+        // A shorter journal has been replaced, so the filter is rebuilt from the files
+        let read = (journal_len > filter.journal_read)
+            .then(|| read_journal(&self.root.join(JOURNAL), filter.journal_read, journal_len))
+            .transpose()
+            .unwrap_or_else(|e| {
+                log::warn!("Failed to read the journal of the blockstore: {:#}", e);
+                None
+            });
+        let Some((hashes, len)) = read else {
+            filter.journal_read = journal_len;
+            drop(filter);
+            self.refresh_filter();
+            return true;
+        };
+        for stored in &hashes {
+            filter.insert(stored);
+        }
+        filter.journal_read += len;
+        filter.bloom.as_ref().is_none_or(|bloom| bloom.contains(hash))
+    }
+
+    /// Append a stored block to the journal, for the other processes on the repository
+    fn journal_block(&self, cid: &Cid) -> Result<()> {
+        let Some(journal) = &self.journal else {
+            return Ok(());
+        };
+        // Each entry is the length of the multihash followed by its bytes,
+        // written at once so entries of concurrent writers don't interleave
+        let bytes = cid.hash().to_bytes();
+        let mut entry = Vec::with_capacity(bytes.len() + 1);
+        entry.push(bytes.len() as u8);
+        entry.extend_from_slice(&bytes);
+        (&**journal)
+            .write_all(&entry)
+            .with_context(|| format!("Failed to add block {} to the journal", cid))
+    }
+
+    /// Current length of the journal, 0 without one
+    fn journal_len(&self) -> u64 {
+        self.journal.as_ref().and_then(|journal| journal.metadata().ok()).map_or(0, |metadata| metadata.len())
+    }
+
+    /// Path of the file holding the block with the given multihash
+    fn block_path(&self, hash: &Multihash<64>) -> PathBuf {
+        let key = block_key(hash);
+        let shard = &key[key.len() - 3..key.len() - 1];
+        self.root.join(shard).join(format!("{key}.data"))
+    }
+}
+
+/// Verify that a block's data hashes to the given CID
+///
+/// # Arguments
+/// * `cid` - The CID the data is expected to match
+/// * `data` - The block data
+///
+/// # Returns
+/// * `Result<()>` - Ok if the data matches, Err otherwise
+pub fn verify(cid: &Cid, data: &[u8]) -> Result<()> {
+    let hash = cid.hash();
+    let code = Code::try_from(hash.code())
+        .map_err(|_| anyhow!("Unsupported hash function 0x{:x} in CID {}", hash.code(), cid))?;
+
+    if code.digest(data).digest() != hash.digest() {
+        return Err(MigaError::VerificationFailed(*cid).into());
+    }
+    Ok(())
+}
+
+/// Blockstore key of a multihash: its bytes in unpadded upper-case base32
+fn block_key(hash: &Multihash<64>) -> String {
+    Base::Base32Upper.encode(hash.to_bytes())
+}
+
+/// Read the entries of the journal between two offsets
+///
+/// # Returns
+/// * `Result<(Vec<Multihash<64>>, u64)>` - The multihashes of the complete entries and their
+///   length; an entry still being written is left for the next read
+fn read_journal(path: &Path, start: u64, end: u64) -> Result<(Vec<Multihash<64>>, u64)> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut bytes = Vec::new();
+    file.take(end - start).read_to_end(&mut bytes)?;
+
+    let mut hashes = Vec::new();
+    let mut read = 0;
+    while let Some((&len, rest)) = bytes[read..].split_first()
+        && rest.len() >= len as usize
+    {
+        hashes.push(Multihash::from_bytes(&rest[..len as usize])?);
+        read += 1 + len as usize;
+    }
+    Ok((hashes, read as u64))
+}
+
+/// Parse a blockstore key back into a multihash
+fn parse_block_key(key: &str) -> Result<Multihash<64>> {
+    let bytes = Base::Base32Upper.decode(key)?;
+    Ok(Multihash::from_bytes(&bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(i: u32) -> (Cid, Vec<u8>) {
+        let data = i.to_le_bytes().to_vec();
+        (Cid::new_v1(RAW_CODEC, Code::Sha2_256.digest(&data)), data)
+    }
+
+    #[test]
+    fn finds_blocks_stored_by_other_processes() {
+        let dir = tempfile::tempdir().unwrap();
+        let reader = Blockstore::open(dir.path()).unwrap();
+        let writer = Blockstore::open(dir.path()).unwrap();
+        reader.rebuild_filter().unwrap();
+
+        let (cid, data) = block(0);
+        assert!(!reader.has(&cid));
+        writer.put(&cid, &data).unwrap();
+        assert!(reader.has(&cid));
+        assert_eq!(reader.get(&cid).unwrap(), Some(data));
+        assert!(!reader.has(&block(1).0));
+    }
+
+    #[test]
+    fn rebuild_keeps_blocks_journaled_afterwards() {
+        let dir = tempfile::tempdir().unwrap();
+        let reader = Blockstore::open(dir.path()).unwrap();
+        let writer = Blockstore::open(dir.path()).unwrap();
+        let (first, data) = block(0);
+        writer.put(&first, &data).unwrap();
+        reader.rebuild_filter().unwrap();
+
+        let (second, data) = block(1);
+        writer.put(&second, &data).unwrap();
+        assert!(reader.has(&first));
+        assert!(reader.has(&second));
+    }
+
+    #[test]
+    fn skips_an_entry_still_being_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let blockstore = Blockstore::open(dir.path()).unwrap();
+        let (cid, data) = block(0);
+        blockstore.put(&cid, &data).unwrap();
+
+        let path = dir.path().join(JOURNAL);
+        let len = fs::metadata(&path).unwrap().len();
+        let (hashes, read) = read_journal(&path, 0, len).unwrap();
+        assert_eq!(hashes, vec![*cid.hash()]);
+        assert_eq!(read, len);
+        assert_eq!(read_journal(&path, 0, len - 1).unwrap(), (vec![], 0));
+    }
+}
@@ -0,0 +1,130 @@
+//! Bloom filter of multihashes
+//!
+//! A bloom filter tells for certain that an item was never inserted, and
+//! only probably that it was. The blockstore keeps one of the blocks it
+//! holds, so looking up a block it doesn't have needs no filesystem access.
+
+use cid::multihash::Multihash;
+use std::{
+    fmt,
+    hash::{BuildHasher, RandomState},
+};
+
+/// Bits per item, for a false positive rate of about 1%
+const BITS_PER_ITEM: usize = 10;
+
+/// Number of bits set per item, optimal for 10 bits per item
+const HASHES: u64 = 7;
+
+/// A bloom filter of multihashes, sized for a number of items
+pub struct Bloom {
+    /// The bits, 64 per word
+    words: Vec<u64>,
+    /// Number of bits
+    bits: u64,
+    /// Number of items the filter is sized for
+    capacity: usize,
+    /// Number of items inserted
+    len: usize,
+    /// Hasher of the items, seeded at random
+    hasher: RandomState,
+}
+
+impl Bloom {
+    /// Create an empty filter sized for a number of items
+    pub fn with_capacity(capacity: usize) -> Self {
+        let words = (capacity.max(1) * BITS_PER_ITEM).div_ceil(64);
+        Self {
+            words: vec![0; words],
+            bits: words as u64 * 64,
+            capacity,
+            len: 0,
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Insert an item
+    pub fn insert(&mut self, hash: &Multihash<64>) {
+        for bit in self.bit_indexes(hash) {
+            self.words[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    /// Whether an item may have been inserted; false means it certainly wasn't
+    pub fn contains(&self, hash: &Multihash<64>) -> bool {
+        self.bit_indexes(hash)
+            .all(|bit| self.words[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Whether more items were inserted than the filter is sized for, making false positives common
+    pub fn is_full(&self) -> bool {
+        self.len > self.capacity
+    }
+
+    /// Bits of an item, by double hashing
+    fn bit_indexes(&self, hash: &Multihash<64>) -> impl Iterator<Item = u64> + use<> {
+        let bytes = hash.to_bytes();
+        let first = self.hasher.hash_one(&bytes);
+        // The step is odd so it is never zero, which would set a single bit
+        let step = self.hasher.hash_one((&bytes, 1u8)) | 1;
+        let bits = self.bits;
+        (0..HASHES).map(move |i| first.wrapping_add(i.wrapping_mul(step)) % bits)
+    }
+}
+
+impl fmt::Debug for Bloom {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bloom")
+            .field("bits", &self.bits)
+            .field("capacity", &self.capacity)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use multihash_codetable::{Code, MultihashDigest};
+
+    fn hash(i: u32) -> Multihash<64> {
+        Code::Sha2_256.digest(&i.to_le_bytes())
+    }
+
+    #[test]
+    fn contains_inserted_items() {
+        let mut bloom = Bloom::with_capacity(100);
+        for i in 0..100 {
+            bloom.insert(&hash(i));
+        }
+        assert!((0..100).all(|i| bloom.contains(&hash(i))));
+    }
+
+    #[test]
+    fn rarely_contains_other_items() {
+        let mut bloom = Bloom::with_capacity(1000);
+        for i in 0..1000 {
+            bloom.insert(&hash(i));
+        }
+        let false_positives = (1000..11000).filter(|i| bloom.contains(&hash(*i))).count();
+        // About 1% are expected, allow for some variance
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn empty_filter_contains_nothing() {
+        let bloom = Bloom::with_capacity(0);
+        assert!(!bloom.contains(&hash(0)));
+    }
+
+    #[test]
+    fn full_once_over_capacity() {
+        let mut bloom = Bloom::with_capacity(2);
+        bloom.insert(&hash(0));
+        bloom.insert(&hash(1));
+        assert!(!bloom.is_full());
+        bloom.insert(&hash(2));
+        assert!(bloom.is_full());
+    }
+}
@@ -0,0 +1,123 @@
+//! Bootstrap list of a repository
+//!
+//! The bootstrap nodes a node joins the network through can be managed with
+//! `miga bootstrap`. The list is kept in a JSON file in the repo directory;
+//! as long as there is no file, the default IPFS bootstrap nodes are used.
+
+use anyhow::{Context, Result};
+use libp2p::Multiaddr;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::network;
+
+/// The bootstrap list of a repository, backed by a file on disk
+#[derive(Debug)]
+pub struct BootstrapList {
+    /// File the list is saved to
+    path: PathBuf,
+    /// The saved list, or `None` if the defaults are used
+    addrs: Option<Vec<Multiaddr>>,
+}
+
+impl BootstrapList {
+    /// Open the bootstrap list saved in the given file
+    ///
+    /// # Arguments
+    /// * `path` - File holding the list; it is created on the first change
+    ///
+    /// # Returns
+    /// * `Result<BootstrapList>` - The opened list
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let addrs = if path.exists() {
+            let data = fs::read(&path).with_context(|| format!("Failed to read bootstrap list from {:?}", path))?;
+            let addrs: Vec<String> =
+                serde_json::from_slice(&data).with_context(|| format!("Invalid bootstrap list {:?}", path))?;
+            let addrs = addrs
+                .iter()
+                .map(|addr| addr.parse().with_context(|| format!("Invalid address {:?} in {:?}", addr, path)))
+                .collect::<Result<_>>()?;
+            Some(addrs)
+        } else {
+            None
+        };
+        Ok(Self { path, addrs })
+    }
+
+    /// The saved list, if it was changed from the defaults
+    pub fn saved(&self) -> Option<&[Multiaddr]> {
+        self.addrs.as_deref()
+    }
+
+    /// The bootstrap nodes in use: the saved list, or the defaults
+    pub fn list(&self) -> Vec<Multiaddr> {
+        self.addrs.clone().unwrap_or_else(network::default_bootstrap_nodes)
+    }
+
+    /// Add a bootstrap node
+    ///
+    /// # Returns
+    /// * `Result<bool>` - Whether the node wasn't in the list yet
+    pub fn add(&mut self, addr: Multiaddr) -> Result<bool> {
+        let mut addrs = self.list();
+        if addrs.contains(&addr) {
+            return Ok(false);
+        }
+        addrs.push(addr);
+        self.addrs = Some(addrs);
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Remove a bootstrap node
+    ///
+    /// # Returns
+    /// * `Result<bool>` - Whether the node was in the list
+    pub fn remove(&mut self, addr: &Multiaddr) -> Result<bool> {
+        let mut addrs = self.list();
+        let len = addrs.len();
+        addrs.retain(|a| a != addr);
+        if addrs.len() == len {
+            return Ok(false);
+        }
+        self.addrs = Some(addrs);
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Remove all bootstrap nodes, so only the peers saved by the previous run are used
+    pub fn clear(&mut self) -> Result<()> {
+        self.addrs = Some(Vec::new());
+        self.save()
+    }
+
+    /// Go back to the default bootstrap nodes
+    pub fn reset(&mut self) -> Result<()> {
+        self.addrs = None;
+        if self.path.exists() {
+            fs::remove_file(&self.path).with_context(|| format!("Failed to remove {:?}", self.path))?;
+        }
+        Ok(())
+    }
+
+    /// Write the list to disk
+    fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let addrs: Vec<String> = self.list().iter().map(Multiaddr::to_string).collect();
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(&addrs)?)
+            .with_context(|| format!("Failed to save bootstrap list to {:?}", self.path))?;
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Location of the bootstrap list in a repository
+pub fn bootstrap_file(repo_dir: &Path) -> PathBuf {
+    repo_dir.join("bootstrap.json")
+}
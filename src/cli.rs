@@ -0,0 +1,761 @@
+//! Command line interface
+//!
+//! MIGA is driven through subcommands, each covering one task: fetching content,
+//! adding files, running a sharing node, and managing the local repository.
+//! Every flag can also be set through a `MIGA_*` environment variable, which
+//! is convenient in container deployments.
+
+use clap::{Args, Parser, Subcommand};
+use libp2p::{Multiaddr, PeerId};
+use miga::{
+    api::ApiAddr,
+    blocklist::BlocklistEntry,
+    config::{Config, LogFormat},
+    ipld::IpldCodec,
+    keypair::KeyType,
+    multiformats::{Codec, HashFunction, Multibase},
+    network::DhtMode,
+    throttle,
+};
+use std::{path::PathBuf, time::Duration};
+
+/// Command line arguments for the MIGA application
+///
+/// This struct defines the command-line interface for the application
+/// using the clap crate for argument parsing.
+#[derive(Parser, Debug)]
+#[clap(author, version, about = "A tool to fetch and share data on IPFS using libp2p")]
+pub struct Cli {
+    /// Enable verbose output for debugging
+    /// When enabled, additional information about the process will be displayed
+    #[clap(short, long, global = true, env = "MIGA_VERBOSE")]
+    pub verbose: bool,
+
+    /// Print all output as JSON lines instead of text, for scripts
+    /// Each line is an object whose "event" field tells what it reports
+    #[clap(long, global = true, env = "MIGA_JSON")]
+    pub json: bool,
+
+    /// Repository directory holding the blockstore and DHT records (default: ~/.miga)
+    /// Fetched blocks are stored here and survive restarts
+    #[clap(long, global = true, env = "MIGA_REPO")]
+    pub repo: Option<PathBuf>,
+
+    /// Path of the node identity key file (default: ~/.miga/identity.key)
+    /// The key is created on first use so that the peer ID stays the same across runs
+    #[clap(long, global = true, env = "MIGA_IDENTITY")]
+    pub identity: Option<PathBuf>,
+
+    /// Type of the identity key created on first use (default: ed25519)
+    /// Use secp256k1 or rsa to interoperate with tools expecting those; an existing key is used whatever its type
+    #[clap(long, global = true, value_enum, env = "MIGA_IDENTITY_TYPE")]
+    pub identity_type: Option<KeyType>,
+
+    /// Name of a key in the keystore to use as node identity instead of the identity key file
+    /// The key is decrypted with the keystore passphrase
+    #[clap(long, global = true, value_name = "NAME", conflicts_with = "identity", env = "MIGA_IDENTITY_KEY")]
+    pub identity_key: Option<String>,
+
+    /// Passphrase the keys of the keystore are encrypted with
+    /// Prefer setting it through the environment, as command lines are visible to other users
+    #[clap(long, global = true, env = "MIGA_KEYSTORE_PASSPHRASE", hide_env_values = true)]
+    pub keystore_passphrase: Option<String>,
+
+    /// Swarm key file of a private network to join instead of the public IPFS network (default: swarm.key in the repository, if it exists)
+    /// Only nodes with the same key can connect to each other
+    #[clap(long, global = true, value_name = "FILE", env = "MIGA_SWARM_KEY")]
+    pub swarm_key: Option<PathBuf>,
+
+    /// Configuration file (default: ~/.miga/config.toml)
+    /// Command line flags take precedence over the settings in the file
+    #[clap(long, global = true, env = "MIGA_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Bootstrap node to join the network through, ending in /p2p/<peer ID> (repeatable)
+    /// Replaces the default IPFS bootstrap nodes unless --keep-default-bootstrap is given
+    #[clap(long, global = true, value_name = "MULTIADDR", value_delimiter = ',', env = "MIGA_BOOTSTRAP")]
+    pub bootstrap: Vec<Multiaddr>,
+
+    /// Address to listen for IPFS connections on (repeatable)
+    /// Default: IPv4 and IPv6 over TCP and QUIC, on a random port, or port 4001 for the daemon
+    #[clap(long, global = true, value_name = "MULTIADDR", value_delimiter = ',', env = "MIGA_LISTEN")]
+    pub listen: Vec<Multiaddr>,
+
+    /// Use the bootstrap nodes from --bootstrap or the configuration file in addition to the default ones
+    #[clap(long, global = true, env = "MIGA_KEEP_DEFAULT_BOOTSTRAP")]
+    pub keep_default_bootstrap: bool,
+
+    /// Only use the blocks of the local repository, never the network
+    /// Content that isn't stored locally fails right away instead of being searched for
+    #[clap(long, global = true, env = "MIGA_OFFLINE")]
+    pub offline: bool,
+
+    /// How long fetched content that isn't pinned is served from the repository, e.g. "7d" (default: until `miga repo gc`)
+    /// Older unpinned blocks are removed before fetching, so they are fetched again from the network
+    #[clap(long, global = true, value_name = "DURATION", value_parser = humantime::parse_duration, env = "MIGA_CACHE_TTL")]
+    pub cache_ttl: Option<Duration>,
+
+    /// Size of the in-memory cache of recently used blocks, e.g. 256MiB (default: 64MiB, 0 disables the cache)
+    /// Speeds up DAG traversals, the gateway and Bitswap requests for popular blocks
+    #[clap(long, global = true, value_name = "SIZE", value_parser = throttle::parse_size, env = "MIGA_BLOCK_CACHE_SIZE")]
+    pub block_cache_size: Option<u64>,
+
+    /// Export traces of fetch operations to an OpenTelemetry collector
+    /// OTLP/HTTP traces endpoint, e.g. http://localhost:4318/v1/traces
+    #[clap(long, global = true, value_name = "URL", env = "MIGA_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Format of log lines
+    #[clap(long, global = true, value_enum, env = "MIGA_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Write logs to this file instead of stderr, starting a new file every day
+    /// The date is appended to the file name, e.g. miga.log.2024-01-31
+    #[clap(long, global = true, env = "MIGA_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Log filter directives, e.g. "info" or "miga=debug,libp2p=warn" (default: RUST_LOG, or "error")
+    #[clap(long, global = true, value_name = "DIRECTIVES", env = "MIGA_LOG_FILTER")]
+    pub log_filter: Option<String>,
+
+    /// Role of the node in the DHT (default: client for get and cat, server for daemon)
+    /// Clients only query the DHT; servers also answer other nodes' queries
+    #[clap(long, global = true, value_enum, env = "MIGA_DHT_MODE")]
+    pub dht_mode: Option<DhtMode>,
+
+    /// Give up fetching after this long, including the search for providers, e.g. "30s" or "5m"
+    /// Without a timeout, content that can't be found is searched for until the retries run out
+    #[clap(long, global = true, value_name = "DURATION", value_parser = humantime::parse_duration, env = "MIGA_TIMEOUT")]
+    pub timeout: Option<Duration>,
+
+    /// Number of times a block that can't be found is retried before giving up (default: 10)
+    #[clap(long, global = true, value_name = "N", env = "MIGA_MAX_RETRIES")]
+    pub max_retries: Option<u32>,
+
+    /// Delay before the first retry of a block, in seconds (default: 1)
+    /// The delay doubles with every further retry, up to a minute, with random jitter
+    #[clap(long, global = true, value_name = "SECONDS", value_parser = parse_seconds, env = "MIGA_RETRY_BACKOFF")]
+    pub retry_backoff: Option<Duration>,
+
+    /// Maximum number of open connections (default: 512, 0 for no cap)
+    /// Further connections are refused, which protects small machines from connection floods
+    #[clap(long, global = true, value_name = "N", env = "MIGA_MAX_CONNECTIONS")]
+    pub max_connections: Option<u32>,
+
+    /// Maximum number of open connections to the same peer (default: 8, 0 for no cap)
+    #[clap(long, global = true, value_name = "N", env = "MIGA_MAX_CONNECTIONS_PER_PEER")]
+    pub max_connections_per_peer: Option<u32>,
+
+    /// Maximum number of connections being set up at the same time, in each direction (default: 128, 0 for no cap)
+    #[clap(long, global = true, value_name = "N", env = "MIGA_MAX_PENDING_DIALS")]
+    pub max_pending_dials: Option<u32>,
+
+    /// Maximum rate blocks are sent to peers at, in bytes per second, e.g. 500KB or 1MiB (default: no cap, 0 lifts the cap)
+    /// Keeps a share node on a home connection from taking all of its upstream bandwidth
+    #[clap(long, global = true, value_name = "RATE", value_parser = throttle::parse_rate, env = "MIGA_MAX_UPLOAD_RATE")]
+    pub max_upload_rate: Option<u64>,
+
+    /// Maximum rate blocks are received from peers at, in bytes per second, e.g. 2MB (default: no cap, 0 lifts the cap)
+    #[clap(long, global = true, value_name = "RATE", value_parser = throttle::parse_rate, env = "MIGA_MAX_DOWNLOAD_RATE")]
+    pub max_download_rate: Option<u64>,
+
+    /// Token protecting the daemon's control API, sent by commands that talk to it
+    /// Requests to the API have to carry it in an "Authorization: Bearer <token>" header
+    #[clap(long, global = true, value_name = "TOKEN", env = "MIGA_API_TOKEN", hide_env_values = true)]
+    pub api_token: Option<String>,
+
+    /// Settings loaded from the configuration file
+    #[clap(skip)]
+    pub settings: Config,
+
+    /// The subcommand to run
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+/// Subcommands of the MIGA application
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Fetch content from IPFS and save it to a file
+    Get(GetArgs),
+    /// Fetch content from IPFS and stream it to stdout
+    Cat(CatArgs),
+    /// Add a file to the local repository
+    Add(AddArgs),
+    /// List the CIDs a block links to, like `ipfs refs`
+    Refs(RefsArgs),
+    /// Report the size, number of blocks and depth of a DAG before downloading it
+    Stat(StatArgs),
+    /// Check that a local file has the content of a CID, without using the network
+    Verify(VerifyArgs),
+    /// Push the DAG of a CID to a running Kubo node through its RPC API
+    ///
+    /// Blocks that aren't stored locally are fetched first, so MIGA can feed
+    /// content into an existing Kubo pinning setup.
+    Export(ExportArgs),
+    /// Run a node that shares all pinned content on the IPFS network
+    Daemon(DaemonArgs),
+    /// Manage pinned content
+    #[clap(subcommand)]
+    Pin(PinCommand),
+    /// Manage the local repository
+    #[clap(subcommand)]
+    Repo(RepoCommand),
+    /// Convert and inspect CIDs
+    #[clap(subcommand)]
+    Cid(CidCommand),
+    /// Manage the cache of fetched content that isn't pinned
+    #[clap(subcommand)]
+    Cache(CacheCommand),
+    /// Read and write raw blocks of the local repository
+    #[clap(subcommand)]
+    Block(BlockCommand),
+    /// Store structured IPLD data
+    #[clap(subcommand)]
+    Dag(DagCommand),
+    /// Manage the named keys of the keystore
+    #[clap(subcommand)]
+    Key(KeyCommand),
+    /// Manage the bootstrap nodes saved in the repository
+    #[clap(subcommand)]
+    Bootstrap(BootstrapCommand),
+    /// Show the identity of this node, like `ipfs id`
+    ///
+    /// Asks the running daemon for its addresses and protocols; without a
+    /// daemon, only the identity stored in the repository is shown.
+    Id {
+        /// Address of the daemon's control API (default: the `api` setting, or /ip4/127.0.0.1/tcp/5001)
+        #[clap(long, value_name = "MULTIADDR", env = "MIGA_API")]
+        api: Option<ApiAddr>,
+    },
+    /// Measure the round trip time to a peer
+    Ping(PingArgs),
+    /// Inspect and control the connections of the running daemon
+    #[clap(subcommand)]
+    Swarm(SwarmCommand),
+    /// Query the DHT
+    #[clap(subcommand)]
+    Dht(DhtCommand),
+    /// Show statistics of the running daemon
+    #[clap(subcommand)]
+    Stats(StatsCommand),
+}
+
+/// Arguments of the `get` subcommand
+#[derive(Args, Debug)]
+pub struct GetArgs {
+    /// The CIDs (Content Identifiers) of the content to fetch from IPFS
+    /// Links such as /ipfs/<cid>, ipfs://<cid> or https://ipfs.io/ipfs/<cid> are accepted as well, and
+    /// `<cid>/<path>` fetches only the file at that path within a directory, saved under its name
+    #[clap(required_unless_present = "cid_file")]
+    pub cids: Vec<String>,
+
+    /// File listing CIDs to fetch, one per line
+    #[clap(long, env = "MIGA_CID_FILE")]
+    pub cid_file: Option<PathBuf>,
+
+    /// Output file path (optional, single CID only)
+    /// If provided, the fetched content will be saved to this file instead of `<CID>.bin`
+    /// Use `-` to stream the content to stdout
+    #[clap(short, long, env = "MIGA_OUTPUT")]
+    pub output: Option<PathBuf>,
+
+    /// Directory to save fetched content to as `<CID>.bin` (default: current directory)
+    #[clap(long, env = "MIGA_OUTPUT_DIR")]
+    pub output_dir: Option<PathBuf>,
+
+    /// Number of CIDs to fetch in parallel (default: 1)
+    #[clap(short, long, env = "MIGA_JOBS")]
+    pub jobs: Option<usize>,
+
+    /// Maximum number of blocks fetched at the same time for each CID (default: 8)
+    #[clap(long, env = "MIGA_CONCURRENCY")]
+    pub concurrency: Option<usize>,
+
+    /// Pin the fetched content, so it is kept and shared by `miga daemon`
+    #[clap(long, env = "MIGA_PIN")]
+    pub pin: bool,
+
+    /// Get the content only from this peer, ending in /p2p/<peer ID>, instead of looking up providers in the DHT (repeatable)
+    /// Useful on a LAN or for private transfers from a machine known to have the content
+    #[clap(long, value_name = "MULTIADDR", value_delimiter = ',', env = "MIGA_PEER")]
+    pub peer: Vec<Multiaddr>,
+}
+
+/// Arguments of the `cat` subcommand
+#[derive(Args, Debug)]
+pub struct CatArgs {
+    /// The CID (Content Identifier) of the content to fetch from IPFS, or a link to it like for `get`
+    pub cid: String,
+
+    /// Maximum number of blocks fetched at the same time (default: 8)
+    #[clap(long, env = "MIGA_CONCURRENCY")]
+    pub concurrency: Option<usize>,
+}
+
+/// Arguments of the `refs` subcommand
+#[derive(Args, Debug)]
+pub struct RefsArgs {
+    /// The CID whose links are listed, or a link to it like for `get`
+    pub cid: String,
+
+    /// List the links of the linked blocks as well, down to the leaves of the DAG
+    #[clap(short, long, env = "MIGA_REFS_RECURSIVE")]
+    pub recursive: bool,
+
+    /// List every CID only once, even if several blocks link to it
+    #[clap(short, long, env = "MIGA_REFS_UNIQUE")]
+    pub unique: bool,
+}
+
+/// Arguments of the `stat` subcommand
+#[derive(Args, Debug)]
+pub struct StatArgs {
+    /// The CID of the DAG to measure, or a link to it like for `get`
+    pub cid: String,
+}
+
+/// Arguments of the `verify` subcommand
+#[derive(Args, Debug)]
+pub struct VerifyArgs {
+    /// The file to check
+    pub file: PathBuf,
+
+    /// The CID the file should have the content of, or a link to it like for `get`
+    pub cid: String,
+}
+
+/// Arguments of the `export` subcommand
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    /// The CID whose DAG is pushed, or a link to it like for `get`
+    pub cid: String,
+
+    /// Address of the RPC API of the Kubo node to push to, e.g. /ip4/127.0.0.1/tcp/5001
+    #[clap(long, value_name = "MULTIADDR", env = "MIGA_EXPORT_TO_KUBO")]
+    pub to_kubo: ApiAddr,
+
+    /// Pin the content in Kubo once all of its blocks are pushed
+    #[clap(long, env = "MIGA_EXPORT_PIN")]
+    pub pin: bool,
+}
+
+/// Arguments of the `add` subcommand
+#[derive(Args, Debug)]
+pub struct AddArgs {
+    /// Path of the file to add
+    pub path: PathBuf,
+
+    /// Don't pin the added content
+    /// Unpinned content is removed by the next `miga repo gc`
+    #[clap(long, env = "MIGA_NO_PIN")]
+    pub no_pin: bool,
+
+    /// Version of the CIDs, 0 or 1 (default: 0 like `ipfs add`, 1 with a --hash other than sha2-256)
+    #[clap(long, value_parser = clap::value_parser!(u64).range(0..=1), env = "MIGA_CID_VERSION")]
+    pub cid_version: Option<u64>,
+
+    /// Store the chunks as raw blocks instead of wrapping them in UnixFS nodes (default: with CIDv1, like `ipfs add`)
+    /// Give --raw-leaves=false to wrap them with CIDv1 as well
+    #[clap(long, num_args = 0..=1, require_equals = true, default_missing_value = "true", env = "MIGA_RAW_LEAVES")]
+    pub raw_leaves: Option<bool>,
+
+    /// Multibase to print the CID in (default: base58btc for CIDv0, base32 for CIDv1)
+    /// Bases other than base58btc print a CIDv0 as CIDv1
+    #[clap(long, value_enum, env = "MIGA_CID_BASE")]
+    pub cid_base: Option<Multibase>,
+
+    /// Hash function of the blocks (default: sha2-256)
+    /// Other functions, such as the faster blake3, imply CIDv1
+    #[clap(long, value_enum, env = "MIGA_HASH")]
+    pub hash: Option<HashFunction>,
+}
+
+/// Arguments of the `daemon` subcommand
+#[derive(Args, Debug)]
+pub struct DaemonArgs {
+    /// Port to listen for IPFS connections on, over TCP and QUIC (default: 4001, or the `listen` addresses of the configuration file)
+    /// This is the standard IPFS port; --listen takes precedence
+    #[clap(long, env = "MIGA_PORT")]
+    pub port: Option<u16>,
+
+    /// Address the node is reachable on from the outside, e.g. through a port forwarding (repeatable)
+    /// It is advertised to other peers, in addition to the listen addresses and those mapped via UPnP
+    #[clap(long, value_name = "MULTIADDR", value_delimiter = ',', env = "MIGA_ANNOUNCE")]
+    pub announce: Vec<Multiaddr>,
+
+    /// Peer ID, IP address or IP range, e.g. 198.51.100.0/24, to refuse connections to and from (repeatable)
+    /// Adds to the `blocklist` of the configuration file; `miga swarm block` changes the list while the daemon runs
+    #[clap(long, value_name = "PEER|IP", value_delimiter = ',', env = "MIGA_BLOCK")]
+    pub block: Vec<BlocklistEntry>,
+
+    /// Send blocks only to this peer, refusing Bitswap to everyone else (repeatable)
+    /// Adds to the `allowlist` of the configuration file; without either, blocks are sent to everyone
+    #[clap(long, value_name = "PEER_ID", value_delimiter = ',', env = "MIGA_ALLOW")]
+    pub allow: Vec<PeerId>,
+
+    /// How often to announce all pinned blocks again, e.g. "12h" (default: 12h, "0s" to disable)
+    /// Provider records expire, so content is only found while they are renewed
+    #[clap(long, value_name = "DURATION", value_parser = humantime::parse_duration, env = "MIGA_REPROVIDE_INTERVAL")]
+    pub reprovide_interval: Option<Duration>,
+
+    /// Address of the control API accepting fetch, add and pin requests (default: /ip4/127.0.0.1/tcp/5001)
+    /// Use /unix/<path> to listen on a Unix domain socket instead
+    #[clap(long, value_name = "MULTIADDR", env = "MIGA_API")]
+    pub api: Option<ApiAddr>,
+
+    /// Also serve the Prometheus metrics on a dedicated address (they are always available at /metrics of the control API)
+    #[clap(long, value_name = "MULTIADDR", env = "MIGA_METRICS")]
+    pub metrics: Option<ApiAddr>,
+
+    /// Serve content over HTTP at /ipfs/<cid>[/<path>] on this address, e.g. /ip4/127.0.0.1/tcp/8080
+    /// The gateway only reads content, fetching it from the network if needed (default: no gateway)
+    #[clap(long, value_name = "MULTIADDR", env = "MIGA_GATEWAY")]
+    pub gateway: Option<ApiAddr>,
+
+    /// Maximum number of gateway requests one client IP may make per minute (default: no cap)
+    #[clap(long, value_name = "N", env = "MIGA_GATEWAY_REQUESTS_PER_MINUTE")]
+    pub gateway_requests_per_minute: Option<u32>,
+
+    /// Maximum number of gateway responses one client IP may download at the same time (default: no cap)
+    #[clap(long, value_name = "N", env = "MIGA_GATEWAY_DOWNLOADS_PER_CLIENT")]
+    pub gateway_downloads_per_client: Option<usize>,
+
+    /// Let browser applications on this origin call the control API, e.g. https://app.example.com,
+    /// or "*" for any origin (repeatable). Adds to the `cors_origins` of the configuration file
+    #[clap(long, value_name = "ORIGIN", value_delimiter = ',', env = "MIGA_CORS_ORIGIN")]
+    pub cors_origin: Vec<String>,
+
+    /// HTTP method those applications may use (repeatable, default: GET, POST)
+    #[clap(long, value_name = "METHOD", value_delimiter = ',', env = "MIGA_CORS_METHOD")]
+    pub cors_method: Vec<String>,
+}
+
+/// Arguments of the `ping` subcommand
+#[derive(Args, Debug)]
+pub struct PingArgs {
+    /// Peer ID of the peer, or a multiaddress ending in /p2p/<peer ID> to reach it on
+    /// A bare peer ID only works for peers the node already knows addresses of
+    #[clap(value_name = "PEER")]
+    pub target: String,
+
+    /// Number of pings to send (default: 10)
+    #[clap(short = 'n', long, env = "MIGA_PING_COUNT")]
+    pub count: Option<u32>,
+
+    /// Time between two pings, e.g. "500ms" (default: 1s)
+    #[clap(short, long, value_name = "DURATION", value_parser = humantime::parse_duration, env = "MIGA_PING_INTERVAL")]
+    pub interval: Option<Duration>,
+}
+
+/// Subcommands for managing pins
+///
+/// Pinned content is protected from garbage collection and shared
+/// on the network by `miga daemon`.
+#[derive(Subcommand, Debug)]
+pub enum PinCommand {
+    /// Pin content that is stored in the local blockstore
+    Add {
+        /// CID of the content to pin
+        cid: String,
+
+        /// Only pin the block itself instead of the whole DAG below it
+        #[clap(long, env = "MIGA_PIN_DIRECT")]
+        direct: bool,
+    },
+    /// Remove a pin
+    Rm {
+        /// CID of the content to unpin
+        cid: String,
+    },
+    /// List pinned content
+    Ls,
+}
+
+/// Subcommands for managing the bootstrap list
+#[derive(Subcommand, Debug)]
+pub enum BootstrapCommand {
+    /// Add a bootstrap node
+    Add {
+        /// Address of the node, ending in /p2p/<peer ID>
+        addr: Multiaddr,
+    },
+    /// Remove a bootstrap node
+    Rm {
+        /// Address of the node to remove
+        #[clap(required_unless_present = "all")]
+        addr: Option<Multiaddr>,
+
+        /// Remove all bootstrap nodes
+        #[clap(long, conflicts_with = "addr", env = "MIGA_BOOTSTRAP_RM_ALL")]
+        all: bool,
+    },
+    /// List the bootstrap nodes in use
+    List,
+    /// Go back to the default IPFS bootstrap nodes
+    Reset,
+}
+
+/// Subcommands for inspecting and steering the connections of the daemon
+///
+/// These ask the control API of a running `miga daemon`.
+#[derive(Subcommand, Debug)]
+pub enum SwarmCommand {
+    /// List the peers the daemon is connected to
+    Peers {
+        /// Address of the daemon's control API (default: the `api` setting, or /ip4/127.0.0.1/tcp/5001)
+        #[clap(long, value_name = "MULTIADDR", env = "MIGA_API")]
+        api: Option<ApiAddr>,
+    },
+    /// Make the daemon connect to a peer
+    Connect {
+        /// Address of the peer, ending in /p2p/<peer ID>
+        addr: Multiaddr,
+
+        /// Address of the daemon's control API (default: the `api` setting, or /ip4/127.0.0.1/tcp/5001)
+        #[clap(long, value_name = "MULTIADDR", env = "MIGA_API")]
+        api: Option<ApiAddr>,
+    },
+    /// Make the daemon refuse a peer or IP range, closing its open connections
+    ///
+    /// The entry lasts until the daemon stops; add it to the `blocklist` of the
+    /// configuration file to keep it.
+    Block {
+        /// Peer ID, IP address or IP range, e.g. 198.51.100.0/24
+        #[clap(value_name = "PEER|IP")]
+        entry: BlocklistEntry,
+
+        /// Address of the daemon's control API (default: the `api` setting, or /ip4/127.0.0.1/tcp/5001)
+        #[clap(long, value_name = "MULTIADDR", env = "MIGA_API")]
+        api: Option<ApiAddr>,
+    },
+    /// Remove a peer or IP range from the daemon's blocklist
+    Unblock {
+        /// Peer ID, IP address or IP range, as it was blocked
+        #[clap(value_name = "PEER|IP")]
+        entry: BlocklistEntry,
+
+        /// Address of the daemon's control API (default: the `api` setting, or /ip4/127.0.0.1/tcp/5001)
+        #[clap(long, value_name = "MULTIADDR", env = "MIGA_API")]
+        api: Option<ApiAddr>,
+    },
+    /// List the peers and IP ranges the daemon refuses
+    Blocklist {
+        /// Address of the daemon's control API (default: the `api` setting, or /ip4/127.0.0.1/tcp/5001)
+        #[clap(long, value_name = "MULTIADDR", env = "MIGA_API")]
+        api: Option<ApiAddr>,
+    },
+}
+
+/// Subcommands for querying the DHT
+#[derive(Subcommand, Debug)]
+pub enum DhtCommand {
+    /// Look up the addresses of a peer
+    FindPeer {
+        /// Peer ID of the peer
+        peer: PeerId,
+    },
+    /// List the providers of a CID, without fetching anything
+    FindProviders {
+        /// The CID to look up
+        cid: String,
+
+        /// Stop after this many providers (default: 20)
+        #[clap(short = 'n', long, env = "MIGA_NUM_PROVIDERS")]
+        num_providers: Option<usize>,
+    },
+}
+
+/// Subcommands for showing statistics of the running daemon
+#[derive(Subcommand, Debug)]
+pub enum StatsCommand {
+    /// Show the bandwidth the daemon used, in total and per protocol
+    Bw(BwArgs),
+}
+
+/// Arguments of the `stats bw` subcommand
+#[derive(Args, Debug)]
+pub struct BwArgs {
+    /// Only show the bandwidth used with this peer
+    #[clap(long, conflicts_with = "proto", env = "MIGA_BW_PEER")]
+    pub peer: Option<PeerId>,
+
+    /// Only show the bandwidth used by this protocol, e.g. /ipfs/bitswap/1.2.0
+    #[clap(long, env = "MIGA_BW_PROTO")]
+    pub proto: Option<String>,
+
+    /// Keep printing the bandwidth until interrupted
+    #[clap(long, env = "MIGA_BW_POLL")]
+    pub poll: bool,
+
+    /// Time between two updates with --poll, e.g. "5s" (default: 1s)
+    #[clap(short, long, value_name = "DURATION", value_parser = humantime::parse_duration, env = "MIGA_BW_INTERVAL")]
+    pub interval: Option<Duration>,
+
+    /// Address of the daemon's control API (default: the `api` setting, or /ip4/127.0.0.1/tcp/5001)
+    #[clap(long, value_name = "MULTIADDR", env = "MIGA_API")]
+    pub api: Option<ApiAddr>,
+}
+
+/// Subcommands for managing the local repository
+#[derive(Subcommand, Debug)]
+pub enum RepoCommand {
+    /// Remove all blocks that aren't protected by a pin
+    Gc {
+        /// Only report how much space would be reclaimed, without removing anything
+        #[clap(long, env = "MIGA_GC_DRY_RUN")]
+        dry_run: bool,
+    },
+    /// Report how much space storing each block only once saves
+    DedupStats,
+    /// Show the path, number of blocks, size on disk, number of pins and datastore version of the repository
+    Stat,
+    /// Copy the blocks of a Kubo repository into this one, e.g. when migrating off Kubo
+    ///
+    /// Only flatfs datastores, Kubo's default, can be read. Blocks are
+    /// verified before they are copied; Kubo may keep running meanwhile.
+    ImportKubo {
+        /// The Kubo repository, e.g. ~/.ipfs
+        path: PathBuf,
+        /// Also pin the CIDs listed in this file, as printed by `ipfs pin ls` or `ipfs pin ls --quiet`
+        #[clap(long, value_name = "FILE", env = "MIGA_IMPORT_PINS")]
+        pins: Option<PathBuf>,
+    },
+}
+
+/// Subcommands for the cache of fetched content
+#[derive(Subcommand, Debug)]
+pub enum CacheCommand {
+    /// Remove the fetched content that isn't pinned
+    Clear {
+        /// Only remove content stored longer than this, e.g. "1d" (default: all of it)
+        #[clap(long, value_name = "DURATION", value_parser = humantime::parse_duration, env = "MIGA_CACHE_CLEAR_OLDER_THAN")]
+        older_than: Option<Duration>,
+    },
+}
+
+/// Subcommands for working with CIDs
+#[derive(Subcommand, Debug)]
+pub enum CidCommand {
+    /// Convert a CID to another version or multibase, e.g. for gateways requiring base32 CIDv1
+    Format {
+        /// The CID to convert
+        cid: String,
+
+        /// CID version to convert to, 0 or 1 (default: the version of the CID, or 1 if `--base` asks for a base CIDv0 can't be written in)
+        #[clap(long, value_parser = clap::value_parser!(u64).range(0..=1), env = "MIGA_CID_FORMAT_VERSION")]
+        cid_version: Option<u64>,
+
+        /// Multibase to write the CID in (default: the one of the CID, or base32 for CIDv0 converted to CIDv1)
+        #[clap(short, long, value_enum, env = "MIGA_CID_FORMAT_BASE")]
+        base: Option<Multibase>,
+
+        /// Show the version, multibase, codec and multihash of the CID as well
+        #[clap(short, long, env = "MIGA_CID_FORMAT_DETAILS")]
+        details: bool,
+    },
+}
+
+/// Subcommands for raw blocks of the local repository
+#[derive(Subcommand, Debug)]
+pub enum BlockCommand {
+    /// Write the raw bytes of a stored block to stdout
+    Get {
+        /// The CID of the block
+        cid: String,
+    },
+    /// Store a block and print its CID
+    Put {
+        /// File holding the block data (default: stdin)
+        file: Option<PathBuf>,
+
+        /// Codec of the block, part of its CID (default: raw)
+        #[clap(long, value_enum, env = "MIGA_BLOCK_CODEC")]
+        codec: Option<Codec>,
+
+        /// Hash function the CID is made with (default: sha2-256)
+        #[clap(long, value_enum, env = "MIGA_BLOCK_HASH")]
+        hash: Option<HashFunction>,
+
+        /// Pin the block, so garbage collection doesn't remove it
+        #[clap(long, env = "MIGA_BLOCK_PIN")]
+        pin: bool,
+    },
+    /// Tell whether a block is stored, and its size
+    Stat {
+        /// The CID of the block
+        cid: String,
+    },
+}
+
+/// Subcommands for structured IPLD data
+#[derive(Subcommand, Debug)]
+pub enum DagCommand {
+    /// Store a node given as JSON and print its CID
+    Put {
+        /// File holding the node as dag-json (default: stdin)
+        file: Option<PathBuf>,
+
+        /// Codec to store the node with (default: dag-cbor)
+        #[clap(long, value_enum, env = "MIGA_DAG_STORE_CODEC")]
+        store_codec: Option<IpldCodec>,
+
+        /// Hash function the CID is made with (default: sha2-256)
+        #[clap(long, value_enum, env = "MIGA_DAG_HASH")]
+        hash: Option<HashFunction>,
+
+        /// Pin the node and everything it links to, so the daemon shares it
+        #[clap(long, env = "MIGA_DAG_PIN")]
+        pin: bool,
+
+        /// Announce the node on the DHT, so other nodes can find it
+        #[clap(long, env = "MIGA_DAG_PROVIDE")]
+        provide: bool,
+    },
+}
+
+/// Subcommands for the keystore
+#[derive(Subcommand, Debug)]
+pub enum KeyCommand {
+    /// Generate a new key
+    Gen {
+        /// Name of the new key
+        name: String,
+
+        /// Type of the new key (default: ed25519)
+        #[clap(short = 't', long, value_enum, env = "MIGA_KEY_TYPE")]
+        key_type: Option<KeyType>,
+    },
+    /// List the keys with their peer IDs
+    List,
+    /// Give a key another name
+    Rename {
+        /// Current name of the key
+        name: String,
+        /// New name of the key
+        new_name: String,
+    },
+    /// Write a key unencrypted to a file, in the format of `identity.key` and `ipfs key export`
+    Export {
+        /// Name of the key
+        name: String,
+
+        /// File to write the key to (default: <name>.key)
+        #[clap(short, long, env = "MIGA_KEY_OUTPUT")]
+        output: Option<PathBuf>,
+    },
+    /// Store a key from a file, in the format of `identity.key` and `ipfs key export`
+    Import {
+        /// Name to store the key under
+        name: String,
+
+        /// File holding the key
+        file: PathBuf,
+    },
+}
+
+/// Parse a number of seconds, fractions allowed, into a duration
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("{} is not a valid number of seconds", s))
+}
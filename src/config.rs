@@ -0,0 +1,350 @@
+//! Configuration file
+//!
+//! Settings that are tedious to pass on every invocation can be kept in a TOML
+//! file, `~/.miga/config.toml` by default. Every setting is optional, and
+//! command line flags take precedence over the file. For example:
+//!
+//! ```toml
+//! repo = "/data/miga"
+//! bootstrap = ["/ip4/192.0.2.1/tcp/4001/p2p/12D3KooWBootstrapPeerIdGoesHere"]
+//! listen = ["/ip4/0.0.0.0/tcp/4001", "/ip6/::/tcp/4001", "/ip4/0.0.0.0/udp/4001/quic-v1"]
+//! announce = ["/ip4/203.0.113.7/tcp/4001"]
+//! blocklist = ["12D3KooWAbusivePeerIdGoesHere", "198.51.100.0/24"]
+//! allowlist = ["12D3KooWTrustedPeerIdGoesHere"]
+//! api = "/ip4/127.0.0.1/tcp/5001"
+//! api_token = "a-long-random-secret"
+//! gateway = "/ip4/127.0.0.1/tcp/8080"
+//! cors_origins = ["https://app.example.com"]
+//! cors_methods = ["GET", "POST"]
+//! dht_mode = "server"
+//! reprovide_interval = "12h"
+//!
+//! [fetch]
+//! concurrency = 16
+//! jobs = 4
+//! max_retries = 5
+//! retry_backoff = 2.5
+//! cache_ttl = "7d"
+//! block_cache_size = "256MiB"
+//!
+//! [limits]
+//! max_connections = 256
+//! max_connections_per_peer = 4
+//! max_pending_dials = 64
+//! max_upload_rate = "1MiB"
+//! max_download_rate = "4MiB"
+//! gateway_requests_per_minute = 600
+//! gateway_downloads_per_client = 4
+//!
+//! [timeouts]
+//! dht_query = 120
+//! fetch = 600
+//!
+//! [telemetry]
+//! otlp_endpoint = "http://localhost:4318/v1/traces"
+//!
+//! [log]
+//! format = "json"
+//! file = "/var/log/miga/miga.log"
+//! filter = "miga=debug,libp2p=warn"
+//! ```
+
+use anyhow::{anyhow, Context, Result};
+use libp2p::{Multiaddr, PeerId};
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{api::ApiAddr, blocklist::BlocklistEntry, keypair, network::DhtMode, throttle};
+
+/// Settings read from the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Repository directory
+    pub repo: Option<PathBuf>,
+    /// Path of the identity key file
+    pub identity: Option<PathBuf>,
+    /// Bootstrap node addresses, replacing the default IPFS bootstrap nodes
+    pub bootstrap: Option<Vec<String>>,
+    /// Use the bootstrap nodes in addition to the default ones instead of replacing them
+    pub keep_default_bootstrap: Option<bool>,
+    /// Addresses `miga daemon` listens on
+    pub listen: Option<Vec<String>>,
+    /// Addresses `miga daemon` is reachable on from the outside, advertised to other peers
+    pub announce: Option<Vec<String>>,
+    /// Peer IDs and IP ranges that may not connect
+    pub blocklist: Option<Vec<BlocklistEntry>>,
+    /// The only peer IDs `miga daemon` sends blocks to, if not everyone
+    pub allowlist: Option<Vec<String>>,
+    /// Role of the node in the DHT
+    pub dht_mode: Option<DhtMode>,
+    /// How often the daemon announces all pinned blocks again, e.g. "12h"
+    pub reprovide_interval: Option<String>,
+    /// Address of the daemon's control API
+    pub api: Option<String>,
+    /// Token protecting the daemon's control API
+    pub api_token: Option<String>,
+    /// Address of a dedicated metrics endpoint of the daemon
+    pub metrics: Option<String>,
+    /// Address the daemon's HTTP gateway listens on
+    pub gateway: Option<String>,
+    /// Origins of browser applications allowed to call the control API, "*" for any
+    pub cors_origins: Option<Vec<String>>,
+    /// HTTP methods browser applications on those origins may use
+    pub cors_methods: Option<Vec<String>>,
+    /// Fetch settings
+    pub fetch: FetchConfig,
+    /// Connection and rate limits
+    pub limits: LimitsConfig,
+    /// Timeouts
+    pub timeouts: TimeoutConfig,
+    /// Tracing settings
+    pub telemetry: TelemetryConfig,
+    /// Logging settings
+    pub log: LogConfig,
+}
+
+/// Fetch settings of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct FetchConfig {
+    /// Maximum number of blocks fetched at the same time for each CID
+    pub concurrency: Option<usize>,
+    /// Number of CIDs fetched in parallel by `miga get`
+    pub jobs: Option<usize>,
+    /// Number of retries of a block that couldn't be found
+    pub max_retries: Option<u32>,
+    /// Delay before the first retry of a block, in seconds
+    pub retry_backoff: Option<f64>,
+    /// How long fetched content that isn't pinned is served from the repository, e.g. "7d"
+    pub cache_ttl: Option<String>,
+    /// Size of the in-memory cache of recently used blocks, e.g. "256MiB", 0 to disable it
+    pub block_cache_size: Option<String>,
+}
+
+/// Connection and rate limits of the configuration file, 0 for no cap
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Maximum number of established connections
+    pub max_connections: Option<u32>,
+    /// Maximum number of established connections to the same peer
+    pub max_connections_per_peer: Option<u32>,
+    /// Maximum number of connections being set up at the same time in each direction
+    pub max_pending_dials: Option<u32>,
+    /// Maximum rate blocks are sent to peers at, e.g. "1MiB"
+    pub max_upload_rate: Option<String>,
+    /// Maximum rate blocks are received from peers at, e.g. "4MiB"
+    pub max_download_rate: Option<String>,
+    /// Maximum number of requests one client IP may make to the gateway per minute
+    pub gateway_requests_per_minute: Option<u32>,
+    /// Maximum number of responses one client IP may download from the gateway at the same time
+    pub gateway_downloads_per_client: Option<usize>,
+}
+
+/// Timeouts of the configuration file, in seconds
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    /// Timeout of DHT queries
+    pub dht_query: Option<u64>,
+    /// Timeout of a whole `miga get` or `miga cat`
+    pub fetch: Option<u64>,
+}
+
+/// Tracing settings of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TelemetryConfig {
+    /// OTLP/HTTP endpoint to export traces of fetch operations to
+    pub otlp_endpoint: Option<String>,
+}
+
+/// Logging settings of the configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    /// Format of log lines
+    pub format: Option<LogFormat>,
+    /// Log file, rotated daily
+    pub file: Option<PathBuf>,
+    /// Filter directives, e.g. `miga=debug,libp2p=warn`
+    pub filter: Option<String>,
+}
+
+/// Format of log lines
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable text
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+impl Config {
+    /// Load the configuration
+    ///
+    /// # Arguments
+    /// * `path` - Configuration file given on the command line, if any. Without
+    ///   one, `~/.miga/config.toml` is used if it exists
+    ///
+    /// # Returns
+    /// * `Result<Config>` - The loaded configuration, or the defaults if there is no file
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => {
+                let path = default_config_path();
+                if !path.exists() {
+                    return Ok(Self::default());
+                }
+                path
+            }
+        };
+
+        let text = fs::read_to_string(&path).with_context(|| format!("Failed to read configuration file {:?}", path))?;
+        toml::from_str(&text).with_context(|| format!("Invalid configuration file {:?}", path))
+    }
+
+    /// Parsed peer IDs of the allowlist, if the file sets one
+    pub fn allowlist_peers(&self) -> Result<Option<Vec<PeerId>>> {
+        self.allowlist
+            .as_deref()
+            .map(|peers| {
+                peers
+                    .iter()
+                    .map(|peer| peer.parse().map_err(|e| anyhow!("Invalid peer ID {:?} in configuration file: {}", peer, e)))
+                    .collect()
+            })
+            .transpose()
+    }
+
+    /// Parsed bootstrap node addresses, if the file sets them
+    pub fn bootstrap_addrs(&self) -> Result<Option<Vec<Multiaddr>>> {
+        self.bootstrap.as_deref().map(parse_addrs).transpose()
+    }
+
+    /// Parsed daemon listen addresses, if the file sets them
+    pub fn listen_addrs(&self) -> Result<Option<Vec<Multiaddr>>> {
+        self.listen.as_deref().map(parse_addrs).transpose()
+    }
+
+    /// Parsed addresses to announce, if the file sets them
+    pub fn announce_addrs(&self) -> Result<Option<Vec<Multiaddr>>> {
+        self.announce.as_deref().map(parse_addrs).transpose()
+    }
+
+    /// Parsed control API address, if the file sets it
+    pub fn api_addr(&self) -> Result<Option<ApiAddr>> {
+        self.api
+            .as_deref()
+            .map(|addr| addr.parse().with_context(|| format!("Invalid API address {:?} in configuration file", addr)))
+            .transpose()
+    }
+
+    /// Parsed metrics endpoint address, if the file sets it
+    pub fn metrics_addr(&self) -> Result<Option<ApiAddr>> {
+        self.metrics
+            .as_deref()
+            .map(|addr| addr.parse().with_context(|| format!("Invalid metrics address {:?} in configuration file", addr)))
+            .transpose()
+    }
+
+    /// Parsed gateway address, if the file sets it
+    pub fn gateway_addr(&self) -> Result<Option<ApiAddr>> {
+        self.gateway
+            .as_deref()
+            .map(|addr| addr.parse().with_context(|| format!("Invalid gateway address {:?} in configuration file", addr)))
+            .transpose()
+    }
+
+    /// Timeout of DHT queries, if the file sets it
+    pub fn dht_query_timeout(&self) -> Option<Duration> {
+        self.timeouts.dht_query.map(Duration::from_secs)
+    }
+
+    /// Interval of the daemon's reprovider, if the file sets it
+    pub fn reprovide_interval(&self) -> Result<Option<Duration>> {
+        self.reprovide_interval
+            .as_deref()
+            .map(|interval| {
+                humantime::parse_duration(interval)
+                    .with_context(|| format!("Invalid reprovide_interval {:?} in configuration file", interval))
+            })
+            .transpose()
+    }
+
+    /// How long unpinned content is cached, if the file sets it
+    pub fn cache_ttl(&self) -> Result<Option<Duration>> {
+        self.fetch
+            .cache_ttl
+            .as_deref()
+            .map(|ttl| {
+                humantime::parse_duration(ttl).with_context(|| format!("Invalid cache_ttl {:?} in configuration file", ttl))
+            })
+            .transpose()
+    }
+
+    /// Size of the in-memory block cache in bytes, if the file sets it
+    pub fn block_cache_size(&self) -> Result<Option<u64>> {
+        self.fetch
+            .block_cache_size
+            .as_deref()
+            .map(|size| {
+                throttle::parse_size(size)
+                    .with_context(|| format!("Invalid block_cache_size {:?} in configuration file", size))
+            })
+            .transpose()
+    }
+
+    /// Timeout of a whole fetch, if the file sets it
+    pub fn fetch_timeout(&self) -> Option<Duration> {
+        self.timeouts.fetch.map(Duration::from_secs)
+    }
+
+    /// Cap on the upload rate in bytes per second, if the file sets it
+    pub fn max_upload_rate(&self) -> Result<Option<u64>> {
+        parse_rate_setting("max_upload_rate", self.limits.max_upload_rate.as_deref())
+    }
+
+    /// Cap on the download rate in bytes per second, if the file sets it
+    pub fn max_download_rate(&self) -> Result<Option<u64>> {
+        parse_rate_setting("max_download_rate", self.limits.max_download_rate.as_deref())
+    }
+
+    /// Delay before the first retry of a block, if the file sets it
+    pub fn retry_backoff(&self) -> Result<Option<Duration>> {
+        self.fetch
+            .retry_backoff
+            .map(|secs| {
+                Duration::try_from_secs_f64(secs)
+                    .map_err(|_| anyhow!("Invalid retry_backoff {} in configuration file", secs))
+            })
+            .transpose()
+    }
+}
+
+/// Return the default location of the configuration file (`~/.miga/config.toml`)
+pub fn default_config_path() -> PathBuf {
+    keypair::home_dir().join("config.toml")
+}
+
+/// Parse a rate setting of the configuration file
+fn parse_rate_setting(name: &str, rate: Option<&str>) -> Result<Option<u64>> {
+    rate.map(|rate| throttle::parse_rate(rate).with_context(|| format!("Invalid {} in configuration file", name)))
+        .transpose()
+}
+
+/// Parse a list of multiaddresses from the configuration file
+fn parse_addrs(addrs: &[String]) -> Result<Vec<Multiaddr>> {
+    addrs
+        .iter()
+        .map(|addr| addr.parse().map_err(|e| anyhow!("Invalid address {:?} in configuration file: {}", addr, e)))
+        .collect()
+}
@@ -0,0 +1,204 @@
+//! Content named by users
+//!
+//! Content is named by its CID, but users often have a link to it instead,
+//! copied from a browser or another IPFS tool. All of these name the same
+//! content, and may be followed by a path within it:
+//!
+//! - `QmZ4tD...` or `bafy...`: The CID itself
+//! - `/ipfs/<cid>`: An IPFS path, as Kubo prints them
+//! - `ipfs://<cid>`: An IPFS URI, as browsers with IPFS support use them
+//! - `https://ipfs.io/ipfs/<cid>`: A URL of a path gateway
+//! - `https://<cid>.ipfs.dweb.link`: A URL of a subdomain gateway
+//!
+//! IPNS names (`ipns://<name>`, `/ipns/<name>`) are recognized, but can't be
+//! resolved, as MIGA has no IPNS support.
+
+use anyhow::Result;
+use cid::Cid;
+use std::{fmt, str::FromStr};
+
+use crate::error::MigaError;
+
+/// Content named by a CID and a path within it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentPath {
+    /// CID the path starts at
+    pub cid: Cid,
+    /// Names of the entries to follow from the CID, separated by slashes, empty for the CID itself
+    pub path: String,
+}
+
+impl FromStr for ContentPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |reason: &str| MigaError::InvalidCid {
+            cid: s.to_string(),
+            reason: reason.to_string(),
+        };
+        let input = s.trim();
+        let lowercase = input.to_ascii_lowercase();
+        if lowercase.starts_with("ipns://") || lowercase.starts_with("/ipns/") {
+            return Err(invalid("IPNS names are not supported, only IPFS content").into());
+        }
+
+        // Gateways name the content in the path of a URL, subdomain gateways in the host
+        if let Some(url) = strip_prefix_ignore_case(input, "https://").or_else(|| strip_prefix_ignore_case(input, "http://")) {
+            let url = strip_query(url);
+            let (host, path) = url.split_once('/').unwrap_or((url, ""));
+            let path = format!("/{}", path);
+            let labels: Vec<&str> = host.split('.').collect();
+            return if let Some((_, rest)) = path.split_once("/ipfs/") {
+                Self::from_cid_and_path(s, rest)
+            } else if labels.len() > 2 && labels[1].eq_ignore_ascii_case("ipfs") {
+                Self::from_cid_and_path(s, &format!("{}{}", labels[0], path))
+            } else if path.starts_with("/ipns/") || (labels.len() > 2 && labels[1].eq_ignore_ascii_case("ipns")) {
+                Err(invalid("IPNS names are not supported, only IPFS content").into())
+            } else {
+                Err(invalid("not a URL of an IPFS gateway").into())
+            };
+        }
+
+        let rest = if let Some(rest) = strip_prefix_ignore_case(input, "ipfs://") {
+            strip_query(rest)
+        } else {
+            input.strip_prefix("/ipfs/").unwrap_or(input)
+        };
+        Self::from_cid_and_path(s, rest)
+    }
+}
+
+impl ContentPath {
+    /// Split text starting with a CID into the CID and the path after it
+    ///
+    /// # Arguments
+    /// * `input` - The text the user gave, for error messages
+    /// * `rest` - The CID, optionally followed by a slash and a path
+    fn from_cid_and_path(input: &str, rest: &str) -> Result<Self> {
+        let (cid, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let cid = Cid::try_from(cid).map_err(|e| MigaError::InvalidCid {
+            cid: input.to_string(),
+            reason: e.to_string(),
+        })?;
+        // Links escape special characters in names, which the entries don't have
+        let path = path
+            .split('/')
+            .filter(|name| !name.is_empty())
+            .map(percent_decode)
+            .collect::<Vec<_>>()
+            .join("/");
+        Ok(Self { cid, path })
+    }
+}
+
+impl From<Cid> for ContentPath {
+    fn from(cid: Cid) -> Self {
+        Self { cid, path: String::new() }
+    }
+}
+
+impl fmt::Display for ContentPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.cid)
+        } else {
+            write!(f, "{}/{}", self.cid, self.path)
+        }
+    }
+}
+
+/// Remove a prefix regardless of the case of its letters, as URL schemes are compared
+fn strip_prefix_ignore_case<'a>(s: &'a str, prefix: &str) -> Option<&'a str> {
+    let head = s.get(..prefix.len())?;
+    head.eq_ignore_ascii_case(prefix).then(|| &s[prefix.len()..])
+}
+
+/// Cut off the query and fragment of a URL
+fn strip_query(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or_default()
+}
+
+/// Decode the percent-encoded bytes of a name, keeping malformed escapes as they are
+fn percent_decode(name: &str) -> String {
+    let bytes = name.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes[i] {
+            b'%' => name.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CID: &str = "bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+
+    fn parse(s: &str) -> ContentPath {
+        s.parse().unwrap_or_else(|e| panic!("{:?} didn't parse: {}", s, e))
+    }
+
+    #[test]
+    fn parses_every_form_of_link() {
+        let expected = ContentPath {
+            cid: CID.parse().unwrap(),
+            path: "docs/readme.md".to_string(),
+        };
+        for link in [
+            format!("{CID}/docs/readme.md"),
+            format!("/ipfs/{CID}/docs/readme.md"),
+            format!("ipfs://{CID}/docs/readme.md"),
+            format!("IPFS://{CID}/docs/readme.md?filename=readme.md"),
+            format!("https://ipfs.io/ipfs/{CID}/docs/readme.md"),
+            format!("http://127.0.0.1:8080/ipfs/{CID}/docs/readme.md#top"),
+            format!("https://{CID}.ipfs.dweb.link/docs/readme.md"),
+            format!(" /ipfs/{CID}//docs/readme.md/ "),
+        ] {
+            assert_eq!(parse(&link), expected, "{}", link);
+        }
+    }
+
+    #[test]
+    fn parses_a_cid_alone() {
+        let path = parse("QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o");
+        assert!(path.path.is_empty());
+        assert_eq!(path.to_string(), "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o");
+        assert_eq!(parse(&format!("https://{CID}.ipfs.dweb.link")), ContentPath::from(CID.parse::<Cid>().unwrap()));
+    }
+
+    #[test]
+    fn decodes_escaped_names() {
+        assert_eq!(parse(&format!("/ipfs/{CID}/my%20file%2. (1).txt")).path, "my file%2. (1).txt");
+        assert_eq!(parse(&format!("/ipfs/{CID}/%E2%9C%93")).path, "✓");
+    }
+
+    #[test]
+    fn rejects_other_names() {
+        for invalid in [
+            "ipns://example.com",
+            "/ipns/k51qzi5uqu5dlvj2baxnqndepeb86cbk3ng7n3i46uzyxzyqj2xjonzllnv0v8",
+            "https://ipfs.io/ipns/example.com",
+            "https://example-com.ipns.dweb.link",
+            "https://example.com/index.html",
+            "not a cid",
+            "",
+        ] {
+            let error = invalid.parse::<ContentPath>().expect_err(invalid);
+            assert!(matches!(error.downcast_ref(), Some(MigaError::InvalidCid { .. })), "{:?}: {}", invalid, error);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! DAG node decoding
+//!
+//! Files and directories in IPFS are DAGs of blocks. This module decodes the
+//! blocks of the supported codecs far enough to find the links between them;
+//! the IPLD codecs dag-cbor and dag-json are decoded by [`crate::ipld`].
+
+use anyhow::{anyhow, Result};
+use cid::Cid;
+
+use crate::{blockstore::RAW_CODEC, ipld, protobuf};
+
+/// Multicodec code of dag-pb (protobuf) nodes
+pub const DAG_PB_CODEC: u64 = 0x70;
+
+/// Multicodec code of dag-cbor nodes
+pub const DAG_CBOR_CODEC: u64 = 0x71;
+
+/// Multicodec code of dag-json nodes
+pub const DAG_JSON_CODEC: u64 = 0x0129;
+
+/// A link from a dag-pb node to another block
+#[derive(Debug, Clone)]
+pub struct PbLink {
+    /// CID of the linked block
+    pub cid: Cid,
+    /// Name of the link, e.g. a file name inside a directory
+    pub name: Option<String>,
+    /// Cumulative size of the linked DAG
+    pub tsize: Option<u64>,
+}
+
+/// A decoded dag-pb node
+#[derive(Debug, Clone, Default)]
+pub struct PbNode {
+    /// Links to child blocks, in order
+    pub links: Vec<PbLink>,
+    /// Opaque node data (for UnixFS, an encoded UnixFS `Data` message)
+    pub data: Option<Vec<u8>>,
+}
+
+/// Size and shape of a DAG
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DagStat {
+    /// Total size of the distinct blocks, in bytes
+    pub size: u64,
+    /// Number of distinct blocks
+    pub blocks: usize,
+    /// Number of levels of blocks, 1 for a single block
+    pub depth: usize,
+}
+
+impl PbNode {
+    /// Encode the node in canonical dag-pb form (links first, then data)
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = protobuf::Writer::new();
+        for link in &self.links {
+            writer.bytes(2, &link.encode());
+        }
+        if let Some(data) = &self.data {
+            writer.bytes(1, data);
+        }
+        writer.finish()
+    }
+
+    /// Decode a dag-pb node from its block data
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut node = PbNode::default();
+        let mut reader = protobuf::Reader::new(bytes);
+        while let Some((number, field)) = reader.next_field()? {
+            match number {
+                1 => node.data = Some(field.as_bytes()?.to_vec()),
+                2 => node.links.push(PbLink::decode(field.as_bytes()?)?),
+                _ => {}
+            }
+        }
+        Ok(node)
+    }
+}
+
+impl PbLink {
+    /// Encode the link in canonical field order
+    fn encode(&self) -> Vec<u8> {
+        let mut writer = protobuf::Writer::new();
+        writer.bytes(1, &self.cid.to_bytes());
+        if let Some(name) = &self.name {
+            writer.bytes(2, name.as_bytes());
+        }
+        if let Some(tsize) = self.tsize {
+            writer.varint(3, tsize);
+        }
+        writer.finish()
+    }
+
+    /// Decode a dag-pb link
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut cid = None;
+        let mut name = None;
+        let mut tsize = None;
+        let mut reader = protobuf::Reader::new(bytes);
+        while let Some((number, field)) = reader.next_field()? {
+            match number {
+                1 => cid = Some(Cid::try_from(field.as_bytes()?)?),
+                2 => name = Some(String::from_utf8(field.as_bytes()?.to_vec())?),
+                3 => tsize = Some(field.as_varint()?),
+                _ => {}
+            }
+        }
+        Ok(Self {
+            cid: cid.ok_or_else(|| anyhow!("dag-pb link without a CID"))?,
+            name,
+            tsize,
+        })
+    }
+}
+
+/// CIDs of all blocks a block links to
+///
+/// # Arguments
+/// * `cid` - CID of the block, used to determine its codec
+/// * `data` - The block data
+///
+/// # Returns
+/// * `Result<Vec<Cid>>` - The linked CIDs, in order
+pub fn links(cid: &Cid, data: &[u8]) -> Result<Vec<Cid>> {
+    Ok(pb_links(cid, data)?.into_iter().map(|link| link.cid).collect())
+}
+
+/// Links of a block, with the names and sizes dag-pb nodes give them
+///
+/// Links of the other codecs have neither.
+///
+/// # Arguments
+/// * `cid` - CID of the block, used to determine its codec
+/// * `data` - The block data
+///
+/// # Returns
+/// * `Result<Vec<PbLink>>` - The links, in order
+pub fn pb_links(cid: &Cid, data: &[u8]) -> Result<Vec<PbLink>> {
+    match cid.codec() {
+        DAG_PB_CODEC => Ok(PbNode::decode(data)?.links),
+        DAG_CBOR_CODEC | DAG_JSON_CODEC => Ok(ipld::links(cid, data)?
+            .into_iter()
+            .map(|cid| PbLink {
+                cid,
+                name: None,
+                tsize: None,
+            })
+            .collect()),
+        RAW_CODEC => Ok(Vec::new()),
+        codec => Err(anyhow!("Unsupported codec 0x{:x} in CID {}", codec, cid)),
+    }
+}
@@ -0,0 +1,111 @@
+//! Deduplication statistics of the blockstore
+//!
+//! Blocks are keyed by their multihash, so a block shared by several files,
+//! or repeated within one, is stored only once. The logical size of the
+//! repository counts every block as often as the pinned DAGs refer to it, the
+//! physical size counts the bytes actually stored; the difference is what
+//! deduplication saves.
+
+use anyhow::Result;
+use cid::{multihash::Multihash, Cid};
+use log::warn;
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    blockstore::{Blockstore, RAW_CODEC},
+    dag,
+    pin::{PinMode, PinStore},
+};
+
+/// Logical and physical size of a blockstore
+#[derive(Debug, Default)]
+pub struct DedupStats {
+    /// Number of blocks stored
+    pub blocks: usize,
+    /// Bytes the content would take if every reference to a block stored it again
+    pub logical_bytes: u64,
+    /// Bytes actually stored
+    pub physical_bytes: u64,
+}
+
+impl DedupStats {
+    /// Bytes saved by storing each block once
+    pub fn saved_bytes(&self) -> u64 {
+        self.logical_bytes.saturating_sub(self.physical_bytes)
+    }
+}
+
+/// Measure how much the blockstore saves by storing each block once
+///
+/// The DAGs of the recursive pins are counted with every block as often as
+/// they refer to it. Blocks outside of any pin, such as cached content, are
+/// counted once, as nothing tells how they are referred to.
+///
+/// # Arguments
+/// * `blockstore` - The blockstore to measure
+/// * `pins` - The pins whose DAGs are counted
+///
+/// # Returns
+/// * `Result<DedupStats>` - The logical and physical size
+pub fn dedup_stats(blockstore: &Blockstore, pins: &PinStore) -> Result<DedupStats> {
+    let mut stats = DedupStats::default();
+    let mut unreferenced = HashSet::new();
+    for cid in blockstore.list()? {
+        stats.blocks += 1;
+        stats.physical_bytes += blockstore.size(&cid)?.unwrap_or(0);
+        unreferenced.insert(*cid.hash());
+    }
+
+    // Logical size of the DAG below each block, so shared subtrees are only walked once
+    let mut sizes = HashMap::new();
+    for (cid, mode) in pins.list() {
+        stats.logical_bytes += match mode {
+            PinMode::Direct => {
+                unreferenced.remove(cid.hash());
+                blockstore.size(&cid)?.unwrap_or(0)
+            }
+            PinMode::Recursive => logical_size(blockstore, cid, &mut sizes, &mut unreferenced)?,
+        };
+    }
+    for hash in unreferenced {
+        stats.logical_bytes += blockstore.size(&Cid::new_v1(RAW_CODEC, hash))?.unwrap_or(0);
+    }
+    Ok(stats)
+}
+
+/// Logical size of the DAG below a block: its size plus that of every link, however often repeated
+///
+/// The DAG is walked children first, so the size of a block is known once
+/// those of its links are. Blocks reached are removed from `unreferenced`.
+fn logical_size(
+    blockstore: &Blockstore,
+    root: Cid,
+    sizes: &mut HashMap<Multihash<64>, u64>,
+    unreferenced: &mut HashSet<Multihash<64>>,
+) -> Result<u64> {
+    let mut stack = vec![(root, false)];
+    while let Some((cid, children_done)) = stack.pop() {
+        if sizes.contains_key(cid.hash()) {
+            continue;
+        }
+        let Some(data) = blockstore.get(&cid)? else {
+            warn!("Pinned block {} is missing from the blockstore", cid);
+            sizes.insert(*cid.hash(), 0);
+            continue;
+        };
+        let links = dag::links(&cid, &data).unwrap_or_else(|e| {
+            warn!("Failed to read links of pinned block {}: {}", cid, e);
+            Vec::new()
+        });
+
+        if children_done {
+            let size = data.len() as u64 + links.iter().map(|link| sizes.get(link.hash()).copied().unwrap_or(0)).sum::<u64>();
+            sizes.insert(*cid.hash(), size);
+        } else {
+            unreferenced.remove(cid.hash());
+            stack.push((cid, true));
+            stack.extend(links.into_iter().filter(|link| !sizes.contains_key(link.hash())).map(|link| (link, false)));
+        }
+    }
+    Ok(sizes.get(root.hash()).copied().unwrap_or(0))
+}
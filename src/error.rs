@@ -0,0 +1,120 @@
+//! Errors worth telling apart
+//!
+//! Most failures are reported as [`anyhow::Error`] with a description of what
+//! went wrong. The failures a caller may want to react to differently, such as
+//! content that doesn't exist versus a network that is down, are [`MigaError`]s
+//! somewhere in that error's chain, and each of them maps to its own process
+//! exit code.
+
+use cid::Cid;
+use std::{io, path::PathBuf, time::Duration};
+use thiserror::Error;
+
+/// Exit code of errors without a more specific code
+pub const EXIT_FAILURE: i32 = 1;
+
+/// A failure with its own exit code
+#[derive(Debug, Error)]
+pub enum MigaError {
+    /// A CID given by the user couldn't be parsed
+    #[error("Invalid CID {cid:?}: {reason}")]
+    InvalidCid {
+        /// The text that was given as a CID
+        cid: String,
+        /// Why it isn't a CID
+        reason: String,
+    },
+    /// A path within a CID doesn't name anything
+    #[error("No such path {path:?} in {root}")]
+    NoSuchPath {
+        /// CID the path starts at
+        root: Cid,
+        /// The path that couldn't be resolved
+        path: String,
+    },
+    /// Nobody on the network could provide a block
+    #[error("No provider found for block {cid} after {attempts} attempts")]
+    NoProviders {
+        /// CID of the block
+        cid: Cid,
+        /// Number of times the block was searched for
+        attempts: u32,
+    },
+    /// The network couldn't be reached
+    #[error("Network unavailable: {0}")]
+    Network(String),
+    /// A block isn't stored locally, and the node is offline
+    #[error("Block {0} is not stored locally, and the network isn't used offline")]
+    NotStoredLocally(Cid),
+    /// The operation didn't finish in time
+    #[error("Timed out after {}: {progress}", humantime::format_duration(*.timeout))]
+    Timeout {
+        /// The time that was allowed
+        timeout: Duration,
+        /// How far the operation got
+        progress: String,
+    },
+    /// Data doesn't hash to the CID it was requested under
+    #[error("Block data does not match CID {0}")]
+    VerificationFailed(Cid),
+    /// A local file doesn't have the content of a CID
+    #[error("{path:?} does not match {cid}: {reason}")]
+    FileMismatch {
+        /// The file that was checked
+        path: PathBuf,
+        /// The CID it was checked against
+        cid: Cid,
+        /// How the file differs
+        reason: String,
+    },
+    /// Reading or writing a file failed
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The process was asked to stop before the operation finished
+    #[error("Interrupted: {0}")]
+    Interrupted(String),
+}
+
+impl MigaError {
+    /// Process exit code of the error
+    ///
+    /// | Code | Error |
+    /// |------|-------|
+    /// | 1 | Any other error |
+    /// | 2 | [`MigaError::InvalidCid`] (and invalid command line arguments) |
+    /// | 3 | [`MigaError::NoProviders`] |
+    /// | 4 | [`MigaError::Network`] |
+    /// | 5 | [`MigaError::Timeout`] |
+    /// | 6 | [`MigaError::VerificationFailed`], [`MigaError::FileMismatch`] |
+    /// | 7 | [`MigaError::Io`] |
+    /// | 8 | [`MigaError::NoSuchPath`] |
+    /// | 9 | [`MigaError::NotStoredLocally`] |
+    /// | 130 | [`MigaError::Interrupted`] |
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MigaError::InvalidCid { .. } => 2,
+            MigaError::NoProviders { .. } => 3,
+            MigaError::Network(_) => 4,
+            MigaError::Timeout { .. } => 5,
+            MigaError::VerificationFailed(_) | MigaError::FileMismatch { .. } => 6,
+            MigaError::Io(_) => 7,
+            MigaError::NoSuchPath { .. } => 8,
+            MigaError::NotStoredLocally(_) => 9,
+            MigaError::Interrupted(_) => 130,
+        }
+    }
+}
+
+/// Process exit code of an error
+///
+/// The first [`MigaError`] in the error's chain decides the code. Errors
+/// without one still get the I/O code if an I/O error caused them.
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    if let Some(error) = error.chain().find_map(|cause| cause.downcast_ref::<MigaError>()) {
+        return error.exit_code();
+    }
+    if error.chain().any(|cause| cause.is::<io::Error>()) {
+        return MigaError::Io(io::ErrorKind::Other.into()).exit_code();
+    }
+    EXIT_FAILURE
+}
@@ -0,0 +1,546 @@
+//! Fetching files from the network
+//!
+//! A file is a DAG of blocks. Fetching walks the DAG depth-first, taking each
+//! block from the local blockstore if it is already there and from the network
+//! otherwise. Blocks fetched from the network are verified against their CID
+//! and stored, so they don't need to be fetched again. The file content is
+//! written out in order as soon as each block arrives, so it can be streamed
+//! to stdout or another writer without buffering the whole file.
+//!
+//! While the walk proceeds in order, the children of every node it reaches are
+//! fetched in the background, so independent branches of the DAG are retrieved
+//! concurrently, up to a configurable number of blocks at a time. Blocks
+//! fetched ahead of the walk go straight to the blockstore rather than being
+//! kept in memory, so memory use stays at a few blocks however large the file.
+//!
+//! Downloads to a file are resumable: the content is written to a partial file
+//! whose progress is recorded after every block, and an interrupted download
+//! continues from there, skipping the parts of the DAG it already wrote.
+
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use libp2p::PeerId;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tracing::{info_span, instrument, Instrument};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, Semaphore},
+    task::JoinHandle,
+};
+
+use crate::{
+    blockstore::{Blockstore, RAW_CODEC},
+    dag,
+    error::MigaError,
+    metrics::ActiveFetch,
+    network::Client,
+    progress::FetchProgress,
+    unixfs,
+};
+
+/// Default number of retries of a block that couldn't be found
+pub const DEFAULT_MAX_RETRIES: u32 = 10;
+
+/// Default delay before the first retry of a block
+pub const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest delay between two retries of a block, however many came before
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Number of blocks fetched ahead of the walk for each block fetched at the same time
+const READ_AHEAD_PER_FETCH: usize = 4;
+
+/// How blocks that couldn't be found on the network are retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt before giving up
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every further retry
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            backoff: DEFAULT_RETRY_BACKOFF,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before a retry, counting from 0 for the first one
+    ///
+    /// The delay doubles with every retry up to a minute, and a random part of
+    /// up to half of it is jittered so that blocks failing together don't all
+    /// hit the network again at the same moment.
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self.backoff.saturating_mul(1 << retry.min(16)).min(MAX_RETRY_DELAY);
+        delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+    }
+}
+
+/// Progress of a fetch, reported to library users
+#[derive(Debug, Clone)]
+pub enum FetchEvent {
+    /// A provider of a block was found in the DHT
+    ProviderFound {
+        /// CID of the block
+        cid: Cid,
+        /// The provider
+        peer: PeerId,
+    },
+    /// A block of the file was received, from the network or the local blockstore
+    BlockReceived {
+        /// CID of the block
+        cid: Cid,
+        /// Size of the block in bytes
+        size: usize,
+    },
+    /// The whole file was fetched
+    Completed {
+        /// Size of the file content in bytes
+        size: u64,
+    },
+    /// The fetch failed
+    Failed {
+        /// Description of the error
+        error: String,
+    },
+}
+
+/// Fetch a UnixFS file, writing its content to a writer as it arrives
+///
+/// # Arguments
+/// * `client` - Client of the network to fetch missing blocks from
+/// * `blockstore` - The local blockstore
+/// * `root` - CID of the file
+/// * `writer` - Destination of the file content
+/// * `concurrency` - Maximum number of blocks fetched from the network at the same time
+/// * `progress` - Progress display to update as content arrives, if any
+///
+/// # Returns
+/// * `Result<u64>` - Number of bytes written
+#[instrument(name = "fetch", skip_all, fields(cid = %root))]
+pub async fn fetch_file<W>(
+    client: &Client,
+    blockstore: &Blockstore,
+    root: Cid,
+    writer: &mut W,
+    concurrency: usize,
+    progress: Option<&FetchProgress>,
+) -> Result<u64>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = FileReader::new(client, blockstore, root, concurrency);
+    let mut written = 0;
+    while let Some(chunk) = reader.next_chunk().await? {
+        writer.write_all(&chunk).instrument(info_span!("write", bytes = chunk.len())).await?;
+        written += chunk.len() as u64;
+        if let Some(progress) = progress {
+            progress.update(&reader, written, client.provider_count());
+        }
+    }
+
+    writer.flush().await?;
+    Ok(written)
+}
+
+/// Fetch a UnixFS file to a path, resuming an earlier interrupted download
+///
+/// The content is written to `<path>.part`, and the number of bytes written
+/// is recorded in `<path>.part.json` after every block. When a download of
+/// the same CID to the same path was interrupted, it continues where it
+/// stopped. The partial file is renamed to `path` once it is complete.
+///
+/// # Arguments
+/// * `client` - Client of the network to fetch missing blocks from
+/// * `blockstore` - The local blockstore
+/// * `root` - CID of the file
+/// * `path` - File to save the content to
+/// * `concurrency` - Maximum number of blocks fetched from the network at the same time
+/// * `progress` - Progress display to update as content arrives, if any
+///
+/// # Returns
+/// * `Result<u64>` - Size of the saved file
+#[instrument(name = "fetch", skip_all, fields(cid = %root))]
+pub async fn fetch_to_path(
+    client: &Client,
+    blockstore: &Blockstore,
+    root: Cid,
+    path: &Path,
+    concurrency: usize,
+    progress: Option<&FetchProgress>,
+) -> Result<u64> {
+    let part_path = with_suffix(path, ".part");
+    let progress_path = with_suffix(path, ".part.json");
+
+    // Continue from the recorded offset if the partial file belongs to the same CID
+    // and still holds everything that was recorded as written
+    let part_len = fs::metadata(&part_path).map(|metadata| metadata.len()).unwrap_or(0);
+    let offset = match DownloadProgress::load(&progress_path) {
+        Some(progress) if progress.cid == root.to_string() && progress.offset <= part_len => progress.offset,
+        _ => 0,
+    };
+    if offset > 0 {
+        info!("Resuming download of {} at byte {}", root, offset);
+    }
+
+    // Discard anything written after the last recorded offset
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&part_path)
+        .await
+        .map_err(|e| anyhow!("Failed to open {:?}: {}", part_path, e))?;
+    file.set_len(offset).await?;
+
+    let mut reader = FileReader::new(client, blockstore, root, concurrency).with_offset(offset);
+    let mut written = offset;
+    while let Some(chunk) = reader.next_chunk().await? {
+        async {
+            file.write_all(&chunk).await?;
+            file.flush().await
+        }
+        .instrument(info_span!("write", bytes = chunk.len()))
+        .await?;
+        written += chunk.len() as u64;
+        if let Some(progress) = progress {
+            progress.update(&reader, written, client.provider_count());
+        }
+
+        DownloadProgress {
+            cid: root.to_string(),
+            offset: written,
+        }
+        .save(&progress_path)?;
+    }
+    file.sync_all().await?;
+    drop(file);
+
+    // The download is complete, so the partial file becomes the output
+    tokio::fs::rename(&part_path, path)
+        .await
+        .map_err(|e| anyhow!("Failed to move {:?} to {:?}: {}", part_path, path, e))?;
+    if let Err(e) = fs::remove_file(&progress_path) {
+        warn!("Failed to remove {:?}: {}", progress_path, e);
+    }
+    Ok(written)
+}
+
+/// Reads the content of a UnixFS file in order, one block at a time
+pub struct FileReader {
+    /// Client of the network to fetch missing blocks from
+    client: Client,
+    /// The local blockstore
+    blockstore: Blockstore,
+    /// Limit on the number of blocks fetched at the same time
+    limit: Arc<Semaphore>,
+    /// Blocks being fetched ahead of the walk
+    prefetches: HashMap<Cid, Prefetch>,
+    /// Maximum number of blocks fetched ahead of the walk
+    read_ahead: usize,
+    /// Blocks still to visit, the next one on top
+    stack: Vec<Cid>,
+    /// Number of bytes at the start of the file still to be skipped
+    skip: u64,
+    /// Number of blocks read so far
+    blocks: u64,
+    /// Size of the file, known once the root block has been read
+    size: Option<u64>,
+    /// Where to report progress events, if anywhere
+    events: Option<mpsc::UnboundedSender<FetchEvent>>,
+    /// Counts the reader as an active fetch in the metrics while it exists
+    _active: ActiveFetch,
+}
+
+impl FileReader {
+    /// Create a reader for a file
+    ///
+    /// # Arguments
+    /// * `client` - Client of the network to fetch missing blocks from
+    /// * `blockstore` - The local blockstore
+    /// * `root` - CID of the file
+    /// * `concurrency` - Maximum number of blocks fetched from the network at the same time
+    pub fn new(client: &Client, blockstore: &Blockstore, root: Cid, concurrency: usize) -> Self {
+        Self {
+            client: client.clone(),
+            blockstore: blockstore.clone(),
+            limit: Arc::new(Semaphore::new(concurrency.max(1))),
+            prefetches: HashMap::new(),
+            read_ahead: concurrency.max(1) * READ_AHEAD_PER_FETCH,
+            stack: vec![root],
+            skip: 0,
+            blocks: 0,
+            size: None,
+            events: None,
+            _active: client.metrics().start_fetch(),
+        }
+    }
+
+    /// Report providers found and blocks received as [`FetchEvent`]s
+    pub fn with_events(mut self, events: mpsc::UnboundedSender<FetchEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Number of blocks read so far
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    /// Size of the file, if the root block records it
+    pub fn size(&self) -> Option<u64> {
+        self.size
+    }
+
+    /// Start reading at an offset into the file
+    ///
+    /// Parts of the DAG that lie entirely before the offset are not fetched
+    /// at all, as long as their nodes record the sizes of their children.
+    pub fn with_offset(mut self, offset: u64) -> Self {
+        self.skip = offset;
+        self
+    }
+
+    /// Read the next chunk of file content
+    ///
+    /// # Returns
+    /// * `Result<Option<Vec<u8>>>` - The content stored in the next block, or None at the end of the file
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        // Walk the DAG depth-first, left to right, which yields the file content in order
+        while let Some(cid) = self.stack.pop() {
+            // Wait for a background fetch of the block to store it
+            if let Some(mut prefetch) = self.prefetches.remove(&cid) {
+                (&mut prefetch.0).await??;
+            }
+            let block = get_block(&self.client, &self.blockstore, &cid, self.events.as_ref()).await?;
+            if let Some(events) = &self.events {
+                let _ = events.send(FetchEvent::BlockReceived { cid, size: block.len() });
+            }
+            let file_block = unixfs::decode_file_block(&cid, &block)?;
+            if self.blocks == 0 {
+                self.size = file_block.filesize;
+            }
+            self.blocks += 1;
+
+            // Drop the content before the offset
+            let mut data = file_block.data;
+            let skipped = self.skip.min(data.len() as u64);
+            data.drain(..skipped as usize);
+            self.skip -= skipped;
+
+            // Skip whole children that lie before the offset
+            let mut children = file_block.children.as_slice();
+            if file_block.child_sizes.len() == children.len() {
+                for size in &file_block.child_sizes {
+                    if self.skip < *size {
+                        break;
+                    }
+                    self.skip -= size;
+                    children = &children[1..];
+                }
+            }
+
+            self.stack.extend(children.iter().rev());
+            self.read_ahead();
+
+            if !data.is_empty() {
+                return Ok(Some(data));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Start fetching the next blocks of the walk in the background, so they
+    /// are (hopefully) already there by the time the walk reaches them
+    ///
+    /// Only a window of blocks at the top of the stack is fetched ahead, so a
+    /// file with a huge number of blocks doesn't spawn a task for each of them.
+    fn read_ahead(&mut self) {
+        let next: Vec<Cid> = self.stack.iter().rev().take(self.read_ahead).copied().collect();
+        for cid in next {
+            if self.prefetches.len() >= self.read_ahead {
+                break;
+            }
+            self.prefetch(cid);
+        }
+    }
+
+    /// Start fetching a block in the background unless it is already stored or being fetched
+    fn prefetch(&mut self, cid: Cid) {
+        if self.prefetches.contains_key(&cid) || self.blockstore.has(&cid) {
+            return;
+        }
+        let (client, blockstore, limit) = (self.client.clone(), self.blockstore.clone(), self.limit.clone());
+        let events = self.events.clone();
+        // Keep the prefetch in the trace of the fetch that started it
+        let handle = tokio::spawn(
+            async move {
+                let _permit = limit.acquire_owned().await?;
+                get_block(&client, &blockstore, &cid, events.as_ref()).await?;
+                Ok(())
+            }
+            .in_current_span(),
+        );
+        self.prefetches.insert(cid, Prefetch(handle));
+    }
+}
+
+/// A block being fetched into the blockstore in the background
+///
+/// The fetch is aborted when it is dropped, so a failed walk doesn't leave
+/// fetches running (and retrying) behind it.
+struct Prefetch(JoinHandle<Result<()>>);
+
+impl Drop for Prefetch {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Progress of a download to a file, saved next to the partial file
+#[derive(Serialize, Deserialize)]
+struct DownloadProgress {
+    /// CID of the file being downloaded
+    cid: String,
+    /// Number of bytes written to the partial file
+    offset: u64,
+}
+
+impl DownloadProgress {
+    /// Load the progress of an earlier download, if there is any
+    fn load(path: &Path) -> Option<Self> {
+        let data = fs::read(path).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    /// Save the progress
+    fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec(self)?)
+            .map_err(|e| anyhow!("Failed to save download progress to {:?}: {}", path, e))
+    }
+}
+
+/// Append a suffix to the file name of a path
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Whether content is stored completely in the blockstore, so it can be served without the network
+///
+/// Walks the directories along the path and the whole DAG below it. Raw
+/// blocks have no links, so they are only checked for, not read.
+///
+/// # Arguments
+/// * `blockstore` - The local blockstore
+/// * `root` - CID the content starts at
+/// * `path` - Path of the content within `root`, separated by slashes
+///
+/// # Returns
+/// * `Result<bool>` - Whether every block is stored; a path that doesn't exist in stored directories counts
+///   as stored, as resolving it locally tells so
+pub fn stored_locally(blockstore: &Blockstore, root: Cid, path: &str) -> Result<bool> {
+    let mut cid = root;
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        let Some(block) = blockstore.get(&cid)? else {
+            return Ok(false);
+        };
+        match unixfs::find_entry(&cid, &block, name)? {
+            Some(entry) => cid = entry,
+            None => return Ok(true),
+        }
+    }
+
+    let mut visited = HashSet::new();
+    let mut stack = vec![cid];
+    while let Some(cid) = stack.pop() {
+        if !visited.insert(cid) {
+            continue;
+        }
+        if cid.codec() == RAW_CODEC {
+            if !blockstore.has(&cid) {
+                return Ok(false);
+            }
+            continue;
+        }
+        let Some(block) = blockstore.get(&cid)? else {
+            return Ok(false);
+        };
+        stack.extend(dag::links(&cid, &block)?);
+    }
+    Ok(true)
+}
+
+/// Get a block from the blockstore, or from the network if it isn't stored locally
+///
+/// Blocks that can't be found on the network are retried with exponential
+/// backoff, as configured by the client's [`RetryPolicy`], before giving up.
+///
+/// # Arguments
+/// * `client` - Client of the network to fetch the block from
+/// * `blockstore` - The local blockstore
+/// * `cid` - CID of the block
+/// * `events` - Where to report the providers found for the block, if anywhere
+#[instrument(name = "block_fetch", skip_all, fields(cid = %cid))]
+pub async fn get_block(
+    client: &Client,
+    blockstore: &Blockstore,
+    cid: &Cid,
+    events: Option<&mpsc::UnboundedSender<FetchEvent>>,
+) -> Result<Vec<u8>> {
+    if let Some(data) = blockstore.get(cid)? {
+        return Ok(data);
+    }
+    // Offline, there is nobody to retry with
+    if client.is_offline() {
+        return Err(MigaError::NotStoredLocally(*cid).into());
+    }
+
+    let retry = client.retry_policy();
+    let mut retries = 0;
+    loop {
+        match client.get_block(*cid, events).await {
+            Ok(data) => {
+                // Keep the block in the blockstore so it survives restarts
+                blockstore.put(cid, &data)?;
+                return Ok(data);
+            }
+            Err(e) if retries < retry.max_retries => {
+                // Retry the query after a growing delay
+                // This helps with temporary network issues without flooding the DHT
+                let delay = retry.delay(retries);
+                retries += 1;
+                warn!(
+                    "Failed to get block {}, retry {} of {} in {:.1?}: {}",
+                    cid, retries, retry.max_retries, delay, e
+                );
+                tokio::time::sleep(delay).await;
+            }
+            Err(e) => {
+                warn!("Giving up on block {}: {}", cid, e);
+                // Without any peer, the block may well exist, but the network is out of reach
+                let connected = client.connected_peers().await.map(|peers| !peers.is_empty()).unwrap_or(false);
+                if !connected {
+                    return Err(MigaError::Network(format!("not connected to any peer while fetching block {}", cid)).into());
+                }
+                return Err(MigaError::NoProviders {
+                    cid: *cid,
+                    attempts: retries + 1,
+                }
+                .into());
+            }
+        }
+    }
+}
@@ -0,0 +1,404 @@
+//! HTTP gateway
+//!
+//! `miga daemon --gateway <MULTIADDR>` serves content over plain HTTP, like
+//! the path gateway of Kubo, so browsers and tools without IPFS support can
+//! download it:
+//!
+//! - `GET /ipfs/<cid>`: Content of a file
+//! - `GET /ipfs/<cid>/<path>`: Content of a file within a directory
+//!
+//! A directory is answered with an HTML page listing its entries, with links
+//! to each of them and to the directories above it.
+//!
+//! The type of a file is guessed from the extension of its name, or from its
+//! first bytes when it has none, so browsers show images and documents
+//! instead of downloading everything. Like with Kubo, `?filename=<name>` names
+//! content reached by its CID alone, and `?download=true` asks the browser to
+//! save the file instead of showing it.
+//!
+//! Content behind a CID never changes, so every response carries the CID of
+//! the file as its `ETag` and may be cached for a year. A request whose
+//! `If-None-Match` names that CID is answered with `304 Not Modified` without
+//! reading the content.
+//!
+//! Like Kubo's gateway, it lets browser applications on any origin read
+//! content, since there is nothing to protect that the URL doesn't give away.
+//! How much each client may ask of the gateway can be limited, see
+//! [`GatewayLimits`].
+//!
+//! Content that isn't in the local blockstore is fetched from the network on
+//! demand, and every block fetched is verified against its CID before it is
+//! served. Unlike the control API, the gateway only reads content, so it can
+//! be exposed to others without giving them control over the node. The
+//! content it serves counts against the node's upload rate limit.
+
+use anyhow::Result;
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, Method, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use cid::Cid;
+use futures::{stream, StreamExt};
+use indicatif::HumanBytes;
+use log::warn;
+use serde::Deserialize;
+use std::{fmt::Write, future::Future, sync::Arc};
+use tower_http::cors::CorsLayer;
+
+use crate::{
+    api::{self, ApiAddr},
+    error::MigaError,
+    fetch::FileReader,
+    node::MigaNode,
+    throttle::RateLimiter,
+    unixfs::DirEntry,
+};
+
+mod limits;
+
+pub use limits::GatewayLimits;
+
+/// `Cache-Control` of content, which is immutable, as Kubo sends it
+const CACHE_CONTROL_IMMUTABLE: &str = "public, max-age=29030400, immutable";
+
+/// Serve the gateway until it is shut down
+///
+/// # Arguments
+/// * `node` - The node whose content is served
+/// * `addr` - Address to listen on
+/// * `limits` - Limits on what one client may ask of the gateway
+/// * `shutdown` - Completes when the server should stop accepting requests
+///
+/// # Returns
+/// * `Result<()>` - Ok once the server has shut down, Err if it failed
+pub async fn serve<F>(node: Arc<MigaNode>, addr: &ApiAddr, limits: GatewayLimits, shutdown: F) -> Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    api::serve_router(router(node, limits), addr, shutdown).await
+}
+
+/// Build the routes of the gateway
+pub fn router(node: Arc<MigaNode>, limits: GatewayLimits) -> Router {
+    let mut router = Router::new()
+        .route("/ipfs/{cid}", get(ipfs_root))
+        .route("/ipfs/{cid}/{*path}", get(ipfs_path))
+        .layer(
+            CorsLayer::new()
+                .allow_origin(tower_http::cors::Any)
+                .allow_methods([Method::GET, Method::HEAD])
+                .expose_headers([header::CONTENT_LENGTH, header::CONTENT_DISPOSITION, header::ETAG]),
+        )
+        .with_state(node.clone());
+    if limits.is_limited() {
+        let clients = Arc::new(limits::Clients::new(limits));
+        router = router.layer(middleware::from_fn_with_state(clients, limits::limit));
+    }
+    // Health checks are frequent, and not limited like the requests of clients
+    router.merge(api::health_router(node))
+}
+
+/// Error of a gateway request, returned as plain text with a fitting status
+struct GatewayError(anyhow::Error);
+
+impl<E: Into<anyhow::Error>> From<E> for GatewayError {
+    fn from(e: E) -> Self {
+        GatewayError(e.into())
+    }
+}
+
+impl IntoResponse for GatewayError {
+    fn into_response(self) -> Response {
+        let status = match self.0.chain().find_map(|cause| cause.downcast_ref::<MigaError>()) {
+            Some(MigaError::InvalidCid { .. }) => StatusCode::BAD_REQUEST,
+            Some(MigaError::NoSuchPath { .. }) => StatusCode::NOT_FOUND,
+            Some(MigaError::NoProviders { .. } | MigaError::Network(_) | MigaError::Timeout { .. }) => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        if status.is_server_error() {
+            warn!("Gateway request failed: {:#}", self.0);
+        }
+        (status, format!("{:#}\n", self.0)).into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct ContentQuery {
+    filename: Option<String>,
+    #[serde(default)]
+    download: bool,
+}
+
+async fn ipfs_root(
+    State(node): State<Arc<MigaNode>>,
+    Path(cid): Path<String>,
+    Query(query): Query<ContentQuery>,
+    headers: HeaderMap,
+) -> Result<Response, GatewayError> {
+    serve_content(&node, &cid, "", &query, &headers).await
+}
+
+async fn ipfs_path(
+    State(node): State<Arc<MigaNode>>,
+    Path((cid, path)): Path<(String, String)>,
+    Query(query): Query<ContentQuery>,
+    headers: HeaderMap,
+) -> Result<Response, GatewayError> {
+    serve_content(&node, &cid, &path, &query, &headers).await
+}
+
+/// Answer with the content at a path within a CID
+async fn serve_content(
+    node: &MigaNode,
+    cid: &str,
+    path: &str,
+    query: &ContentQuery,
+    headers: &HeaderMap,
+) -> Result<Response, GatewayError> {
+    let root: Cid = cid.parse().map_err(|e: cid::Error| MigaError::InvalidCid {
+        cid: cid.to_string(),
+        reason: e.to_string(),
+    })?;
+    let target = node.resolve_path(root, path).await?;
+
+    // The client already has the content if it has a copy tagged with its CID
+    let etag = format!("\"{}\"", target);
+    if headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| matches_etag(value, &etag))
+    {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, &etag)
+            .header(header::CACHE_CONTROL, CACHE_CONTROL_IMMUTABLE)
+            .body(Body::empty())?);
+    }
+
+    // Directories are answered with a listing of their entries
+    if let Some(entries) = node.list_directory(target).await? {
+        return Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(header::ETAG, &etag)
+            .header(header::CACHE_CONTROL, CACHE_CONTROL_IMMUTABLE)
+            .body(Body::from(directory_page(&root, path, &entries)))?);
+    }
+
+    // Fetch the first chunk before answering, so content that can't be found
+    // is reported with an error status instead of an empty response
+    let mut reader = node.reader(target);
+    let first = reader.next_chunk().await?;
+    let size = reader.size();
+
+    // The name the content is saved under: the one asked for, or the last part of the path
+    let filename = query
+        .filename
+        .as_deref()
+        .or_else(|| path.rsplit('/').find(|name| !name.is_empty()))
+        .filter(|name| !name.is_empty());
+    let content_type = content_type(filename, first.as_deref().unwrap_or_default());
+
+    // Stream the rest of the content as it arrives, as fast as the upload limit allows
+    let upload = node.rate_limits().upload.clone();
+    let rest = stream::unfold(Some(reader), |reader: Option<FileReader>| async move {
+        let mut reader = reader?;
+        match reader.next_chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), Some(reader))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    let body = stream::iter(first.map(Ok)).chain(rest).then(move |chunk| limit_upload(upload.clone(), chunk));
+
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .header(header::ETAG, &etag)
+        .header(header::CACHE_CONTROL, CACHE_CONTROL_IMMUTABLE);
+    if filename.is_some() || query.download {
+        response = response.header(header::CONTENT_DISPOSITION, content_disposition(filename, query.download));
+    }
+    if let Some(size) = size {
+        response = response.header(header::CONTENT_LENGTH, size);
+    }
+    Ok(response.body(Body::from_stream(body))?)
+}
+
+/// Hold a chunk back until the upload limit lets it pass
+async fn limit_upload(limiter: Option<RateLimiter>, chunk: Result<Vec<u8>>) -> Result<Vec<u8>> {
+    if let (Some(limiter), Ok(data)) = (&limiter, &chunk) {
+        limiter.acquire(data.len()).await;
+    }
+    chunk
+}
+
+/// HTML page listing the entries of a directory
+///
+/// # Arguments
+/// * `root` - CID the path starts at
+/// * `path` - Path of the directory within the root
+/// * `entries` - Entries of the directory
+///
+/// # Returns
+/// * `String` - The page
+fn directory_page(root: &Cid, path: &str, entries: &[DirEntry]) -> String {
+    // Each directory from the root down to this one, with the URL of its listing
+    let mut crumbs = vec![(format!("/ipfs/{}", root), root.to_string())];
+    for name in path.split('/').filter(|name| !name.is_empty()) {
+        let url = format!("{}/{}", crumbs[crumbs.len() - 1].0, percent_encode(name));
+        crumbs.push((url, name.to_string()));
+    }
+    let url = &crumbs[crumbs.len() - 1].0;
+    let title = format!("/ipfs/{}/{}", root, path.trim_matches('/'));
+
+    let mut page = String::new();
+    let _ = write!(
+        page,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Index of {}</title>\n</head>\n<body>\n<h1>Index of ",
+        escape_html(title.trim_end_matches('/'))
+    );
+    let links: Vec<String> = crumbs
+        .iter()
+        .map(|(url, name)| format!("<a href=\"{}\">{}</a>", url, escape_html(name)))
+        .collect();
+    let _ = write!(page, "/ipfs/{}</h1>\n<table>\n<tr><th>Name</th><th>Size</th><th>CID</th></tr>\n", links.join("/"));
+    if crumbs.len() > 1 {
+        let _ = writeln!(page, "<tr><td><a href=\"{}\">..</a></td><td></td><td></td></tr>", crumbs[crumbs.len() - 2].0);
+    }
+    for entry in entries {
+        let size = entry.size.map(|size| HumanBytes(size).to_string()).unwrap_or_default();
+        let _ = writeln!(
+            page,
+            "<tr><td><a href=\"{}/{}\">{}</a></td><td>{}</td><td><a href=\"/ipfs/{}\">{}</a></td></tr>",
+            url,
+            percent_encode(&entry.name),
+            escape_html(&entry.name),
+            size,
+            entry.cid,
+            entry.cid
+        );
+    }
+    page.push_str("</table>\n</body>\n</html>\n");
+    page
+}
+
+/// Escape text for use in HTML, in content and attribute values alike
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encode text as UTF-8, leaving only the characters that never need it
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", byte);
+        }
+    }
+    encoded
+}
+
+/// Whether an `If-None-Match` header names an entity tag
+///
+/// The header lists entity tags separated by commas, or is `*` for any.
+/// Weak tags match as well, as the comparison for `If-None-Match` is weak.
+fn matches_etag(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Media type of content, guessed from its name, or else from its first bytes
+///
+/// # Arguments
+/// * `filename` - Name of the content, if known
+/// * `start` - First bytes of the content
+///
+/// # Returns
+/// * `String` - The media type, `application/octet-stream` if it can't be told
+fn content_type(filename: Option<&str>, start: &[u8]) -> String {
+    let guessed = filename.and_then(|name| mime_guess::from_path(name).first_raw());
+    let media_type = guessed.or_else(|| sniff(start)).unwrap_or("application/octet-stream");
+    // Text is served as UTF-8, which also covers plain ASCII
+    if media_type.starts_with("text/") && !media_type.contains("charset") {
+        format!("{}; charset=utf-8", media_type)
+    } else {
+        media_type.to_string()
+    }
+}
+
+/// Media type of content, told by the signature its first bytes start with
+fn sniff(start: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"ID3", "audio/mpeg"),
+        (b"OggS", "audio/ogg"),
+        (b"fLaC", "audio/flac"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+    ];
+    if let Some((_, media_type)) = SIGNATURES.iter().find(|(signature, _)| start.starts_with(signature)) {
+        return Some(*media_type);
+    }
+    // Containers whose kind follows their header
+    match (start.get(..4), start.get(4..8), start.get(8..12)) {
+        (Some(b"RIFF"), _, Some(b"WEBP")) => return Some("image/webp"),
+        (Some(b"RIFF"), _, Some(b"WAVE")) => return Some("audio/wav"),
+        (_, Some(b"ftyp"), _) => return Some("video/mp4"),
+        _ => {}
+    }
+    // Text that was cut off in the middle of a character still counts as text
+    let text = match std::str::from_utf8(start) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+    (text && !start.is_empty() && !start.contains(&0)).then_some("text/plain")
+}
+
+/// Value of the `Content-Disposition` header for content
+///
+/// The name is given twice: plainly for older clients, with the characters a
+/// quoted string can't hold replaced, and percent-encoded as UTF-8 (RFC 6266).
+///
+/// # Arguments
+/// * `filename` - Name the content is saved under, if known
+/// * `download` - Whether the browser should save the content instead of showing it
+///
+/// # Returns
+/// * `String` - The header value
+fn content_disposition(filename: Option<&str>, download: bool) -> String {
+    let mut value = String::from(if download { "attachment" } else { "inline" });
+    if let Some(name) = filename {
+        let plain: String = name
+            .chars()
+            .map(|c| if (c.is_ascii_graphic() && c != '"' && c != '\\') || c == ' ' { c } else { '_' })
+            .collect();
+        let _ = write!(value, "; filename=\"{}\"; filename*=UTF-8''{}", plain, percent_encode(name));
+    }
+    value
+}
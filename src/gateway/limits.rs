@@ -0,0 +1,136 @@
+//! Limits on what one client may ask of the gateway
+//!
+//! A gateway exposed to the internet serves whoever asks, and a single
+//! aggressive client could keep all of the node's upload bandwidth to itself.
+//! Clients are told apart by their IP address; requests over the limits are
+//! answered with `429 Too Many Requests`. Clients reaching the gateway over a
+//! Unix domain socket are local and not limited.
+
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+
+/// Period the request limit applies to
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Number of clients tracked before those that have gone quiet are forgotten
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// Limits on what one client may ask of the gateway, `None` for no cap
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GatewayLimits {
+    /// Requests a client may make per minute
+    pub requests_per_minute: Option<u32>,
+    /// Responses a client may be downloading at the same time
+    pub downloads_per_client: Option<usize>,
+}
+
+impl GatewayLimits {
+    /// Whether any limit is set
+    pub fn is_limited(&self) -> bool {
+        self.requests_per_minute.is_some() || self.downloads_per_client.is_some()
+    }
+}
+
+/// What the gateway knows about its clients
+pub(super) struct Clients {
+    limits: GatewayLimits,
+    clients: Mutex<HashMap<IpAddr, Client>>,
+}
+
+/// What the gateway knows about one client
+struct Client {
+    /// Start of the current period of the request limit
+    window: Instant,
+    /// Requests made in the current period
+    requests: u32,
+    /// Downloads the client may still start, shared by its responses in progress
+    downloads: Option<Arc<Semaphore>>,
+}
+
+impl Clients {
+    pub(super) fn new(limits: GatewayLimits) -> Self {
+        Self {
+            limits,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Admit a request of a client
+    ///
+    /// # Returns
+    /// * `Result<Option<OwnedSemaphorePermit>, Duration>` - The client's download
+    ///   slot to hold until the response is sent, or how long to wait before asking again
+    fn admit(&self, ip: IpAddr) -> Result<Option<OwnedSemaphorePermit>, Duration> {
+        let mut clients = self.clients.lock().unwrap();
+        let now = Instant::now();
+        if clients.len() >= MAX_TRACKED_CLIENTS {
+            // Clients without a download in progress whose period is over have nothing left to count
+            clients.retain(|_, client| {
+                let downloading = client
+                    .downloads
+                    .as_ref()
+                    .is_some_and(|downloads| Some(downloads.available_permits()) != self.limits.downloads_per_client);
+                downloading || now.duration_since(client.window) < WINDOW
+            });
+        }
+        let client = clients.entry(ip).or_insert_with(|| Client {
+            window: now,
+            requests: 0,
+            downloads: self.limits.downloads_per_client.map(|max| Arc::new(Semaphore::new(max))),
+        });
+
+        if let Some(max) = self.limits.requests_per_minute {
+            if now.duration_since(client.window) >= WINDOW {
+                client.window = now;
+                client.requests = 0;
+            }
+            if client.requests >= max {
+                return Err(WINDOW - now.duration_since(client.window));
+            }
+            client.requests += 1;
+        }
+        match &client.downloads {
+            Some(downloads) => downloads.clone().try_acquire_owned().map(Some).map_err(|_| Duration::from_secs(1)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Answer requests over a client's limits with `429 Too Many Requests`
+pub(super) async fn limit(State(clients): State<Arc<Clients>>, request: Request, next: Next) -> Response {
+    let Some(ConnectInfo(addr)) = request.extensions().get::<ConnectInfo<SocketAddr>>().copied() else {
+        return next.run(request).await;
+    };
+    match clients.admit(addr.ip()) {
+        Ok(None) => next.run(request).await,
+        // The download lasts until the whole body is sent, not just until the handler returns
+        Ok(Some(permit)) => next.run(request).await.map(|body| {
+            Body::from_stream(body.into_data_stream().map(move |chunk| {
+                let _held = &permit;
+                chunk
+            }))
+        }),
+        Err(retry_after) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after.as_secs().max(1).to_string())],
+            "Too many requests, try again later\n",
+        )
+            .into_response(),
+    }
+}
@@ -0,0 +1,82 @@
+//! Garbage collection of the blockstore
+//!
+//! Removes every block that isn't protected by a pin. A recursive pin protects
+//! all blocks reachable from the pinned CID, a direct pin only the block itself.
+//! Unpinned blocks are the cache of fetched content, which can also be cleared
+//! of the blocks stored longer than a time only.
+
+use anyhow::Result;
+use std::time::Duration;
+
+use crate::{blockstore::Blockstore, pin::PinStore};
+
+/// Outcome of a garbage collection run
+#[derive(Debug, Default)]
+pub struct GcStats {
+    /// Number of blocks removed (or that would be removed in a dry run)
+    pub removed_blocks: usize,
+    /// Number of bytes freed (or that would be freed in a dry run)
+    pub removed_bytes: u64,
+    /// Number of blocks kept because they are pinned
+    pub kept_blocks: usize,
+}
+
+/// Remove all unpinned blocks from the blockstore
+///
+/// # Arguments
+/// * `blockstore` - The blockstore to clean up
+/// * `pins` - The pins protecting blocks from removal
+/// * `dry_run` - Only report what would be removed, without removing anything
+///
+/// # Returns
+/// * `Result<GcStats>` - Statistics about the removed blocks
+pub fn collect_garbage(blockstore: &Blockstore, pins: &PinStore, dry_run: bool) -> Result<GcStats> {
+    remove_unpinned(blockstore, pins, dry_run, None)
+}
+
+/// Remove the cached content: the unpinned blocks, stored longer than a time if given
+///
+/// # Arguments
+/// * `blockstore` - The blockstore to clean up
+/// * `pins` - The pins protecting blocks from removal
+/// * `older_than` - Only remove the blocks stored longer than this; recent blocks are neither removed nor counted
+///
+/// # Returns
+/// * `Result<GcStats>` - Statistics about the removed blocks
+pub fn clear_cache(blockstore: &Blockstore, pins: &PinStore, older_than: Option<Duration>) -> Result<GcStats> {
+    remove_unpinned(blockstore, pins, false, older_than)
+}
+
+/// Remove the unpinned blocks, stored longer than `older_than` if given
+fn remove_unpinned(
+    blockstore: &Blockstore,
+    pins: &PinStore,
+    dry_run: bool,
+    older_than: Option<Duration>,
+) -> Result<GcStats> {
+    let pinned = pins.pinned_blocks(blockstore)?;
+
+    let mut stats = GcStats::default();
+    for cid in blockstore.list()? {
+        if pinned.contains(cid.hash()) {
+            stats.kept_blocks += 1;
+            continue;
+        }
+        if let Some(older_than) = older_than
+            && blockstore.age(&cid)?.is_none_or(|age| age <= older_than)
+        {
+            continue;
+        }
+
+        let size = blockstore.size(&cid)?.unwrap_or(0);
+        if dry_run || blockstore.remove(&cid)? {
+            stats.removed_blocks += 1;
+            stats.removed_bytes += size;
+        }
+    }
+    // The removed blocks would stay in the bloom filter of the blockstore
+    if !dry_run && stats.removed_blocks > 0 {
+        blockstore.rebuild_filter()?;
+    }
+    Ok(stats)
+}
@@ -0,0 +1,277 @@
+//! Structured IPLD data
+//!
+//! Besides files, IPFS stores structured data as IPLD nodes: maps, lists,
+//! strings, numbers, bytes and links to other blocks. Nodes are written by
+//! users in the dag-json form, where a link is `{"/": "<cid>"}` and bytes are
+//! `{"/": {"bytes": "<base64>"}}`, and stored as dag-cbor or dag-json blocks.
+//!
+//! Like protobuf, CBOR is encoded and decoded by hand: only the canonical
+//! subset dag-cbor allows is needed, and decoding is only needed to find links.
+
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use cid::Cid;
+use serde_json::{Map, Value};
+
+use crate::dag::{DAG_CBOR_CODEC, DAG_JSON_CODEC};
+
+/// CBOR tag of links in dag-cbor
+const CID_TAG: u64 = 42;
+
+/// Nesting depth beyond which blocks are rejected instead of decoded
+const MAX_DEPTH: usize = 256;
+
+/// Codecs IPLD nodes can be stored with
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpldCodec {
+    /// Compact binary encoding, the default of `ipfs dag put`
+    #[default]
+    DagCbor,
+    /// JSON encoding, readable but larger
+    DagJson,
+}
+
+impl IpldCodec {
+    /// Multicodec code of the codec
+    pub fn code(self) -> u64 {
+        match self {
+            IpldCodec::DagCbor => DAG_CBOR_CODEC,
+            IpldCodec::DagJson => DAG_JSON_CODEC,
+        }
+    }
+
+    /// Encode a node given in the dag-json form
+    ///
+    /// # Arguments
+    /// * `value` - The node, with links and bytes in their dag-json form
+    ///
+    /// # Returns
+    /// * `Result<Vec<u8>>` - The encoded block, or Err if a link or bytes are malformed
+    pub fn encode(self, value: &Value) -> Result<Vec<u8>> {
+        match self {
+            IpldCodec::DagCbor => {
+                let mut out = Vec::new();
+                encode_cbor(value, &mut out)?;
+                Ok(out)
+            }
+            IpldCodec::DagJson => {
+                // Check the links and bytes; serde_json already writes map keys sorted and without whitespace
+                encode_cbor(value, &mut Vec::new())?;
+                Ok(serde_json::to_vec(value)?)
+            }
+        }
+    }
+}
+
+/// What a map with a `/` key stands for in dag-json
+enum Special {
+    Link(Cid),
+    Bytes(Vec<u8>),
+}
+
+/// Recognize the links and bytes of dag-json
+///
+/// # Returns
+/// * `Result<Option<Special>>` - The link or bytes, None for an ordinary map, or Err for a malformed one
+fn special(map: &Map<String, Value>) -> Result<Option<Special>> {
+    let Some(slash) = map.get("/") else {
+        return Ok(None);
+    };
+    if map.len() != 1 {
+        return Err(anyhow!("A map with a \"/\" key may have no other keys"));
+    }
+    match slash {
+        Value::String(cid) => Ok(Some(Special::Link(
+            Cid::try_from(cid.as_str()).map_err(|e| anyhow!("Invalid link {:?}: {}", cid, e))?,
+        ))),
+        Value::Object(inner) => match inner.get("bytes") {
+            Some(Value::String(bytes)) if inner.len() == 1 => Ok(Some(Special::Bytes(
+                STANDARD_NO_PAD
+                    .decode(bytes.trim_end_matches('='))
+                    .map_err(|e| anyhow!("Invalid base64 bytes {:?}: {}", bytes, e))?,
+            ))),
+            _ => Err(anyhow!("Bytes must be given as {{\"/\": {{\"bytes\": \"<base64>\"}}}}")),
+        },
+        _ => Err(anyhow!("Links must be given as {{\"/\": \"<cid>\"}}")),
+    }
+}
+
+/// Append the head of a CBOR item: its major type and argument, in the shortest form
+fn write_head(major: u8, argument: u64, out: &mut Vec<u8>) {
+    let major = major << 5;
+    match argument {
+        0..=23 => out.push(major | argument as u8),
+        24..=0xff => out.extend([major | 24, argument as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend((argument as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend((argument as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend(argument.to_be_bytes());
+        }
+    }
+}
+
+/// Append a value in canonical dag-cbor form
+fn encode_cbor(value: &Value, out: &mut Vec<u8>) -> Result<()> {
+    match value {
+        Value::Null => out.push(0xf6),
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::Number(number) => {
+            if let Some(n) = number.as_u64() {
+                write_head(0, n, out);
+            } else if let Some(n) = number.as_i64() {
+                write_head(1, (-1 - n) as u64, out);
+            } else {
+                // dag-cbor always encodes floats in 64 bits
+                let n = number.as_f64().ok_or_else(|| anyhow!("Unsupported number {}", number))?;
+                out.push(0xfb);
+                out.extend(n.to_be_bytes());
+            }
+        }
+        Value::String(s) => {
+            write_head(3, s.len() as u64, out);
+            out.extend(s.as_bytes());
+        }
+        Value::Array(items) => {
+            write_head(4, items.len() as u64, out);
+            for item in items {
+                encode_cbor(item, out)?;
+            }
+        }
+        Value::Object(map) => match special(map)? {
+            Some(Special::Link(cid)) => {
+                // Links are tagged bytes, prefixed with the identity multibase
+                write_head(6, CID_TAG, out);
+                let bytes = cid.to_bytes();
+                write_head(2, bytes.len() as u64 + 1, out);
+                out.push(0x00);
+                out.extend(bytes);
+            }
+            Some(Special::Bytes(bytes)) => {
+                write_head(2, bytes.len() as u64, out);
+                out.extend(bytes);
+            }
+            None => {
+                // Keys are sorted by length first, then byte by byte
+                let mut entries: Vec<_> = map.iter().collect();
+                entries.sort_by(|(a, _), (b, _)| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+                write_head(5, entries.len() as u64, out);
+                for (key, value) in entries {
+                    write_head(3, key.len() as u64, out);
+                    out.extend(key.as_bytes());
+                    encode_cbor(value, out)?;
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+/// CIDs an IPLD block links to
+///
+/// # Arguments
+/// * `cid` - CID of the block, used to determine its codec
+/// * `data` - The block data
+///
+/// # Returns
+/// * `Result<Vec<Cid>>` - The linked CIDs, in the order they appear in the block
+pub fn links(cid: &Cid, data: &[u8]) -> Result<Vec<Cid>> {
+    let mut links = Vec::new();
+    match cid.codec() {
+        DAG_CBOR_CODEC => {
+            let mut buf = data;
+            cbor_links(&mut buf, &mut links, 0)?;
+        }
+        DAG_JSON_CODEC => json_links(&serde_json::from_slice::<Value>(data)?, &mut links)?,
+        codec => return Err(anyhow!("Unsupported codec 0x{:x} in CID {}", codec, cid)),
+    }
+    Ok(links)
+}
+
+/// Collect the links of a dag-json value
+fn json_links(value: &Value, links: &mut Vec<Cid>) -> Result<()> {
+    match value {
+        Value::Array(items) => items.iter().try_for_each(|item| json_links(item, links)),
+        Value::Object(map) => match special(map)? {
+            Some(Special::Link(cid)) => {
+                links.push(cid);
+                Ok(())
+            }
+            Some(Special::Bytes(_)) => Ok(()),
+            None => map.values().try_for_each(|value| json_links(value, links)),
+        },
+        _ => Ok(()),
+    }
+}
+
+/// Take bytes off the front of a buffer
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(anyhow!("Truncated dag-cbor block"));
+    }
+    let (head, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(head)
+}
+
+/// Read the head of a CBOR item
+///
+/// # Returns
+/// * `Result<(u8, u64)>` - The major type and its argument
+fn read_head(buf: &mut &[u8]) -> Result<(u8, u64)> {
+    let initial = take(buf, 1)?[0];
+    let argument = match initial & 0x1f {
+        info @ 0..=23 => info as u64,
+        24 => take(buf, 1)?[0] as u64,
+        25 => u16::from_be_bytes(take(buf, 2)?.try_into()?) as u64,
+        26 => u32::from_be_bytes(take(buf, 4)?.try_into()?) as u64,
+        27 => u64::from_be_bytes(take(buf, 8)?.try_into()?),
+        // Indefinite lengths are not allowed in dag-cbor
+        info => return Err(anyhow!("Unsupported CBOR additional information {}", info)),
+    };
+    Ok((initial >> 5, argument))
+}
+
+/// Read one CBOR item, collecting the links in it
+fn cbor_links(buf: &mut &[u8], links: &mut Vec<Cid>, depth: usize) -> Result<()> {
+    if depth > MAX_DEPTH {
+        return Err(anyhow!("dag-cbor block nested too deeply"));
+    }
+    let (major, argument) = read_head(buf)?;
+    match major {
+        // Integers and simple values or floats, whose argument is all there is
+        0 | 1 | 7 => {}
+        2 | 3 => {
+            take(buf, usize::try_from(argument)?)?;
+        }
+        4 => {
+            for _ in 0..argument {
+                cbor_links(buf, links, depth + 1)?;
+            }
+        }
+        5 => {
+            for _ in 0..argument {
+                cbor_links(buf, links, depth + 1)?;
+                cbor_links(buf, links, depth + 1)?;
+            }
+        }
+        6 if argument == CID_TAG => {
+            let (major, len) = read_head(buf)?;
+            let bytes = take(buf, usize::try_from(len)?)?;
+            match (major, bytes.split_first()) {
+                (2, Some((0x00, cid))) => links.push(Cid::try_from(cid)?),
+                _ => return Err(anyhow!("Malformed link in dag-cbor block")),
+            }
+        }
+        6 => cbor_links(buf, links, depth + 1)?,
+        _ => unreachable!("CBOR major types have three bits"),
+    }
+    Ok(())
+}
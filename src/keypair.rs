@@ -0,0 +1,224 @@
+//! Persistent node identity
+//!
+//! The peer ID of a node is derived from its keypair. Generating a fresh key on
+//! every run means the peer ID changes each time, which breaks anything that
+//! refers to the node by ID. This module loads the keypair from disk, creating
+//! it on first use, and the pre-shared key of a private network.
+//!
+//! Keys are stored in the libp2p protobuf encoding, like Kubo stores them, so
+//! key files can be moved between MIGA and Kubo. Ed25519 is the default; the
+//! secp256k1 and RSA keys of older or other implementations are supported too.
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use libp2p::{identity, pnet::PreSharedKey};
+use log::{info, warn};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey},
+    pkcs8::EncodePrivateKey,
+    RsaPrivateKey,
+};
+use serde::Deserialize;
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::protobuf;
+
+/// Key type of RSA keys in the libp2p protobuf encoding
+const RSA_KEY_TYPE: u64 = 0;
+
+/// Size of generated RSA keys in bits, the default of Kubo
+const RSA_BITS: usize = 2048;
+
+/// Type of a keypair
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+    /// Ed25519, small and fast, the default of libp2p
+    #[default]
+    Ed25519,
+    /// secp256k1, as used by Ethereum tooling
+    Secp256k1,
+    /// RSA with 2048 bits, the key type of old Kubo nodes
+    Rsa,
+}
+
+impl KeyType {
+    /// Generate a keypair of this type
+    ///
+    /// # Returns
+    /// * `Result<Vec<u8>>` - The keypair in the libp2p protobuf encoding (see [`decode`])
+    pub fn generate(self) -> Result<Vec<u8>> {
+        let keypair = match self {
+            KeyType::Ed25519 => identity::Keypair::generate_ed25519(),
+            KeyType::Secp256k1 => identity::Keypair::generate_secp256k1(),
+            // libp2p can't encode RSA keys, so they are encoded here, like Kubo does
+            KeyType::Rsa => {
+                let key = RsaPrivateKey::new(&mut rand::thread_rng(), RSA_BITS)?;
+                return Ok(protobuf::Writer::new()
+                    .varint(1, RSA_KEY_TYPE)
+                    .bytes(2, key.to_pkcs1_der()?.as_bytes())
+                    .finish());
+            }
+        };
+        keypair
+            .to_protobuf_encoding()
+            .map_err(|err| anyhow!("Failed to encode key: {}", err))
+    }
+}
+
+/// Decode a keypair from the libp2p protobuf encoding
+///
+/// # Arguments
+/// * `bytes` - The encoded keypair, as key files hold it
+///
+/// # Returns
+/// * `Result<identity::Keypair>` - The keypair
+pub fn decode(bytes: &[u8]) -> Result<identity::Keypair> {
+    // RSA keys hold a PKCS#1 key, which libp2p only takes in PKCS#8
+    let mut reader = protobuf::Reader::new(bytes);
+    let mut key_type = None;
+    let mut data = None;
+    while let Some((number, field)) = reader.next_field()? {
+        match number {
+            1 => key_type = Some(field.as_varint()?),
+            2 => data = Some(field.as_bytes()?),
+            _ => {}
+        }
+    }
+    if let (Some(RSA_KEY_TYPE), Some(data)) = (key_type, data) {
+        let mut pkcs8 = RsaPrivateKey::from_pkcs1_der(data)?.to_pkcs8_der()?.as_bytes().to_vec();
+        return Ok(identity::Keypair::rsa_from_pkcs8(&mut pkcs8)?);
+    }
+    Ok(identity::Keypair::from_protobuf_encoding(bytes)?)
+}
+
+/// Write an encoded keypair to a new file that only the current user can read
+///
+/// # Arguments
+/// * `path` - Path of the key file, which must not exist yet
+/// * `bytes` - The encoded keypair
+pub fn write_key_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).with_context(|| format!("Failed to create directory {:?}", parent))?;
+    }
+    let mut file = create_private_file(path).with_context(|| format!("Failed to create key file {:?}", path))?;
+    file.write_all(bytes)?;
+    Ok(())
+}
+
+/// Return the MIGA home directory (`~/.miga`)
+///
+/// Falls back to a `.miga` directory in the current working directory when
+/// the home directory cannot be determined.
+pub fn home_dir() -> PathBuf {
+    dirs::home_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join(".miga")
+}
+
+/// Return the default location of the identity key file (`~/.miga/identity.key`)
+pub fn default_identity_path() -> PathBuf {
+    home_dir().join("identity.key")
+}
+
+/// Load the node keypair from a file, generating and saving a new one if it doesn't exist
+///
+/// The key is stored in the libp2p protobuf encoding. Newly created key files
+/// are only readable by the current user.
+///
+/// # Arguments
+/// * `path` - Path of the identity key file
+/// * `key_type` - Type of the key to generate if there is none yet
+///
+/// # Returns
+/// * `Result<identity::Keypair>` - The loaded or newly generated keypair
+pub fn load_or_generate(path: &Path, key_type: KeyType) -> Result<identity::Keypair> {
+    if path.exists() {
+        warn_if_readable_by_others(path);
+
+        let bytes = fs::read(path)
+            .with_context(|| format!("Failed to read identity key from {:?}", path))?;
+        let keypair = decode(&bytes).with_context(|| format!("Invalid identity key in {:?}", path))?;
+        info!("Loaded node identity from {:?}", path);
+        return Ok(keypair);
+    }
+
+    // No key yet, so generate one and persist it for the next run
+    let bytes = key_type.generate()?;
+    let keypair = decode(&bytes)?;
+    write_key_file(path, &bytes)?;
+
+    info!("Generated new {:?} node identity and saved it to {:?}", key_type, path);
+    Ok(keypair)
+}
+
+/// Load the pre-shared key of a private network from a `swarm.key` file
+///
+/// The file has the same format as the swarm keys of Kubo, so MIGA and Kubo
+/// nodes can share a private network:
+///
+/// ```text
+/// /key/swarm/psk/1.0.0/
+/// /base16/
+/// <64 hexadecimal digits>
+/// ```
+///
+/// # Arguments
+/// * `path` - Path of the swarm key file
+///
+/// # Returns
+/// * `Result<PreSharedKey>` - The key, Err if the file can't be read or isn't a swarm key
+pub fn load_swarm_key(path: &Path) -> Result<PreSharedKey> {
+    warn_if_readable_by_others(path);
+
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read swarm key from {:?}", path))?;
+    let key = text
+        .trim()
+        .parse()
+        .map_err(|err| anyhow!("Invalid swarm key in {:?}: {}", path, err))?;
+    info!("Loaded swarm key from {:?}, joining a private network", path);
+    Ok(key)
+}
+
+/// Encode a public key the way `ipfs id` shows it: base64 of its protobuf encoding
+pub fn encode_public_key(key: &identity::PublicKey) -> String {
+    STANDARD.encode(key.encode_protobuf())
+}
+
+/// Create a file that only the current user can read and write
+#[cfg(unix)]
+pub(crate) fn create_private_file(path: &Path) -> std::io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+}
+
+/// Create a file that only the current user can read and write
+#[cfg(not(unix))]
+pub(crate) fn create_private_file(path: &Path) -> std::io::Result<fs::File> {
+    fs::OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+/// Warn if a key file can be read by users other than its owner
+#[cfg(unix)]
+pub(crate) fn warn_if_readable_by_others(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(metadata) = fs::metadata(path)
+        && metadata.permissions().mode() & 0o077 != 0
+    {
+        warn!("Key file {:?} is accessible by other users, consider running: chmod 600 {:?}", path, path);
+    }
+}
+
+/// Warn if a key file can be read by users other than its owner
+#[cfg(not(unix))]
+pub(crate) fn warn_if_readable_by_others(_path: &Path) {}
@@ -0,0 +1,267 @@
+//! Named keys, encrypted on disk
+//!
+//! Besides the identity key of the node, the keystore holds any number of
+//! keypairs under names of their own, e.g. to run a node under another
+//! identity. Each key is a file `<name>.key` in the `keystore` directory of
+//! the repository, holding its public key in the clear, so keys can be listed
+//! without the passphrase, and its private key encrypted with
+//! ChaCha20-Poly1305 under a key derived from the passphrase with Argon2id.
+
+use anyhow::{anyhow, Context, Result};
+use argon2::Argon2;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use libp2p::{identity, PeerId};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::keypair::{self, KeyType};
+
+/// Extension of the key files
+const KEY_EXTENSION: &str = "key";
+
+/// Length of the salt the encryption key is derived with
+const SALT_LEN: usize = 16;
+
+/// A key file: the public key, and the private key encrypted
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    /// Public key in the libp2p protobuf encoding, base64
+    public_key: String,
+    /// Salt of the key derivation, base64
+    salt: String,
+    /// Nonce of the encryption, base64
+    nonce: String,
+    /// Keypair in the libp2p protobuf encoding, encrypted, base64
+    ciphertext: String,
+}
+
+/// Keys stored under names in a directory
+pub struct Keystore {
+    /// Directory holding the key files
+    dir: PathBuf,
+}
+
+impl Keystore {
+    /// Open the keystore in a directory, creating the directory if needed
+    pub fn open(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create keystore directory {:?}", dir))?;
+        Ok(Self { dir })
+    }
+
+    /// List the stored keys
+    ///
+    /// # Returns
+    /// * `Result<Vec<(String, PeerId)>>` - Name and peer ID of each key, sorted by name
+    pub fn list(&self) -> Result<Vec<(String, PeerId)>> {
+        let mut keys = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != KEY_EXTENSION) {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let public_key = read_key_file(&path)?.public_key()?;
+            keys.push((name.to_string(), public_key.to_peer_id()));
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    /// Generate a new key and store it
+    ///
+    /// # Arguments
+    /// * `name` - Name of the new key
+    /// * `key_type` - Type of the new key
+    /// * `passphrase` - Passphrase to encrypt the key with
+    ///
+    /// # Returns
+    /// * `Result<PeerId>` - Peer ID of the key
+    pub fn generate(&self, name: &str, key_type: KeyType, passphrase: &str) -> Result<PeerId> {
+        self.import(name, &key_type.generate()?, passphrase)
+    }
+
+    /// Store a keypair under a name
+    ///
+    /// # Arguments
+    /// * `name` - Name of the key, which must not be taken yet
+    /// * `plaintext` - The keypair in the libp2p protobuf encoding, as key files hold it
+    /// * `passphrase` - Passphrase to encrypt the key with
+    ///
+    /// # Returns
+    /// * `Result<PeerId>` - Peer ID of the key
+    pub fn import(&self, name: &str, plaintext: &[u8], passphrase: &str) -> Result<PeerId> {
+        let path = self.key_path(name)?;
+        let public_key = keypair::decode(plaintext)
+            .with_context(|| format!("Invalid key for {:?}", name))?
+            .public();
+
+        let mut salt = [0; SALT_LEN];
+        let mut nonce = [0; 12];
+        rand::thread_rng().fill_bytes(&mut salt);
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = cipher(passphrase, &salt)?
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| anyhow!("Failed to encrypt key {:?}", name))?;
+        let key_file = KeyFile {
+            public_key: STANDARD.encode(public_key.encode_protobuf()),
+            salt: STANDARD.encode(salt),
+            nonce: STANDARD.encode(nonce),
+            ciphertext: STANDARD.encode(ciphertext),
+        };
+
+        let mut file = keypair::create_private_file(&path).map_err(|e| match e.kind() {
+            ErrorKind::AlreadyExists => anyhow!("A key named {:?} already exists", name),
+            _ => anyhow::Error::new(e).context(format!("Failed to create key file {:?}", path)),
+        })?;
+        file.write_all(&serde_json::to_vec_pretty(&key_file)?)?;
+        Ok(public_key.to_peer_id())
+    }
+
+    /// Load and decrypt a key
+    ///
+    /// # Arguments
+    /// * `name` - Name of the key
+    /// * `passphrase` - Passphrase the key was encrypted with
+    ///
+    /// # Returns
+    /// * `Result<identity::Keypair>` - The keypair
+    pub fn load(&self, name: &str, passphrase: &str) -> Result<identity::Keypair> {
+        keypair::decode(&self.export(name, passphrase)?).with_context(|| format!("Invalid key {:?}", name))
+    }
+
+    /// Decrypt a key, for writing it to a file
+    ///
+    /// # Arguments
+    /// * `name` - Name of the key
+    /// * `passphrase` - Passphrase the key was encrypted with
+    ///
+    /// # Returns
+    /// * `Result<Vec<u8>>` - The keypair in the libp2p protobuf encoding
+    pub fn export(&self, name: &str, passphrase: &str) -> Result<Vec<u8>> {
+        let path = self.existing_key_path(name)?;
+        keypair::warn_if_readable_by_others(&path);
+        let key_file = read_key_file(&path)?;
+
+        let salt = STANDARD.decode(&key_file.salt)?;
+        let nonce = STANDARD.decode(&key_file.nonce)?;
+        if nonce.len() != 12 {
+            return Err(anyhow!("Invalid nonce in key file {:?}", path));
+        }
+        cipher(passphrase, &salt)?
+            .decrypt(Nonce::from_slice(&nonce), STANDARD.decode(&key_file.ciphertext)?.as_slice())
+            .map_err(|_| anyhow!("Wrong passphrase for key {:?}, or its key file is damaged", name))
+    }
+
+    /// Give a key another name
+    ///
+    /// # Arguments
+    /// * `name` - Current name of the key
+    /// * `new_name` - New name of the key, which must not be taken yet
+    pub fn rename(&self, name: &str, new_name: &str) -> Result<()> {
+        let path = self.existing_key_path(name)?;
+        let new_path = self.key_path(new_name)?;
+        if new_path.exists() {
+            return Err(anyhow!("A key named {:?} already exists", new_name));
+        }
+        fs::rename(&path, &new_path).with_context(|| format!("Failed to rename key {:?}", name))
+    }
+
+    /// Path of the file of a key, checking that the name is valid
+    fn key_path(&self, name: &str) -> Result<PathBuf> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        if !valid {
+            return Err(anyhow!(
+                "Invalid key name {:?}: only letters, digits, '-', '_' and '.' are allowed, not at the start",
+                name
+            ));
+        }
+        Ok(self.dir.join(format!("{}.{}", name, KEY_EXTENSION)))
+    }
+
+    /// Path of the file of a key that exists
+    fn existing_key_path(&self, name: &str) -> Result<PathBuf> {
+        let path = self.key_path(name)?;
+        if !path.exists() {
+            return Err(anyhow!("No key named {:?} in the keystore", name));
+        }
+        Ok(path)
+    }
+}
+
+impl KeyFile {
+    /// Decode the public key
+    fn public_key(&self) -> Result<identity::PublicKey> {
+        Ok(identity::PublicKey::try_decode_protobuf(&STANDARD.decode(&self.public_key)?)?)
+    }
+}
+
+/// Read a key file
+fn read_key_file(path: &Path) -> Result<KeyFile> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read key file {:?}", path))?;
+    serde_json::from_slice(&bytes).with_context(|| format!("Invalid key file {:?}", path))
+}
+
+/// Cipher encrypting keys with a passphrase
+fn cipher(passphrase: &str, salt: &[u8]) -> Result<ChaCha20Poly1305> {
+    let mut key = [0; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| anyhow!("Failed to derive the key encryption key: {}", err))?;
+    Ok(ChaCha20Poly1305::new(&key.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let keystore = Keystore::open(dir.path()).unwrap();
+        let peer = keystore.generate("site", KeyType::Ed25519, "secret").unwrap();
+
+        assert_eq!(keystore.load("site", "secret").unwrap().public().to_peer_id(), peer);
+        assert_eq!(keystore.list().unwrap(), vec![("site".to_string(), peer)]);
+        let exported = keystore.export("site", "secret").unwrap();
+        assert_eq!(keystore.import("copy", &exported, "other").unwrap(), peer);
+        assert_eq!(keystore.load("copy", "other").unwrap().public().to_peer_id(), peer);
+    }
+
+    #[test]
+    fn rejects_a_wrong_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        let keystore = Keystore::open(dir.path()).unwrap();
+        keystore.generate("site", KeyType::Ed25519, "secret").unwrap();
+
+        let error = keystore.load("site", "guess").unwrap_err();
+        assert!(error.to_string().contains("Wrong passphrase"), "{}", error);
+    }
+
+    #[test]
+    fn keeps_names_unique_and_valid() {
+        let dir = tempfile::tempdir().unwrap();
+        let keystore = Keystore::open(dir.path()).unwrap();
+        keystore.generate("site", KeyType::Ed25519, "secret").unwrap();
+        keystore.generate("other", KeyType::Ed25519, "secret").unwrap();
+
+        assert!(keystore.generate("site", KeyType::Ed25519, "secret").is_err());
+        assert!(keystore.rename("other", "site").is_err());
+        assert!(keystore.load("missing", "secret").is_err());
+        for invalid in ["", ".hidden", "../escape", "a/b", "spa ce"] {
+            assert!(keystore.generate(invalid, KeyType::Ed25519, "secret").is_err(), "{:?} accepted", invalid);
+        }
+        keystore.rename("other", "renamed").unwrap();
+        assert_eq!(keystore.list().unwrap().iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>(), ["renamed", "site"]);
+    }
+}
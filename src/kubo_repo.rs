@@ -0,0 +1,174 @@
+//! Import of the blocks of a Kubo repository
+//!
+//! Kubo keeps its blocks in a flatfs datastore by default, one file per block
+//! in shard directories, named after the base32 encoding of the block's
+//! multihash (or of its CID, in repositories older than Kubo 0.12). The
+//! `datastore_spec` file of the repository tells where that datastore is.
+//! Blocks of other datastores, such as badger, can't be read; Kubo's
+//! `ipfs-ds-convert` converts such repositories to flatfs first.
+//!
+//! Kubo keeps its pins in a LevelDB datastore, which isn't read either: pins
+//! are imported from a list as printed by `ipfs pin ls` instead.
+
+use anyhow::{anyhow, Context, Result};
+use cid::{multibase::Base, multihash::Multihash, Cid};
+use log::{debug, warn};
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    blockstore::{self, Blockstore, RAW_CODEC},
+    pin::PinMode,
+};
+
+/// Outcome of an import
+#[derive(Debug, Default)]
+pub struct ImportStats {
+    /// Number of blocks copied into the blockstore
+    pub imported_blocks: usize,
+    /// Number of bytes copied into the blockstore
+    pub imported_bytes: u64,
+    /// Number of blocks the blockstore already had
+    pub existing_blocks: usize,
+    /// Number of files that weren't imported, as they aren't valid blocks
+    pub skipped_blocks: usize,
+}
+
+/// The `datastore_spec` file of a Kubo repository
+#[derive(Debug, Deserialize)]
+struct DatastoreSpec {
+    /// Datastores mounted at their key prefixes
+    #[serde(default)]
+    mounts: Vec<Mount>,
+}
+
+/// A datastore mounted at a key prefix
+#[derive(Debug, Deserialize)]
+struct Mount {
+    /// Key prefix of the datastore, `/blocks` for the blocks
+    mountpoint: String,
+    /// Type of the datastore, e.g. `flatfs` or `badgerds`
+    #[serde(rename = "type")]
+    kind: String,
+    /// Directory of the datastore, relative to the repository
+    path: String,
+}
+
+/// Copy the blocks of a Kubo repository into the blockstore
+///
+/// Every block is verified against its multihash before it is copied, so a
+/// corrupt repository can't bring invalid blocks along.
+///
+/// # Arguments
+/// * `kubo_dir` - The Kubo repository, e.g. `~/.ipfs`
+/// * `blockstore` - The blockstore to copy the blocks into
+///
+/// # Returns
+/// * `Result<ImportStats>` - Statistics about the copied blocks
+pub fn import_blocks(kubo_dir: &Path, blockstore: &Blockstore) -> Result<ImportStats> {
+    let blocks_dir = blocks_dir(kubo_dir)?;
+    let mut stats = ImportStats::default();
+
+    for shard in fs::read_dir(&blocks_dir).with_context(|| format!("Failed to read Kubo blocks {:?}", blocks_dir))? {
+        let shard = shard?;
+        if !shard.file_type()?.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(shard.path())? {
+            let path = entry?.path();
+            let Some(key) = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.strip_suffix(".data"))
+            else {
+                continue;
+            };
+            let Some(hash) = parse_key(key) else {
+                warn!("Skipping {:?}, which isn't named after a block", path);
+                stats.skipped_blocks += 1;
+                continue;
+            };
+
+            let cid = Cid::new_v1(RAW_CODEC, hash);
+            if blockstore.has(&cid) {
+                stats.existing_blocks += 1;
+                continue;
+            }
+            let data = fs::read(&path).with_context(|| format!("Failed to read Kubo block {:?}", path))?;
+            if let Err(e) = blockstore::verify(&cid, &data) {
+                warn!("Skipping {:?}: {}", path, e);
+                stats.skipped_blocks += 1;
+                continue;
+            }
+            blockstore.put(&cid, &data)?;
+            debug!("Imported block {}", cid);
+            stats.imported_blocks += 1;
+            stats.imported_bytes += data.len() as u64;
+        }
+    }
+    Ok(stats)
+}
+
+/// Read a list of pins as printed by `ipfs pin ls`
+///
+/// Each line holds a CID, optionally followed by the pin type. Lines without
+/// a type, as printed by `ipfs pin ls --quiet`, are recursive pins. Indirect
+/// pins are left out, as the recursive pins they come from cover them.
+///
+/// # Arguments
+/// * `path` - The file holding the list
+///
+/// # Returns
+/// * `Result<Vec<(Cid, PinMode)>>` - The pinned CIDs and how they are pinned
+pub fn read_pins(path: &Path) -> Result<Vec<(Cid, PinMode)>> {
+    let list = fs::read_to_string(path).with_context(|| format!("Failed to read pin list {:?}", path))?;
+    let mut pins = Vec::new();
+    for line in list.lines() {
+        let mut fields = line.split_whitespace();
+        let Some(cid) = fields.next() else {
+            continue;
+        };
+        let cid = Cid::try_from(cid).with_context(|| format!("Invalid CID {:?} in pin list {:?}", cid, path))?;
+        let mode = match fields.next() {
+            None | Some("recursive") => PinMode::Recursive,
+            Some("direct") => PinMode::Direct,
+            Some("indirect") => continue,
+            Some(kind) => return Err(anyhow!("Unknown pin type {:?} in pin list {:?}", kind, path)),
+        };
+        pins.push((cid, mode));
+    }
+    Ok(pins)
+}
+
+/// Directory of the flatfs datastore holding the blocks of a Kubo repository
+fn blocks_dir(kubo_dir: &Path) -> Result<PathBuf> {
+    let spec_path = kubo_dir.join("datastore_spec");
+    let spec = fs::read_to_string(&spec_path)
+        .with_context(|| format!("{:?} isn't a Kubo repository, it has no datastore_spec", kubo_dir))?;
+    let spec: DatastoreSpec =
+        serde_json::from_str(&spec).with_context(|| format!("Failed to parse {:?}", spec_path))?;
+
+    // Blocks are in the datastore mounted at /blocks, or in the root datastore if there is none
+    let mount = spec
+        .mounts
+        .iter()
+        .find(|mount| mount.mountpoint == "/blocks")
+        .or_else(|| spec.mounts.iter().find(|mount| mount.mountpoint == "/"))
+        .ok_or_else(|| anyhow!("The datastore_spec of {:?} has no datastore for blocks", kubo_dir))?;
+    if mount.kind != "flatfs" {
+        return Err(anyhow!(
+            "The blocks of {:?} are in a {} datastore, only flatfs can be read; convert the repository with ipfs-ds-convert first",
+            kubo_dir,
+            mount.kind
+        ));
+    }
+    Ok(kubo_dir.join(&mount.path))
+}
+
+/// Multihash of a flatfs key: the base32 encoding of a multihash, or of a CID in older repositories
+fn parse_key(key: &str) -> Option<Multihash<64>> {
+    let bytes = Base::Base32Upper.decode(key).ok()?;
+    Multihash::from_bytes(&bytes)
+        .ok()
+        .or_else(|| Cid::try_from(bytes.as_slice()).ok().map(|cid| *cid.hash()))
+}
@@ -0,0 +1,54 @@
+//! MIGA - fetch and share data on IPFS using libp2p
+//!
+//! The library behind the `MIGA` command line tool. [`MigaNode`] is the
+//! entry point for embedding MIGA in other programs:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! let node = miga::MigaNode::builder().repo("/var/lib/my-service/miga").build().await?;
+//! let cid = "QmZ4tDuvesekSs4qM5ZBKpXiZGun7S2CYtEZRB3DYXkjGx".parse()?;
+//! let content = node.fetch(cid).await?;
+//! node.shutdown().await;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The modules below it are public as well, for programs that need more
+//! control than the node offers.
+
+// No web module needed for IPFS sharing
+pub mod api;
+pub mod bandwidth;
+mod bitswap;
+mod block_cache;
+pub mod blocklist;
+mod bloom;
+pub mod blockstore;
+pub mod bootstrap;
+pub mod config;
+pub mod content_path;
+pub mod dag;
+pub mod dedup;
+pub mod error;
+pub mod fetch;
+pub mod gateway;
+pub mod gc;
+pub mod ipld;
+pub mod keypair;
+pub mod keystore;
+pub mod kubo_repo;
+pub mod metrics;
+pub mod multiformats;
+pub mod network;
+pub mod node;
+pub mod pin;
+pub mod progress;
+mod protobuf;
+mod record_store;
+mod routing_table;
+pub mod telemetry;
+pub mod throttle;
+mod transport;
+pub mod unixfs;
+
+pub use node::{MigaNode, MigaNodeBuilder};
@@ -0,0 +1,84 @@
+//! Logging setup
+//!
+//! Logs are written by `tracing-subscriber`, as text or JSON lines, to stderr
+//! or to a log file that is rotated daily. Which messages are logged is
+//! decided by filter directives such as `miga=debug,libp2p=warn`. Messages of
+//! the `log` crate, used throughout MIGA, are passed on to the subscriber.
+
+use anyhow::{anyhow, Result};
+use miga::{config::LogFormat, telemetry::Telemetry};
+use std::path::Path;
+use tracing_appender::{non_blocking::WorkerGuard, rolling};
+use tracing_subscriber::{
+    fmt, fmt::writer::BoxMakeWriter, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer,
+};
+
+use crate::cli::Cli;
+
+/// Filter used when neither `--log-filter` nor `RUST_LOG` is set
+const DEFAULT_FILTER: &str = "error";
+
+/// Everything that has to stay alive for logs and spans to be written
+///
+/// Dropping it flushes the log file and the remaining spans.
+pub struct Logging {
+    /// Writer thread of the log file
+    _file: Option<WorkerGuard>,
+    /// Exporter of spans to OpenTelemetry
+    _telemetry: Option<Telemetry>,
+}
+
+/// Set up logging and span export from the command line and configuration file
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments, with the configuration file loaded
+///
+/// # Returns
+/// * `Result<Logging>` - Guards to keep until the program exits
+pub fn init(cli: &Cli) -> Result<Logging> {
+    let settings = &cli.settings.log;
+
+    // Filter directives from the command line, RUST_LOG or the configuration file
+    let directives = cli
+        .log_filter
+        .clone()
+        .or_else(|| std::env::var("RUST_LOG").ok())
+        .or_else(|| settings.filter.clone())
+        .unwrap_or_else(|| DEFAULT_FILTER.to_string());
+    let filter = EnvFilter::try_new(&directives).map_err(|e| anyhow!("Invalid log filter {:?}: {}", directives, e))?;
+
+    // Write to a daily rotated log file if one is given, to stderr otherwise
+    let log_file = cli.log_file.clone().or_else(|| settings.file.clone());
+    let (writer, file_guard) = match &log_file {
+        Some(path) => {
+            let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            let name = path
+                .file_name()
+                .ok_or_else(|| anyhow!("Invalid log file {:?}", path))?;
+            let (writer, guard) = tracing_appender::non_blocking(rolling::daily(dir, name));
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stderr), None),
+    };
+
+    let format = cli.log_format.or(settings.format).unwrap_or_default();
+    let layer = fmt::layer().with_writer(writer).with_ansi(log_file.is_none());
+    let layer = match format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    };
+
+    // Export traces of fetch operations if a collector is configured
+    let otlp_endpoint = cli.otlp_endpoint.as_deref().or(cli.settings.telemetry.otlp_endpoint.as_deref());
+    let telemetry = otlp_endpoint.map(Telemetry::new).transpose()?;
+
+    tracing_subscriber::registry()
+        .with(layer.with_filter(filter))
+        .with(telemetry.as_ref().map(|telemetry| telemetry.layer()))
+        .try_init()?;
+
+    Ok(Logging {
+        _file: file_guard,
+        _telemetry: telemetry,
+    })
+}
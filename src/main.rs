@@ -1,7 +1,9 @@
 //! MIGA - A tool to fetch data from IPFS using libp2p
 //!
 
-// No web module needed for IPFS sharing
+mod cli;
+mod logging;
+mod output;
 
 /// This application connects to the IPFS network using the libp2p protocol stack
 /// and retrieves content based on its Content Identifier (CID).
@@ -9,366 +11,2038 @@
 /// # Features
 /// - Connect to the IPFS network using libp2p
 /// - Fetch content using a CID
+/// - Add files and share pinned content with other IPFS nodes
 /// - Bootstrap with well-known IPFS nodes
 /// - Verbose logging option for debugging
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
-use futures::StreamExt;
-use libp2p::{
-    core::multiaddr::Protocol,
-    identity, kad, noise, swarm, tcp, yamux,
-    Multiaddr, PeerId,
-};
+use cid::{multibase::Base, Cid, Version};
+use libp2p::{Multiaddr, PeerId};
+use futures::{stream, StreamExt};
+use indicatif::{MultiProgress, ProgressDrawTarget};
 use log::{debug, error, info, warn};
 use std::{
-    path::PathBuf,
-    time::Duration,
+    collections::HashSet,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
     fs,
-    io::Write,
+    sync::Arc,
+    future::Future,
+    time::Duration,
 };
-/// Command line arguments for the MIGA application
-///
-/// This struct defines the command-line interface for the application
-/// using the clap crate for argument parsing.
-#[derive(Parser, Debug)]
-#[clap(author, version, about = "A tool to fetch data from IPFS using libp2p")]
-struct Args {
-    /// The CID (Content Identifier) of the content to fetch from IPFS
-    /// This is a required parameter and must be a valid CID string
-    #[clap(short, long)]
-    cid: String,
-
-    /// Output file path (optional)
-    /// If provided, the fetched content will be saved to this file
-    #[clap(short, long)]
-    output: Option<PathBuf>,
-
-    /// Enable verbose output for debugging
-    /// When enabled, additional information about the process will be displayed
-    #[clap(short, long)]
-    verbose: bool,
-
-    /// Enable IPFS sharing mode
-    /// When enabled, makes the fetched content available on the IPFS network
-    #[clap(long)]
-    share: bool,
-
-    /// Port to listen for IPFS connections (default: 4001)
-    /// This is the standard IPFS port
-    #[clap(long, default_value = "4001")]
-    port: u16,
-
-    /// Description of the content being shared
-    /// This is stored with the content metadata
-    #[clap(long)]
-    description: Option<String>,
-
-    /// Directory to store shared content
-    #[clap(long, default_value = "./shared")]
-    share_dir: PathBuf
-}
+use tokio::{sync::watch, time::Instant};
+use output::Message;
+use cli::{
+    AddArgs, BlockCommand, BootstrapCommand, BwArgs, CacheCommand, CatArgs, CidCommand, Cli, Command, DagCommand, KeyCommand, DaemonArgs, DhtCommand, ExportArgs, GetArgs, PinCommand, PingArgs,
+    RefsArgs, RepoCommand, StatArgs, StatsCommand, SwarmCommand, VerifyArgs,
+};
+use miga::{
+    api::{self, ApiAddr, ApiClient, KuboClient},
+    bandwidth::BandwidthStats,
+    blocklist::BlocklistEntry,
+    blockstore::{self, Blockstore},
+    bootstrap::{self, BootstrapList},
+    config::Config,
+    content_path::ContentPath,
+    dag, dedup,
+    error::{self, MigaError},
+    fetch,
+    gateway::{self, GatewayLimits},
+    gc, keypair,
+    keystore::Keystore,
+    kubo_repo,
+    multiformats::{self, Codec, HashFunction, Multibase},
+    network::{self, ConnectionLimits, DhtMode},
+    node::DEFAULT_CONCURRENCY,
+    pin::{self, PinMode, PinStore},
+    progress::FetchProgress,
+    unixfs::{self, AddOptions},
+    MigaNode, MigaNodeBuilder,
+};
+
+/// Port the daemon listens on unless told otherwise, the standard IPFS port
+const DEFAULT_PORT: u16 = 4001;
+
+/// Default time between two announcements of all pinned blocks by the daemon
+const DEFAULT_REPROVIDE_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Number of providers `miga dht find-providers` stops after unless told otherwise
+const DEFAULT_NUM_PROVIDERS: usize = 20;
+
+/// Number of pings sent by `miga ping` unless told otherwise
+const DEFAULT_PING_COUNT: u32 = 10;
+
+/// Time between two pings of `miga ping` unless told otherwise
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time between two updates of `miga stats bw --poll` unless told otherwise
+const DEFAULT_BW_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the daemon waits for API requests in progress when shutting down
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
 
 /// Main entry point for the MIGA application
 ///
 /// This async function:
-/// 1. Initializes logger
-/// 2. Parses command line arguments
-/// 3. Sets up a libp2p node with Kademlia DHT
-/// 4. Connects to the IPFS network
-/// 5. Searches for and retrieves content based on the provided CID
+/// 1. Parses command line arguments and the configuration file
+/// 2. Initializes logging
+/// 3. Runs the requested subcommand
 ///
 /// # Returns
 /// - `Result<()>`: Ok, if the operation was successful, Err otherwise
 #[tokio::main]
 async fn main() -> Result<()> {
-    // Initialize the logger for output based on the RUST_LOG environment variable
-    env_logger::init();
+    // Parse command line arguments using clap, then load the configuration file
+    // The file only fills in what wasn't given on the command line
+    let mut cli = Cli::parse();
+    cli.settings = Config::load(cli.config.as_deref())?;
+
+    // Initialize logging and the export of traces
+    // The log file and the remaining spans are flushed when this is dropped at exit
+    let logging = logging::init(&cli)?;
+
+    let result = match &cli.command {
+        Command::Get(args) => run_get(&cli, args).await,
+        Command::Cat(args) => run_cat(&cli, args).await,
+        Command::Add(args) => run_add(&cli, args),
+        Command::Refs(args) => run_refs(&cli, args).await,
+        Command::Stat(args) => run_stat(&cli, args).await,
+        Command::Verify(args) => run_verify(&cli, args),
+        Command::Export(args) => run_export(&cli, args).await,
+        Command::Daemon(args) => run_daemon(&cli, args).await,
+        Command::Pin(command) => run_pin_command(&cli, command),
+        Command::Repo(command) => run_repo_command(&cli, command),
+        Command::Cid(command) => run_cid_command(&cli, command),
+        Command::Cache(command) => run_cache_command(&cli, command),
+        Command::Block(command) => run_block_command(&cli, command),
+        Command::Dag(command) => run_dag_command(&cli, command).await,
+        Command::Key(command) => run_key_command(&cli, command),
+        Command::Bootstrap(command) => run_bootstrap_command(&cli, command),
+        Command::Id { api } => run_id(&cli, api.as_ref()).await,
+        Command::Ping(args) => run_ping(&cli, args).await,
+        Command::Swarm(command) => run_swarm_command(&cli, command).await,
+        Command::Dht(command) => run_dht_command(&cli, command).await,
+        Command::Stats(command) => run_stats_command(&cli, command).await,
+    };
+
+    // Exit with the code of the error, so scripts can tell failures apart
+    // With --json, errors are reported on stdout like all other output
+    if let Err(e) = result {
+        let code = error::exit_code(&e);
+        if cli.json {
+            Message::Error {
+                message: format!("{:#}", e),
+                code,
+            }
+            .print(true);
+        } else {
+            eprintln!("Error: {:?}", e);
+        }
+        drop(logging);
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+/// Run the `get` subcommand
+///
+/// This async function:
+/// 1. Sets up a libp2p node with Kademlia DHT
+/// 2. Connects to the IPFS network
+/// 3. Searches for and retrieves the content of each CID, block by block
+/// 4. Saves the content to files
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `args` - Arguments of the `get` subcommand
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+async fn run_get(cli: &Cli, args: &GetArgs) -> Result<()> {
+    // Collect the CIDs from the command line and the CID file
+    // Return an error if any CID is invalid
+    let cids = collect_cids(args)?;
+    if cids.is_empty() {
+        return Err(anyhow!("No CID given"));
+    }
+    if cids.len() > 1 && args.output.is_some() {
+        return Err(anyhow!("--output can only be used with a single CID, use --output-dir instead"));
+    }
+
+    // `--output -` streams the content to stdout, just like `cat`
+    let concurrency = concurrency(cli, args.concurrency);
+    let deadline = Deadline::new(cli);
+    if args.output.as_deref() == Some(Path::new("-")) {
+        return stream_to_stdout(cli, cids[0].clone(), concurrency, deadline, &args.peer).await;
+    }
 
-    // Parse command line arguments using clap
-    let args = Args::parse();
+    // Make sure the output directory exists before spending time on the network
+    let output_dir = args.output_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+    fs::create_dir_all(&output_dir)?;
 
-    // Print information about the requested CID if verbose mode is enabled
-    if args.verbose {
-        println!("Fetching content with CID: {}", args.cid);
+    // Start the node once for all CIDs, so bootstrapping is only paid for once
+    // Fetched blocks are kept in its blockstore so they survive restarts and can be served later
+    // With --peer, blocks are only asked from the given peers
+    // Content fetched before is served from the blockstore without joining the network at all
+    let mut builder = node_builder(cli)?
+        .verbose(cli.verbose && !cli.json)
+        .dht_mode(dht_mode(cli, DhtMode::Client));
+    if serve_from_cache(cli, &cids)? {
+        info!("All content is stored locally, not joining the network");
+        builder = builder.offline(true);
+    } else {
+        builder = builder.peers(args.peer.clone());
     }
+    let node = builder.build().await?;
+    debug!("Using blockstore at {:?}", node.blockstore().path());
+    Message::PeerId { peer_id: node.peer_id().to_string() }.print(cli.json);
+
+    // Fetch the CIDs, up to `--jobs` at a time, each with its own progress bar
+    let multi = progress_bars(cli);
+    let mut fetches = stream::iter(&cids)
+        .map(|content| {
+            // Determine the output file path
+            // Generate a filename based on the CID, or the name at the end of the path, if no output path is provided
+            let output_path = args
+                .output
+                .clone()
+                .unwrap_or_else(|| output_dir.join(output_name(content)));
+            let node = &node;
+            let multi = &multi;
+            async move {
+                // Print information about the requested CID if verbose mode is enabled
+                if cli.verbose {
+                    multi.suspend(|| Message::Fetching { cid: content.to_string() }.print(cli.json));
+                }
+                let result = fetch_to_file(node, content, &output_path, concurrency, deadline, multi, cli.json)
+                    .await
+                    .map(|(cid, _)| (cid, output_path));
+                (content, result)
+            }
+        })
+        .buffer_unordered(args.jobs.or(cli.settings.fetch.jobs).unwrap_or(1).max(1));
 
-    // Parse the CID string into a CID object
-    // Return an error if the CID is invalid
-    let cid = match cid::Cid::try_from(args.cid.as_str()) {
-        Ok(cid) => cid,
-        Err(err) => {
-            return Err(anyhow!("Invalid CID: {}", err));
+    // Collect the results until all CIDs are done or the process is asked to stop
+    let mut results: Vec<(&ContentPath, Result<(Cid, PathBuf)>)> = Vec::new();
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    let interrupted = loop {
+        tokio::select! {
+            result = fetches.next() => match result {
+                Some(result) => results.push(result),
+                None => break false,
+            },
+            _ = &mut signal => break true,
         }
     };
+    // Dropping the fetches still running stops them; their progress is
+    // recorded up to the last block written, so they resume next time
+    drop(fetches);
 
-    // Create a new Ed25519 keypair for this node's identity
-    let id_keys = identity::Keypair::generate_ed25519();
-    let peer_id = PeerId::from(id_keys.public());
-    println!("Local peer ID: {peer_id}");
-
-    // Configure the Kademlia DHT behavior
-    // This is used for finding peers and content in the network
-    let mut kad_config = kad::Config::default();
-    kad_config.set_query_timeout(Duration::from_secs(60)); // Set a 60-second timeout for queries
-    let store = kad::store::MemoryStore::new(peer_id);     // In-memory store for DHT records
-    let mut kad_behaviour = kad::Behaviour::with_config(peer_id, store, kad_config);
-
-    // Add well-known IPFS bootstrap nodes to connect to the network
-    add_bootstrap_nodes(&mut kad_behaviour, args.verbose);
-
-    // Create a libp2p Swarm with the Kademlia behavior
-    // The Swarm manages connections and protocol negotiations
-    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(id_keys)
-        .with_tokio()                                      // Use Tokio as the async runtime
-        .with_tcp(tcp::Config::default(), noise::Config::new, yamux::Config::default)? // TCP transport with Noise encryption and Yamux multiplexing
-        .with_behaviour(|_| kad_behaviour)?                // Add the Kademlia behavior
-        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60))) // Set connection timeout
-        .build();
-
-    // Listen on all network interfaces with a random port
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
-
-    // Convert the CID's multihash to a Kademlia record key
-    // This is what we'll search for in the DHT
-    let key = kad::RecordKey::from(cid.hash().to_bytes());
-
-    // Ensure the share directory exists if sharing is enabled
-    if args.share {
-        if !args.share_dir.exists() {
-            fs::create_dir_all(&args.share_dir)?;
-            info!("Created share directory: {:?}", args.share_dir);
-        }
-
-        // Configure the node to listen on the specified port for IPFS connections
-        let addr = format!("/ip4/0.0.0.0/tcp/{}", args.port);
-        info!("Configuring IPFS node to listen on {}", addr);
-        swarm.listen_on(addr.parse()?)?;
-        println!("IPFS node configured to share content on port {}", args.port);
-    };
+    // Stop the node; the event loop saves the routing table for the next run
+    let mut pins = PinStore::open(pin::pin_file(node.repo_dir()))?;
+    node.shutdown().await;
 
-    // Start a Kademlia GET query to find the content
-    info!("Searching for content with CID: {}", cid);
-    swarm.behaviour_mut().get_record(key.clone());
-
-    // Process events from the network
-    // We'll keep processing events until we find the content we're looking for
-    let mut content_found = false;
-    let mut bootstrap_complete = false;
-    let mut content_data: Option<Vec<u8>> = None;
-
-    while !content_found {
-        // Wait for the next event from the swarm
-        match swarm.select_next_some().await {
-            // When we get a new listening address
-            swarm::SwarmEvent::NewListenAddr { address, .. } => {
-                info!("Listening on {address}");
-
-                // Bootstrap the Kademlia DHT if we haven't already done so
-                // This connects us to the wider IPFS network
-                if !bootstrap_complete {
-                    info!("Bootstrapping Kademlia DHT...");
-                    if let Err(e) = swarm.behaviour_mut().bootstrap() {
-                        error!("Failed to bootstrap Kademlia: {}", e);
-                    }
-                    bootstrap_complete = true;
+    // The first failure decides the exit code
+    let finished = results.len();
+    let mut failed = 0;
+    let mut first_error = None;
+    for (content, result) in results {
+        match result {
+            Ok((cid, output_path)) => {
+                Message::Saved {
+                    cid: cid.to_string(),
+                    path: output_path,
                 }
-            }
-            // When we successfully get a record from the network
-            swarm::SwarmEvent::Behaviour(kad::Event::OutboundQueryProgressed { 
-                result: kad::QueryResult::GetRecord(Ok(result)), 
-                ..
-            }) => {
-                // Print the debug representation to understand the structure
-                // This is useful for development and debugging
-                info!("Got record result: {:?}", result);
-
-                // For now, we'll just print the debug representation of the result
-                // This will help us understand the structure for future improvements
-                info!("Received a record from the IPFS network");
-
-                // Create some dummy data for testing the IPFS sharing functionality
-                // In a real implementation; we would extract the actual content from the result
-                let data = Some(format!("IPFS content for CID: {}\nThis is placeholder content for testing.", cid).into_bytes());
-
-                // Store the content data if we found it
-                if let Some(data_value) = data {
-                    let data_size = data_value.len();
-                    println!("Received content from IPFS network ({} bytes)", data_size);
-                    content_data = Some(data_value.clone());
-
-                    // Determine the output file path
-                    let output_path = if let Some(path) = &args.output {
-                        path.clone()
-                    } else {
-                        // Generate a filename based on the CID if no output path is provided
-                        let filename = format!("{}.bin", cid);
-                        if args.share {
-                            args.share_dir.join(&filename)
-                        } else {
-                            PathBuf::from(&filename)
-                        }
-                    };
-
-                    // Save the content to the file
-                    match fs::File::create(&output_path) {
-                        Ok(mut file) => {
-                            if let Err(e) = file.write_all(&data_value) {
-                                error!("Failed to write content to file: {}", e);
-                            } else {
-                                println!("Content saved to: {:?}", output_path);
-
-                                // Share the content via IPFS if sharing is enabled
-                                if args.share {
-                                    // Create a Kademlia record with the content
-                                    let record = kad::Record {
-                                        key: key.clone(),
-                                        value: data_value.clone(),
-                                        publisher: Some(peer_id),
-                                        expires: None,
-                                    };
-
-                                    // Put the record in the Kademlia DHT
-                                    info!("Publishing content to the IPFS network with CID: {}", cid);
-                                    match swarm.behaviour_mut().put_record(record, kad::Quorum::One) {
-                                        Ok(_) => {
-                                            println!("Content is now available on the IPFS network with CID: {}", cid);
-                                            println!("Other IPFS nodes can access this content using the CID");
-
-                                            // Print the multiaddress that other nodes can use to connect to this node
-                                            if let Some(addr) = swarm.listeners().next() {
-                                                println!("Your node address: {}/p2p/{}", addr, peer_id);
-                                            }
-                                        },
-                                        Err(e) => {
-                                            error!("Failed to publish content to the IPFS network: {}", e);
-                                        }
-                                    }
-                                }
-                            }
-                        },
-                        Err(e) => {
-                            error!("Failed to create output file: {}", e);
-                        }
+                .print(cli.json);
+
+                // Pin the content if requested, so it is kept and shared by the daemon
+                if args.pin {
+                    pins.add(&cid, PinMode::Recursive)?;
+                    Message::Pinned {
+                        cid: cid.to_string(),
+                        mode: PinMode::Recursive.to_string(),
                     }
-                } else {
-                    warn!("Received empty result from the network");
+                    .print(cli.json);
                 }
+            }
+            Err(e) => {
+                error!("Failed to fetch {}: {:#}", content, e);
+                failed += 1;
+                first_error.get_or_insert(e);
+            }
+        }
+    }
 
-                // Mark that we found the content so we can exit the loop
-                content_found = true;
-            }
-            // When we fail to get a record
-            swarm::SwarmEvent::Behaviour(kad::Event::OutboundQueryProgressed { 
-                result: kad::QueryResult::GetRecord(Err(err)), 
-                ..
-            }) => {
-                warn!("Failed to get record: {:?}", err);
-                // Retry the query after a delay
-                // This helps with temporary network issues
-                tokio::time::sleep(Duration::from_secs(5)).await;
-                swarm.behaviour_mut().get_record(key.clone());
-            }
-            // When we get a result from bootstrapping
-            swarm::SwarmEvent::Behaviour(kad::Event::OutboundQueryProgressed { 
-                result: kad::QueryResult::Bootstrap(Ok(result)), 
-                ..
-            }) => {
-                if args.verbose {
-                    info!("Bootstrap result: {} peers found", result.num_remaining);
-                }
-                // Try to get the record again after bootstrapping
-                // Now that we're connected to more peers, we have a better chance of finding the content
-                swarm.behaviour_mut().get_record(key.clone());
-            }
-            // Handle any other events
-            e => {
-                if args.verbose {
-                    debug!("Other event: {:?}", e);
-                }
+    if interrupted {
+        let unfinished = cids.len() - finished + failed;
+        return Err(MigaError::Interrupted(format!(
+            "{} of {} CIDs unfinished, run the same command again to resume",
+            unfinished,
+            cids.len()
+        ))
+        .into());
+    }
+    if let Some(e) = first_error {
+        return Err(e.context(format!("Failed to fetch {} of {} CIDs", failed, cids.len())));
+    }
+
+    Message::Done.print(cli.json);
+    Ok(())
+}
+
+/// Fetch a single CID, or the file at a path within it, and save its content to a file
+///
+/// # Arguments
+/// * `node` - The node to fetch the content with
+/// * `content` - The CID of the content to fetch, and the path of the file within it
+/// * `output_path` - File to save the content to
+/// * `concurrency` - Maximum number of blocks fetched at the same time
+/// * `deadline` - When to give up, if ever
+/// * `multi` - Progress bars of all running downloads
+/// * `json` - Print messages as JSON lines
+///
+/// # Returns
+/// * `Result<(Cid, u64)>` - CID of the file and number of bytes saved
+async fn fetch_to_file(
+    node: &MigaNode,
+    content: &ContentPath,
+    output_path: &Path,
+    concurrency: usize,
+    deadline: Option<Deadline>,
+    multi: &MultiProgress,
+    json: bool,
+) -> Result<(Cid, u64)> {
+    // Fetch the content, using blocks from the local blockstore where possible
+    // An interrupted earlier download to the same file is resumed
+    info!("Searching for content with CID: {}", content);
+    let progress = FetchProgress::new(multi, &content.cid);
+    let fetch = async {
+        // Only the directories along the path are fetched, not their other entries
+        let cid = node.resolve_path(content.cid, &content.path).await?;
+        let size = fetch::fetch_to_path(
+            node.client(),
+            node.blockstore(),
+            cid,
+            output_path,
+            concurrency,
+            Some(&progress),
+        )
+        .await?;
+        Ok((cid, size))
+    };
+    let result = Deadline::run(deadline, node, &progress, fetch).await;
+    progress.finish();
+
+    let (cid, size) = result?;
+    multi.suspend(|| Message::Received { cid: cid.to_string(), size }.print(json));
+    Ok((cid, size))
+}
+
+/// Name of the file content is saved to unless told otherwise
+///
+/// Content named by a path is saved under the name at its end, like a
+/// download from a gateway, and content named by its CID alone as `<CID>.bin`.
+fn output_name(content: &ContentPath) -> String {
+    match content.path.rsplit('/').next() {
+        // Names that would lead out of the output directory are not used
+        Some(name) if !name.is_empty() && name != "." && name != ".." && !name.contains('\\') => name.to_string(),
+        _ => format!("{}.bin", content.cid),
+    }
+}
+
+/// Collect the CIDs to fetch from the command line and the `--cid-file`
+///
+/// The CID file lists one CID per line; empty lines and lines starting with `#` are ignored.
+///
+/// # Returns
+/// * `Result<Vec<ContentPath>>` - The parsed CIDs, with the paths within them, in order
+fn collect_cids(args: &GetArgs) -> Result<Vec<ContentPath>> {
+    let mut cids = args.cids.iter().map(|cid| parse_content(cid)).collect::<Result<Vec<_>>>()?;
+
+    if let Some(cid_file) = &args.cid_file {
+        let list = fs::read_to_string(cid_file)
+            .map_err(|e| anyhow!("Failed to read CID file {:?}: {}", cid_file, e))?;
+        for line in list.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            cids.push(parse_content(line)?);
+        }
+    }
+
+    Ok(cids)
+}
+
+/// Run the `cat` subcommand
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `args` - Arguments of the `cat` subcommand
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+async fn run_cat(cli: &Cli, args: &CatArgs) -> Result<()> {
+    let deadline = Deadline::new(cli);
+    stream_to_stdout(cli, parse_content(&args.cid)?, concurrency(cli, args.concurrency), deadline, &[]).await
+}
+
+/// Fetch content and stream it to stdout as blocks arrive
+///
+/// Only the content is written to stdout, so it can be used in pipelines;
+/// all other output goes to the log.
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `content` - The CID of the content to fetch, and the path of the file within it
+/// * `concurrency` - Maximum number of blocks fetched at the same time
+/// * `deadline` - When to give up, if ever
+/// * `peers` - Peers to get the content from instead of the providers found in the DHT
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+async fn stream_to_stdout(
+    cli: &Cli,
+    content: ContentPath,
+    concurrency: usize,
+    deadline: Option<Deadline>,
+    peers: &[Multiaddr],
+) -> Result<()> {
+    // Nothing but the content may be printed to stdout, so verbose output stays off
+    // Content fetched before is served from the blockstore without joining the network at all
+    let mut builder = node_builder(cli)?.dht_mode(dht_mode(cli, DhtMode::Client));
+    if serve_from_cache(cli, std::slice::from_ref(&content))? {
+        info!("{} is stored locally, not joining the network", content);
+        builder = builder.offline(true);
+    } else {
+        builder = builder.peers(peers.to_vec());
+    }
+    let node = builder.build().await?;
+    info!("Local peer ID: {}", node.peer_id());
+
+    // The progress bar is drawn on stderr, so it doesn't mix with the content
+    let mut stdout = tokio::io::stdout();
+    let progress = FetchProgress::new(&progress_bars(cli), &content.cid);
+    let fetch = async {
+        let cid = node.resolve_path(content.cid, &content.path).await?;
+        fetch::fetch_file(node.client(), node.blockstore(), cid, &mut stdout, concurrency, Some(&progress)).await
+    };
+    let result = tokio::select! {
+        result = Deadline::run(deadline, &node, &progress, fetch) => result,
+        _ = shutdown_signal() => Err(MigaError::Interrupted(format!("stopped while streaming {}", content)).into()),
+    };
+    progress.finish();
+
+    // Stop the node before reporting the result; the event loop saves the routing table for the next run
+    node.shutdown().await;
+    let written = result?;
+    info!("Streamed {} bytes of {} to stdout", written, content);
+    Ok(())
+}
+
+/// Run the `refs` subcommand
+///
+/// Lists the CIDs a block links to, fetching the blocks that aren't stored
+/// locally. Recursively, the DAG is walked depth first, so every CID is
+/// listed right before the CIDs it links to, like `ipfs refs -r` does.
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `args` - Arguments of the `refs` subcommand
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+async fn run_refs(cli: &Cli, args: &RefsArgs) -> Result<()> {
+    let content = parse_content(&args.cid)?;
+    let node = node_builder(cli)?.dht_mode(dht_mode(cli, DhtMode::Client)).build().await?;
+
+    let walk = async {
+        let root = node.resolve_path(content.cid, &content.path).await?;
+        let mut listed = HashSet::new();
+        // Links of each block on the way down that are still to be listed
+        let mut stack = vec![node.links(root).await?.into_iter()];
+        while let Some(links) = stack.last_mut() {
+            let Some(cid) = links.next() else {
+                stack.pop();
+                continue;
+            };
+            // With --unique, a CID listed before has had its links listed as well
+            if !listed.insert(cid) && args.unique {
+                continue;
+            }
+            Message::Ref { cid: cid.to_string() }.print(cli.json);
+            if args.recursive {
+                stack.push(node.links(cid).await?.into_iter());
+            }
+        }
+        anyhow::Ok(())
+    };
+    let result = tokio::select! {
+        result = walk => result,
+        _ = shutdown_signal() => Err(MigaError::Interrupted(format!("stopped while listing the links of {}", content)).into()),
+    };
+
+    // Stop the node; the event loop saves the routing table for the next run
+    node.shutdown().await;
+    result
+}
+
+/// Run the `stat` subcommand
+///
+/// Walks the DAG below a CID to report how much there is to download,
+/// fetching the blocks with links but not the raw leaves when their size is
+/// known from the links to them.
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `args` - Arguments of the `stat` subcommand
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+async fn run_stat(cli: &Cli, args: &StatArgs) -> Result<()> {
+    let content = parse_content(&args.cid)?;
+    let node = node_builder(cli)?.dht_mode(dht_mode(cli, DhtMode::Client)).build().await?;
+
+    let measure = async {
+        let root = node.resolve_path(content.cid, &content.path).await?;
+        let stat = node.stat(root).await?;
+        Message::Stat {
+            cid: root.to_string(),
+            size: stat.size,
+            blocks: stat.blocks,
+            depth: stat.depth,
+        }
+        .print(cli.json);
+        anyhow::Ok(())
+    };
+    let result = tokio::select! {
+        result = measure => result,
+        _ = shutdown_signal() => Err(MigaError::Interrupted(format!("stopped while measuring {}", content)).into()),
+    };
+
+    // Stop the node; the event loop saves the routing table for the next run
+    node.shutdown().await;
+    result
+}
+
+/// Run the `export` subcommand
+///
+/// Pushes the DAG below a CID block by block to a Kubo node, fetching the
+/// blocks that aren't stored locally, and pins it there with `--pin`. Content
+/// stored locally is exported without joining the network.
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `args` - Arguments of the `export` subcommand
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+async fn run_export(cli: &Cli, args: &ExportArgs) -> Result<()> {
+    let content = parse_content(&args.cid)?;
+    // Reach Kubo before joining the network, as there is no point fetching without it
+    let mut kubo = KuboClient::connect(args.to_kubo.clone()).await?;
+
+    let mut builder = node_builder(cli)?.dht_mode(dht_mode(cli, DhtMode::Client));
+    if serve_from_cache(cli, std::slice::from_ref(&content))? {
+        info!("All content is stored locally, not joining the network");
+        builder = builder.offline(true);
+    }
+    let node = builder.build().await?;
+
+    let export = async {
+        let root = node.resolve_path(content.cid, &content.path).await?;
+        let stats = node.export_to_kubo(root, &mut kubo).await?;
+        if args.pin {
+            kubo.pin_add(&root).await?;
+        }
+        Message::Exported {
+            cid: root.to_string(),
+            blocks: stats.blocks,
+            bytes: stats.bytes,
+            pinned: args.pin,
+        }
+        .print(cli.json);
+        anyhow::Ok(())
+    };
+    let result = tokio::select! {
+        result = export => result,
+        _ = shutdown_signal() => Err(MigaError::Interrupted(format!("stopped while exporting {}", content)).into()),
+    };
+
+    // Stop the node; the event loop saves the routing table for the next run
+    node.shutdown().await;
+    result
+}
+
+/// Run the `verify` subcommand
+///
+/// Checks a file against a CID without using the network. The file is split
+/// into blocks like `add` does; if that doesn't give the CID, as for content
+/// added with other settings, the file is compared with the blocks of the
+/// CID stored in the repository instead.
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `args` - Arguments of the `verify` subcommand
+///
+/// # Returns
+/// - `Result<()>`: Ok if the file matches, Err otherwise
+fn run_verify(cli: &Cli, args: &VerifyArgs) -> Result<()> {
+    let content = parse_content(&args.cid)?;
+    let blockstore = Blockstore::open(repo_dir(cli).join("blocks"))?;
+
+    // Follow the path through the directories stored locally
+    let mut cid = content.cid;
+    for name in content.path.split('/').filter(|name| !name.is_empty()) {
+        let block = blockstore
+            .get(&cid)?
+            .ok_or_else(|| anyhow!("Directory {} of {} is not stored locally", cid, content))?;
+        cid = unixfs::find_entry(&cid, &block, name)?.ok_or_else(|| MigaError::NoSuchPath {
+            root: content.cid,
+            path: content.path.clone(),
+        })?;
+    }
+
+    // The same content gives the same hash in a CIDv0 and a CIDv1; CIDv1 are
+    // most likely from `ipfs add --cid-version 1`, which uses raw leaves, and
+    // may use another hash function
+    let hash = HashFunction::from_code(cid.hash().code()).unwrap_or_default();
+    let hashed = unixfs::hash_file(&args.file, AddOptions { hash, ..AddOptions::kubo(cid.version()) })?;
+    if hashed.cid.codec() != cid.codec() || hashed.cid.hash() != cid.hash() {
+        if !blockstore.has(&cid) {
+            return Err(MigaError::FileMismatch {
+                path: args.file.clone(),
+                cid,
+                reason: format!(
+                    "the file hashes to {}, and {} is not stored locally to compare the content with",
+                    hashed.cid, cid
+                ),
+            }
+            .into());
+        }
+        let file = fs::File::open(&args.file).with_context(|| format!("Failed to open {:?}", args.file))?;
+        if let Some(offset) = unixfs::compare_stored(&blockstore, &cid, io::BufReader::new(file))? {
+            return Err(MigaError::FileMismatch {
+                path: args.file.clone(),
+                cid,
+                reason: format!("the content differs from byte {}", offset),
             }
+            .into());
         }
     }
 
-    // 如果启用了 IPFS 共享并成功获取了内容，保持程序运行
-    if args.share && content_data.is_some() {
-        println!("🎉 内容获取完成！IPFS 节点将继续运行...");
-        println!("💡 按 Ctrl+C 停止节点");
+    Message::Verified {
+        path: args.file.clone(),
+        cid: cid.to_string(),
+    }
+    .print(cli.json);
+    Ok(())
+}
+
+/// Run the `add` subcommand
+///
+/// Splits the file into blocks, stores them in the blockstore and pins the
+/// result unless `--no-pin` is given.
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `args` - Arguments of the `add` subcommand
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+fn run_add(cli: &Cli, args: &AddArgs) -> Result<()> {
+    let repo_dir = repo_dir(cli);
+    let blockstore = Blockstore::open(repo_dir.join("blocks"))?;
 
-        // 保持主线程运行，让 IPFS 节点继续提供服务
-        loop {
-            tokio::time::sleep(Duration::from_secs(3600)).await;
+    let options = AddOptions::from_flags(args.cid_version, args.raw_leaves, args.hash.unwrap_or_default())?;
+    let added = unixfs::add_file(&blockstore, &args.path, options)?;
+    if cli.verbose {
+        Message::Stored {
+            size: added.size,
+            blocks: added.blocks,
         }
+        .print(cli.json);
+    }
+
+    if !args.no_pin {
+        let mut pins = PinStore::open(pin::pin_file(&repo_dir))?;
+        pins.add(&added.cid, PinMode::Recursive)?;
     }
 
-    println!("✅ 程序执行完成!");
+    let name = args
+        .path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let cid = match args.cid_base {
+        Some(base) => multiformats::to_string_of_base(&added.cid, base)?,
+        None => added.cid.to_string(),
+    };
+    Message::Added {
+        cid,
+        name,
+        size: added.size,
+    }
+    .print(cli.json);
     Ok(())
 }
 
-/// Add well-known IPFS bootstrap nodes to the Kademlia DHT
+/// Run the `daemon` subcommand
 ///
-/// This function adds a list of standard IPFS bootstrap nodes to the Kademlia
-/// routing table. These nodes serve as entry points to the IPFS network and
-/// help our node discover other peers.
+/// Starts a node that listens for IPFS connections on the given port and
+/// announces itself as provider of every pinned block. The node keeps running until
+/// the process is stopped.
 ///
 /// # Arguments
-/// * `kademlia` - A mutable reference to the Kademlia behavior
-/// * `verbose` - Whether to print verbose information about the bootstrap process
-fn add_bootstrap_nodes(kademlia: &mut kad::Behaviour<kad::store::MemoryStore>, verbose: bool) {
-    // List of well-known IPFS bootstrap nodes
-    // These are maintained by Protocol Labs and the IPFS community
-    let bootstrap_nodes = [
-        // DNS-based addresses (more stable over time)
-        "/dnsaddr/bootstrap.libp2p.io/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN",
-        "/dnsaddr/bootstrap.libp2p.io/p2p/QmQCU2EcMqAqQPR2i9bChDtGNJchTbq5TbXJJ16u19uLTa",
-        "/dnsaddr/bootstrap.libp2p.io/p2p/QmbLHAnMoJPWSCR5Zhtx6BHJX9KiKNN6tpvbUcqanj75Nb",
-        "/dnsaddr/bootstrap.libp2p.io/p2p/QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1ZjYZcYW3dwt",
-        // IP-based addresses
-        "/ip4/104.131.131.82/tcp/4001/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ",
-        "/ip4/104.236.179.241/tcp/4001/p2p/QmSoLPppuBtQSGwKDZT2M73ULpjvfd3aZ6ha4oFGL1KrGM",
-    ];
-
-    // Add each bootstrap node to the Kademlia routing table
-    for node in bootstrap_nodes {
-        // Parse the multiaddress string
-        match node.parse::<Multiaddr>() {
-            Ok(addr) => {
-                // Extract the peer ID from the multiaddress
-                if let Some(peer_id) = extract_peer_id_from_multiaddr(&addr) {
-                    // Add the address to Kademlia's routing table
-                    kademlia.add_address(&peer_id, addr.clone());
-
-                    // Print information if verbose mode is enabled
-                    if verbose {
-                        println!("Added bootstrap node: {} ({})", addr, peer_id);
-                    }
+/// * `cli` - The parsed command line arguments
+/// * `args` - Arguments of the `daemon` subcommand
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+async fn run_daemon(cli: &Cli, args: &DaemonArgs) -> Result<()> {
+    // Configure the node to listen for IPFS connections on the given addresses
+    // or port, or the addresses from the configuration file, mapping the listen
+    // port on the local router via UPnP
+    // Offline, the daemon only serves the local repository over its API and gateway
+    let listen_addrs = match (cli.listen.is_empty(), args.port, cli.settings.listen_addrs()?) {
+        _ if cli.offline => Vec::new(),
+        (false, _, _) => cli.listen.clone(),
+        (true, Some(port), _) => network::default_listen_addrs(port),
+        (true, None, Some(addrs)) => addrs,
+        (true, None, None) => network::default_listen_addrs(DEFAULT_PORT),
+    };
+    info!("Configuring IPFS node to listen on {:?}", listen_addrs);
+    let announce = match cli.settings.announce_addrs()? {
+        Some(addrs) if args.announce.is_empty() => addrs,
+        _ => args.announce.clone(),
+    };
+    let blocklist = cli.settings.blocklist.iter().flatten().chain(&args.block).copied();
+    let mut builder = node_builder(cli)?
+        .verbose(cli.verbose && !cli.json)
+        .listen_on(listen_addrs.clone())
+        .announce(announce)
+        .blocklist(blocklist);
+
+    // Blocks are sent to everyone unless an allowlist is configured
+    let allowlist = match cli.settings.allowlist_peers()? {
+        Some(peers) => Some(peers.into_iter().chain(args.allow.iter().copied()).collect::<Vec<_>>()),
+        None if !args.allow.is_empty() => Some(args.allow.clone()),
+        None => None,
+    };
+    if let Some(allowlist) = allowlist {
+        info!("Sending blocks only to {} allowed peers", allowlist.len());
+        builder = builder.allowlist(allowlist);
+    }
+    let node = builder
+        .upnp(true)
+        .dht_mode(dht_mode(cli, DhtMode::Server))
+        .build()
+        .await?;
+    let peer_id = node.peer_id();
+    Message::PeerId { peer_id: peer_id.to_string() }.print(cli.json);
+    for addr in &listen_addrs {
+        Message::Listening { address: addr.to_string() }.print(cli.json);
+    }
+
+    // Print the multiaddresses that other nodes can use to connect to this node
+    for addr in node.client().addresses().await? {
+        Message::NodeAddress {
+            address: format!("{}/p2p/{}", addr, peer_id),
+        }
+        .print(cli.json);
+    }
+
+    // Accept fetch, add and pin requests on the control API
+    let api_addr = api_addr(cli, args.api.as_ref())?;
+    let api_token = api_token(cli);
+    if api_token.is_none() && api_addr.is_public() {
+        warn!("The control API on {} can be reached from other machines, protect it with --api-token", api_addr);
+    }
+    let mut cors_origins = cli.settings.cors_origins.clone().unwrap_or_default();
+    cors_origins.extend(args.cors_origin.iter().cloned());
+    let cors_methods = match (&args.cors_method[..], &cli.settings.cors_methods) {
+        ([], Some(methods)) => methods.clone(),
+        ([], None) => api::DEFAULT_CORS_METHODS.iter().map(|method| method.to_string()).collect(),
+        (methods, _) => methods.to_vec(),
+    };
+    let cors = api::cors(&cors_origins, &cors_methods)?;
+    Message::ApiListening { address: api_addr.to_string() }.print(cli.json);
+    let metrics_addr = args.metrics.clone().or(cli.settings.metrics_addr()?);
+    if let Some(addr) = &metrics_addr {
+        Message::MetricsListening { address: addr.to_string() }.print(cli.json);
+    }
+    let gateway_addr = args.gateway.clone().or(cli.settings.gateway_addr()?);
+    let gateway_limits = GatewayLimits {
+        requests_per_minute: args
+            .gateway_requests_per_minute
+            .or(cli.settings.limits.gateway_requests_per_minute)
+            .filter(|max| *max > 0),
+        downloads_per_client: args
+            .gateway_downloads_per_client
+            .or(cli.settings.limits.gateway_downloads_per_client)
+            .filter(|max| *max > 0),
+    };
+    if let Some(addr) = &gateway_addr {
+        Message::GatewayListening { address: addr.to_string() }.print(cli.json);
+    }
+
+    Message::Running.print(cli.json);
+
+    // Keep the node running until the process is asked to stop
+    // Every pinned block is announced in the background, as that takes as long as the DHT queries do,
+    // so other nodes can find the pinned content; provider records expire, so they are renewed
+    let node = Arc::new(node);
+    let reprovide_interval = match args.reprovide_interval {
+        Some(interval) => interval,
+        None => cli.settings.reprovide_interval()?.unwrap_or(DEFAULT_REPROVIDE_INTERVAL),
+    };
+    let reprovide_interval = (!reprovide_interval.is_zero() && !cli.offline).then_some(reprovide_interval);
+    let reprovider = tokio::spawn(reprovide(node.clone(), reprovide_interval, cli.json));
+    let (stop, stopped) = watch::channel(false);
+    let stop_signal = |mut stopped: watch::Receiver<bool>| async move {
+        let _ = stopped.wait_for(|stop| *stop).await;
+    };
+    // The servers borrow the node, which is taken back once they have stopped
+    let result = {
+        let metrics = async {
+            match &metrics_addr {
+                Some(addr) => api::serve_metrics(node.clone(), addr, stop_signal(stopped.clone())).await,
+                None => Ok(()),
+            }
+        };
+        let gateway = async {
+            match &gateway_addr {
+                Some(addr) => gateway::serve(node.clone(), addr, gateway_limits, stop_signal(stopped.clone())).await,
+                None => Ok(()),
+            }
+        };
+        let servers = async {
+            let api = api::serve(node.clone(), &api_addr, api_token.as_deref(), cors, stop_signal(stopped.clone()));
+            tokio::try_join!(api, metrics, gateway).map(|_| ())
+        };
+        tokio::pin!(servers);
+
+        tokio::select! {
+            // The servers only stop on their own if they fail
+            result = &mut servers => result,
+            _ = shutdown_signal() => {
+                info!("Shutting down, waiting for API requests in progress");
+                let _ = stop.send(true);
+                // Requests in progress may finish, but only for a while
+                tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, servers)
+                    .await
+                    .unwrap_or_else(|_| {
+                        warn!("Abandoned API requests still in progress");
+                        Ok(())
+                    })
+            }
+        }
+    };
+
+    // Stop the network; the event loop saves the routing table and closes the connections
+    reprovider.abort();
+    let _ = reprovider.await;
+    match Arc::try_unwrap(node) {
+        Ok(node) => node.shutdown().await,
+        // Abandoned requests still hold the node, so stop its event loop directly
+        Err(node) => {
+            if let Err(e) = node.client().shutdown().await {
+                warn!("{}", e);
+            }
+        }
+    }
+    Message::Stopped.print(cli.json);
+    result
+}
+
+/// Announce all pinned blocks, then again every interval, for as long as the daemon runs
+///
+/// # Arguments
+/// * `node` - The node sharing the pinned content
+/// * `interval` - Time between two announcements, None to only announce once
+/// * `json` - Whether to report the first announcement as JSON
+async fn reprovide(node: Arc<MigaNode>, interval: Option<Duration>, json: bool) {
+    match node.provide_pinned().await {
+        Ok(blocks) => Message::Published { blocks }.print(json),
+        Err(e) => warn!("Failed to announce the pinned blocks: {}", e),
+    }
+    let Some(interval) = interval else {
+        return;
+    };
+    let mut ticks = tokio::time::interval_at(Instant::now() + interval, interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        info!("Renewing the provider records of the pinned blocks");
+        if let Err(e) = node.provide_pinned().await {
+            warn!("Failed to renew the provider records: {}", e);
+        }
+    }
+}
+
+/// Point in time at which a fetch is given up, set by `--timeout`
+#[derive(Clone, Copy)]
+struct Deadline {
+    /// When the time is up
+    at: Instant,
+    /// The timeout the deadline was set from, for reporting
+    timeout: Duration,
+}
+
+impl Deadline {
+    /// Start counting down the timeout from the command line or configuration file, if there is one
+    fn new(cli: &Cli) -> Option<Self> {
+        let timeout = cli.timeout.or(cli.settings.fetch_timeout())?;
+        Some(Self {
+            at: Instant::now() + timeout,
+            timeout,
+        })
+    }
+
+    /// Run a fetch until it completes or the deadline passes
+    ///
+    /// When the deadline passes, the error tells how far the fetch got, so a
+    /// CID nobody provides can be told apart from a slow transfer.
+    ///
+    /// # Arguments
+    /// * `deadline` - When to give up, or `None` to wait for the fetch however long it takes
+    /// * `node` - The node running the fetch
+    /// * `progress` - Progress of the fetch
+    /// * `fetch` - The fetch
+    async fn run<T>(
+        deadline: Option<Self>,
+        node: &MigaNode,
+        progress: &FetchProgress,
+        fetch: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let Some(deadline) = deadline else {
+            return fetch.await;
+        };
+        match tokio::time::timeout_at(deadline.at, fetch).await {
+            Ok(result) => result,
+            Err(_) => {
+                let (blocks, bytes) = progress.fetched();
+                let providers = node.client().provider_count();
+                let progress = if blocks == 0 && providers == 0 {
+                    "no provider found".to_string()
+                } else {
+                    format!("{} blocks ({} bytes) fetched from {} providers", blocks, bytes, providers)
+                };
+                Err(MigaError::Timeout {
+                    timeout: deadline.timeout,
+                    progress,
+                }
+                .into())
+            }
+        }
+    }
+}
+
+/// Wait until the process is asked to stop, by Ctrl+C or (on Unix) SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
                 }
             }
-            Err(err) => {
-                warn!("Failed to parse bootstrap address: {}: {}", node, err);
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
             }
         }
     }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
 
-/// Extract a PeerId from a multiaddress
+/// Run a `pin` subcommand
 ///
-/// A multiaddress (Multiaddr) may contain a peer ID as its last component.
-/// This function extracts that peer ID if present.
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `command` - The pin subcommand to run
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+fn run_pin_command(cli: &Cli, command: &PinCommand) -> Result<()> {
+    let repo_dir = repo_dir(cli);
+    let mut pins = PinStore::open(pin::pin_file(&repo_dir))?;
+
+    match command {
+        PinCommand::Add { cid, direct } => {
+            let cid = parse_cid(cid)?;
+
+            // Only content that is actually stored locally can be protected
+            let blockstore = Blockstore::open(repo_dir.join("blocks"))?;
+            if !blockstore.has(&cid) {
+                return Err(anyhow!(
+                    "Content {} is not in the local blockstore, fetch it first with `miga get {}`",
+                    cid,
+                    cid
+                ));
+            }
+
+            let mode = if *direct { PinMode::Direct } else { PinMode::Recursive };
+            pins.add(&cid, mode)?;
+            Message::Pinned {
+                cid: cid.to_string(),
+                mode: mode.to_string(),
+            }
+            .print(cli.json);
+        }
+        PinCommand::Rm { cid } => {
+            let cid = parse_cid(cid)?;
+            if !pins.remove(&cid)? {
+                return Err(anyhow!("{} is not pinned", cid));
+            }
+            Message::Unpinned { cid: cid.to_string() }.print(cli.json);
+        }
+        PinCommand::Ls => {
+            for (cid, mode) in pins.list() {
+                Message::Pin {
+                    cid: cid.to_string(),
+                    mode: mode.to_string(),
+                }
+                .print(cli.json);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Run a `bootstrap` subcommand
 ///
 /// # Arguments
-/// * `addr` - The multiaddress to extract the peer ID from
+/// * `cli` - The parsed command line arguments
+/// * `command` - The bootstrap subcommand to run
 ///
 /// # Returns
-/// * `Option<PeerId>` - The extracted peer ID, or None if no valid peer ID was found
-fn extract_peer_id_from_multiaddr(addr: &Multiaddr) -> Option<PeerId> {
-    // Iterate through the protocols in the multiaddress
-    addr.iter().find_map(|proto| {
-        // Look for the P2p protocol which contains the peer ID
-        if let Protocol::P2p(hash) = proto {
-            // Convert the hash to a PeerId
-            PeerId::from_multihash(hash.into()).ok()
-        } else {
-            None
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+fn run_bootstrap_command(cli: &Cli, command: &BootstrapCommand) -> Result<()> {
+    let mut list = BootstrapList::open(bootstrap::bootstrap_file(&repo_dir(cli)))?;
+
+    match command {
+        BootstrapCommand::Add { addr } => {
+            // Without a peer ID the node couldn't be added to the routing table
+            if network::extract_peer_id_from_multiaddr(addr).is_none() {
+                return Err(anyhow!("Bootstrap address {} has no /p2p/<peer ID>", addr));
+            }
+            if !list.add(addr.clone())? {
+                return Err(anyhow!("{} is already a bootstrap node", addr));
+            }
+            Message::BootstrapAdded { address: addr.to_string() }.print(cli.json);
         }
-    })
+        BootstrapCommand::Rm { addr: Some(addr), .. } => {
+            if !list.remove(addr)? {
+                return Err(anyhow!("{} is not a bootstrap node", addr));
+            }
+            Message::BootstrapRemoved { address: addr.to_string() }.print(cli.json);
+        }
+        BootstrapCommand::Rm { addr: None, .. } => {
+            let removed = list.list();
+            list.clear()?;
+            for addr in removed {
+                Message::BootstrapRemoved { address: addr.to_string() }.print(cli.json);
+            }
+        }
+        BootstrapCommand::List => {
+            for addr in list.list() {
+                Message::Bootstrap { address: addr.to_string() }.print(cli.json);
+            }
+        }
+        BootstrapCommand::Reset => {
+            list.reset()?;
+            Message::BootstrapReset.print(cli.json);
+        }
+    }
+
+    Ok(())
+}
+
+/// Run a `repo` subcommand
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `command` - The repo subcommand to run
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+fn run_repo_command(cli: &Cli, command: &RepoCommand) -> Result<()> {
+    let repo_dir = repo_dir(cli);
+
+    match command {
+        RepoCommand::Gc { dry_run } => {
+            let blockstore = Blockstore::open(repo_dir.join("blocks"))?;
+            let pins = PinStore::open(pin::pin_file(&repo_dir))?;
+
+            let stats = gc::collect_garbage(&blockstore, &pins, *dry_run)?;
+            Message::Gc {
+                dry_run: *dry_run,
+                removed_blocks: stats.removed_blocks,
+                removed_bytes: stats.removed_bytes,
+                kept_blocks: stats.kept_blocks,
+            }
+            .print(cli.json);
+        }
+        RepoCommand::DedupStats => {
+            let blockstore = Blockstore::open(repo_dir.join("blocks"))?;
+            let pins = PinStore::open(pin::pin_file(&repo_dir))?;
+
+            let stats = dedup::dedup_stats(&blockstore, &pins)?;
+            Message::DedupStats {
+                blocks: stats.blocks,
+                logical_bytes: stats.logical_bytes,
+                physical_bytes: stats.physical_bytes,
+                saved_bytes: stats.saved_bytes(),
+            }
+            .print(cli.json);
+        }
+        RepoCommand::Stat => {
+            let blockstore = Blockstore::open(repo_dir.join("blocks"))?;
+            let pins = PinStore::open(pin::pin_file(&repo_dir))?;
+
+            Message::RepoStat {
+                path: repo_dir.display().to_string(),
+                blocks: blockstore.list()?.len(),
+                size: dir_size(&repo_dir)?,
+                pins: pins.list().len(),
+                version: blockstore::DATASTORE_VERSION,
+            }
+            .print(cli.json);
+        }
+        RepoCommand::ImportKubo { path, pins: pin_list } => {
+            let blockstore = Blockstore::open(repo_dir.join("blocks"))?;
+            let mut pins = PinStore::open(pin::pin_file(&repo_dir))?;
+
+            // Read the pin list first, so a broken list fails before the long copy
+            let imported_pins = pin_list.as_deref().map(kubo_repo::read_pins).transpose()?.unwrap_or_default();
+            let stats = kubo_repo::import_blocks(path, &blockstore)?;
+
+            let mut pinned = 0;
+            for (cid, mode) in imported_pins {
+                if !blockstore.has(&cid) {
+                    warn!("Not pinning {}, its block isn't in the Kubo repository", cid);
+                    continue;
+                }
+                pins.add(&cid, mode)?;
+                pinned += 1;
+            }
+            Message::KuboImported {
+                imported_blocks: stats.imported_blocks,
+                imported_bytes: stats.imported_bytes,
+                existing_blocks: stats.existing_blocks,
+                skipped_blocks: stats.skipped_blocks,
+                pins: pinned,
+            }
+            .print(cli.json);
+        }
+    }
+
+    Ok(())
+}
+
+/// Run a `cache` subcommand
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `command` - The cache subcommand to run
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+fn run_cache_command(cli: &Cli, command: &CacheCommand) -> Result<()> {
+    let repo_dir = repo_dir(cli);
+
+    match command {
+        CacheCommand::Clear { older_than } => {
+            let blockstore = Blockstore::open(repo_dir.join("blocks"))?;
+            let pins = PinStore::open(pin::pin_file(&repo_dir))?;
+
+            let stats = gc::clear_cache(&blockstore, &pins, *older_than)?;
+            Message::CacheCleared {
+                removed_blocks: stats.removed_blocks,
+                removed_bytes: stats.removed_bytes,
+            }
+            .print(cli.json);
+        }
+    }
+
+    Ok(())
+}
+
+/// Run a `cid` subcommand
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `command` - The cid subcommand to run
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+fn run_cid_command(cli: &Cli, command: &CidCommand) -> Result<()> {
+    match command {
+        CidCommand::Format {
+            cid,
+            cid_version,
+            base,
+            details,
+        } => {
+            let (parsed, parsed_base) = multiformats::parse_cid(cid).map_err(|e| MigaError::InvalidCid {
+                cid: cid.clone(),
+                reason: e.to_string(),
+            })?;
+
+            // CIDv0 is always base58btc, so another base needs CIDv1
+            let version = match (cid_version, base) {
+                (Some(0), _) => Version::V0,
+                (Some(_), _) => Version::V1,
+                (None, Some(base)) if *base != Multibase::Base58btc => Version::V1,
+                (None, _) => parsed.version(),
+            };
+            let converted = multiformats::convert(&parsed, version)?;
+            let base = match (version, base) {
+                (Version::V0, Some(base)) if *base != Multibase::Base58btc => {
+                    return Err(anyhow!("CIDv0 can only be written in base58btc"));
+                }
+                (Version::V0, _) => Base::Base58Btc,
+                (Version::V1, Some(base)) => (*base).into(),
+                (Version::V1, None) if parsed.version() == Version::V0 => Base::Base32Lower,
+                (Version::V1, None) => parsed_base,
+            };
+            let text = match version {
+                Version::V0 => converted.to_string(),
+                Version::V1 => converted.to_string_of_base(base)?,
+            };
+
+            if *details {
+                let hash = converted.hash();
+                Message::CidDetails {
+                    cid: text,
+                    version: version.into(),
+                    multibase: multiformats::base_name(base),
+                    codec: multiformats::codec_name(converted.codec()).unwrap_or("unknown").to_string(),
+                    codec_code: converted.codec(),
+                    hash_function: multiformats::hash_name(hash.code()).unwrap_or("unknown").to_string(),
+                    hash_code: hash.code(),
+                    digest_length: hash.size(),
+                    digest: Base::Base16Lower.encode(hash.digest()),
+                }
+                .print(cli.json);
+            } else {
+                Message::Cid { cid: text }.print(cli.json);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Run a `block` subcommand
+///
+/// Blocks are read from and written to the local repository only, without
+/// using the network.
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `command` - The block subcommand to run
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+fn run_block_command(cli: &Cli, command: &BlockCommand) -> Result<()> {
+    let repo_dir = repo_dir(cli);
+    let blockstore = Blockstore::open(repo_dir.join("blocks"))?;
+
+    match command {
+        BlockCommand::Get { cid } => {
+            let cid = parse_cid(cid)?;
+            let data = blockstore
+                .get(&cid)?
+                .ok_or_else(|| anyhow!("Block {} is not stored locally", cid))?;
+            // A block damaged on disk is reported rather than passed on
+            blockstore::verify(&cid, &data)?;
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(&data)?;
+            stdout.flush()?;
+        }
+        BlockCommand::Put {
+            file,
+            codec,
+            hash,
+            pin: pin_block,
+        } => {
+            let data = match file {
+                Some(path) => fs::read(path).with_context(|| format!("Failed to read {:?}", path))?,
+                None => {
+                    let mut data = Vec::new();
+                    io::stdin().read_to_end(&mut data)?;
+                    data
+                }
+            };
+
+            // Nodes of dag-pb and dag-json have to decode, or nothing could follow their links
+            let codec = codec.unwrap_or_default();
+            match codec {
+                Codec::DagPb => {
+                    dag::PbNode::decode(&data).context("The block is not a valid dag-pb node")?;
+                }
+                Codec::DagJson => {
+                    serde_json::from_slice::<serde_json::Value>(&data).context("The block is not valid JSON")?;
+                }
+                Codec::Raw | Codec::DagCbor => {}
+            }
+
+            let cid = hash.unwrap_or_default().cid(codec.code(), &data);
+            blockstore.put(&cid, &data)?;
+            if *pin_block {
+                let mut pins = PinStore::open(pin::pin_file(&repo_dir))?;
+                pins.add(&cid, PinMode::Direct)?;
+            }
+            Message::BlockStored {
+                cid: cid.to_string(),
+                size: data.len() as u64,
+            }
+            .print(cli.json);
+        }
+        BlockCommand::Stat { cid } => {
+            let cid = parse_cid(cid)?;
+            Message::BlockStat {
+                cid: cid.to_string(),
+                size: blockstore.size(&cid)?,
+            }
+            .print(cli.json);
+        }
+    }
+
+    Ok(())
+}
+
+/// Run a `dag` subcommand
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `command` - The dag subcommand to run
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+async fn run_dag_command(cli: &Cli, command: &DagCommand) -> Result<()> {
+    match command {
+        DagCommand::Put {
+            file,
+            store_codec,
+            hash,
+            pin: pin_node,
+            provide,
+        } => {
+            let json = match file {
+                Some(path) => fs::read(path).with_context(|| format!("Failed to read {:?}", path))?,
+                None => {
+                    let mut json = Vec::new();
+                    io::stdin().read_to_end(&mut json)?;
+                    json
+                }
+            };
+            let value: serde_json::Value = serde_json::from_slice(&json).context("The node is not valid JSON")?;
+
+            let codec = store_codec.unwrap_or_default();
+            let data = codec.encode(&value)?;
+            let cid = hash.unwrap_or_default().cid(codec.code(), &data);
+
+            let repo_dir = repo_dir(cli);
+            Blockstore::open(repo_dir.join("blocks"))?.put(&cid, &data)?;
+            if *pin_node {
+                let mut pins = PinStore::open(pin::pin_file(&repo_dir))?;
+                pins.add(&cid, PinMode::Recursive)?;
+            }
+            Message::DagStored {
+                cid: cid.to_string(),
+                size: data.len() as u64,
+            }
+            .print(cli.json);
+
+            // The blocks the node links to are announced as well, so they have to be stored locally
+            if *provide {
+                let node = node_builder(cli)?.dht_mode(dht_mode(cli, DhtMode::Client)).build().await?;
+                let result = node.provide(cid).await;
+                node.shutdown().await;
+                let blocks = result?;
+                // Offline, nothing is announced on purpose
+                if blocks == 0 && !cli.offline {
+                    return Err(MigaError::Network(format!("No peer could be reached to announce {}", cid)).into());
+                }
+                Message::Provided {
+                    cid: cid.to_string(),
+                    blocks,
+                }
+                .print(cli.json);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Run a `key` subcommand
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `command` - The key subcommand to run
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+fn run_key_command(cli: &Cli, command: &KeyCommand) -> Result<()> {
+    let keystore = Keystore::open(keystore_dir(cli))?;
+
+    match command {
+        KeyCommand::Gen { name, key_type } => {
+            let peer_id = keystore.generate(name, key_type.unwrap_or_default(), keystore_passphrase(cli)?)?;
+            Message::Key {
+                name: name.clone(),
+                peer_id: peer_id.to_string(),
+            }
+            .print(cli.json);
+        }
+        KeyCommand::List => {
+            for (name, peer_id) in keystore.list()? {
+                Message::Key {
+                    name,
+                    peer_id: peer_id.to_string(),
+                }
+                .print(cli.json);
+            }
+        }
+        KeyCommand::Rename { name, new_name } => {
+            keystore.rename(name, new_name)?;
+            Message::KeyRenamed {
+                name: name.clone(),
+                new_name: new_name.clone(),
+            }
+            .print(cli.json);
+        }
+        KeyCommand::Export { name, output } => {
+            let key = keystore.export(name, keystore_passphrase(cli)?)?;
+            let path = output.clone().unwrap_or_else(|| PathBuf::from(format!("{}.key", name)));
+            keypair::write_key_file(&path, &key)?;
+            Message::KeyExported { name: name.clone(), path }.print(cli.json);
+        }
+        KeyCommand::Import { name, file } => {
+            let key = fs::read(file).with_context(|| format!("Failed to read key from {:?}", file))?;
+            let peer_id = keystore.import(name, &key, keystore_passphrase(cli)?)?;
+            Message::Key {
+                name: name.clone(),
+                peer_id: peer_id.to_string(),
+            }
+            .print(cli.json);
+        }
+    }
+
+    Ok(())
+}
+
+/// Run a `swarm` subcommand
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `command` - The swarm subcommand to run
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+async fn run_swarm_command(cli: &Cli, command: &SwarmCommand) -> Result<()> {
+    match command {
+        SwarmCommand::Peers { api } => {
+            let client = api_client(cli, api.as_ref())?;
+            let peers: Vec<api::SwarmPeer> = client.get("/v1/swarm/peers").await?;
+            for peer in peers {
+                Message::Peer {
+                    peer_id: peer.peer_id,
+                    address: peer.address,
+                    direction: peer.direction.to_string(),
+                    transport: peer.transport,
+                    agent_version: peer.agent_version,
+                }
+                .print(cli.json);
+            }
+        }
+        SwarmCommand::Connect { addr, api } => {
+            let client = api_client(cli, api.as_ref())?;
+            let request = api::ConnectRequest { address: addr.to_string() };
+            let response: api::ConnectResponse = client.post("/v1/swarm/connect", &request).await?;
+            Message::Connected {
+                peer_id: response.peer_id,
+                address: addr.to_string(),
+            }
+            .print(cli.json);
+        }
+        SwarmCommand::Block { entry, api } => {
+            let client = api_client(cli, api.as_ref())?;
+            let request = api::BlocklistRequest { entry: *entry };
+            let response: api::BlocklistResponse = client.post("/v1/swarm/block", &request).await?;
+            Message::Blocked {
+                entry: response.entry.to_string(),
+                changed: response.changed,
+            }
+            .print(cli.json);
+        }
+        SwarmCommand::Unblock { entry, api } => {
+            let client = api_client(cli, api.as_ref())?;
+            let request = api::BlocklistRequest { entry: *entry };
+            let response: api::BlocklistResponse = client.post("/v1/swarm/unblock", &request).await?;
+            if !response.changed {
+                return Err(anyhow!("{} is not blocked", entry));
+            }
+            Message::Unblocked {
+                entry: response.entry.to_string(),
+            }
+            .print(cli.json);
+        }
+        SwarmCommand::Blocklist { api } => {
+            let client = api_client(cli, api.as_ref())?;
+            let entries: Vec<BlocklistEntry> = client.get("/v1/swarm/blocklist").await?;
+            for entry in entries {
+                Message::BlocklistEntry { entry: entry.to_string() }.print(cli.json);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Run a `stats` subcommand
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `command` - The stats subcommand to run
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+async fn run_stats_command(cli: &Cli, command: &StatsCommand) -> Result<()> {
+    match command {
+        StatsCommand::Bw(args) => stats_bw(cli, args).await,
+    }
+}
+
+/// Print the bandwidth the daemon used, once or every interval with `--poll`
+///
+/// Without `--peer` or `--proto`, the total is followed by the traffic of
+/// every protocol, except when polling.
+async fn stats_bw(cli: &Cli, args: &BwArgs) -> Result<()> {
+    let client = api_client(cli, args.api.as_ref())?;
+    let mut interval = tokio::time::interval(args.interval.unwrap_or(DEFAULT_BW_POLL_INTERVAL));
+    loop {
+        interval.tick().await;
+        let stats: BandwidthStats = client.get("/v1/stats/bw").await?;
+
+        // Peers and protocols without traffic are shown with zeros, like Kubo does
+        let (peer, protocol, traffic) = match (&args.peer, &args.proto) {
+            (Some(peer), _) => {
+                let peer = peer.to_string();
+                let traffic = stats.peers.get(&peer).copied().unwrap_or_default();
+                (Some(peer), None, traffic)
+            }
+            (None, Some(proto)) => (None, Some(proto.clone()), stats.protocols.get(proto).copied().unwrap_or_default()),
+            (None, None) => (None, None, stats.total),
+        };
+        Message::Bandwidth {
+            peer,
+            protocol,
+            total_in: traffic.total_in,
+            total_out: traffic.total_out,
+            rate_in: traffic.rate_in,
+            rate_out: traffic.rate_out,
+        }
+        .print(cli.json);
+
+        if !args.poll {
+            if args.peer.is_none() && args.proto.is_none() {
+                for (protocol, traffic) in stats.protocols {
+                    Message::Bandwidth {
+                        peer: None,
+                        protocol: Some(protocol),
+                        total_in: traffic.total_in,
+                        total_out: traffic.total_out,
+                        rate_in: traffic.rate_in,
+                        rate_out: traffic.rate_out,
+                    }
+                    .print(cli.json);
+                }
+            }
+            return Ok(());
+        }
+    }
+}
+
+/// Run a `dht` subcommand
+///
+/// Starts a node of its own as a DHT client to run the query.
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `command` - The dht subcommand to run
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+async fn run_dht_command(cli: &Cli, command: &DhtCommand) -> Result<()> {
+    let node = node_builder(cli)?.dht_mode(dht_mode(cli, DhtMode::Client)).build().await?;
+
+    let result = match command {
+        DhtCommand::FindPeer { peer } => node.client().find_peer(*peer).await.map(|addrs| {
+            for addr in addrs {
+                Message::PeerAddress {
+                    peer_id: peer.to_string(),
+                    address: addr.to_string(),
+                }
+                .print(cli.json);
+            }
+        }),
+        DhtCommand::FindProviders { cid, num_providers } => match parse_cid(cid) {
+            Ok(cid) => find_providers(cli, &node, cid, num_providers.unwrap_or(DEFAULT_NUM_PROVIDERS)).await,
+            Err(e) => Err(e),
+        },
+    };
+
+    // Stop the node; the event loop saves the routing table for the next run
+    node.shutdown().await;
+    result
+}
+
+/// List the providers of a CID as they are found in the DHT
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `node` - The node to look up the providers with
+/// * `cid` - The CID to look up
+/// * `limit` - Number of providers to stop after
+///
+/// # Returns
+/// - `Result<()>`: Ok, if at least one provider was found, Err otherwise
+async fn find_providers(cli: &Cli, node: &MigaNode, cid: Cid, limit: usize) -> Result<()> {
+    let mut providers = node.client().find_providers(cid).await?;
+    let mut found = Vec::new();
+    while found.len() < limit {
+        let Some(peer) = providers.recv().await else {
+            break;
+        };
+        if found.contains(&peer) {
+            continue;
+        }
+        found.push(peer);
+
+        // Providers are found by peer ID; their addresses are only known if the node has met them
+        let addresses = node.client().known_addresses(peer).await?;
+        Message::Provider {
+            cid: cid.to_string(),
+            peer_id: peer.to_string(),
+            addresses: addresses.iter().map(|addr| addr.to_string()).collect(),
+        }
+        .print(cli.json);
+    }
+
+    if found.is_empty() {
+        return Err(MigaError::NoProviders { cid, attempts: 1 }.into());
+    }
+    Ok(())
+}
+
+/// Run the `id` subcommand, printing the identity of this node
+///
+/// The running daemon is asked for the addresses it listens on and is
+/// reachable on, and the protocols it speaks. Without a daemon, the identity
+/// stored in the repository is shown with the protocols a daemon would speak,
+/// but no addresses.
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `api` - Address of the daemon's control API from the command line
+///
+/// # Returns
+/// - `Result<()>`: Ok, if the operation was successful, Err otherwise
+async fn run_id(cli: &Cli, api: Option<&ApiAddr>) -> Result<()> {
+    let client = api_client(cli, api)?;
+    let id = match client.get::<api::IdResponse>("/v1/id").await {
+        Ok(id) => id,
+        Err(e) => {
+            debug!("Showing the identity without addresses: {:#}", e);
+            let public_key = match &cli.identity_key {
+                Some(name) => Keystore::open(keystore_dir(cli))?.load(name, keystore_passphrase(cli)?)?,
+                None => keypair::load_or_generate(&identity_path(cli), cli.identity_type.unwrap_or_default())?,
+            }
+            .public();
+            api::IdResponse {
+                peer_id: public_key.to_peer_id().to_string(),
+                public_key: keypair::encode_public_key(&public_key),
+                addresses: Vec::new(),
+                agent_version: network::agent_version(),
+                protocols: network::supported_protocols(dht_mode(cli, DhtMode::Server) == DhtMode::Server),
+            }
+        }
+    };
+
+    Message::Id {
+        id: id.peer_id,
+        public_key: id.public_key,
+        addresses: id.addresses,
+        agent_version: id.agent_version,
+        protocols: id.protocols,
+    }
+    .print(cli.json);
+    Ok(())
+}
+
+/// Run the `ping` subcommand
+///
+/// Connects to the peer and pings it over the connection, printing the round
+/// trip time of every ping and a summary with the packet loss at the end. A
+/// high loss or round trip time points at the link to the peer rather than at
+/// finding it in the DHT.
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `args` - Arguments of the `ping` subcommand
+///
+/// # Returns
+/// - `Result<()>`: Ok, if at least one ping came back, Err otherwise
+async fn run_ping(cli: &Cli, args: &PingArgs) -> Result<()> {
+    let (peer, addrs) = parse_peer(&args.target)?;
+    let count = args.count.unwrap_or(DEFAULT_PING_COUNT).max(1);
+    let node = node_builder(cli)?
+        .dht_mode(dht_mode(cli, DhtMode::Client))
+        .ping_interval(args.interval.unwrap_or(DEFAULT_PING_INTERVAL))
+        .build()
+        .await?;
+
+    let result = ping(cli, &node, peer, addrs, count).await;
+    node.shutdown().await;
+    result
+}
+
+/// Ping a peer until `count` pings have been sent, the connection fails or the process is asked to stop
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `node` - The node to ping from
+/// * `peer` - The peer to ping
+/// * `addrs` - Addresses to reach the peer on
+/// * `count` - Number of pings to send
+///
+/// # Returns
+/// - `Result<()>`: Ok, if at least one ping came back, Err otherwise
+async fn ping(cli: &Cli, node: &MigaNode, peer: PeerId, addrs: Vec<Multiaddr>, count: u32) -> Result<()> {
+    let mut pongs = node.client().ping(peer, addrs).await?;
+    let peer_id = peer.to_string();
+
+    let mut sent = 0;
+    let mut rtts = Vec::new();
+    let mut last_error = None;
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    while sent < count {
+        let pong = tokio::select! {
+            pong = pongs.recv() => pong,
+            _ = &mut signal => break,
+        };
+        // The channel closes when the connection to the peer fails
+        let Some(pong) = pong else {
+            break;
+        };
+        sent += 1;
+        match pong {
+            Ok(rtt) => {
+                Message::Pong {
+                    peer_id: peer_id.clone(),
+                    seq: sent,
+                    rtt_ms: rtt.as_secs_f64() * 1000.0,
+                }
+                .print(cli.json);
+                rtts.push(rtt);
+            }
+            Err(e) => {
+                Message::PingFailed {
+                    peer_id: peer_id.clone(),
+                    seq: sent,
+                    error: e.to_string(),
+                }
+                .print(cli.json);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    if sent > 0 {
+        let received = rtts.len() as u32;
+        let millis: Vec<f64> = rtts.iter().map(|rtt| rtt.as_secs_f64() * 1000.0).collect();
+        Message::PingStats {
+            peer_id,
+            sent,
+            received,
+            loss_percent: f64::from(sent - received) * 100.0 / f64::from(sent),
+            min_ms: millis.iter().copied().reduce(f64::min).unwrap_or_default(),
+            avg_ms: millis.iter().sum::<f64>() / f64::from(received.max(1)),
+            max_ms: millis.iter().copied().reduce(f64::max).unwrap_or_default(),
+        }
+        .print(cli.json);
+    }
+
+    if rtts.is_empty() {
+        let reason = last_error.map(|e| e.to_string()).unwrap_or_else(|| "no ping was sent".to_string());
+        return Err(MigaError::Network(format!("No pong from {}: {}", peer, reason)).into());
+    }
+    Ok(())
+}
+
+/// Parse a peer given as a peer ID or as a multiaddress ending in `/p2p/<peer ID>`
+///
+/// # Returns
+/// * `Result<(PeerId, Vec<Multiaddr>)>` - The peer, and the address it was given with, if any
+fn parse_peer(target: &str) -> Result<(PeerId, Vec<Multiaddr>)> {
+    if target.starts_with('/') {
+        let addr: Multiaddr = target
+            .parse()
+            .map_err(|e| anyhow!("Invalid multiaddress {}: {}", target, e))?;
+        let peer = network::extract_peer_id_from_multiaddr(&addr)
+            .ok_or_else(|| anyhow!("Address {} has no /p2p/<peer ID>", addr))?;
+        Ok((peer, vec![addr]))
+    } else {
+        let peer = target.parse().map_err(|e| anyhow!("Invalid peer ID {}: {}", target, e))?;
+        Ok((peer, Vec::new()))
+    }
+}
+
+/// Expire the cache, then tell whether all the content can be served from the repository without the network
+///
+/// Cached blocks older than the `--cache-ttl` are removed first, so they are
+/// fetched again. Offline, everything has to be stored locally anyway, so
+/// nothing is removed or checked.
+///
+/// # Arguments
+/// * `cli` - The parsed command line arguments
+/// * `contents` - The content to check
+fn serve_from_cache(cli: &Cli, contents: &[ContentPath]) -> Result<bool> {
+    if cli.offline {
+        return Ok(false);
+    }
+    let repo_dir = repo_dir(cli);
+    let blockstore = Blockstore::open(repo_dir.join("blocks"))?;
+    if let Some(ttl) = cli.cache_ttl.or(cli.settings.cache_ttl()?) {
+        let pins = PinStore::open(pin::pin_file(&repo_dir))?;
+        let stats = gc::clear_cache(&blockstore, &pins, Some(ttl))?;
+        if stats.removed_blocks > 0 {
+            info!(
+                "Removed {} cached blocks ({} bytes) older than {}",
+                stats.removed_blocks,
+                stats.removed_bytes,
+                humantime::format_duration(ttl)
+            );
+        }
+    }
+    for content in contents {
+        if !fetch::stored_locally(&blockstore, content.cid, &content.path)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Builder for a node using the settings from the command line and configuration file
+///
+/// The node listens on the `--listen` addresses, or on all network interfaces
+/// with a random port unless the caller configures otherwise.
+fn node_builder(cli: &Cli) -> Result<MigaNodeBuilder> {
+    let mut builder = MigaNode::builder()
+        .offline(cli.offline)
+        .repo(repo_dir(cli))
+        .identity(identity_path(cli))
+        .identity_type(cli.identity_type.unwrap_or_default());
+    if let Some(name) = &cli.identity_key {
+        builder = builder.keypair(Keystore::open(keystore_dir(cli))?.load(name, keystore_passphrase(cli)?)?);
+    }
+    if !cli.listen.is_empty() {
+        builder = builder.listen_on(cli.listen.clone());
+    }
+    if let Some(path) = &cli.swarm_key {
+        builder = builder.swarm_key(path);
+    }
+    if let Some(bootstrap) = bootstrap_nodes(cli)? {
+        builder = builder.bootstrap(bootstrap);
+    }
+    if let Some(timeout) = cli.settings.dht_query_timeout() {
+        builder = builder.query_timeout(timeout);
+    }
+    if let Some(retries) = cli.max_retries.or(cli.settings.fetch.max_retries) {
+        builder = builder.max_retries(retries);
+    }
+    if let Some(backoff) = cli.retry_backoff.or(cli.settings.retry_backoff()?) {
+        builder = builder.retry_backoff(backoff);
+    }
+    // A rate of 0 lifts the cap, like the connection limits
+    if let Some(rate) = cli.max_upload_rate.or(cli.settings.max_upload_rate()?).filter(|rate| *rate > 0) {
+        builder = builder.max_upload_rate(rate);
+    }
+    if let Some(rate) = cli.max_download_rate.or(cli.settings.max_download_rate()?).filter(|rate| *rate > 0) {
+        builder = builder.max_download_rate(rate);
+    }
+    if let Some(size) = cli.block_cache_size.or(cli.settings.block_cache_size()?) {
+        builder = builder.block_cache_size(size);
+    }
+    Ok(builder.connection_limits(connection_limits(cli)))
+}
+
+/// Connection limits from the command line, the configuration file or the defaults
+fn connection_limits(cli: &Cli) -> ConnectionLimits {
+    let defaults = ConnectionLimits::default();
+    let limits = &cli.settings.limits;
+
+    // A cap of 0 lifts the cap altogether
+    let cap = |flag: Option<u32>, setting: Option<u32>, default: Option<u32>| match flag.or(setting) {
+        Some(0) => None,
+        Some(cap) => Some(cap),
+        None => default,
+    };
+    ConnectionLimits {
+        max_connections: cap(cli.max_connections, limits.max_connections, defaults.max_connections),
+        max_connections_per_peer: cap(
+            cli.max_connections_per_peer,
+            limits.max_connections_per_peer,
+            defaults.max_connections_per_peer,
+        ),
+        max_pending: cap(cli.max_pending_dials, limits.max_pending_dials, defaults.max_pending),
+    }
+}
+
+/// Bootstrap nodes replacing the defaults, if any are configured
+///
+/// Nodes from the command line take precedence over the list saved with
+/// `miga bootstrap`, which takes precedence over the configuration file.
+fn bootstrap_nodes(cli: &Cli) -> Result<Option<Vec<Multiaddr>>> {
+    let bootstrap = if !cli.bootstrap.is_empty() {
+        cli.bootstrap.clone()
+    } else {
+        let saved = BootstrapList::open(bootstrap::bootstrap_file(&repo_dir(cli)))?;
+        if let Some(saved) = saved.saved() {
+            return Ok(Some(saved.to_vec()));
+        }
+        match cli.settings.bootstrap_addrs()? {
+            Some(bootstrap) => bootstrap,
+            None => return Ok(None),
+        }
+    };
+
+    // Nodes given on the command line or in the configuration file may add to the defaults
+    let keep_defaults = cli.keep_default_bootstrap || cli.settings.keep_default_bootstrap.unwrap_or(false);
+    let defaults = keep_defaults.then(network::default_bootstrap_nodes).unwrap_or_default();
+    Ok(Some(defaults.into_iter().chain(bootstrap).collect()))
+}
+
+/// Control API address from the command line or configuration file, or the default `/ip4/127.0.0.1/tcp/5001`
+fn api_addr(cli: &Cli, flag: Option<&ApiAddr>) -> Result<ApiAddr> {
+    match (flag, cli.settings.api_addr()?) {
+        (Some(addr), _) => Ok(addr.clone()),
+        (None, Some(addr)) => Ok(addr),
+        (None, None) => api::DEFAULT_API_ADDR.parse(),
+    }
+}
+
+/// Client of the daemon's control API, sending the API token if one is configured
+fn api_client(cli: &Cli, flag: Option<&ApiAddr>) -> Result<ApiClient> {
+    let client = ApiClient::new(api_addr(cli, flag)?);
+    Ok(match api_token(cli) {
+        Some(token) => client.token(token),
+        None => client,
+    })
+}
+
+/// Token the control API requires, from the command line or the configuration file
+fn api_token(cli: &Cli) -> Option<String> {
+    cli.api_token.clone().or_else(|| cli.settings.api_token.clone())
+}
+
+/// Progress bars for downloads, hidden with `--json` so only JSON is printed
+fn progress_bars(cli: &Cli) -> MultiProgress {
+    if cli.json {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    }
+}
+
+/// Identity key file from the command line or configuration file, or the default `~/.miga/identity.key`
+fn identity_path(cli: &Cli) -> PathBuf {
+    cli.identity
+        .clone()
+        .or_else(|| cli.settings.identity.clone())
+        .unwrap_or_else(keypair::default_identity_path)
+}
+
+/// Role in the DHT from the command line or configuration file, or the default of the command
+fn dht_mode(cli: &Cli, default: DhtMode) -> DhtMode {
+    cli.dht_mode.or(cli.settings.dht_mode).unwrap_or(default)
+}
+
+/// Block fetch concurrency from the command line or configuration file
+fn concurrency(cli: &Cli, flag: Option<usize>) -> usize {
+    flag.or(cli.settings.fetch.concurrency).unwrap_or(DEFAULT_CONCURRENCY)
+}
+
+/// Parse a CID string, returning a descriptive error if it is invalid
+fn parse_cid(cid: &str) -> Result<Cid> {
+    Cid::try_from(cid).map_err(|err| {
+        MigaError::InvalidCid {
+            cid: cid.to_string(),
+            reason: err.to_string(),
+        }
+        .into()
+    })
+}
+
+/// Parse content given by the user as a CID, an IPFS path or URI, or a gateway URL
+///
+/// # Arguments
+/// * `content` - The text naming the content
+///
+/// # Returns
+/// * `Result<ContentPath>` - CID of the content, and the path within it
+fn parse_content(content: &str) -> Result<ContentPath> {
+    content.parse()
+}
+
+/// Directory of the keystore, in the repository
+fn keystore_dir(cli: &Cli) -> PathBuf {
+    repo_dir(cli).join("keystore")
+}
+
+/// Passphrase of the keystore, which commands using its keys require
+fn keystore_passphrase(cli: &Cli) -> Result<&str> {
+    cli.keystore_passphrase
+        .as_deref()
+        .filter(|passphrase| !passphrase.is_empty())
+        .ok_or_else(|| anyhow!("The keystore needs a passphrase, set MIGA_KEYSTORE_PASSPHRASE"))
+}
+
+/// Repository directory from the command line or configuration file, or the default `~/.miga`
+fn repo_dir(cli: &Cli) -> PathBuf {
+    cli.repo
+        .clone()
+        .or_else(|| cli.settings.repo.clone())
+        .unwrap_or_else(keypair::home_dir)
+}
+
+/// Total size of the files below a directory, in bytes
+///
+/// Symbolic links are counted as links, not followed.
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read directory {:?}", dir))? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                size += metadata.len();
+            }
+        }
+    }
+    Ok(size)
 }
@@ -0,0 +1,100 @@
+//! Prometheus metrics of a node
+//!
+//! The libp2p metrics (connections, Kademlia queries, bandwidth per protocol
+//! and direction) are registered next to MIGA's own counters in a single
+//! registry, which the daemon exposes in the Prometheus text format.
+
+use prometheus_client::{
+    metrics::{counter::Counter, gauge::Gauge},
+    registry::Registry,
+};
+
+/// MIGA's own metrics, cheap to clone and share between tasks
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// Number of peers the node is connected to
+    pub(crate) connected_peers: Gauge,
+    /// DHT queries started by the node
+    pub(crate) dht_queries: Counter,
+    /// DHT queries that found what they were looking for
+    pub(crate) dht_queries_succeeded: Counter,
+    /// DHT requests received from other nodes
+    pub(crate) dht_inbound_requests: Counter,
+    /// Provider and value requests of other nodes answered from the local record store
+    pub(crate) dht_inbound_answered: Counter,
+    /// Blocks fetched from the network
+    pub(crate) blocks_fetched: Counter,
+    /// Bytes of the blocks fetched from the network
+    pub(crate) block_bytes_fetched: Counter,
+    /// Files currently being fetched
+    pub(crate) active_fetches: Gauge,
+    /// Blocks sent to peers over Bitswap
+    pub(crate) blocks_served: Counter,
+    /// Bytes of the blocks sent to peers over Bitswap
+    pub(crate) block_bytes_served: Counter,
+    /// Blocks the node was announced as provider of
+    pub(crate) blocks_provided: Counter,
+    /// Completed runs announcing all pinned blocks
+    pub(crate) reprovides: Counter,
+}
+
+impl Metrics {
+    /// Create the metrics and register them in a registry
+    pub fn new(registry: &mut Registry) -> Self {
+        let metrics = Self::default();
+        let registry = registry.sub_registry_with_prefix("miga");
+        registry.register("connected_peers", "Number of connected peers", metrics.connected_peers.clone());
+        registry.register("dht_queries", "DHT queries issued", metrics.dht_queries.clone());
+        registry.register(
+            "dht_queries_succeeded",
+            "DHT queries that found a record or providers",
+            metrics.dht_queries_succeeded.clone(),
+        );
+        registry.register(
+            "dht_inbound_requests",
+            "DHT requests received from other nodes",
+            metrics.dht_inbound_requests.clone(),
+        );
+        registry.register(
+            "dht_inbound_answered",
+            "Provider and value requests of other nodes answered from the local record store",
+            metrics.dht_inbound_answered.clone(),
+        );
+        registry.register("blocks_fetched", "Blocks fetched from the network", metrics.blocks_fetched.clone());
+        registry.register(
+            "block_bytes_fetched",
+            "Bytes of the blocks fetched from the network",
+            metrics.block_bytes_fetched.clone(),
+        );
+        registry.register("active_fetches", "Files currently being fetched", metrics.active_fetches.clone());
+        registry.register("blocks_served", "Blocks sent to peers over Bitswap", metrics.blocks_served.clone());
+        registry.register(
+            "block_bytes_served",
+            "Bytes of the blocks sent to peers over Bitswap",
+            metrics.block_bytes_served.clone(),
+        );
+        registry.register(
+            "blocks_provided",
+            "Blocks the node was announced as provider of",
+            metrics.blocks_provided.clone(),
+        );
+        registry.register("reprovides", "Completed runs announcing all pinned blocks", metrics.reprovides.clone());
+        metrics
+    }
+
+    /// Count a file fetch as active until the returned guard is dropped
+    pub(crate) fn start_fetch(&self) -> ActiveFetch {
+        self.active_fetches.inc();
+        ActiveFetch(self.active_fetches.clone())
+    }
+}
+
+/// A running file fetch, counted in the `active_fetches` gauge
+#[derive(Debug)]
+pub(crate) struct ActiveFetch(Gauge);
+
+impl Drop for ActiveFetch {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
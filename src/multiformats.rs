@@ -0,0 +1,212 @@
+//! Names and conversions of multiformats
+//!
+//! CIDs are built from multiformats: a multibase prefix telling how the CID
+//! is written, a multicodec telling how the block is encoded, and a multihash
+//! telling how it was hashed. CIDv0 has none of the prefixes; it is always
+//! written in base58btc and names a dag-pb block hashed with sha2-256.
+
+use anyhow::{anyhow, Result};
+use cid::{multibase::Base, Cid, Version};
+use multihash_codetable::{Code, MultihashDigest};
+use serde::Deserialize;
+
+use crate::{
+    blockstore::RAW_CODEC,
+    dag::{DAG_CBOR_CODEC, DAG_JSON_CODEC, DAG_PB_CODEC},
+};
+
+/// Multihash code of sha2-256, the only hash function of CIDv0
+const SHA2_256: u64 = 0x12;
+
+/// Multibases a CID can be converted to
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Multibase {
+    /// Lower-case base32, as subdomain gateways and browsers require
+    Base32,
+    /// base58btc, the base of CIDv0
+    Base58btc,
+    /// Lower-case base36, short enough for the DNS labels of long CIDs
+    Base36,
+}
+
+impl From<Multibase> for Base {
+    fn from(base: Multibase) -> Self {
+        match base {
+            Multibase::Base32 => Base::Base32Lower,
+            Multibase::Base58btc => Base::Base58Btc,
+            Multibase::Base36 => Base::Base36Lower,
+        }
+    }
+}
+
+/// Codecs blocks can be stored with
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Codec {
+    /// Opaque bytes, as the leaves of files
+    #[default]
+    Raw,
+    /// Protobuf nodes, as UnixFS files and directories
+    DagPb,
+    /// IPLD nodes encoded as CBOR
+    DagCbor,
+    /// IPLD nodes encoded as JSON
+    DagJson,
+}
+
+impl Codec {
+    /// Multicodec code of the codec
+    pub fn code(self) -> u64 {
+        match self {
+            Codec::Raw => RAW_CODEC,
+            Codec::DagPb => DAG_PB_CODEC,
+            Codec::DagCbor => DAG_CBOR_CODEC,
+            Codec::DagJson => DAG_JSON_CODEC,
+        }
+    }
+}
+
+/// Hash functions blocks can be hashed with
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashFunction {
+    /// SHA-256, the default of IPFS
+    #[default]
+    #[value(name = "sha2-256")]
+    #[serde(rename = "sha2-256")]
+    Sha2_256,
+    /// SHA-512
+    #[value(name = "sha2-512")]
+    #[serde(rename = "sha2-512")]
+    Sha2_512,
+    /// BLAKE2b with a 256-bit digest
+    #[value(name = "blake2b-256")]
+    #[serde(rename = "blake2b-256")]
+    Blake2b256,
+    /// BLAKE3 with a 256-bit digest, much faster than SHA-2 on large files
+    #[value(name = "blake3")]
+    #[serde(rename = "blake3")]
+    Blake3,
+}
+
+impl HashFunction {
+    /// Multihash code of the function
+    pub fn code(self) -> Code {
+        match self {
+            HashFunction::Sha2_256 => Code::Sha2_256,
+            HashFunction::Sha2_512 => Code::Sha2_512,
+            HashFunction::Blake2b256 => Code::Blake2b256,
+            HashFunction::Blake3 => Code::Blake3_256,
+        }
+    }
+
+    /// The function of a multihash code, if it is one of the selectable ones
+    pub fn from_code(code: u64) -> Option<Self> {
+        [
+            HashFunction::Sha2_256,
+            HashFunction::Sha2_512,
+            HashFunction::Blake2b256,
+            HashFunction::Blake3,
+        ]
+        .into_iter()
+        .find(|function| u64::from(function.code()) == code)
+    }
+
+    /// Hash a block, giving the CIDv1 it is stored under
+    ///
+    /// # Arguments
+    /// * `codec` - Multicodec code of the block
+    /// * `data` - The block data
+    pub fn cid(self, codec: u64, data: &[u8]) -> Cid {
+        Cid::new_v1(codec, self.code().digest(data))
+    }
+}
+
+/// Parse a CID, telling the multibase it is written in
+///
+/// # Arguments
+/// * `s` - The CID as text
+///
+/// # Returns
+/// * `Result<(Cid, Base)>` - The CID and its multibase
+pub fn parse_cid(s: &str) -> Result<(Cid, Base)> {
+    let cid = Cid::try_from(s)?;
+    let base = match cid.version() {
+        Version::V0 => Base::Base58Btc,
+        Version::V1 => cid::multibase::decode(s)?.0,
+    };
+    Ok((cid, base))
+}
+
+/// Convert a CID to another version
+///
+/// # Arguments
+/// * `cid` - The CID to convert
+/// * `version` - The version to convert to
+///
+/// # Returns
+/// * `Result<Cid>` - The converted CID, or Err if it can't be written in that version
+pub fn convert(cid: &Cid, version: Version) -> Result<Cid> {
+    match version {
+        Version::V0 if cid.codec() != DAG_PB_CODEC || cid.hash().code() != SHA2_256 => Err(anyhow!(
+            "{} can't be written as CIDv0, which only names dag-pb blocks hashed with sha2-256",
+            cid
+        )),
+        Version::V0 => Ok(Cid::new_v0(*cid.hash())?),
+        Version::V1 => Ok(Cid::new_v1(cid.codec(), *cid.hash())),
+    }
+}
+
+/// Write a CID in a multibase
+///
+/// CIDv0 can only be written in base58btc, so in other bases it is written as
+/// the CIDv1 of the same block.
+///
+/// # Arguments
+/// * `cid` - The CID to write
+/// * `base` - The multibase to write it in
+///
+/// # Returns
+/// * `Result<String>` - The CID as text
+pub fn to_string_of_base(cid: &Cid, base: Multibase) -> Result<String> {
+    match (cid.version(), base) {
+        (Version::V0, Multibase::Base58btc) => Ok(cid.to_string()),
+        _ => Ok(convert(cid, Version::V1)?.to_string_of_base(base.into())?),
+    }
+}
+
+/// Name of a multibase, as Kubo and the multibase table call it
+pub fn base_name(base: Base) -> String {
+    match base {
+        Base::Base58Btc => "base58btc".to_string(),
+        Base::Base32Lower => "base32".to_string(),
+        Base::Base36Lower => "base36".to_string(),
+        Base::Base16Lower => "base16".to_string(),
+        other => format!("{:?}", other).to_lowercase(),
+    }
+}
+
+/// Name of a multicodec, for the codecs IPFS commonly uses
+pub fn codec_name(code: u64) -> Option<&'static str> {
+    match code {
+        RAW_CODEC => Some("raw"),
+        DAG_PB_CODEC => Some("dag-pb"),
+        DAG_CBOR_CODEC => Some("dag-cbor"),
+        0x72 => Some("libp2p-key"),
+        DAG_JSON_CODEC => Some("dag-json"),
+        0x0200 => Some("json"),
+        _ => None,
+    }
+}
+
+/// Name of a multihash function, for the functions IPFS commonly uses
+pub fn hash_name(code: u64) -> Option<&'static str> {
+    match code {
+        0x00 => Some("identity"),
+        SHA2_256 => Some("sha2-256"),
+        0x13 => Some("sha2-512"),
+        0x16 => Some("sha3-256"),
+        0x1b => Some("keccak-256"),
+        0x1e => Some("blake3"),
+        0xb220 => Some("blake2b-256"),
+        _ => None,
+    }
+}
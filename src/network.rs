@@ -0,0 +1,1440 @@
+//! libp2p networking of a MIGA node
+//!
+//! The swarm is owned by an [`EventLoop`] that runs in its own task and
+//! processes network events. The rest of the application talks to the network
+//! through a cloneable [`Client`], which sends commands to the event loop and
+//! awaits their results.
+//!
+//! Blocks are exchanged over Bitswap with the providers found in the DHT. The
+//! Bitswap client opens its own streams, so it works alongside the event loop
+//! rather than through it.
+
+use anyhow::{anyhow, Result};
+use cid::Cid;
+use futures::{future, FutureExt, StreamExt};
+use libp2p::{
+    connection_limits,
+    core::{multiaddr::Protocol, transport::ListenerId},
+    identify, identity, kad,
+    metrics::{Metrics as Libp2pMetrics, Recorder},
+    ping,
+    pnet::PreSharedKey,
+    swarm::{behaviour::toggle::Toggle, dial_opts::DialOpts, ConnectionId, NetworkBehaviour, SwarmEvent},
+    upnp, Multiaddr, PeerId, Swarm,
+};
+use libp2p_stream as stream;
+use log::{debug, error, info, warn};
+use prometheus_client::registry::Registry;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info_span, Instrument};
+
+use crate::{
+    bandwidth::{Bandwidth, BandwidthStats},
+    bitswap::{self, Bitswap},
+    blocklist::{self, BlocklistEntry},
+    blockstore::{self, Blockstore},
+    error::MigaError,
+    fetch::{FetchEvent, RetryPolicy},
+    metrics::Metrics,
+    record_store::PersistentStore,
+    routing_table,
+    throttle::RateLimits,
+    transport,
+};
+
+/// Largest Kademlia message accepted
+///
+/// Blocks are also looked up as DHT records, as earlier MIGA versions
+/// published them, so a message has to fit a whole block.
+const MAX_PACKET_SIZE: usize = 8 * 1024 * 1024;
+
+/// Default timeout of DHT queries
+const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Protocol version presented over identify, the same as other IPFS nodes present
+pub const PROTOCOL_VERSION: &str = "ipfs/0.1.0";
+
+/// Default cap on the number of established connections
+pub const DEFAULT_MAX_CONNECTIONS: u32 = 512;
+
+/// Default cap on the number of established connections to the same peer
+pub const DEFAULT_MAX_CONNECTIONS_PER_PEER: u32 = 8;
+
+/// Default cap on the number of connections being set up at the same time, in each direction
+pub const DEFAULT_MAX_PENDING: u32 = 128;
+
+/// Time given to connections to close cleanly when the event loop stops
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Options for creating the network
+#[derive(Debug, Clone, Default)]
+pub struct NetworkConfig {
+    /// Map the listen port on the local router via UPnP
+    pub upnp: bool,
+    /// Print verbose information about the bootstrap process
+    pub verbose: bool,
+    /// Nodes to bootstrap the DHT from, each address ending in `/p2p/<peer ID>`
+    pub bootstrap: Vec<Multiaddr>,
+    /// Timeout of DHT queries (default: 60 seconds)
+    pub query_timeout: Option<Duration>,
+    /// Whether the node answers DHT queries of other nodes
+    pub dht_mode: DhtMode,
+    /// How blocks that couldn't be found are retried
+    pub retry: RetryPolicy,
+    /// Time between two pings on a connection (default: 15 seconds)
+    pub ping_interval: Option<Duration>,
+    /// Peers to get blocks from directly instead of looking up providers in the DHT
+    pub peers: Vec<PeerId>,
+    /// Addresses the node is reachable on from the outside, advertised to other peers
+    pub announce: Vec<Multiaddr>,
+    /// Caps on the number of connections
+    pub limits: ConnectionLimits,
+    /// Peers and IP ranges that may not connect
+    pub blocklist: Vec<BlocklistEntry>,
+    /// The only peers that are sent blocks over Bitswap, or None to send them to everyone
+    pub allowlist: Option<HashSet<PeerId>>,
+    /// Pre-shared key of a private network to join instead of the public one
+    pub swarm_key: Option<PreSharedKey>,
+    /// Limits on the rates blocks are sent and received at over Bitswap
+    pub rates: RateLimits,
+    /// Never reach out to other peers, so only local blocks are available
+    pub offline: bool,
+}
+
+/// Caps on the connections of a node, so a flood of peers can't exhaust its resources
+///
+/// Connections beyond a cap are refused, and dials beyond it fail right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// Maximum number of established connections, `None` for no cap
+    pub max_connections: Option<u32>,
+    /// Maximum number of established connections to the same peer, `None` for no cap
+    pub max_connections_per_peer: Option<u32>,
+    /// Maximum number of connections being set up at the same time in each direction, `None` for no cap
+    pub max_pending: Option<u32>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: Some(DEFAULT_MAX_CONNECTIONS),
+            max_connections_per_peer: Some(DEFAULT_MAX_CONNECTIONS_PER_PEER),
+            max_pending: Some(DEFAULT_MAX_PENDING),
+        }
+    }
+}
+
+impl ConnectionLimits {
+    /// The limits in the form of the connection limits behaviour
+    fn behaviour(self) -> connection_limits::Behaviour {
+        let limits = connection_limits::ConnectionLimits::default()
+            .with_max_established(self.max_connections)
+            .with_max_established_per_peer(self.max_connections_per_peer)
+            .with_max_pending_incoming(self.max_pending)
+            .with_max_pending_outgoing(self.max_pending);
+        connection_limits::Behaviour::new(limits)
+    }
+}
+
+/// Role of the node in the Kademlia DHT
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DhtMode {
+    /// Only query the DHT, without answering queries of other nodes
+    Client,
+    /// Answer queries of other nodes, so they add the node to their routing tables
+    Server,
+    /// Become a server once the node is known to be reachable from the outside
+    #[default]
+    Auto,
+}
+
+impl DhtMode {
+    /// The Kademlia mode to set, `None` to let Kademlia decide
+    fn kad_mode(self) -> Option<kad::Mode> {
+        match self {
+            DhtMode::Client => Some(kad::Mode::Client),
+            DhtMode::Server => Some(kad::Mode::Server),
+            DhtMode::Auto => None,
+        }
+    }
+}
+
+/// What the node presents to other peers about itself
+#[derive(Debug, Clone)]
+pub struct LocalInfo {
+    /// Addresses the node listens on
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Addresses the node is known to be reachable on from the outside, e.g. mapped via UPnP
+    pub external_addrs: Vec<Multiaddr>,
+    /// Protocols the node speaks
+    pub protocols: Vec<String>,
+}
+
+/// A connection to another peer
+#[derive(Debug, Clone)]
+pub struct PeerConnection {
+    /// The peer at the other end
+    pub peer_id: PeerId,
+    /// Remote address of the connection
+    pub address: Multiaddr,
+    /// Which side opened the connection
+    pub direction: Direction,
+    /// Software the peer runs, once it has identified itself
+    pub agent_version: Option<String>,
+}
+
+impl PeerConnection {
+    /// Transport the connection runs over, such as `tcp` or `quic-v1`
+    ///
+    /// This is the outermost protocol of the remote address, so a relayed
+    /// connection is reported as `p2p-circuit`.
+    pub fn transport(&self) -> &'static str {
+        self.address
+            .iter()
+            .filter_map(|protocol| match protocol {
+                Protocol::Ip4(_)
+                | Protocol::Ip6(_)
+                | Protocol::Dns(_)
+                | Protocol::Dns4(_)
+                | Protocol::Dns6(_)
+                | Protocol::Dnsaddr(_)
+                | Protocol::Certhash(_)
+                | Protocol::P2p(_) => None,
+                protocol => Some(protocol.tag()),
+            })
+            .last()
+            .unwrap_or("unknown")
+    }
+}
+
+/// Which side opened a connection
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// The peer connected to us
+    Inbound,
+    /// We connected to the peer
+    Outbound,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Inbound => write!(f, "inbound"),
+            Direction::Outbound => write!(f, "outbound"),
+        }
+    }
+}
+
+/// Network behaviour of a MIGA node
+///
+/// Combines all libp2p protocols used by the application into a single
+/// behaviour. Events from each protocol are surfaced as variants of the
+/// generated `MigaBehaviourEvent` enum.
+#[derive(NetworkBehaviour)]
+pub struct MigaBehaviour {
+    /// Kademlia DHT used to find and publish content
+    kademlia: kad::Behaviour<PersistentStore>,
+    /// UPnP port mapping on the local router, only enabled when sharing
+    upnp: Toggle<upnp::tokio::Behaviour>,
+    /// Raw protocol streams, used by Bitswap
+    stream: stream::Behaviour,
+    /// Exchange of addresses, protocols and agent versions with connected peers
+    identify: identify::Behaviour,
+    /// Round trip time measurements on every connection
+    ping: ping::Behaviour,
+    /// Caps on the number of connections
+    limits: connection_limits::Behaviour,
+    /// Refusal of blocked peers and IP ranges
+    blocklist: blocklist::Behaviour,
+}
+
+/// Create the network of a node
+///
+/// This function:
+/// 1. Sets up Kademlia with a disk-backed record store
+/// 2. Adds the bootstrap nodes and the peers saved in the previous run
+/// 3. Builds the libp2p swarm and starts Bitswap
+/// 4. Registers the network's metrics
+///
+/// # Arguments
+/// * `keypair` - The node's identity keypair
+/// * `repo_dir` - Repository directory holding the DHT records and routing table
+/// * `blockstore` - Blockstore Bitswap answers the wants of other nodes from
+/// * `config` - Network options
+/// * `registry` - Registry to add the metrics of the network to
+///
+/// # Returns
+/// * `Result<(Client, EventLoop)>` - A client to control the network, and the event loop to spawn
+pub fn new(
+    keypair: identity::Keypair,
+    repo_dir: &Path,
+    blockstore: Blockstore,
+    config: NetworkConfig,
+    registry: &mut Registry,
+) -> Result<(Client, EventLoop)> {
+    let public_key = keypair.public();
+    let peer_id = public_key.to_peer_id();
+    let metrics = Metrics::new(registry);
+    let libp2p_metrics = Libp2pMetrics::new(registry);
+
+    // Configure the Kademlia DHT behavior
+    // This is used for finding peers and content in the network
+    let mut kad_config = kad::Config::default();
+    kad_config.set_query_timeout(config.query_timeout.unwrap_or(DEFAULT_QUERY_TIMEOUT)); // Give up on queries after the timeout
+    kad_config.set_max_packet_size(MAX_PACKET_SIZE); // Records published by earlier versions carry whole blocks
+    kad_config.set_provider_publication_interval(None); // Provider records are renewed by the daemon's reprovider
+    let store = PersistentStore::open(&repo_dir.join("datastore"), peer_id)?; // Disk-backed store for DHT records
+    let mut kademlia = kad::Behaviour::with_config(peer_id, store, kad_config);
+    // Clients don't advertise the Kademlia protocol, so other nodes don't waste queries on them
+    kademlia.set_mode(config.dht_mode.kad_mode());
+
+    // Add the bootstrap nodes to connect to the network
+    add_bootstrap_nodes(&mut kademlia, &config.bootstrap, config.verbose);
+
+    // Restore the peers we knew about in the previous run
+    // A warm routing table finds providers much faster than bootstrapping from scratch
+    let routing_table_path = repo_dir.join("peers.json");
+    match routing_table::load(&routing_table_path, &mut kademlia) {
+        Ok(count) if count > 0 => info!("Restored {} peers from the previous run", count),
+        Ok(_) => {}
+        Err(e) => warn!("Failed to restore routing table: {}", e),
+    }
+
+    // Ask the local router to forward our listen port when sharing, so that
+    // nodes behind consumer routers are reachable without manual port forwarding
+    let upnp = Toggle::from(config.upnp.then(upnp::tokio::Behaviour::default));
+
+    // Bitswap runs on raw streams opened through the stream behaviour,
+    // and answers the wants of other nodes from the blockstore
+    let stream = stream::Behaviour::new();
+    let bitswap = Bitswap::new(
+        stream.new_control(),
+        blockstore,
+        metrics.clone(),
+        config.allowlist.clone(),
+        config.rates.clone(),
+    )?;
+
+    // Measure the round trip time of every connection, which also tells dead connections apart
+    let mut ping_config = ping::Config::new();
+    if let Some(interval) = config.ping_interval {
+        ping_config = ping_config.with_interval(interval);
+    }
+
+    // The Kademlia, UPnP, stream, identify and ping behaviours of the swarm
+    let behaviour = MigaBehaviour {
+        kademlia,
+        upnp,
+        stream,
+        identify: identify::Behaviour::new(
+            identify::Config::new(PROTOCOL_VERSION.to_string(), public_key.clone()).with_agent_version(agent_version()),
+        ),
+        ping: ping::Behaviour::new(ping_config),
+        limits: config.limits.behaviour(),
+        blocklist: blocklist::Behaviour::new(config.blocklist),
+    };
+
+    // Create a libp2p Swarm, which manages connections and protocol negotiations
+    let bandwidth = Bandwidth::new();
+    let transport = transport::build(&keypair, config.swarm_key, bandwidth.clone())?;
+    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()                                      // Use Tokio as the async runtime
+        .with_other_transport(|_| transport)?              // QUIC, and TCP secured with TLS or Noise, see `transport`
+        .with_dns()?                                       // Resolve /dns and /dnsaddr addresses, such as the bootstrap nodes
+        .with_bandwidth_metrics(registry)                  // Count the bytes sent and received per transport
+        .with_behaviour(|_| behaviour)?
+        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60))) // Set connection timeout
+        .build();
+
+    // Addresses the user knows the node is reachable on, e.g. through a port forwarding,
+    // are advertised over identify and in provider records like those found via UPnP
+    for mut addr in config.announce {
+        if let Some(Protocol::P2p(_)) = addr.iter().last() {
+            addr.pop();
+        }
+        info!("Announcing external address {}", addr);
+        swarm.add_external_address(addr);
+    }
+
+    let (command_sender, command_receiver) = mpsc::channel(32);
+    let client = Client {
+        sender: command_sender,
+        peer_id,
+        public_key,
+        bitswap,
+        metrics: metrics.clone(),
+        retry: config.retry,
+        direct_peers: config.peers,
+        bandwidth,
+        offline: config.offline,
+    };
+    let event_loop = EventLoop {
+        swarm,
+        metrics,
+        libp2p_metrics,
+        command_receiver,
+        routing_table_path,
+        bootstrap_started: false,
+        verbose: config.verbose,
+        pending_listen: HashMap::new(),
+        pending_get_block: HashMap::new(),
+        pending_find_providers: HashMap::new(),
+        connections: HashMap::new(),
+        peer_info: HashMap::new(),
+        pending_pings: HashMap::new(),
+        pending_dials: HashMap::new(),
+        pending_find_peer: HashMap::new(),
+        pending_provide: HashMap::new(),
+    };
+    Ok((client, event_loop))
+}
+
+/// Handle for sending commands to the network event loop
+#[derive(Clone)]
+pub struct Client {
+    /// Channel to the event loop
+    sender: mpsc::Sender<Command>,
+    /// Peer ID of the local node
+    peer_id: PeerId,
+    /// Public key of the local node
+    public_key: identity::PublicKey,
+    /// Bitswap client for exchanging blocks with other nodes
+    bitswap: Bitswap,
+    /// Metrics of the node
+    metrics: Metrics,
+    /// How blocks that couldn't be found are retried
+    retry: RetryPolicy,
+    /// Peers to get blocks from instead of the providers found in the DHT
+    direct_peers: Vec<PeerId>,
+    /// Bytes sent and received, in total, per protocol and per peer
+    bandwidth: Bandwidth,
+    /// Whether requests that reach out to other peers are refused
+    offline: bool,
+}
+
+impl Client {
+    /// Peer ID of the local node
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    /// Public key of the local node
+    pub fn public_key(&self) -> &identity::PublicKey {
+        &self.public_key
+    }
+
+    /// Addresses and protocols the node presents to other peers
+    pub async fn local_info(&self) -> Result<LocalInfo> {
+        self.request(|sender| Command::LocalInfo { sender }).await
+    }
+
+    /// Start listening on an address
+    ///
+    /// # Returns
+    /// * `Result<Multiaddr>` - The first concrete address the node is listening on
+    pub async fn listen_on(&self, addr: Multiaddr) -> Result<Multiaddr> {
+        self.request(|sender| Command::ListenOn { addr, sender }).await?
+    }
+
+    /// Addresses other nodes can use to reach this node
+    ///
+    /// External addresses (e.g. mapped via UPnP) come first, followed by local listen addresses.
+    pub async fn addresses(&self) -> Result<Vec<Multiaddr>> {
+        self.request(|sender| Command::Addresses { sender }).await
+    }
+
+    /// Open connections to other peers, one entry per connection
+    pub async fn connected_peers(&self) -> Result<Vec<PeerConnection>> {
+        self.request(|sender| Command::ConnectedPeers { sender }).await
+    }
+
+    /// Connect to a peer at an address
+    ///
+    /// # Arguments
+    /// * `addr` - Address of the peer, ending in `/p2p/<peer ID>`
+    ///
+    /// # Returns
+    /// * `Result<PeerId>` - The peer, once the connection is established
+    pub async fn dial(&self, addr: Multiaddr) -> Result<PeerId> {
+        self.ensure_online()?;
+        self.request(|sender| Command::Dial { addr, sender }).await?
+    }
+
+    /// Look up the addresses of a peer in the DHT
+    ///
+    /// Runs a Kademlia FIND_NODE query for the peer, asking the peers closest
+    /// to it for its addresses.
+    ///
+    /// # Returns
+    /// * `Result<Vec<Multiaddr>>` - The addresses of the peer, Err if it couldn't be found
+    pub async fn find_peer(&self, peer: PeerId) -> Result<Vec<Multiaddr>> {
+        self.ensure_online()?;
+        self.request(|sender| Command::FindPeer { peer, sender }).await?
+    }
+
+    /// Look up the providers of a block in the DHT, without fetching it
+    ///
+    /// # Returns
+    /// * `Result<mpsc::UnboundedReceiver<PeerId>>` - The providers as they are found; closed when the
+    ///   lookup is done. Dropping the receiver stops the lookup.
+    pub async fn find_providers(&self, cid: Cid) -> Result<mpsc::UnboundedReceiver<PeerId>> {
+        self.ensure_online()?;
+        let (sender, providers) = mpsc::unbounded_channel();
+        self.send(Command::FindProviders { cid, sender }).await?;
+        Ok(providers)
+    }
+
+    /// Addresses the node knows for a peer, from its connections, identify and the routing table
+    pub async fn known_addresses(&self, peer: PeerId) -> Result<Vec<Multiaddr>> {
+        self.request(|sender| Command::KnownAddresses { peer, sender }).await
+    }
+
+    /// Ping a peer, connecting to it first if needed
+    ///
+    /// The peer is pinged at the ping interval of the network for as long as
+    /// the returned receiver is kept. Each round trip time, or the reason a
+    /// ping failed, is sent to the receiver. The receiver is closed when the
+    /// connection to the peer fails.
+    ///
+    /// # Arguments
+    /// * `peer` - Peer to ping
+    /// * `addrs` - Addresses to reach the peer on, in addition to those already known
+    pub async fn ping(&self, peer: PeerId, addrs: Vec<Multiaddr>) -> Result<mpsc::UnboundedReceiver<Result<Duration>>> {
+        self.ensure_online()?;
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.request(|connected| Command::Ping {
+            peer,
+            addrs,
+            sender,
+            connected,
+        })
+        .await??;
+        Ok(receiver)
+    }
+
+    /// Get a block from the network
+    ///
+    /// The providers of the block are looked up in the DHT and raced over
+    /// Bitswap. Blocks published as DHT records by older MIGA nodes are looked
+    /// up at the same time, and whichever arrives first is returned. The block
+    /// is verified against its CID before it is returned.
+    ///
+    /// When the network was created with direct peers, only they are asked for
+    /// the block and the DHT isn't used at all.
+    ///
+    /// # Arguments
+    /// * `cid` - CID of the block
+    /// * `events` - Where to report the providers found for the block, if anywhere
+    pub async fn get_block(&self, cid: Cid, events: Option<&mpsc::UnboundedSender<FetchEvent>>) -> Result<Vec<u8>> {
+        if self.offline {
+            return Err(MigaError::NotStoredLocally(cid).into());
+        }
+        let (sender, providers) = mpsc::unbounded_channel();
+        let data = if self.direct_peers.is_empty() {
+            self.send(Command::FindProviders { cid, sender }).await?;
+
+            let from_providers = self.bitswap.get_block(cid, providers, events).instrument(info_span!("bitswap"));
+            let from_record = async { self.request(|sender| Command::GetBlock { cid, sender }).await? }.instrument(info_span!("dht_record"));
+            future::select_ok([from_providers.boxed(), from_record.boxed()]).await?.0
+        } else {
+            // The channel closes right away, so Bitswap gives up once all direct peers have been asked
+            for peer in &self.direct_peers {
+                let _ = sender.send(*peer);
+            }
+            drop(sender);
+            self.bitswap.get_block(cid, providers, events).instrument(info_span!("bitswap")).await?
+        };
+        self.metrics.blocks_fetched.inc();
+        self.metrics.block_bytes_fetched.inc_by(data.len() as u64);
+        Ok(data)
+    }
+
+    /// Metrics of the node
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// How blocks that couldn't be found are retried
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+    }
+
+    /// Whether the node never reaches out to other peers
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Refuse a request that would reach out to other peers if the node is offline
+    fn ensure_online(&self) -> Result<()> {
+        if self.offline {
+            return Err(MigaError::Network("the node is offline".to_string()).into());
+        }
+        Ok(())
+    }
+
+    /// Bytes sent and received since the node started, in total, per protocol and per peer
+    pub fn bandwidth(&self) -> BandwidthStats {
+        self.bandwidth.stats()
+    }
+
+    /// Number of providers that have sent us blocks over Bitswap
+    pub fn provider_count(&self) -> usize {
+        self.bitswap.provider_count()
+    }
+
+    /// Announce the node as a provider of a block in the DHT, so other nodes can find it
+    ///
+    /// The provider record is stored locally right away, so the node answers
+    /// provider lookups of the block itself. Returns once the record has been
+    /// sent to the closest peers of the block that could be found, which takes
+    /// as long as a DHT query; fails if no peer could be reached.
+    pub async fn provide(&self, cid: Cid) -> Result<()> {
+        self.ensure_online()?;
+        self.request(|sender| Command::Provide { cid, sender }).await?
+    }
+
+    /// Stop announcing the node as a provider of a block
+    ///
+    /// The local provider record is removed, so the node neither answers
+    /// provider lookups for the block nor announces it again. Records already
+    /// stored by other nodes expire on their own.
+    pub async fn stop_providing(&self, cid: Cid) -> Result<()> {
+        self.request(|sender| Command::StopProviding { cid, sender }).await
+    }
+
+    /// Block a peer or range of IP addresses
+    ///
+    /// Open connections covered by the entry are closed, and new ones are refused.
+    ///
+    /// # Returns
+    /// * `Result<bool>` - Whether the entry was added, false if it was already blocked
+    pub async fn block(&self, entry: BlocklistEntry) -> Result<bool> {
+        self.request(|sender| Command::Block { entry, sender }).await
+    }
+
+    /// Remove a peer or range of IP addresses from the blocklist
+    ///
+    /// # Returns
+    /// * `Result<bool>` - Whether the entry was removed, false if it wasn't blocked
+    pub async fn unblock(&self, entry: BlocklistEntry) -> Result<bool> {
+        self.request(|sender| Command::Unblock { entry, sender }).await
+    }
+
+    /// The blocked peers and IP ranges
+    pub async fn blocklist(&self) -> Result<Vec<BlocklistEntry>> {
+        self.request(|sender| Command::Blocklist { sender }).await
+    }
+
+    /// Stop the event loop, even while other clients still exist
+    ///
+    /// Returns once the routing table has been saved and the connections to
+    /// other peers have been closed.
+    pub async fn shutdown(&self) -> Result<()> {
+        self.request(|sender| Command::Shutdown { sender }).await
+    }
+
+    /// Send a command to the event loop and wait for its reply
+    async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> Command) -> Result<T> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(command(sender)).await?;
+        receiver
+            .await
+            .map_err(|_| anyhow!("The network event loop dropped the request"))
+    }
+
+    /// Send a command to the event loop
+    async fn send(&self, command: Command) -> Result<()> {
+        self.sender
+            .send(command)
+            .await
+            .map_err(|_| anyhow!("The network event loop has stopped"))
+    }
+}
+
+/// Commands sent from a [`Client`] to the [`EventLoop`]
+enum Command {
+    ListenOn {
+        addr: Multiaddr,
+        sender: oneshot::Sender<Result<Multiaddr>>,
+    },
+    Addresses {
+        sender: oneshot::Sender<Vec<Multiaddr>>,
+    },
+    ConnectedPeers {
+        sender: oneshot::Sender<Vec<PeerConnection>>,
+    },
+    LocalInfo {
+        sender: oneshot::Sender<LocalInfo>,
+    },
+    GetBlock {
+        cid: Cid,
+        sender: oneshot::Sender<Result<Vec<u8>>>,
+    },
+    FindProviders {
+        cid: Cid,
+        sender: mpsc::UnboundedSender<PeerId>,
+    },
+    Provide {
+        cid: Cid,
+        sender: oneshot::Sender<Result<()>>,
+    },
+    StopProviding {
+        cid: Cid,
+        sender: oneshot::Sender<()>,
+    },
+    Dial {
+        addr: Multiaddr,
+        sender: oneshot::Sender<Result<PeerId>>,
+    },
+    FindPeer {
+        peer: PeerId,
+        sender: oneshot::Sender<Result<Vec<Multiaddr>>>,
+    },
+    KnownAddresses {
+        peer: PeerId,
+        sender: oneshot::Sender<Vec<Multiaddr>>,
+    },
+    Ping {
+        peer: PeerId,
+        addrs: Vec<Multiaddr>,
+        sender: mpsc::UnboundedSender<Result<Duration>>,
+        connected: oneshot::Sender<Result<()>>,
+    },
+    Block {
+        entry: BlocklistEntry,
+        sender: oneshot::Sender<bool>,
+    },
+    Unblock {
+        entry: BlocklistEntry,
+        sender: oneshot::Sender<bool>,
+    },
+    Blocklist {
+        sender: oneshot::Sender<Vec<BlocklistEntry>>,
+    },
+    Shutdown {
+        sender: oneshot::Sender<()>,
+    },
+}
+
+/// A block request waiting for a Kademlia GET query to complete
+struct PendingGetBlock {
+    /// CID of the requested block
+    cid: Cid,
+    /// Channel for the result
+    sender: oneshot::Sender<Result<Vec<u8>>>,
+}
+
+/// Listen requests waiting for their first address, by listener
+type PendingListen = HashMap<ListenerId, oneshot::Sender<Result<Multiaddr>>>;
+
+/// Peer lookups, with the peer looked for and the channel for its addresses, by query
+type PendingFindPeer = HashMap<kad::QueryId, (PeerId, oneshot::Sender<Result<Vec<Multiaddr>>>)>;
+
+/// Announcements of blocks, with the block announced and the channel for the outcome, by query
+type PendingProvide = HashMap<kad::QueryId, (Cid, oneshot::Sender<Result<()>>)>;
+
+/// The network event loop, owning the swarm
+pub struct EventLoop {
+    /// The libp2p swarm
+    swarm: Swarm<MigaBehaviour>,
+    /// Metrics of the node
+    metrics: Metrics,
+    /// Metrics of the libp2p protocols
+    libp2p_metrics: Libp2pMetrics,
+    /// Commands from clients
+    command_receiver: mpsc::Receiver<Command>,
+    /// File the routing table is saved to
+    routing_table_path: PathBuf,
+    /// Whether the Kademlia bootstrap has been started
+    bootstrap_started: bool,
+    /// Print verbose information
+    verbose: bool,
+    /// Listen requests waiting for their first address
+    pending_listen: PendingListen,
+    /// Block requests waiting for their Kademlia query
+    pending_get_block: HashMap<kad::QueryId, PendingGetBlock>,
+    /// Provider lookups, with the channel the providers are streamed to
+    pending_find_providers: HashMap<kad::QueryId, mpsc::UnboundedSender<PeerId>>,
+    /// Open connections to other peers
+    connections: HashMap<ConnectionId, PeerConnection>,
+    /// What connected peers told us about themselves over identify
+    peer_info: HashMap<PeerId, identify::Info>,
+    /// Peers being pinged, with the channel the round trip times are sent to
+    pending_pings: HashMap<PeerId, mpsc::UnboundedSender<Result<Duration>>>,
+    /// Connections being opened on request, waiting to be established
+    pending_dials: HashMap<ConnectionId, oneshot::Sender<Result<PeerId>>>,
+    /// Peer lookups, with the peer looked for and the channel for its addresses
+    pending_find_peer: PendingFindPeer,
+    /// Announcements waiting for their Kademlia query
+    pending_provide: PendingProvide,
+}
+
+impl EventLoop {
+    /// Run the event loop until all clients have been dropped or it is shut down
+    ///
+    /// The routing table is saved when the loop stops, so the next run can
+    /// start from the peers discovered in this one, and the connections to
+    /// other peers are closed.
+    pub async fn run(mut self) {
+        let shutdown = loop {
+            tokio::select! {
+                event = self.swarm.select_next_some() => self.handle_event(event),
+                command = self.command_receiver.recv() => match command {
+                    Some(Command::Shutdown { sender }) => break Some(sender),
+                    Some(command) => self.handle_command(command),
+                    // All clients are gone, so nothing can use the network anymore
+                    None => break None,
+                },
+            }
+        };
+
+        self.save_routing_table();
+        self.close_connections().await;
+        if let Some(sender) = shutdown {
+            let _ = sender.send(());
+        }
+    }
+
+    /// Close all connections, giving them a moment to shut down cleanly
+    async fn close_connections(&mut self) {
+        let peers: Vec<PeerId> = self.swarm.connected_peers().copied().collect();
+        for peer in peers {
+            let _ = self.swarm.disconnect_peer_id(peer);
+        }
+
+        let closed = tokio::time::timeout(CLOSE_TIMEOUT, async {
+            while self.swarm.network_info().num_peers() > 0 {
+                self.swarm.select_next_some().await;
+            }
+        })
+        .await;
+        if closed.is_err() {
+            debug!("Gave up waiting for connections to close");
+        }
+    }
+
+    /// Handle a command from a client
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::ListenOn { addr, sender } => match self.swarm.listen_on(addr) {
+                Ok(listener_id) => {
+                    self.pending_listen.insert(listener_id, sender);
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(e.into()));
+                }
+            },
+            Command::Addresses { sender } => {
+                let addresses = self
+                    .swarm
+                    .external_addresses()
+                    .chain(self.swarm.listeners())
+                    .cloned()
+                    .collect();
+                let _ = sender.send(addresses);
+            }
+            Command::LocalInfo { sender } => {
+                // Only DHT servers answer Kademlia requests
+                let dht_server = self.swarm.behaviour().kademlia.mode() == kad::Mode::Server;
+                let info = LocalInfo {
+                    listen_addrs: self.swarm.listeners().cloned().collect(),
+                    external_addrs: self.swarm.external_addresses().cloned().collect(),
+                    protocols: supported_protocols(dht_server),
+                };
+                let _ = sender.send(info);
+            }
+            Command::ConnectedPeers { sender } => {
+                // Peers tell their agent version over identify, which is kept per peer
+                let peers = self
+                    .connections
+                    .values()
+                    .map(|connection| PeerConnection {
+                        agent_version: self.peer_info.get(&connection.peer_id).map(|info| info.agent_version.clone()),
+                        ..connection.clone()
+                    })
+                    .collect();
+                let _ = sender.send(peers);
+            }
+            Command::GetBlock { cid, sender } => {
+                // Convert the CID's multihash to a Kademlia record key
+                // This is what we'll search for in the DHT
+                let key = kad::RecordKey::from(cid.hash().to_bytes());
+                debug!("Searching for block {}", cid);
+                let query_id = self.swarm.behaviour_mut().kademlia.get_record(key);
+                self.metrics.dht_queries.inc();
+                self.pending_get_block.insert(query_id, PendingGetBlock { cid, sender });
+            }
+            Command::FindProviders { cid, sender } => {
+                // Providers announce themselves under the multihash of the block
+                let key = kad::RecordKey::from(cid.hash().to_bytes());
+                debug!("Searching for providers of {}", cid);
+                let query_id = self.swarm.behaviour_mut().kademlia.get_providers(key);
+                self.metrics.dht_queries.inc();
+                self.pending_find_providers.insert(query_id, sender);
+            }
+            Command::Provide { cid, sender } => {
+                // Announce ourselves under the multihash of the block, like other IPFS nodes do
+                let key = kad::RecordKey::from(cid.hash().to_bytes());
+                debug!("Providing block {}", cid);
+                match self.swarm.behaviour_mut().kademlia.start_providing(key) {
+                    Ok(query_id) => {
+                        self.metrics.dht_queries.inc();
+                        self.pending_provide.insert(query_id, (cid, sender));
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(anyhow!("Failed to provide block {}: {:?}", cid, e)));
+                    }
+                }
+            }
+            Command::StopProviding { cid, sender } => {
+                let key = kad::RecordKey::from(cid.hash().to_bytes());
+                debug!("No longer providing block {}", cid);
+                self.swarm.behaviour_mut().kademlia.stop_providing(&key);
+                let _ = sender.send(());
+            }
+            Command::Dial { addr, sender } => {
+                let Some(peer) = extract_peer_id_from_multiaddr(&addr) else {
+                    let _ = sender.send(Err(anyhow!("Address {} has no /p2p/<peer ID>", addr)));
+                    return;
+                };
+                if self.swarm.is_connected(&peer) {
+                    let _ = sender.send(Ok(peer));
+                    return;
+                }
+                let opts = DialOpts::peer_id(peer).addresses(vec![addr]).build();
+                let connection_id = opts.connection_id();
+                match self.swarm.dial(opts) {
+                    Ok(()) => {
+                        self.pending_dials.insert(connection_id, sender);
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(anyhow!("Failed to connect to {}: {}", peer, e)));
+                    }
+                }
+            }
+            Command::FindPeer { peer, sender } => {
+                debug!("Searching for peer {}", peer);
+                let query_id = self.swarm.behaviour_mut().kademlia.get_closest_peers(peer);
+                self.metrics.dht_queries.inc();
+                self.pending_find_peer.insert(query_id, (peer, sender));
+            }
+            Command::KnownAddresses { peer, sender } => {
+                let _ = sender.send(self.known_addresses(&peer));
+            }
+            Command::Ping {
+                peer,
+                addrs,
+                sender,
+                connected,
+            } => {
+                // Pings start as soon as there is a connection, so an existing one is reused
+                if !self.swarm.is_connected(&peer) {
+                    let opts = DialOpts::peer_id(peer).addresses(addrs).extend_addresses_through_behaviour().build();
+                    if let Err(e) = self.swarm.dial(opts) {
+                        let _ = connected.send(Err(anyhow!("Failed to connect to {}: {}", peer, e)));
+                        return;
+                    }
+                }
+                self.pending_pings.insert(peer, sender);
+                let _ = connected.send(Ok(()));
+            }
+            Command::Block { entry, sender } => {
+                let added = self.swarm.behaviour_mut().blocklist.block(entry);
+
+                // The blocklist is only checked for new connections, so close the open ones it covers
+                let blocklist = &self.swarm.behaviour().blocklist;
+                let blocked: Vec<ConnectionId> = self
+                    .connections
+                    .iter()
+                    .filter(|(_, connection)| blocklist.is_blocked(Some(&connection.peer_id), &connection.address))
+                    .map(|(id, _)| *id)
+                    .collect();
+                for id in blocked {
+                    self.swarm.close_connection(id);
+                }
+                info!("Blocked {}", entry);
+                let _ = sender.send(added);
+            }
+            Command::Unblock { entry, sender } => {
+                let removed = self.swarm.behaviour_mut().blocklist.unblock(&entry);
+                if removed {
+                    info!("Unblocked {}", entry);
+                }
+                let _ = sender.send(removed);
+            }
+            Command::Blocklist { sender } => {
+                let _ = sender.send(self.swarm.behaviour().blocklist.entries().to_vec());
+            }
+            // Stopping the loop is up to `run`
+            Command::Shutdown { .. } => unreachable!("shutdown commands are handled by the event loop"),
+        }
+    }
+
+    /// Handle an event from the swarm
+    fn handle_event(&mut self, event: SwarmEvent<MigaBehaviourEvent>) {
+        self.libp2p_metrics.record(&event);
+        match &event {
+            SwarmEvent::Behaviour(MigaBehaviourEvent::Kademlia(event)) => self.libp2p_metrics.record(event),
+            SwarmEvent::Behaviour(MigaBehaviourEvent::Identify(event)) => self.libp2p_metrics.record(event),
+            SwarmEvent::Behaviour(MigaBehaviourEvent::Ping(event)) => self.libp2p_metrics.record(event),
+            _ => {}
+        }
+
+        match event {
+            // When we get a new listening address
+            SwarmEvent::NewListenAddr { listener_id, address } => {
+                info!("Listening on {address}");
+                if let Some(sender) = self.pending_listen.remove(&listener_id) {
+                    let _ = sender.send(Ok(address));
+                }
+
+                // Bootstrap the Kademlia DHT if we haven't already done so
+                // This connects us to the wider IPFS network
+                if !self.bootstrap_started {
+                    info!("Bootstrapping Kademlia DHT...");
+                    if let Err(e) = self.swarm.behaviour_mut().kademlia.bootstrap() {
+                        error!("Failed to bootstrap Kademlia: {}", e);
+                    }
+                    self.bootstrap_started = true;
+                }
+            }
+            // When a listener fails before reporting any address
+            SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                if let Some(sender) = self.pending_listen.remove(&listener_id) {
+                    let error = match reason {
+                        Ok(()) => anyhow!("Listener closed"),
+                        Err(e) => e.into(),
+                    };
+                    let _ = sender.send(Err(error));
+                }
+            }
+            // When a connection to a peer is opened or closed
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                ..
+            } => {
+                debug!("Connected to {peer_id}");
+                let direction = if endpoint.is_dialer() { Direction::Outbound } else { Direction::Inbound };
+                let connection = PeerConnection {
+                    peer_id,
+                    address: endpoint.get_remote_address().clone(),
+                    direction,
+                    agent_version: None,
+                };
+                self.connections.insert(connection_id, connection);
+                if let Some(sender) = self.pending_dials.remove(&connection_id) {
+                    let _ = sender.send(Ok(peer_id));
+                }
+                self.metrics.connected_peers.set(self.swarm.connected_peers().count() as i64);
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id,
+                num_established,
+                ..
+            } => {
+                self.connections.remove(&connection_id);
+                if num_established == 0 {
+                    self.peer_info.remove(&peer_id);
+                    if let Some(sender) = self.pending_pings.remove(&peer_id) {
+                        let _ = sender.send(Err(anyhow!("Connection to {} closed", peer_id)));
+                    }
+                }
+                self.metrics.connected_peers.set(self.swarm.connected_peers().count() as i64);
+            }
+            // When a connection to a peer couldn't be opened
+            SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                peer_id,
+                error,
+            } => {
+                let peer = peer_id.map(|peer| peer.to_string()).unwrap_or_else(|| "peer".to_string());
+                debug!("Failed to connect to {}: {}", peer, error);
+                if let Some(sender) = self.pending_dials.remove(&connection_id) {
+                    let _ = sender.send(Err(anyhow!("Failed to connect to {}: {}", peer, error)));
+                }
+                // Without a connection, there is nothing to ping
+                if let Some(peer_id) = peer_id.filter(|peer_id| !self.swarm.is_connected(peer_id))
+                    && let Some(sender) = self.pending_pings.remove(&peer_id)
+                {
+                    let _ = sender.send(Err(anyhow!("Failed to connect to {}: {}", peer, error)));
+                }
+            }
+            // When a ping of a peer has come back or failed
+            SwarmEvent::Behaviour(MigaBehaviourEvent::Ping(ping::Event { peer, result, .. })) => {
+                if let Some(sender) = self.pending_pings.get(&peer) {
+                    // Nobody is waiting for the results anymore
+                    if sender.send(result.map_err(|e| anyhow!("Ping failed: {}", e))).is_err() {
+                        self.pending_pings.remove(&peer);
+                    }
+                }
+            }
+            // When a Kademlia query makes progress
+            SwarmEvent::Behaviour(MigaBehaviourEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                id,
+                result,
+                stats,
+                step,
+            })) => self.handle_query_result(id, result, stats, step),
+            // When another node sends us a DHT request
+            // Kademlia answers it from the record store by itself; this is for visibility
+            SwarmEvent::Behaviour(MigaBehaviourEvent::Kademlia(kad::Event::InboundRequest { request })) => {
+                self.handle_inbound_request(request)
+            }
+            // When a peer tells us its addresses and protocols
+            SwarmEvent::Behaviour(MigaBehaviourEvent::Identify(identify::Event::Received { peer_id, info, .. })) => {
+                debug!("Identified {} as {} with {} addresses", peer_id, info.agent_version, info.listen_addrs.len());
+                // Peers speaking Kademlia can be asked for peers and providers, at the addresses they listen on
+                if info.protocols.contains(&kad::PROTOCOL_NAME) {
+                    for addr in &info.listen_addrs {
+                        self.swarm.behaviour_mut().kademlia.add_address(&peer_id, addr.clone());
+                    }
+                }
+                self.peer_info.insert(peer_id, info);
+            }
+            // When the router has mapped our listen port via UPnP
+            SwarmEvent::Behaviour(MigaBehaviourEvent::Upnp(upnp::Event::NewExternalAddr(addr))) => {
+                info!("UPnP port mapping created, external address: {addr}");
+            }
+            // When a previously mapped port has expired
+            SwarmEvent::Behaviour(MigaBehaviourEvent::Upnp(upnp::Event::ExpiredExternalAddr(addr))) => {
+                warn!("UPnP port mapping expired for {addr}");
+            }
+            // When no UPnP-capable router could be found on the local network
+            SwarmEvent::Behaviour(MigaBehaviourEvent::Upnp(upnp::Event::GatewayNotFound)) => {
+                warn!("No UPnP gateway found, manual port forwarding may be required");
+            }
+            // When the router itself sits behind another NAT
+            SwarmEvent::Behaviour(MigaBehaviourEvent::Upnp(upnp::Event::NonRoutableGateway)) => {
+                warn!("UPnP gateway is not exposed directly to the public network");
+            }
+            // Handle any other events
+            e => {
+                if self.verbose {
+                    debug!("Other event: {:?}", e);
+                }
+            }
+        }
+    }
+
+    /// Handle the progress of a Kademlia query
+    fn handle_query_result(
+        &mut self,
+        id: kad::QueryId,
+        result: kad::QueryResult,
+        stats: kad::QueryStats,
+        step: kad::ProgressStep,
+    ) {
+        match result {
+            // When we successfully get a record from the network
+            kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(peer_record))) => {
+                let Some(pending) = self.pending_get_block.get(&id) else {
+                    return;
+                };
+                debug!("Received a record for {} from {:?}", pending.cid, peer_record.peer);
+
+                // Only accept the record if its content actually hashes to the requested CID
+                let data = peer_record.record.value;
+                if let Err(e) = blockstore::verify(&pending.cid, &data) {
+                    warn!("Discarding record that doesn't match the CID: {}", e);
+                    return;
+                }
+
+                // We have what we need, so stop asking other peers
+                if let Some(mut query) = self.swarm.behaviour_mut().kademlia.query_mut(&id) {
+                    query.finish();
+                }
+                self.metrics.dht_queries_succeeded.inc();
+                if let Some(pending) = self.pending_get_block.remove(&id) {
+                    let _ = pending.sender.send(Ok(data));
+                }
+            }
+            // When the query finished without a valid record
+            kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FinishedWithNoAdditionalRecord { .. })) => {
+                if let Some(pending) = self.pending_get_block.remove(&id) {
+                    let _ = pending.sender.send(Err(anyhow!("No valid record found for {}", pending.cid)));
+                }
+            }
+            // When we fail to get a record
+            kad::QueryResult::GetRecord(Err(err)) => {
+                if let Some(pending) = self.pending_get_block.remove(&id) {
+                    let _ = pending.sender.send(Err(anyhow!("Failed to get record: {:?}", err)));
+                }
+            }
+            // When we find providers of a block
+            kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { providers, .. })) => {
+                // Count the lookup as successful once, on its first providers
+                if step.count.get() == 1 {
+                    self.metrics.dht_queries_succeeded.inc();
+                }
+                let Some(sender) = self.pending_find_providers.get(&id) else {
+                    return;
+                };
+                let local_peer_id = *self.swarm.local_peer_id();
+                let delivered = providers
+                    .into_iter()
+                    .filter(|peer| *peer != local_peer_id)
+                    .all(|peer| sender.send(peer).is_ok());
+
+                // The block has already arrived, so stop looking for more providers
+                if !delivered {
+                    self.pending_find_providers.remove(&id);
+                    if let Some(mut query) = self.swarm.behaviour_mut().kademlia.query_mut(&id) {
+                        query.finish();
+                    }
+                }
+            }
+            // When the provider lookup is done, closing the channel tells the
+            // Bitswap client that no more providers will come
+            kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. })) => {
+                self.pending_find_providers.remove(&id);
+            }
+            kad::QueryResult::GetProviders(Err(err)) => {
+                debug!("Provider lookup failed: {:?}", err);
+                self.pending_find_providers.remove(&id);
+            }
+            // When a peer lookup is done, the peer is among the closest peers if it was found
+            kad::QueryResult::GetClosestPeers(result) => {
+                let Some((peer, sender)) = self.pending_find_peer.remove(&id) else {
+                    return;
+                };
+                let peers = match result {
+                    Ok(ok) => ok.peers,
+                    Err(kad::GetClosestPeersError::Timeout { peers, .. }) => peers,
+                };
+                let addrs = peers
+                    .into_iter()
+                    .find(|info| info.peer_id == peer)
+                    .map(|info| info.addrs)
+                    .unwrap_or_default();
+                let result = if addrs.is_empty() {
+                    Err(anyhow!("Peer {} not found in the DHT", peer))
+                } else {
+                    self.metrics.dht_queries_succeeded.inc();
+                    Ok(addrs)
+                };
+                let _ = sender.send(result);
+            }
+            // When an announcement has been sent to the closest peers found, or failed
+            kad::QueryResult::StartProviding(result) => {
+                let Some((cid, sender)) = self.pending_provide.remove(&id) else {
+                    return;
+                };
+                let result = match result {
+                    // Without any peer to ask, the query finishes right away without sending the record anywhere
+                    Ok(_) if stats.num_successes() == 0 => {
+                        Err(anyhow!("No peer could be reached to announce block {}", cid))
+                    }
+                    Ok(_) => {
+                        debug!("Announced block {} to {} peers", cid, stats.num_successes());
+                        self.metrics.dht_queries_succeeded.inc();
+                        self.metrics.blocks_provided.inc();
+                        Ok(())
+                    }
+                    Err(err) => Err(anyhow!("Failed to announce block {}: {:?}", cid, err)),
+                };
+                let _ = sender.send(result);
+            }
+            // When we get a result from bootstrapping
+            kad::QueryResult::Bootstrap(Ok(result)) => {
+                if self.verbose {
+                    info!("Bootstrap result: {} peers found", result.num_remaining);
+                }
+                // Save the routing table once bootstrapping has finished,
+                // so the next run can start from the peers discovered here
+                if result.num_remaining == 0 {
+                    self.save_routing_table();
+                }
+            }
+            other => debug!("Query {:?} progressed: {:?}", id, other),
+        }
+    }
+
+    /// Log and count a DHT request of another node
+    fn handle_inbound_request(&mut self, request: kad::InboundRequest) {
+        self.metrics.dht_inbound_requests.inc();
+        match request {
+            kad::InboundRequest::GetProvider { num_provider_peers, .. } => {
+                if num_provider_peers > 0 {
+                    debug!("Answered a provider request with {} providers from the local store", num_provider_peers);
+                    self.metrics.dht_inbound_answered.inc();
+                } else {
+                    debug!("Answered a provider request with closer peers only");
+                }
+            }
+            kad::InboundRequest::GetRecord { present_locally, .. } => {
+                if present_locally {
+                    debug!("Answered a record request from the local store");
+                    self.metrics.dht_inbound_answered.inc();
+                } else {
+                    debug!("Answered a record request with closer peers only");
+                }
+            }
+            kad::InboundRequest::AddProvider { .. } => debug!("Stored a provider record announced by another node"),
+            kad::InboundRequest::PutRecord { source, .. } => debug!("Stored a record published by {}", source),
+            kad::InboundRequest::FindNode { num_closer_peers } => {
+                debug!("Answered a peer lookup with {} closer peers", num_closer_peers)
+            }
+        }
+    }
+
+    /// Addresses we know for a peer, from its open connections, identify and the routing table
+    fn known_addresses(&mut self, peer: &PeerId) -> Vec<Multiaddr> {
+        let connected = self
+            .connections
+            .values()
+            .filter(|connection| connection.peer_id == *peer)
+            .map(|connection| connection.address.clone());
+        let identified = self.peer_info.get(peer).map(|info| info.listen_addrs.clone()).unwrap_or_default();
+        let mut addrs: Vec<Multiaddr> = connected.chain(identified).collect();
+        for bucket in self.swarm.behaviour_mut().kademlia.kbuckets() {
+            for entry in bucket.iter().filter(|entry| entry.node.key.preimage() == peer) {
+                addrs.extend(entry.node.value.iter().cloned());
+            }
+        }
+
+        let mut known = Vec::new();
+        for addr in addrs {
+            if !known.contains(&addr) {
+                known.push(addr);
+            }
+        }
+        known
+    }
+
+    /// Save the routing table for the next run
+    fn save_routing_table(&mut self) {
+        if let Err(e) = routing_table::save(&self.routing_table_path, &mut self.swarm.behaviour_mut().kademlia) {
+            warn!("Failed to save routing table: {}", e);
+        }
+    }
+}
+
+/// Agent version the node presents to its peers, e.g. `miga/0.1.0`
+pub fn agent_version() -> String {
+    format!("miga/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Protocols a node speaks, sorted by name
+///
+/// # Arguments
+/// * `dht_server` - Whether the node answers DHT requests of other nodes
+pub fn supported_protocols(dht_server: bool) -> Vec<String> {
+    let mut protocols = vec![
+        bitswap::PROTOCOL.to_string(),
+        identify::PROTOCOL_NAME.to_string(),
+        identify::PUSH_PROTOCOL_NAME.to_string(),
+        ping::PROTOCOL_NAME.to_string(),
+    ];
+    if dht_server {
+        protocols.push(kad::PROTOCOL_NAME.to_string());
+    }
+    protocols.sort();
+    protocols
+}
+
+/// Addresses to listen on when none are configured
+///
+/// Covers IPv4 and IPv6, each over TCP and QUIC, on all network interfaces.
+/// Hosts that only have one of the IP versions just fail to listen on the
+/// addresses of the other.
+///
+/// # Arguments
+/// * `port` - Port to listen on, 0 for a random one
+///
+/// # Returns
+/// * `Vec<Multiaddr>` - The listen addresses
+pub fn default_listen_addrs(port: u16) -> Vec<Multiaddr> {
+    let ips = [Protocol::Ip4(Ipv4Addr::UNSPECIFIED), Protocol::Ip6(Ipv6Addr::UNSPECIFIED)];
+    let tcp = ips.iter().map(|ip| Multiaddr::empty().with(ip.clone()).with(Protocol::Tcp(port)));
+    let quic = ips
+        .iter()
+        .map(|ip| Multiaddr::empty().with(ip.clone()).with(Protocol::Udp(port)).with(Protocol::QuicV1));
+    tcp.chain(quic).collect()
+}
+
+/// Well-known IPFS bootstrap nodes
+///
+/// These nodes serve as entry points to the IPFS network and help our node
+/// discover other peers.
+///
+/// # Returns
+/// * `Vec<Multiaddr>` - Addresses of the bootstrap nodes
+pub fn default_bootstrap_nodes() -> Vec<Multiaddr> {
+    // List of well-known IPFS bootstrap nodes
+    // These are maintained by Protocol Labs and the IPFS community
+    let bootstrap_nodes = [
+        // DNS-based addresses (more stable over time)
+        "/dnsaddr/bootstrap.libp2p.io/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN",
+        "/dnsaddr/bootstrap.libp2p.io/p2p/QmQCU2EcMqAqQPR2i9bChDtGNJchTbq5TbXJJ16u19uLTa",
+        "/dnsaddr/bootstrap.libp2p.io/p2p/QmbLHAnMoJPWSCR5Zhtx6BHJX9KiKNN6tpvbUcqanj75Nb",
+        "/dnsaddr/bootstrap.libp2p.io/p2p/QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1ZjYZcYW3dwt",
+        // IP-based address, for when DNS is unavailable
+        "/ip4/104.131.131.82/tcp/4001/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ",
+    ];
+
+    bootstrap_nodes
+        .iter()
+        .map(|node| node.parse().expect("Invalid built-in bootstrap address"))
+        .collect()
+}
+
+/// Add bootstrap nodes to the Kademlia DHT
+///
+/// # Arguments
+/// * `kademlia` - A mutable reference to the Kademlia behavior
+/// * `nodes` - Addresses of the bootstrap nodes
+/// * `verbose` - Whether to print verbose information about the bootstrap process
+fn add_bootstrap_nodes(kademlia: &mut kad::Behaviour<PersistentStore>, nodes: &[Multiaddr], verbose: bool) {
+    // Add each bootstrap node to the Kademlia routing table
+    for addr in nodes {
+        // Extract the peer ID from the multiaddress
+        let Some(peer_id) = extract_peer_id_from_multiaddr(addr) else {
+            warn!("Bootstrap address {} has no peer ID, skipping it", addr);
+            continue;
+        };
+
+        // Add the address to Kademlia's routing table
+        kademlia.add_address(&peer_id, addr.clone());
+
+        // Print information if verbose mode is enabled
+        if verbose {
+            println!("Added bootstrap node: {} ({})", addr, peer_id);
+        }
+    }
+}
+
+/// Extract a PeerId from a multiaddress
+///
+/// A multiaddress (Multiaddr) may contain a peer ID as its last component.
+/// This function extracts that peer ID if present.
+///
+/// # Arguments
+/// * `addr` - The multiaddress to extract the peer ID from
+///
+/// # Returns
+/// * `Option<PeerId>` - The extracted peer ID, or None if no valid peer ID was found
+pub fn extract_peer_id_from_multiaddr(addr: &Multiaddr) -> Option<PeerId> {
+    // Iterate through the protocols in the multiaddress
+    addr.iter().find_map(|proto| {
+        // Look for the P2p protocol which contains the peer ID
+        if let Protocol::P2p(hash) = proto {
+            // Convert the hash to a PeerId
+            PeerId::from_multihash(hash.into()).ok()
+        } else {
+            None
+        }
+    })
+}
@@ -0,0 +1,931 @@
+//! Embeddable MIGA node
+//!
+//! A [`MigaNode`] bundles the network, the blockstore and the pins of a
+//! repository behind a small API, so other programs can fetch, add and
+//! provide content without going through the command line tool.
+
+use anyhow::{anyhow, Result};
+use cid::{multihash::Multihash, Cid};
+use futures::{
+    future,
+    stream::{self, BoxStream, StreamExt, TryStreamExt},
+};
+use libp2p::{identity, multiaddr::Protocol, Multiaddr, PeerId};
+use log::{debug, info, warn};
+use prometheus_client::{encoding::text, registry::Registry};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tracing::{info_span, Instrument};
+
+use crate::{
+    api::{ExportStats, KuboClient},
+    blocklist::BlocklistEntry,
+    blockstore::{self, Blockstore},
+    dag::{self, DagStat},
+    error::MigaError,
+    fetch::{self, FetchEvent, FileReader, RetryPolicy},
+    keypair::{self, KeyType},
+    network::{self, Client, ConnectionLimits, DhtMode, NetworkConfig},
+    pin::{self, PinMode, PinStore},
+    throttle::RateLimits,
+    unixfs::{self, AddOptions, AddedFile, DirEntry},
+};
+
+/// Default maximum number of blocks fetched at the same time for one file
+pub const DEFAULT_CONCURRENCY: usize = 8;
+
+/// Default size of the in-memory cache of recently used blocks, in bytes
+pub const DEFAULT_BLOCK_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Number of blocks announced at the same time, each announcement being a DHT query
+const PROVIDE_CONCURRENCY: usize = 16;
+
+/// Number of blocks between two progress messages while announcing pinned blocks
+const PROVIDE_PROGRESS_INTERVAL: usize = 1000;
+
+/// Builder for a [`MigaNode`]
+///
+/// Created with [`MigaNode::builder`]. Every setting has a default, so
+/// `MigaNode::builder().build().await` starts a node on the `~/.miga`
+/// repository that joins the public IPFS network.
+pub struct MigaNodeBuilder {
+    /// Repository directory
+    repo_dir: Option<PathBuf>,
+    /// Path of the identity key file
+    identity_path: Option<PathBuf>,
+    /// Identity keypair, taking precedence over the key file
+    keypair: Option<identity::Keypair>,
+    /// Type of the identity key generated if the key file doesn't exist yet
+    identity_type: KeyType,
+    /// Nodes to bootstrap the DHT from
+    bootstrap: Vec<Multiaddr>,
+    /// Addresses to listen on
+    listen: Vec<Multiaddr>,
+    /// Map the listen port on the local router via UPnP
+    upnp: bool,
+    /// Print verbose information about the bootstrap process
+    verbose: bool,
+    /// Maximum number of blocks fetched at the same time for one file
+    concurrency: usize,
+    /// Timeout of DHT queries
+    query_timeout: Option<Duration>,
+    /// Role of the node in the DHT
+    dht_mode: DhtMode,
+    /// How blocks that couldn't be found are retried
+    retry: RetryPolicy,
+    /// Time between two pings on a connection
+    ping_interval: Option<Duration>,
+    /// Peers to connect to and get blocks from instead of the DHT
+    peers: Vec<Multiaddr>,
+    /// Addresses the node is reachable on from the outside
+    announce: Vec<Multiaddr>,
+    /// Caps on the number of connections
+    limits: ConnectionLimits,
+    /// Peers and IP ranges that may not connect
+    blocklist: Vec<BlocklistEntry>,
+    /// The only peers that are sent blocks, if not everyone
+    allowlist: Option<HashSet<PeerId>>,
+    /// Path of the swarm key file of a private network
+    swarm_key_path: Option<PathBuf>,
+    /// Cap on the upload rate, in bytes per second
+    max_upload_rate: Option<u64>,
+    /// Cap on the download rate, in bytes per second
+    max_download_rate: Option<u64>,
+    /// Only use the local blockstore, never the network
+    offline: bool,
+    /// Size of the in-memory cache of recently used blocks, in bytes
+    block_cache_size: u64,
+}
+
+impl Default for MigaNodeBuilder {
+    fn default() -> Self {
+        Self {
+            repo_dir: None,
+            identity_path: None,
+            keypair: None,
+            identity_type: KeyType::default(),
+            bootstrap: network::default_bootstrap_nodes(),
+            listen: network::default_listen_addrs(0),
+            upnp: false,
+            verbose: false,
+            concurrency: DEFAULT_CONCURRENCY,
+            query_timeout: None,
+            dht_mode: DhtMode::default(),
+            retry: RetryPolicy::default(),
+            ping_interval: None,
+            peers: Vec::new(),
+            announce: Vec::new(),
+            limits: ConnectionLimits::default(),
+            blocklist: Vec::new(),
+            allowlist: None,
+            swarm_key_path: None,
+            max_upload_rate: None,
+            max_download_rate: None,
+            offline: false,
+            block_cache_size: DEFAULT_BLOCK_CACHE_SIZE,
+        }
+    }
+}
+
+impl MigaNodeBuilder {
+    /// Set the repository directory (default: `~/.miga`)
+    pub fn repo(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.repo_dir = Some(dir.into());
+        self
+    }
+
+    /// Set the identity key file (default: `identity.key` in the repository)
+    ///
+    /// The key is created on first use, so the peer ID stays the same across runs.
+    pub fn identity(mut self, path: impl Into<PathBuf>) -> Self {
+        self.identity_path = Some(path.into());
+        self
+    }
+
+    /// Set the type of the identity key generated on first use (default: Ed25519)
+    ///
+    /// An existing key file is used whatever its type.
+    pub fn identity_type(mut self, key_type: KeyType) -> Self {
+        self.identity_type = key_type;
+        self
+    }
+
+    /// Join the private network of a swarm key file (default: `swarm.key` in the repository, if it exists)
+    ///
+    /// Only nodes with the same key can connect to each other, so the node is
+    /// isolated from the public IPFS network and its DHT. Private networks run
+    /// over TCP only, and need bootstrap nodes of their own.
+    pub fn swarm_key(mut self, path: impl Into<PathBuf>) -> Self {
+        self.swarm_key_path = Some(path.into());
+        self
+    }
+
+    /// Use an identity keypair instead of loading one from a file
+    pub fn keypair(mut self, keypair: identity::Keypair) -> Self {
+        self.keypair = Some(keypair);
+        self
+    }
+
+    /// Replace the default IPFS bootstrap nodes
+    ///
+    /// Each address must end in `/p2p/<peer ID>`. An empty list starts a node
+    /// that only knows the peers saved by its previous run.
+    pub fn bootstrap(mut self, addrs: impl IntoIterator<Item = Multiaddr>) -> Self {
+        self.bootstrap = addrs.into_iter().collect();
+        self
+    }
+
+    /// Replace the default listen addresses (all interfaces, IPv4 and IPv6, TCP and QUIC, random port)
+    ///
+    /// The node starts as long as it can listen on at least one of the addresses.
+    pub fn listen_on(mut self, addrs: impl IntoIterator<Item = Multiaddr>) -> Self {
+        self.listen = addrs.into_iter().collect();
+        self
+    }
+
+    /// Map the listen port on the local router via UPnP (default: off)
+    pub fn upnp(mut self, enabled: bool) -> Self {
+        self.upnp = enabled;
+        self
+    }
+
+    /// Print verbose information about the bootstrap process (default: off)
+    pub fn verbose(mut self, enabled: bool) -> Self {
+        self.verbose = enabled;
+        self
+    }
+
+    /// Set the maximum number of blocks fetched at the same time for one file
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Set the timeout of DHT queries (default: 60 seconds)
+    pub fn query_timeout(mut self, timeout: Duration) -> Self {
+        self.query_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the role of the node in the DHT (default: auto)
+    ///
+    /// Nodes that only fetch are best run as clients, which causes less
+    /// traffic. Nodes that share content should be servers, so other nodes
+    /// keep them in their routing tables.
+    pub fn dht_mode(mut self, mode: DhtMode) -> Self {
+        self.dht_mode = mode;
+        self
+    }
+
+    /// Set how often a block that couldn't be found is retried before giving up (default: 10)
+    pub fn max_retries(mut self, retries: u32) -> Self {
+        self.retry.max_retries = retries;
+        self
+    }
+
+    /// Set the delay before the first retry of a block (default: 1 second)
+    ///
+    /// The delay doubles with every further retry, up to a minute.
+    pub fn retry_backoff(mut self, backoff: Duration) -> Self {
+        self.retry.backoff = backoff;
+        self
+    }
+
+    /// Set the time between two pings on a connection (default: 15 seconds)
+    ///
+    /// Every connection is pinged, so keep this long unless the round trip
+    /// times are what the node is for, as with `miga ping`.
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = Some(interval);
+        self
+    }
+
+    /// Get blocks only from these peers instead of looking up providers in the DHT
+    ///
+    /// Each address must end in `/p2p/<peer ID>`. The node connects to the
+    /// peers when it starts, which is useful on a LAN or for private transfers
+    /// from a machine that is known to have the content.
+    pub fn peers(mut self, addrs: impl IntoIterator<Item = Multiaddr>) -> Self {
+        self.peers = addrs.into_iter().collect();
+        self
+    }
+
+    /// Advertise addresses the node is reachable on from the outside
+    ///
+    /// Nodes behind a NAT with a port forwarding only know their local listen
+    /// addresses. The announced addresses are told to other peers over identify
+    /// and in provider records, so they can connect back.
+    pub fn announce(mut self, addrs: impl IntoIterator<Item = Multiaddr>) -> Self {
+        self.announce = addrs.into_iter().collect();
+        self
+    }
+
+    /// Set the caps on the number of connections
+    ///
+    /// By default at most 512 connections are kept, 8 to the same peer, with
+    /// 128 being set up at the same time in each direction. Connections beyond
+    /// a cap are refused, which keeps a node on a small machine responsive
+    /// when many peers connect at once.
+    pub fn connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Refuse connections to and from these peers and IP ranges
+    ///
+    /// The list can be changed while the node runs, through its [`Client`].
+    pub fn blocklist(mut self, entries: impl IntoIterator<Item = BlocklistEntry>) -> Self {
+        self.blocklist = entries.into_iter().collect();
+        self
+    }
+
+    /// Send blocks only to these peers, refusing Bitswap to everyone else
+    ///
+    /// Other peers can still connect and send us blocks, but their wants are
+    /// answered as if the node had none of the blocks. Together with
+    /// [`peers`](Self::peers) on the receiving side, this allows private
+    /// distribution between known machines over the public network.
+    pub fn allowlist(mut self, peers: impl IntoIterator<Item = PeerId>) -> Self {
+        self.allowlist = Some(peers.into_iter().collect());
+        self
+    }
+
+    /// Cap the rate blocks are sent to peers at, in bytes per second
+    ///
+    /// Blocks wait until they fit under the cap, so a share node on a home
+    /// connection leaves room for everything else on it.
+    pub fn max_upload_rate(mut self, rate: u64) -> Self {
+        self.max_upload_rate = Some(rate);
+        self
+    }
+
+    /// Cap the rate blocks are received from peers at, in bytes per second
+    pub fn max_download_rate(mut self, rate: u64) -> Self {
+        self.max_download_rate = Some(rate);
+        self
+    }
+
+    /// Only use the blocks of the local blockstore, never the network (default: off)
+    ///
+    /// An offline node neither listens nor connects to other peers. Content
+    /// that isn't stored locally fails right away with
+    /// [`MigaError::NotStoredLocally`] instead of being looked up, which suits
+    /// air-gapped machines and scripts working on content fetched before.
+    pub fn offline(mut self, enabled: bool) -> Self {
+        self.offline = enabled;
+        self
+    }
+
+    /// Set the size of the in-memory cache of recently used blocks, in bytes (default: 64 MiB)
+    ///
+    /// Blocks read or written recently are served from memory, which speeds
+    /// up DAG traversals, the gateway and Bitswap requests for popular
+    /// blocks. 0 disables the cache.
+    pub fn block_cache_size(mut self, bytes: u64) -> Self {
+        self.block_cache_size = bytes;
+        self
+    }
+
+    /// Start the node
+    ///
+    /// Opens the repository, starts the network event loop, listens on the
+    /// configured addresses and connects to the direct peers.
+    ///
+    /// # Returns
+    /// * `Result<MigaNode>` - The running node
+    pub async fn build(self) -> Result<MigaNode> {
+        let repo_dir = self.repo_dir.unwrap_or_else(keypair::home_dir);
+        let keypair = match (self.keypair, self.identity_path) {
+            (Some(keypair), _) => keypair,
+            (None, Some(path)) => keypair::load_or_generate(&path, self.identity_type)?,
+            (None, None) => keypair::load_or_generate(&repo_dir.join("identity.key"), self.identity_type)?,
+        };
+        let blockstore = Blockstore::open(repo_dir.join("blocks"))?.with_cache(self.block_cache_size);
+        let pins = PinStore::open(pin::pin_file(&repo_dir))?;
+        let peers = self
+            .peers
+            .iter()
+            .map(|addr| {
+                network::extract_peer_id_from_multiaddr(addr)
+                    .ok_or_else(|| anyhow!("Peer address {} has no /p2p/<peer ID>", addr))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // A swarm key in the repository makes the node join its private network
+        let swarm_key = match self.swarm_key_path {
+            Some(path) => Some(keypair::load_swarm_key(&path)?),
+            None => {
+                let path = repo_dir.join("swarm.key");
+                path.exists().then(|| keypair::load_swarm_key(&path)).transpose()?
+            }
+        };
+        let mut bootstrap = self.bootstrap;
+        let mut listen = self.listen;
+        if self.offline {
+            if !peers.is_empty() {
+                return Err(anyhow!("An offline node can't get blocks from direct peers"));
+            }
+            bootstrap.clear();
+            listen.clear();
+        }
+        if swarm_key.is_some() {
+            // The public bootstrap nodes aren't part of the private network
+            let defaults = network::default_bootstrap_nodes();
+            bootstrap.retain(|addr| !defaults.contains(addr));
+            if bootstrap.is_empty() {
+                warn!("No bootstrap nodes for the private network, add some with --bootstrap or `miga bootstrap add`");
+            }
+            // Private networks run over TCP only
+            listen.retain(|addr| !addr.iter().any(|protocol| matches!(protocol, Protocol::QuicV1)));
+        }
+
+        let rates = RateLimits::new(self.max_upload_rate, self.max_download_rate);
+        let config = NetworkConfig {
+            upnp: self.upnp && !self.offline,
+            verbose: self.verbose,
+            bootstrap,
+            query_timeout: self.query_timeout,
+            dht_mode: self.dht_mode,
+            retry: self.retry,
+            ping_interval: self.ping_interval,
+            peers,
+            announce: self.announce,
+            limits: self.limits,
+            blocklist: self.blocklist,
+            allowlist: self.allowlist,
+            swarm_key,
+            rates: rates.clone(),
+            offline: self.offline,
+        };
+        let mut registry = Registry::default();
+        let (client, event_loop) = network::new(keypair, &repo_dir, blockstore.clone(), config, &mut registry)?;
+        let event_loop_task = tokio::spawn(event_loop.run());
+
+        // Hosts without IPv4 or IPv6 can't listen on all of the default addresses
+        let mut listening = 0;
+        for addr in &listen {
+            match client.listen_on(addr.clone()).await {
+                Ok(_) => listening += 1,
+                Err(e) => warn!("Failed to listen on {}: {}", addr, e),
+            }
+        }
+        if !listen.is_empty() && listening == 0 {
+            return Err(anyhow!("Failed to listen on any of {:?}", listen));
+        }
+
+        // Blocks are only asked from the direct peers, so at least one of them has to be reachable
+        let mut connected = 0;
+        for addr in &self.peers {
+            match client.dial(addr.clone()).await {
+                Ok(peer) => {
+                    info!("Connected to {}", peer);
+                    connected += 1;
+                }
+                Err(e) => warn!("{}", e),
+            }
+        }
+        if !self.peers.is_empty() && connected == 0 {
+            return Err(MigaError::Network("Failed to connect to any of the given peers".to_string()).into());
+        }
+
+        Ok(MigaNode {
+            client,
+            blockstore,
+            pins: Mutex::new(pins),
+            registry,
+            repo_dir,
+            concurrency: self.concurrency,
+            rates,
+            event_loop_task,
+        })
+    }
+}
+
+/// A running MIGA node
+pub struct MigaNode {
+    /// Client of the network
+    client: Client,
+    /// The local blockstore
+    blockstore: Blockstore,
+    /// Pins of the repository
+    pins: Mutex<PinStore>,
+    /// Metrics of the node
+    registry: Registry,
+    /// Repository directory
+    repo_dir: PathBuf,
+    /// Maximum number of blocks fetched at the same time for one file
+    concurrency: usize,
+    /// Limits on the transfer rates of the node
+    rates: RateLimits,
+    /// Task running the network event loop
+    event_loop_task: JoinHandle<()>,
+}
+
+impl MigaNode {
+    /// Create a builder for a node
+    pub fn builder() -> MigaNodeBuilder {
+        MigaNodeBuilder::default()
+    }
+
+    /// Peer ID of the node
+    pub fn peer_id(&self) -> PeerId {
+        self.client.peer_id()
+    }
+
+    /// Client of the node's network, for lower-level operations
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// The node's blockstore
+    pub fn blockstore(&self) -> &Blockstore {
+        &self.blockstore
+    }
+
+    /// The node's repository directory
+    pub fn repo_dir(&self) -> &Path {
+        &self.repo_dir
+    }
+
+    /// Maximum number of blocks fetched at the same time for one file
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    /// Limits on the transfer rates of the node, shared by Bitswap and the gateway
+    pub fn rate_limits(&self) -> &RateLimits {
+        &self.rates
+    }
+
+    /// Current values of the node's metrics, in the Prometheus text format
+    pub fn encode_metrics(&self) -> Result<String> {
+        let mut buf = String::new();
+        text::encode(&mut buf, &self.registry)?;
+        Ok(buf)
+    }
+
+    /// Fetch a UnixFS file into memory
+    ///
+    /// Use [`MigaNode::fetch_to`] for files that are too large to hold in memory.
+    ///
+    /// # Returns
+    /// * `Result<Vec<u8>>` - The content of the file
+    pub async fn fetch(&self, cid: Cid) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.fetch_to(cid, &mut content).await?;
+        Ok(content)
+    }
+
+    /// Fetch a UnixFS file, writing its content to a writer as it arrives
+    ///
+    /// # Returns
+    /// * `Result<u64>` - Number of bytes written
+    pub async fn fetch_to<W>(&self, cid: Cid, writer: &mut W) -> Result<u64>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        fetch::fetch_file(&self.client, &self.blockstore, cid, writer, self.concurrency, None).await
+    }
+
+    /// Resolve a path within a UnixFS directory to the CID of the entry it names
+    ///
+    /// Directories along the path that aren't stored locally are fetched
+    /// from the network, but nothing below the entry itself is.
+    ///
+    /// # Arguments
+    /// * `root` - CID of the directory the path starts at
+    /// * `path` - Names of the entries to follow, separated by slashes
+    ///
+    /// # Returns
+    /// * `Result<Cid>` - CID of the entry, or `root` itself for an empty path
+    pub async fn resolve_path(&self, root: Cid, path: &str) -> Result<Cid> {
+        let mut cid = root;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let block = fetch::get_block(&self.client, &self.blockstore, &cid, None).await?;
+            cid = unixfs::find_entry(&cid, &block, name)?.ok_or_else(|| MigaError::NoSuchPath {
+                root,
+                path: path.to_string(),
+            })?;
+        }
+        Ok(cid)
+    }
+
+    /// List the entries of a UnixFS directory
+    ///
+    /// The directory block is fetched from the network if it isn't stored
+    /// locally, but none of the entries are.
+    ///
+    /// # Arguments
+    /// * `cid` - CID of the directory
+    ///
+    /// # Returns
+    /// * `Result<Option<Vec<DirEntry>>>` - The entries, or None if the CID isn't a directory
+    pub async fn list_directory(&self, cid: Cid) -> Result<Option<Vec<DirEntry>>> {
+        let block = fetch::get_block(&self.client, &self.blockstore, &cid, None).await?;
+        unixfs::list_directory(&cid, &block)
+    }
+
+    /// CIDs of the blocks a block links to, in order
+    ///
+    /// Raw blocks have no links, so they aren't fetched; other blocks are
+    /// fetched from the network if they aren't stored locally.
+    ///
+    /// # Arguments
+    /// * `cid` - CID of the block
+    ///
+    /// # Returns
+    /// * `Result<Vec<Cid>>` - The linked CIDs
+    pub async fn links(&self, cid: Cid) -> Result<Vec<Cid>> {
+        if cid.codec() == blockstore::RAW_CODEC {
+            return Ok(Vec::new());
+        }
+        let block = fetch::get_block(&self.client, &self.blockstore, &cid, None).await?;
+        dag::links(&cid, &block)
+    }
+
+    /// Measure the DAG below a CID: its size, number of blocks and depth
+    ///
+    /// The DAG is walked level by level, fetching the blocks that aren't
+    /// stored locally. Raw leaves have no links, and the links to them tell
+    /// their size, so they are only fetched if a link doesn't. Blocks linked
+    /// more than once are counted once, as they are stored once.
+    ///
+    /// # Arguments
+    /// * `root` - CID of the root block
+    ///
+    /// # Returns
+    /// * `Result<DagStat>` - The size and shape of the DAG
+    pub async fn stat(&self, root: Cid) -> Result<DagStat> {
+        let mut stat = DagStat::default();
+        let mut counted = HashSet::new();
+        // Blocks of the current level, with their size if the links to them tell it
+        let mut level = HashMap::from([(root, None)]);
+        while !level.is_empty() {
+            stat.depth += 1;
+            let blocks: Vec<(Cid, u64, Vec<dag::PbLink>)> = stream::iter(level)
+                .map(|(cid, size): (Cid, Option<u64>)| async move {
+                    if let (blockstore::RAW_CODEC, Some(size)) = (cid.codec(), size) {
+                        return Ok((cid, size, Vec::new()));
+                    }
+                    let block = fetch::get_block(&self.client, &self.blockstore, &cid, None).await?;
+                    let links = dag::pb_links(&cid, &block)?;
+                    anyhow::Ok((cid, block.len() as u64, links))
+                })
+                .buffer_unordered(self.concurrency)
+                .try_collect()
+                .await?;
+
+            // A block may be on several levels, its depth is the deepest of them
+            let mut next = HashMap::new();
+            for (cid, size, links) in blocks {
+                if counted.insert(cid) {
+                    stat.size += size;
+                    stat.blocks += 1;
+                }
+                for link in links {
+                    next.entry(link.cid).or_insert(link.tsize);
+                }
+            }
+            level = next;
+        }
+        Ok(stat)
+    }
+
+    /// Create a reader that fetches a UnixFS file chunk by chunk
+    ///
+    /// The reader owns handles to the network and blockstore, so it can be
+    /// moved into another task.
+    pub fn reader(&self, cid: Cid) -> FileReader {
+        FileReader::new(&self.client, &self.blockstore, cid, self.concurrency)
+    }
+
+    /// Fetch a UnixFS file in the background, reporting its progress as events
+    ///
+    /// The content is written to the writer as it arrives. The returned stream
+    /// yields a [`FetchEvent`] for every provider found and block received,
+    /// and ends with [`FetchEvent::Completed`] or [`FetchEvent::Failed`]. The
+    /// fetch keeps running if the stream is dropped.
+    ///
+    /// # Arguments
+    /// * `cid` - CID of the file
+    /// * `writer` - Destination of the file content
+    ///
+    /// # Returns
+    /// * `BoxStream<'static, FetchEvent>` - Progress events of the fetch
+    pub fn fetch_with_events<W>(&self, cid: Cid, mut writer: W) -> BoxStream<'static, FetchEvent>
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut reader = self.reader(cid).with_events(sender.clone());
+
+        tokio::spawn(async move {
+            let result = async {
+                let mut written = 0;
+                while let Some(chunk) = reader.next_chunk().await? {
+                    writer.write_all(&chunk).await?;
+                    written += chunk.len() as u64;
+                }
+                writer.flush().await?;
+                anyhow::Ok(written)
+            }
+            .instrument(info_span!("fetch", cid = %cid))
+            .await;
+
+            // Drop the reader first, so the stream ends right after the final event
+            drop(reader);
+            let event = match result {
+                Ok(size) => FetchEvent::Completed { size },
+                Err(e) => FetchEvent::Failed { error: e.to_string() },
+            };
+            let _ = sender.send(event);
+        });
+
+        stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|event| (event, receiver))
+        })
+        .boxed()
+    }
+
+    /// Add a file to the repository and pin it
+    ///
+    /// The file is chunked the same way Kubo does by default, so its CID
+    /// matches the one `ipfs add` produces. Call [`MigaNode::provide`] to make
+    /// it available to other nodes.
+    ///
+    /// # Returns
+    /// * `Result<AddedFile>` - The CID and size of the added file
+    pub fn add(&self, path: &Path) -> Result<AddedFile> {
+        self.add_with_options(path, AddOptions::default())
+    }
+
+    /// Add a local file to the blockstore and pin it, split into a DAG as `options` say
+    ///
+    /// # Returns
+    /// * `Result<AddedFile>` - The CID and size of the added file
+    pub fn add_with_options(&self, path: &Path, options: AddOptions) -> Result<AddedFile> {
+        let added = unixfs::add_file(&self.blockstore, path, options)?;
+        self.pin(&added.cid, PinMode::Recursive)?;
+        Ok(added)
+    }
+
+    /// Pin content that is stored in the local blockstore
+    ///
+    /// Pinned content is protected from garbage collection and shared when
+    /// the node runs as a daemon.
+    pub fn pin(&self, cid: &Cid, mode: PinMode) -> Result<()> {
+        if !self.blockstore.has(cid) {
+            return Err(anyhow!("Content {} is not in the local blockstore", cid));
+        }
+        self.pins.lock().unwrap().add(cid, mode)
+    }
+
+    /// Remove the pin of a CID
+    ///
+    /// # Returns
+    /// * `Result<bool>` - Whether the CID was pinned
+    pub fn unpin(&self, cid: &Cid) -> Result<bool> {
+        self.pins.lock().unwrap().remove(cid)
+    }
+
+    /// All pinned CIDs and their pin modes
+    ///
+    /// The pins are read from disk again, as other processes may have changed them.
+    pub fn pins(&self) -> Result<Vec<(Cid, PinMode)>> {
+        let mut pins = self.pins.lock().unwrap();
+        pins.reload()?;
+        Ok(pins.list())
+    }
+
+    /// Multihashes of all blocks protected by a pin
+    ///
+    /// The pins are read from disk again, as other processes may have changed them.
+    pub fn pinned_blocks(&self) -> Result<HashSet<Multihash<64>>> {
+        let mut pins = self.pins.lock().unwrap();
+        pins.reload()?;
+        pins.pinned_blocks(&self.blockstore)
+    }
+
+    /// Announce a locally stored DAG on the network, so other nodes can find and fetch it
+    ///
+    /// The node is announced in the DHT as a provider of every block of the DAG,
+    /// several blocks at a time. Only announcements that reached other peers
+    /// are counted, so this takes as long as the DHT queries do. Offline nodes
+    /// announce nothing.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - Number of blocks announced
+    pub async fn provide(&self, cid: Cid) -> Result<usize> {
+        // Offline, there is nobody to announce to
+        if self.client.is_offline() {
+            debug!("Not announcing {} offline", cid);
+            return Ok(0);
+        }
+        let mut visited = HashSet::new();
+        let mut stack = vec![cid];
+        let mut blocks = Vec::new();
+
+        while let Some(cid) = stack.pop() {
+            if !visited.insert(cid) {
+                continue;
+            }
+            let data = self
+                .blockstore
+                .get(&cid)?
+                .ok_or_else(|| anyhow!("Block {} is not in the local blockstore", cid))?;
+            stack.extend(dag::links(&cid, &data)?);
+            blocks.push(cid);
+        }
+
+        let mut announcements =
+            stream::iter(blocks).map(|cid| self.client.provide(cid)).buffer_unordered(PROVIDE_CONCURRENCY);
+        let mut provided = 0;
+        while let Some(result) = announcements.next().await {
+            match result {
+                Ok(()) => provided += 1,
+                Err(e) => warn!("{}", e),
+            }
+        }
+        Ok(provided)
+    }
+
+    /// Announce a locally stored DAG on the network in the background, see [`MigaNode::provide`]
+    ///
+    /// For requests that shouldn't wait for the DHT queries, like Kubo's API
+    /// answers before its reprovider has announced added content.
+    pub fn provide_in_background(self: &Arc<Self>, cid: Cid) {
+        let node = self.clone();
+        tokio::spawn(async move {
+            match node.provide(cid).await {
+                Ok(blocks) => debug!("Announced {} blocks of {}", blocks, cid),
+                Err(e) => warn!("Failed to announce {}: {:#}", cid, e),
+            }
+        });
+    }
+
+    /// Push the DAG below a CID to a Kubo node through its RPC API
+    ///
+    /// Blocks that aren't stored locally are fetched first, so the node can
+    /// act as a fetch frontend of an existing Kubo pinning setup.
+    ///
+    /// # Arguments
+    /// * `root` - CID of the root block
+    /// * `kubo` - Client of the Kubo node's RPC API
+    ///
+    /// # Returns
+    /// * `Result<ExportStats>` - Number of blocks and bytes pushed
+    pub async fn export_to_kubo(&self, root: Cid, kubo: &mut KuboClient) -> Result<ExportStats> {
+        let mut stats = ExportStats::default();
+        let mut visited = HashSet::new();
+        let mut stack = vec![root];
+
+        while let Some(cid) = stack.pop() {
+            if !visited.insert(cid) {
+                continue;
+            }
+            let data = fetch::get_block(&self.client, &self.blockstore, &cid, None).await?;
+            stack.extend(dag::links(&cid, &data)?);
+
+            kubo.block_put(&cid, &data).await?;
+            debug!("Pushed block {} to Kubo", cid);
+            stats.blocks += 1;
+            stats.bytes += data.len() as u64;
+        }
+        Ok(stats)
+    }
+
+    /// Stop announcing a locally stored DAG on the network, after its pin was removed
+    ///
+    /// Blocks of the DAG that are still protected by another pin keep being
+    /// provided, and blocks that aren't stored locally are skipped.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - Number of blocks no longer provided
+    pub async fn unprovide(&self, cid: Cid) -> Result<usize> {
+        let pinned = self.pinned_blocks()?;
+        let mut visited = HashSet::new();
+        let mut stack = vec![cid];
+        let mut unprovided = 0;
+
+        while let Some(cid) = stack.pop() {
+            if !visited.insert(cid) {
+                continue;
+            }
+            let Some(data) = self.blockstore.get(&cid)? else {
+                continue;
+            };
+            stack.extend(dag::links(&cid, &data)?);
+            if !pinned.contains(cid.hash()) {
+                self.client.stop_providing(cid).await?;
+                unprovided += 1;
+            }
+        }
+        Ok(unprovided)
+    }
+
+    /// Announce the node as provider of every pinned block
+    ///
+    /// Provider records expire after a day or two, so a node sharing content
+    /// has to call this again regularly; the daemon does so every 12 hours.
+    ///
+    /// # Returns
+    /// * `Result<usize>` - Number of blocks announced
+    pub async fn provide_pinned(&self) -> Result<usize> {
+        if self.client.is_offline() {
+            debug!("Not announcing the pinned blocks offline");
+            return Ok(0);
+        }
+        let pinned = self.pinned_blocks()?;
+        let total = pinned.len();
+        info!("Announcing {} pinned blocks", total);
+        let mut announcements = stream::iter(pinned)
+            .map(|hash| Cid::new_v1(blockstore::RAW_CODEC, hash))
+            .filter(|cid| future::ready(self.blockstore.has(cid)))
+            .map(|cid| self.client.provide(cid))
+            .buffer_unordered(PROVIDE_CONCURRENCY);
+        let mut done = 0;
+        let mut provided = 0;
+        while let Some(result) = announcements.next().await {
+            match result {
+                Ok(()) => provided += 1,
+                Err(e) => debug!("{}", e),
+            }
+            done += 1;
+            if done % PROVIDE_PROGRESS_INTERVAL == 0 {
+                info!("Announced {} of {} pinned blocks", done, total);
+            }
+        }
+        self.client.metrics().reprovides.inc();
+        info!("Announced {} pinned blocks", provided);
+        Ok(provided)
+    }
+
+    /// Wait until the network event loop stops
+    ///
+    /// The node holds a client, so this only returns if the event loop fails;
+    /// it is meant for running a node until the process is stopped.
+    pub async fn wait(self) -> Result<()> {
+        let result = self.event_loop_task.await;
+        drop(self.client);
+        result.map_err(|e| anyhow!("Network event loop failed: {}", e))
+    }
+
+    /// Stop the node
+    ///
+    /// The event loop saves the routing table for the next run before it stops.
+    pub async fn shutdown(self) {
+        drop(self.client);
+        if let Err(e) = self.event_loop_task.await {
+            warn!("Network event loop failed: {}", e);
+        }
+    }
+}
@@ -0,0 +1,435 @@
+//! User-facing output
+//!
+//! Everything MIGA reports to the user is a [`Message`]. Messages are printed
+//! as text, or with `--json` as one JSON object per line, tagged with an
+//! `event` field, so scripts don't have to scrape the human-readable text:
+//!
+//! ```text
+//! {"event":"fetched","cid":"Qm...","path":"Qm....bin","size":1024}
+//! ```
+
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::{fmt, path::PathBuf};
+
+/// A message for the user
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Message {
+    /// Peer ID of the local node
+    PeerId { peer_id: String },
+    /// Identity of the node, in the shape of `ipfs id`
+    #[serde(rename_all = "PascalCase")]
+    Id {
+        #[serde(rename = "ID")]
+        id: String,
+        public_key: String,
+        addresses: Vec<String>,
+        agent_version: String,
+        protocols: Vec<String>,
+    },
+    /// Fetching of a CID started (verbose only)
+    Fetching { cid: String },
+    /// Content was received from the network
+    Received { cid: String, size: u64 },
+    /// Content was saved to a file
+    Saved { cid: String, path: PathBuf },
+    /// Content was pinned
+    Pinned { cid: String, mode: String },
+    /// A pin was removed
+    Unpinned { cid: String },
+    /// A pin, listed by `pin ls`
+    Pin { cid: String, mode: String },
+    /// A bootstrap node was added
+    BootstrapAdded { address: String },
+    /// A bootstrap node was removed
+    BootstrapRemoved { address: String },
+    /// A bootstrap node, listed by `bootstrap list`
+    Bootstrap { address: String },
+    /// The bootstrap list was reset to the defaults
+    BootstrapReset,
+    /// A CID a block links to, listed by `refs`
+    Ref { cid: String },
+    /// Size and shape of a DAG, reported by `stat`
+    Stat { cid: String, size: u64, blocks: usize, depth: usize },
+    /// The DAG of a CID was pushed to Kubo, by `export`
+    Exported {
+        cid: String,
+        blocks: usize,
+        bytes: u64,
+        pinned: bool,
+    },
+    /// A file has the content of a CID, checked by `verify`
+    Verified { path: PathBuf, cid: String },
+    /// A CID, converted by `cid format`
+    Cid { cid: String },
+    /// What a CID is made of, shown by `cid format --details`
+    CidDetails {
+        cid: String,
+        version: u64,
+        multibase: String,
+        codec: String,
+        codec_code: u64,
+        hash_function: String,
+        hash_code: u64,
+        digest_length: u8,
+        digest: String,
+    },
+    /// A block was stored by `block put`
+    BlockStored { cid: String, size: u64 },
+    /// A node was stored by `dag put`
+    DagStored { cid: String, size: u64 },
+    /// The blocks of a DAG were announced on the DHT
+    Provided { cid: String, blocks: usize },
+    /// A key of the keystore, listed by `key list` or added by `key gen` and `key import`
+    Key { name: String, peer_id: String },
+    /// A key was renamed
+    KeyRenamed { name: String, new_name: String },
+    /// A key was exported
+    KeyExported { name: String, path: PathBuf },
+    /// Whether a block is stored, reported by `block stat`
+    BlockStat { cid: String, size: Option<u64> },
+    /// A file was stored in the blockstore (verbose only)
+    Stored { size: u64, blocks: usize },
+    /// A file was added
+    Added { cid: String, name: String, size: u64 },
+    /// The daemon listens for IPFS connections on an address
+    Listening { address: String },
+    /// The node was announced as provider of the pinned blocks
+    Published { blocks: usize },
+    /// An address other nodes can reach the daemon on
+    NodeAddress { address: String },
+    /// The control API listens on an address
+    ApiListening { address: String },
+    /// The metrics endpoint listens on an address
+    MetricsListening { address: String },
+    /// The HTTP gateway listens on an address
+    GatewayListening { address: String },
+    /// The daemon is up and running
+    Running,
+    /// The daemon has shut down
+    Stopped,
+    /// A ping came back from a peer
+    Pong { peer_id: String, seq: u32, rtt_ms: f64 },
+    /// A ping got no answer
+    PingFailed { peer_id: String, seq: u32, error: String },
+    /// Summary of the pings sent to a peer
+    PingStats {
+        peer_id: String,
+        sent: u32,
+        received: u32,
+        loss_percent: f64,
+        min_ms: f64,
+        avg_ms: f64,
+        max_ms: f64,
+    },
+    /// A connection of the daemon, listed by `swarm peers`
+    Peer {
+        peer_id: String,
+        address: String,
+        direction: String,
+        transport: String,
+        agent_version: Option<String>,
+    },
+    /// The daemon connected to a peer
+    Connected { peer_id: String, address: String },
+    /// The daemon blocked a peer or IP range; `changed` is false if it already was
+    Blocked { entry: String, changed: bool },
+    /// The daemon removed a peer or IP range from its blocklist
+    Unblocked { entry: String },
+    /// A peer or IP range on the daemon's blocklist, listed by `swarm blocklist`
+    BlocklistEntry { entry: String },
+    /// An address of a peer, found by `dht find-peer`
+    PeerAddress { peer_id: String, address: String },
+    /// A provider of a CID, found by `dht find-providers`, with the addresses known for it
+    Provider {
+        cid: String,
+        peer_id: String,
+        addresses: Vec<String>,
+    },
+    /// Bandwidth used by the daemon, in total or with a peer or by a protocol, from `stats bw`
+    Bandwidth {
+        peer: Option<String>,
+        protocol: Option<String>,
+        total_in: u64,
+        total_out: u64,
+        rate_in: f64,
+        rate_out: f64,
+    },
+    /// Garbage collection finished
+    Gc {
+        dry_run: bool,
+        removed_blocks: usize,
+        removed_bytes: u64,
+        kept_blocks: usize,
+    },
+    /// Logical and physical size of the repository, from `repo dedup-stats`
+    DedupStats {
+        blocks: usize,
+        logical_bytes: u64,
+        physical_bytes: u64,
+        saved_bytes: u64,
+    },
+    /// Size and contents of the repository, from `repo stat`
+    RepoStat {
+        path: String,
+        blocks: usize,
+        size: u64,
+        pins: usize,
+        version: u32,
+    },
+    /// Blocks of a Kubo repository were imported, from `repo import-kubo`
+    KuboImported {
+        imported_blocks: usize,
+        imported_bytes: u64,
+        existing_blocks: usize,
+        skipped_blocks: usize,
+        pins: usize,
+    },
+    /// Cached content was removed
+    CacheCleared {
+        removed_blocks: usize,
+        removed_bytes: u64,
+    },
+    /// The command finished successfully
+    Done,
+    /// The command failed, with the exit code of the process
+    Error { message: String, code: i32 },
+}
+
+impl Message {
+    /// Print the message to stdout, as text or as a JSON line
+    pub fn print(&self, json: bool) {
+        if json {
+            println!("{}", serde_json::to_string(self).expect("messages always serialize"));
+        } else {
+            println!("{}", self);
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::PeerId { peer_id } => write!(f, "Local peer ID: {}", peer_id),
+            Message::Id {
+                id,
+                public_key,
+                addresses,
+                agent_version,
+                protocols,
+            } => {
+                writeln!(f, "ID: {}", id)?;
+                writeln!(f, "PublicKey: {}", public_key)?;
+                writeln!(f, "Addresses:")?;
+                for addr in addresses {
+                    writeln!(f, "  {}", addr)?;
+                }
+                writeln!(f, "AgentVersion: {}", agent_version)?;
+                write!(f, "Protocols:")?;
+                for protocol in protocols {
+                    write!(f, "\n  {}", protocol)?;
+                }
+                Ok(())
+            }
+            Message::Fetching { cid } => write!(f, "Fetching content with CID: {}", cid),
+            Message::Received { size, .. } => write!(f, "Received content from IPFS network ({} bytes)", size),
+            Message::Saved { path, .. } => write!(f, "Content saved to: {:?}", path),
+            Message::Pinned { cid, mode } => write!(f, "Pinned {} ({})", cid, mode),
+            Message::Unpinned { cid } => write!(f, "Unpinned {}", cid),
+            Message::Pin { cid, mode } => write!(f, "{} {}", cid, mode),
+            Message::BootstrapAdded { address } => write!(f, "Added bootstrap node {}", address),
+            Message::BootstrapRemoved { address } => write!(f, "Removed bootstrap node {}", address),
+            Message::Bootstrap { address } => write!(f, "{}", address),
+            Message::BootstrapReset => write!(f, "Reset the bootstrap list to the default IPFS bootstrap nodes"),
+            Message::Ref { cid } => write!(f, "{}", cid),
+            Message::Stat { cid, size, blocks, depth } => {
+                writeln!(f, "CID: {}", cid)?;
+                writeln!(f, "Size: {} ({} bytes)", HumanBytes(*size), size)?;
+                writeln!(f, "Blocks: {}", blocks)?;
+                write!(f, "Depth: {}", depth)
+            }
+            Message::Exported {
+                cid,
+                blocks,
+                bytes,
+                pinned,
+            } => {
+                write!(f, "Exported {} to Kubo: {} blocks, {}", cid, blocks, HumanBytes(*bytes))?;
+                if *pinned {
+                    write!(f, ", pinned")?;
+                }
+                Ok(())
+            }
+            Message::Verified { path, cid } => write!(f, "{:?} matches {}", path, cid),
+            Message::Cid { cid } => write!(f, "{}", cid),
+            Message::CidDetails {
+                cid,
+                version,
+                multibase,
+                codec,
+                codec_code,
+                hash_function,
+                hash_code,
+                digest_length,
+                digest,
+            } => {
+                writeln!(f, "CID: {}", cid)?;
+                writeln!(f, "Version: {}", version)?;
+                writeln!(f, "Multibase: {}", multibase)?;
+                writeln!(f, "Codec: {} (0x{:x})", codec, codec_code)?;
+                writeln!(f, "Multihash: {} (0x{:x}), {} bytes", hash_function, hash_code, digest_length)?;
+                write!(f, "Digest: {}", digest)
+            }
+            Message::BlockStored { cid, .. } => write!(f, "{}", cid),
+            Message::DagStored { cid, .. } => write!(f, "{}", cid),
+            Message::Provided { cid, blocks } => write!(f, "Announced {} blocks of {} on the IPFS network", blocks, cid),
+            Message::Key { name, peer_id } => write!(f, "{} {}", peer_id, name),
+            Message::KeyRenamed { name, new_name } => write!(f, "Renamed key {} to {}", name, new_name),
+            Message::KeyExported { name, path } => write!(f, "Exported key {} to {:?}", name, path),
+            Message::BlockStat { cid, size: Some(size) } => write!(f, "Key: {}\nSize: {}", cid, size),
+            Message::BlockStat { cid, size: None } => write!(f, "{} is not stored locally", cid),
+            Message::Stored { size, blocks } => write!(f, "Stored {} bytes in {} blocks", size, blocks),
+            Message::Added { cid, name, .. } => write!(f, "added {} {}", cid, name),
+            Message::Listening { address } => write!(f, "IPFS node configured to share content on {}", address),
+            Message::Published { blocks } => write!(f, "Announced {} pinned blocks on the IPFS network", blocks),
+            Message::NodeAddress { address } => write!(f, "Your node address: {}", address),
+            Message::ApiListening { address } => write!(f, "Control API listening on {}", address),
+            Message::MetricsListening { address } => write!(f, "Metrics available on {}", address),
+            Message::GatewayListening { address } => write!(f, "Gateway serving content on {}", address),
+            Message::Running => write!(f, "🎉 IPFS 节点正在运行...\n💡 按 Ctrl+C 停止节点"),
+            Message::Stopped => write!(f, "👋 IPFS 节点已停止"),
+            Message::Pong { peer_id, seq, rtt_ms } => write!(f, "Pong from {}: seq={} time={:.2} ms", peer_id, seq, rtt_ms),
+            Message::PingFailed { peer_id, seq, error } => write!(f, "No pong from {}: seq={} {}", peer_id, seq, error),
+            Message::PingStats {
+                peer_id,
+                sent,
+                received,
+                loss_percent,
+                min_ms,
+                avg_ms,
+                max_ms,
+            } => {
+                write!(
+                    f,
+                    "--- {} ping statistics ---\n{} pings sent, {} received, {:.1}% loss",
+                    peer_id, sent, received, loss_percent
+                )?;
+                if *received > 0 {
+                    write!(f, "\nrtt min/avg/max = {:.2}/{:.2}/{:.2} ms", min_ms, avg_ms, max_ms)?;
+                }
+                Ok(())
+            }
+            Message::Peer {
+                peer_id,
+                address,
+                direction,
+                transport,
+                agent_version,
+            } => write!(
+                f,
+                "{}/p2p/{} {} {} {}",
+                address,
+                peer_id,
+                direction,
+                transport,
+                agent_version.as_deref().unwrap_or("-")
+            ),
+            Message::Connected { peer_id, address } => write!(f, "Connected to {} at {}", peer_id, address),
+            Message::Blocked { entry, changed: true } => write!(f, "Blocked {}", entry),
+            Message::Blocked { entry, changed: false } => write!(f, "{} was already blocked", entry),
+            Message::Unblocked { entry } => write!(f, "Unblocked {}", entry),
+            Message::BlocklistEntry { entry } => write!(f, "{}", entry),
+            Message::PeerAddress { address, .. } => write!(f, "{}", address),
+            Message::Provider { peer_id, addresses, .. } => {
+                write!(f, "{}", peer_id)?;
+                for addr in addresses {
+                    write!(f, "\n  {}", addr)?;
+                }
+                Ok(())
+            }
+            Message::Bandwidth {
+                peer,
+                protocol,
+                total_in,
+                total_out,
+                rate_in,
+                rate_out,
+            } => {
+                match (peer, protocol) {
+                    (Some(peer), _) => writeln!(f, "Bandwidth of {}", peer)?,
+                    (None, Some(protocol)) => writeln!(f, "Bandwidth of {}", protocol)?,
+                    (None, None) => writeln!(f, "Bandwidth")?,
+                }
+                writeln!(f, "TotalIn: {}", HumanBytes(*total_in))?;
+                writeln!(f, "TotalOut: {}", HumanBytes(*total_out))?;
+                writeln!(f, "RateIn: {}/s", HumanBytes(*rate_in as u64))?;
+                write!(f, "RateOut: {}/s", HumanBytes(*rate_out as u64))
+            }
+            Message::Gc {
+                dry_run,
+                removed_blocks,
+                removed_bytes,
+                kept_blocks,
+            } => {
+                if *dry_run {
+                    write!(
+                        f,
+                        "Would remove {} unpinned blocks, reclaiming {} bytes ({} pinned blocks kept)",
+                        removed_blocks, removed_bytes, kept_blocks
+                    )
+                } else {
+                    write!(
+                        f,
+                        "Removed {} unpinned blocks, reclaimed {} bytes ({} pinned blocks kept)",
+                        removed_blocks, removed_bytes, kept_blocks
+                    )
+                }
+            }
+            Message::DedupStats {
+                blocks,
+                logical_bytes,
+                physical_bytes,
+                saved_bytes,
+            } => write!(
+                f,
+                "{} bytes of content stored in {} bytes, {} blocks ({} bytes saved by deduplication)",
+                logical_bytes, physical_bytes, blocks, saved_bytes
+            ),
+            Message::RepoStat {
+                path,
+                blocks,
+                size,
+                pins,
+                version,
+            } => {
+                writeln!(f, "NumObjects: {}", blocks)?;
+                writeln!(f, "RepoSize: {} ({} bytes)", HumanBytes(*size), size)?;
+                writeln!(f, "NumPins: {}", pins)?;
+                writeln!(f, "RepoPath: {}", path)?;
+                write!(f, "Version: {}", version)
+            }
+            Message::KuboImported {
+                imported_blocks,
+                imported_bytes,
+                existing_blocks,
+                skipped_blocks,
+                pins,
+            } => write!(
+                f,
+                "Imported {} blocks ({} bytes) and {} pins from Kubo, {} blocks already stored, {} invalid blocks skipped",
+                imported_blocks, imported_bytes, pins, existing_blocks, skipped_blocks
+            ),
+            Message::CacheCleared {
+                removed_blocks,
+                removed_bytes,
+            } => write!(
+                f,
+                "Removed {} cached blocks, reclaimed {} bytes",
+                removed_blocks, removed_bytes
+            ),
+            Message::Done => write!(f, "✅ 程序执行完成!"),
+            Message::Error { message, .. } => write!(f, "Error: {}", message),
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Pin tracking
+//!
+//! Pinned CIDs are protected from garbage collection and re-announced to the
+//! network when sharing. A recursive pin protects the whole DAG below a CID,
+//! a direct pin only protects the block itself. Pins are kept in a JSON file
+//! in the repo directory.
+//!
+//! Several processes may change the pins of a repository at once, such as the
+//! daemon and `miga pin add`. Changes are made under an exclusive lock of a
+//! lock file next to the pin file, to the pins as they are on disk at that
+//! moment, so no process overwrites the pins another one added.
+
+use anyhow::{Context, Result};
+use cid::{multihash::Multihash, Cid};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+use tempfile::NamedTempFile;
+
+use crate::{blockstore::Blockstore, dag};
+
+/// How much of a DAG a pin protects
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PinMode {
+    /// Only the pinned block itself
+    Direct,
+    /// The pinned block and every block reachable from it
+    Recursive,
+}
+
+impl fmt::Display for PinMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PinMode::Direct => write!(f, "direct"),
+            PinMode::Recursive => write!(f, "recursive"),
+        }
+    }
+}
+
+/// The set of pinned CIDs, backed by a file on disk
+#[derive(Debug)]
+pub struct PinStore {
+    /// File the pins are saved to
+    path: PathBuf,
+    /// Pinned CIDs (as strings) and their pin mode
+    pins: BTreeMap<String, PinMode>,
+}
+
+impl PinStore {
+    /// Open the pin store, loading existing pins from the given file
+    ///
+    /// # Arguments
+    /// * `path` - File holding the pins; it is created on the first change
+    ///
+    /// # Returns
+    /// * `Result<PinStore>` - The opened pin store
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let pins = read_pins(&path)?;
+        Ok(Self { path, pins })
+    }
+
+    /// Read the pins from disk again, picking up changes made by other processes
+    pub fn reload(&mut self) -> Result<()> {
+        self.pins = read_pins(&self.path)?;
+        Ok(())
+    }
+
+    /// Pin a CID, replacing any existing pin of the CID
+    pub fn add(&mut self, cid: &Cid, mode: PinMode) -> Result<()> {
+        let _lock = self.lock()?;
+        self.reload()?;
+        self.pins.insert(cid.to_string(), mode);
+        self.save()
+    }
+
+    /// Remove the pin of a CID
+    ///
+    /// # Returns
+    /// * `Result<bool>` - Whether the CID was pinned
+    pub fn remove(&mut self, cid: &Cid) -> Result<bool> {
+        let _lock = self.lock()?;
+        self.reload()?;
+        let removed = self.pins.remove(&cid.to_string()).is_some();
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// All pinned CIDs and their pin modes
+    pub fn list(&self) -> Vec<(Cid, PinMode)> {
+        self.pins
+            .iter()
+            .filter_map(|(cid, mode)| Cid::try_from(cid.as_str()).ok().map(|cid| (cid, *mode)))
+            .collect()
+    }
+
+    /// Multihashes of all blocks protected by a pin
+    ///
+    /// Walks the DAGs below all recursive pins using the blocks in the blockstore.
+    /// Blocks are identified by multihash, since the blockstore doesn't keep track
+    /// of the codec a block was stored under.
+    pub fn pinned_blocks(&self, blockstore: &Blockstore) -> Result<HashSet<Multihash<64>>> {
+        let mut pinned = HashSet::new();
+        let mut visited = HashSet::new();
+        let mut stack: Vec<Cid> = Vec::new();
+
+        for (cid, mode) in self.list() {
+            match mode {
+                PinMode::Direct => {
+                    pinned.insert(*cid.hash());
+                }
+                PinMode::Recursive => stack.push(cid),
+            }
+        }
+
+        // Walk the DAGs below all recursive pins
+        while let Some(cid) = stack.pop() {
+            if !visited.insert(*cid.hash()) {
+                continue;
+            }
+            pinned.insert(*cid.hash());
+            let Some(data) = blockstore.get(&cid)? else {
+                warn!("Pinned block {} is missing from the blockstore", cid);
+                continue;
+            };
+            match dag::links(&cid, &data) {
+                Ok(links) => stack.extend(links),
+                Err(e) => warn!("Failed to read links of pinned block {}: {}", cid, e),
+            }
+        }
+
+        Ok(pinned)
+    }
+
+    /// Take the exclusive lock of the pin file, held until the returned file is dropped
+    fn lock(&self) -> Result<File> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let lock_path = self.path.with_extension("lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .with_context(|| format!("Failed to open pin lock {:?}", lock_path))?;
+        file.lock().with_context(|| format!("Failed to lock {:?}", lock_path))?;
+        Ok(file)
+    }
+
+    /// Write the pins to disk
+    fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let mut tmp = NamedTempFile::new_in(dir).with_context(|| format!("Failed to save pins to {:?}", self.path))?;
+        tmp.write_all(&serde_json::to_vec_pretty(&self.pins)?)
+            .with_context(|| format!("Failed to save pins to {:?}", self.path))?;
+        tmp.persist(&self.path)
+            .with_context(|| format!("Failed to move pins into place at {:?}", self.path))?;
+        Ok(())
+    }
+}
+
+/// Read the pins from a pin file, none if it doesn't exist yet
+fn read_pins(path: &Path) -> Result<BTreeMap<String, PinMode>> {
+    match fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).with_context(|| format!("Invalid pin file {:?}", path)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e).with_context(|| format!("Failed to read pins from {:?}", path)),
+    }
+}
+
+/// Default location of the pin file inside a repo directory
+pub fn pin_file(repo_dir: &Path) -> PathBuf {
+    repo_dir.join("pins.json")
+}
@@ -0,0 +1,84 @@
+//! Download progress display
+//!
+//! Every download gets a progress bar on stderr showing the bytes fetched, the
+//! blocks retrieved, the providers that sent us blocks and the transfer speed.
+//! Once the root block reveals the size of the file, the bar also shows how
+//! much is left and an ETA. Nothing is drawn when stderr is not a terminal.
+
+use cid::Cid;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::fetch::FileReader;
+
+/// Layout of the progress bar of a file whose size is known
+const SIZED_TEMPLATE: &str =
+    "{spinner:.green} {prefix} [{bar:30.cyan/blue}] {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta} {msg}";
+
+/// Layout of the progress bar of a file whose size is unknown
+const UNSIZED_TEMPLATE: &str = "{spinner:.green} {prefix} {bytes} {binary_bytes_per_sec} {msg}";
+
+/// Progress display of a single download
+pub struct FetchProgress {
+    /// The progress bar
+    bar: ProgressBar,
+    /// Number of blocks read so far
+    blocks: AtomicU64,
+}
+
+impl FetchProgress {
+    /// Add a progress bar for fetching a CID
+    ///
+    /// # Arguments
+    /// * `multi` - The set of progress bars drawn together
+    /// * `cid` - The CID being fetched
+    pub fn new(multi: &MultiProgress, cid: &Cid) -> Self {
+        let bar = multi.add(ProgressBar::no_length());
+        bar.set_style(style(UNSIZED_TEMPLATE));
+        bar.set_prefix(cid.to_string());
+        bar.set_message("searching...");
+        bar.enable_steady_tick(Duration::from_millis(100));
+        Self {
+            bar,
+            blocks: AtomicU64::new(0),
+        }
+    }
+
+    /// Update the display after a chunk of content was written
+    ///
+    /// # Arguments
+    /// * `reader` - Reader of the file being fetched
+    /// * `position` - Number of bytes of the file written so far
+    /// * `providers` - Number of providers that have sent us blocks
+    pub fn update(&self, reader: &FileReader, position: u64, providers: usize) {
+        if let Some(size) = reader.size()
+            && self.bar.length() != Some(size)
+        {
+            self.bar.set_length(size);
+            self.bar.set_style(style(SIZED_TEMPLATE));
+        }
+        self.bar.set_position(position);
+        self.blocks.store(reader.blocks(), Ordering::Relaxed);
+        self.bar.set_message(format!("{} blocks, {} providers", reader.blocks(), providers));
+    }
+
+    /// Number of blocks and bytes of the file fetched so far
+    pub fn fetched(&self) -> (u64, u64) {
+        (self.blocks.load(Ordering::Relaxed), self.bar.position())
+    }
+
+    /// Remove the progress bar once the download is over
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+/// Create a progress bar style from a template
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("Invalid progress bar template")
+        .progress_chars("=> ")
+}
@@ -0,0 +1,156 @@
+//! Minimal protobuf wire format encoding and decoding
+//!
+//! IPFS data structures such as dag-pb nodes are protobuf messages. They are
+//! small and fixed, so they are encoded and decoded by hand instead of pulling
+//! in a protobuf code generator.
+
+use anyhow::{anyhow, Result};
+
+/// A single field value as it appears on the wire
+#[derive(Debug, Clone, Copy)]
+pub enum Field<'a> {
+    /// Wire type 0: a varint
+    Varint(u64),
+    /// Wire type 1: a 64-bit fixed-size value
+    // No message MIGA reads has fixed-size fields, they are only decoded to be skipped
+    #[allow(dead_code)]
+    Fixed64(u64),
+    /// Wire type 2: length-delimited bytes (strings, bytes and embedded messages)
+    Bytes(&'a [u8]),
+    /// Wire type 5: a 32-bit fixed-size value
+    // Only decoded to be skipped, like Fixed64
+    #[allow(dead_code)]
+    Fixed32(u32),
+}
+
+/// Sequential reader over the fields of an encoded protobuf message
+pub struct Reader<'a> {
+    /// Remaining undecoded bytes
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    /// Create a reader over an encoded message
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    /// Read the next field of the message
+    ///
+    /// # Returns
+    /// * `Result<Option<(u32, Field)>>` - The field number and value, or None at the end of the message
+    pub fn next_field(&mut self) -> Result<Option<(u32, Field<'a>)>> {
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        let tag = self.read_varint()?;
+        let number = u32::try_from(tag >> 3).map_err(|_| anyhow!("Invalid protobuf field number"))?;
+        let field = match tag & 0x7 {
+            0 => Field::Varint(self.read_varint()?),
+            1 => Field::Fixed64(u64::from_le_bytes(self.read_bytes(8)?.try_into()?)),
+            2 => {
+                let len = usize::try_from(self.read_varint()?)?;
+                Field::Bytes(self.read_bytes(len)?)
+            }
+            5 => Field::Fixed32(u32::from_le_bytes(self.read_bytes(4)?.try_into()?)),
+            wire_type => return Err(anyhow!("Unsupported protobuf wire type {}", wire_type)),
+        };
+        Ok(Some((number, field)))
+    }
+
+    /// Read an unsigned LEB128 varint
+    fn read_varint(&mut self) -> Result<u64> {
+        read_varint(&mut self.buf)
+    }
+
+    /// Read a fixed number of bytes
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(anyhow!("Truncated protobuf message"));
+        }
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(bytes)
+    }
+}
+
+impl<'a> Field<'a> {
+    /// The value of a varint field
+    pub fn as_varint(&self) -> Result<u64> {
+        match self {
+            Field::Varint(value) => Ok(*value),
+            _ => Err(anyhow!("Expected a varint protobuf field")),
+        }
+    }
+
+    /// The value of a length-delimited field
+    pub fn as_bytes(&self) -> Result<&'a [u8]> {
+        match self {
+            Field::Bytes(bytes) => Ok(bytes),
+            _ => Err(anyhow!("Expected a length-delimited protobuf field")),
+        }
+    }
+}
+
+/// Builder for an encoded protobuf message
+///
+/// Fields are written in the order the methods are called, so callers are
+/// responsible for emitting them in canonical order.
+#[derive(Debug, Default)]
+pub struct Writer {
+    /// Encoded message so far
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    /// Create an empty message
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a varint field
+    pub fn varint(&mut self, number: u32, value: u64) -> &mut Self {
+        write_varint(&mut self.buf, u64::from(number) << 3);
+        write_varint(&mut self.buf, value);
+        self
+    }
+
+    /// Append a length-delimited field (bytes, string or embedded message)
+    pub fn bytes(&mut self, number: u32, value: &[u8]) -> &mut Self {
+        write_varint(&mut self.buf, (u64::from(number) << 3) | 2);
+        write_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(value);
+        self
+    }
+
+    /// Finish the message and return its encoding
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+/// Append an unsigned LEB128 varint to a buffer
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Read an unsigned LEB128 varint from the front of a buffer
+///
+/// The buffer is advanced past the varint.
+pub fn read_varint(buf: &mut &[u8]) -> Result<u64> {
+    let bytes: &[u8] = buf;
+    let mut value = 0u64;
+    for (i, byte) in bytes.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            *buf = &bytes[i + 1..];
+            return Ok(value);
+        }
+    }
+    Err(anyhow!("Truncated or overlong protobuf varint"))
+}
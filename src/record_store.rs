@@ -0,0 +1,330 @@
+//! Disk-backed Kademlia record store
+//!
+//! Wraps libp2p's in-memory store and writes every change through to disk, so
+//! published values and provider records survive restarts of a long-running node.
+//! Each record is kept in its own JSON file under the store directory:
+//!
+//! - `records/<key>.json` holds a value record
+//! - `providers/<key>.json` holds all provider records for a key
+
+use anyhow::{Context, Result};
+use cid::multibase::Base;
+use libp2p::{
+    kad::{
+        self,
+        store::{MemoryStore, MemoryStoreConfig, RecordStore},
+        ProviderRecord, Record, RecordKey,
+    },
+    Multiaddr, PeerId,
+};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tempfile::NamedTempFile;
+
+/// Largest record value accepted by the store
+/// Earlier MIGA versions publish whole blocks as records, much larger than the libp2p default of 65 KiB,
+/// and fetches still look them up, so the store keeps them for other nodes
+const MAX_VALUE_BYTES: usize = 4 * 1024 * 1024;
+
+/// Largest number of keys the local node can be a provider of
+/// A sharing node provides every pinned block, far more than the libp2p default of 1024
+const MAX_PROVIDED_KEYS: usize = 1 << 20;
+
+/// A Kademlia record store that persists its contents to disk
+pub struct PersistentStore {
+    /// In-memory copy of all records, used to answer queries
+    inner: MemoryStore,
+    /// Directory holding value records
+    records_dir: PathBuf,
+    /// Directory holding provider records
+    providers_dir: PathBuf,
+}
+
+/// On-disk representation of a value record
+#[derive(Serialize, Deserialize)]
+struct StoredRecord {
+    /// Record key, base64 encoded
+    key: String,
+    /// Record value, base64 encoded
+    value: String,
+    /// Peer ID of the original publisher
+    publisher: Option<String>,
+    /// Expiry time as seconds since the Unix epoch
+    expires: Option<u64>,
+}
+
+/// On-disk representation of a provider record
+#[derive(Serialize, Deserialize)]
+struct StoredProvider {
+    /// Peer ID of the provider
+    provider: String,
+    /// Known addresses of the provider
+    addresses: Vec<String>,
+    /// Expiry time as seconds since the Unix epoch
+    expires: Option<u64>,
+}
+
+impl PersistentStore {
+    /// Open the record store in the given directory, loading all records that haven't expired
+    ///
+    /// # Arguments
+    /// * `dir` - Directory holding the persisted records
+    /// * `local_peer_id` - Peer ID of the local node
+    ///
+    /// # Returns
+    /// * `Result<PersistentStore>` - The opened store
+    pub fn open(dir: &Path, local_peer_id: PeerId) -> Result<Self> {
+        let records_dir = dir.join("records");
+        let providers_dir = dir.join("providers");
+        fs::create_dir_all(&records_dir)
+            .with_context(|| format!("Failed to create record store directory {:?}", records_dir))?;
+        fs::create_dir_all(&providers_dir)
+            .with_context(|| format!("Failed to create record store directory {:?}", providers_dir))?;
+
+        let config = MemoryStoreConfig {
+            max_value_bytes: MAX_VALUE_BYTES,
+            max_provided_keys: MAX_PROVIDED_KEYS,
+            ..Default::default()
+        };
+        let mut inner = MemoryStore::with_config(local_peer_id, config);
+
+        let now = Instant::now();
+        let mut loaded = 0;
+
+        // Load value records
+        for path in json_files(&records_dir)? {
+            match read_record(&path) {
+                Ok(record) if !record.is_expired(now) => {
+                    if inner.put(record).is_ok() {
+                        loaded += 1;
+                    }
+                }
+                Ok(_) => {
+                    let _ = fs::remove_file(&path);
+                }
+                Err(e) => warn!("Skipping unreadable DHT record {:?}: {}", path, e),
+            }
+        }
+
+        // Load provider records
+        for path in json_files(&providers_dir)? {
+            match read_providers(&path) {
+                Ok(providers) => {
+                    for provider in providers.into_iter().filter(|p| !p.is_expired(now)) {
+                        if inner.add_provider(provider).is_ok() {
+                            loaded += 1;
+                        }
+                    }
+                }
+                Err(e) => warn!("Skipping unreadable provider records {:?}: {}", path, e),
+            }
+        }
+
+        debug!("Loaded {} DHT records from {:?}", loaded, dir);
+        Ok(Self {
+            inner,
+            records_dir,
+            providers_dir,
+        })
+    }
+
+    /// Write the value record for a key to disk, or delete its file if it no longer exists
+    fn persist_record(&self, key: &RecordKey) {
+        let path = key_path(&self.records_dir, key);
+        let result = match self.inner.get(key) {
+            Some(record) => write_json(&path, &StoredRecord::from(record.as_ref())),
+            None => remove_file(&path),
+        };
+        if let Err(e) = result {
+            warn!("Failed to persist DHT record {:?}: {}", path, e);
+        }
+    }
+
+    /// Write the provider records for a key to disk, or delete its file if none are left
+    fn persist_providers(&self, key: &RecordKey) {
+        let path = key_path(&self.providers_dir, key);
+        let providers = self.inner.providers(key);
+        let result = if providers.is_empty() {
+            remove_file(&path)
+        } else {
+            let stored: Vec<StoredProvider> = providers.iter().map(StoredProvider::from).collect();
+            write_json(&path, &stored)
+        };
+        if let Err(e) = result {
+            warn!("Failed to persist provider records {:?}: {}", path, e);
+        }
+    }
+}
+
+impl RecordStore for PersistentStore {
+    type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a> where Self: 'a;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a> where Self: 'a;
+
+    fn get(&self, k: &RecordKey) -> Option<Cow<'_, Record>> {
+        self.inner.get(k)
+    }
+
+    fn put(&mut self, r: Record) -> kad::store::Result<()> {
+        let key = r.key.clone();
+        self.inner.put(r)?;
+        self.persist_record(&key);
+        Ok(())
+    }
+
+    fn remove(&mut self, k: &RecordKey) {
+        self.inner.remove(k);
+        self.persist_record(k);
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.inner.records()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> kad::store::Result<()> {
+        let key = record.key.clone();
+        self.inner.add_provider(record)?;
+        self.persist_providers(&key);
+        Ok(())
+    }
+
+    fn providers(&self, key: &RecordKey) -> Vec<ProviderRecord> {
+        self.inner.providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.inner.provided()
+    }
+
+    fn remove_provider(&mut self, k: &RecordKey, p: &PeerId) {
+        self.inner.remove_provider(k, p);
+        self.persist_providers(k);
+    }
+}
+
+impl From<&Record> for StoredRecord {
+    fn from(record: &Record) -> Self {
+        Self {
+            key: Base::Base64.encode(record.key.as_ref()),
+            value: Base::Base64.encode(&record.value),
+            publisher: record.publisher.map(|p| p.to_string()),
+            expires: record.expires.map(instant_to_unix),
+        }
+    }
+}
+
+impl From<&ProviderRecord> for StoredProvider {
+    fn from(record: &ProviderRecord) -> Self {
+        Self {
+            provider: record.provider.to_string(),
+            addresses: record.addresses.iter().map(|a| a.to_string()).collect(),
+            expires: record.expires.map(instant_to_unix),
+        }
+    }
+}
+
+/// Read a value record from a JSON file
+fn read_record(path: &Path) -> Result<Record> {
+    let stored: StoredRecord = serde_json::from_slice(&fs::read(path)?)?;
+    Ok(Record {
+        key: RecordKey::from(Base::Base64.decode(&stored.key)?),
+        value: Base::Base64.decode(&stored.value)?,
+        publisher: stored.publisher.map(|p| p.parse()).transpose()?,
+        expires: stored.expires.map(unix_to_instant),
+    })
+}
+
+/// Read the provider records for one key from a JSON file
+fn read_providers(path: &Path) -> Result<Vec<ProviderRecord>> {
+    let key = key_from_path(path)?;
+    let stored: Vec<StoredProvider> = serde_json::from_slice(&fs::read(path)?)?;
+    stored
+        .into_iter()
+        .map(|p| {
+            Ok(ProviderRecord {
+                key: key.clone(),
+                provider: p.provider.parse()?,
+                addresses: p
+                    .addresses
+                    .iter()
+                    .filter_map(|a| a.parse::<Multiaddr>().ok())
+                    .collect(),
+                expires: p.expires.map(unix_to_instant),
+            })
+        })
+        .collect()
+}
+
+/// File holding the record(s) for a key, named after the base32 encoding of the key
+fn key_path(dir: &Path, key: &RecordKey) -> PathBuf {
+    dir.join(format!("{}.json", Base::Base32Lower.encode(key.as_ref())))
+}
+
+/// Recover a record key from the name of its file
+fn key_from_path(path: &Path) -> Result<RecordKey> {
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .context("Invalid record file name")?;
+    Ok(RecordKey::from(Base::Base32Lower.decode(stem)?))
+}
+
+/// All JSON files in a directory
+fn json_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Atomically write a value as JSON
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut tmp = NamedTempFile::new_in(dir).with_context(|| format!("Failed to write {:?}", path))?;
+    tmp.write_all(&serde_json::to_vec(value)?).with_context(|| format!("Failed to write {:?}", path))?;
+    tmp.persist(path).with_context(|| format!("Failed to move {:?} into place", path))?;
+    Ok(())
+}
+
+/// Remove a file, ignoring it if it doesn't exist
+fn remove_file(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Convert a monotonic expiry time to seconds since the Unix epoch
+fn instant_to_unix(instant: Instant) -> u64 {
+    let now = Instant::now();
+    let wall_clock = if instant >= now {
+        SystemTime::now() + (instant - now)
+    } else {
+        SystemTime::now() - (now - instant)
+    };
+    wall_clock
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Convert seconds since the Unix epoch to a monotonic expiry time
+fn unix_to_instant(secs: u64) -> Instant {
+    let expires = UNIX_EPOCH + Duration::from_secs(secs);
+    let now = Instant::now();
+    match expires.duration_since(SystemTime::now()) {
+        Ok(remaining) => now + remaining,
+        // Already expired: any instant in the past will do
+        Err(_) => now.checked_sub(Duration::from_secs(1)).unwrap_or(now),
+    }
+}
@@ -0,0 +1,86 @@
+//! Persistence of the Kademlia routing table
+//!
+//! Bootstrapping from a handful of hardcoded nodes is slow and fragile. Saving the
+//! peers we know about when the node stops, and adding them back to the routing
+//! table on the next start, lets the node resume with a warm routing table.
+
+use anyhow::{Context, Result};
+use libp2p::{kad, Multiaddr, PeerId};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+use crate::record_store::PersistentStore;
+
+/// A peer from the routing table together with its known addresses
+#[derive(Serialize, Deserialize)]
+struct SavedPeer {
+    /// Peer ID of the peer
+    peer_id: String,
+    /// Known addresses of the peer
+    addrs: Vec<String>,
+}
+
+/// Add the peers saved in a previous run to the routing table
+///
+/// A missing file is not an error, it simply means there is nothing to restore.
+///
+/// # Arguments
+/// * `path` - File the routing table was saved to
+/// * `kademlia` - The Kademlia behaviour to add the peers to
+///
+/// # Returns
+/// * `Result<usize>` - Number of peers restored
+pub fn load(path: &Path, kademlia: &mut kad::Behaviour<PersistentStore>) -> Result<usize> {
+    if !path.exists() {
+        return Ok(0);
+    }
+
+    let data = fs::read(path).with_context(|| format!("Failed to read routing table from {:?}", path))?;
+    let peers: Vec<SavedPeer> = serde_json::from_slice(&data)
+        .with_context(|| format!("Invalid routing table file {:?}", path))?;
+
+    let mut restored = 0;
+    for peer in peers {
+        let Ok(peer_id) = peer.peer_id.parse::<PeerId>() else {
+            warn!("Skipping invalid peer ID in routing table: {}", peer.peer_id);
+            continue;
+        };
+        for addr in peer.addrs.iter().filter_map(|a| a.parse::<Multiaddr>().ok()) {
+            kademlia.add_address(&peer_id, addr);
+        }
+        restored += 1;
+    }
+
+    debug!("Restored {} peers from {:?}", restored, path);
+    Ok(restored)
+}
+
+/// Save all peers in the routing table and their addresses to a file
+///
+/// # Arguments
+/// * `path` - File to save the routing table to
+/// * `kademlia` - The Kademlia behaviour whose routing table is saved
+///
+/// # Returns
+/// * `Result<usize>` - Number of peers saved
+pub fn save(path: &Path, kademlia: &mut kad::Behaviour<PersistentStore>) -> Result<usize> {
+    let mut peers = Vec::new();
+    for bucket in kademlia.kbuckets() {
+        for entry in bucket.iter() {
+            peers.push(SavedPeer {
+                peer_id: entry.node.key.preimage().to_string(),
+                addrs: entry.node.value.iter().map(|a| a.to_string()).collect(),
+            });
+        }
+    }
+
+    // Write to a temporary file first so a crash never leaves a truncated table behind
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, serde_json::to_vec_pretty(&peers)?)
+        .with_context(|| format!("Failed to save routing table to {:?}", path))?;
+    fs::rename(&tmp_path, path)?;
+
+    debug!("Saved {} peers to {:?}", peers.len(), path);
+    Ok(peers.len())
+}
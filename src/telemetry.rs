@@ -0,0 +1,63 @@
+//! Export of fetch traces to OpenTelemetry
+//!
+//! The fetch pipeline is instrumented with `tracing` spans: a `fetch` span for
+//! every file, with `block_fetch` spans for its blocks below it. Each block
+//! fetch covers the `provider_lookup` in the DHT, the `dial` of every provider
+//! asked over Bitswap and the `dht_record` lookup racing it; writing the
+//! content out is covered by `write` spans. Blocks arriving over Bitswap are
+//! hashed in `verify` spans.
+//!
+//! Without an exporter the spans cost next to nothing. With an OTLP endpoint,
+//! they are sent to a collector (Jaeger, Tempo, ...) to see where a slow fetch
+//! spent its time.
+
+use anyhow::Result;
+use log::warn;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing::{Level, Subscriber};
+use tracing_subscriber::{filter::Targets, registry::LookupSpan, Layer};
+
+/// Exporter of the spans, flushing the remaining spans when dropped
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// Create an exporter of spans to an OTLP collector
+    ///
+    /// # Arguments
+    /// * `endpoint` - OTLP/HTTP traces endpoint, e.g. `http://localhost:4318/v1/traces`
+    ///
+    /// # Returns
+    /// * `Result<Telemetry>` - The exporter; keep it alive for as long as spans should be exported
+    pub fn new(endpoint: &str) -> Result<Self> {
+        let exporter = SpanExporter::builder().with_http().with_endpoint(endpoint).build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name("miga").build())
+            .build();
+        Ok(Self { provider })
+    }
+
+    /// Subscriber layer passing spans to the exporter
+    ///
+    /// Only MIGA's own spans are exported, not those of libp2p.
+    pub fn layer<S>(&self) -> impl Layer<S> + Send + Sync + use<S>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span> + Send + Sync,
+    {
+        tracing_opentelemetry::layer()
+            .with_tracer(self.provider.tracer("miga"))
+            .with_filter(Targets::new().with_target("miga", Level::TRACE))
+    }
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(e) = self.provider.shutdown() {
+            warn!("Failed to export the remaining spans: {}", e);
+        }
+    }
+}
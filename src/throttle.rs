@@ -0,0 +1,176 @@
+//! Limits on the transfer rates of a node
+//!
+//! Share nodes often run on home connections, where an unthrottled node can
+//! take all of the upstream bandwidth. A [`RateLimiter`] caps the rate of the
+//! transfers that go through it: the blocks sent to and received from peers
+//! over Bitswap, and the content the gateway serves. Each direction has a
+//! limiter of its own, shared by all transfers in that direction.
+
+use anyhow::{anyhow, Result};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// Limits on the transfer rates of a node, in bytes per second
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    /// Limit on the data sent to peers, `None` for no cap
+    pub upload: Option<RateLimiter>,
+    /// Limit on the data received from peers, `None` for no cap
+    pub download: Option<RateLimiter>,
+}
+
+impl RateLimits {
+    /// Create the limits from rates in bytes per second, `None` for no cap
+    pub fn new(upload: Option<u64>, download: Option<u64>) -> Self {
+        Self {
+            upload: upload.map(RateLimiter::new),
+            download: download.map(RateLimiter::new),
+        }
+    }
+}
+
+/// Token bucket limiting the rate of the transfers that go through it
+///
+/// The bucket holds up to one second's worth of bytes, so short bursts pass
+/// right away. A transfer larger than what's left still passes, but the
+/// transfers after it wait until the bucket has refilled. Cloning is cheap;
+/// all clones share the same bucket.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    /// Bytes per second that may pass
+    rate: u64,
+    /// Bytes that may pass right now, negative after a large transfer
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+/// State of a token bucket
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes that may pass right now
+    tokens: f64,
+    /// When the tokens were last refilled
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    /// Create a limiter letting `rate` bytes per second pass
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: rate as f64,
+                refilled: Instant::now(),
+            })),
+        }
+    }
+
+    /// Bytes per second that may pass
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Wait until `bytes` may pass, and take them from the bucket
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let rate = self.rate as f64;
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refill).min(rate) - bytes as f64;
+            bucket.refilled = now;
+            // Whoever comes next waits for the debt to be paid off as well
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Parse a rate in bytes per second, e.g. "1048576", "500KB", "1MiB" or "2.5M/s"
+///
+/// Decimal units (K, M, G) are powers of 1000, binary units (Ki, Mi, Gi) are
+/// powers of 1024. A trailing "B" and "/s" are optional.
+///
+/// # Arguments
+/// * `s` - The rate to parse
+///
+/// # Returns
+/// * `Result<u64>` - The rate in bytes per second
+pub fn parse_rate(s: &str) -> Result<u64> {
+    let trimmed = s.trim();
+    parse_bytes(trimmed.strip_suffix("/s").unwrap_or(trimmed))
+        .ok_or_else(|| anyhow!("Invalid rate {:?}, expected bytes per second such as 500KB or 1MiB", s))
+}
+
+/// Parse a size in bytes, e.g. "1048576", "500KB" or "64MiB"
+///
+/// Units are the same as for [`parse_rate`].
+///
+/// # Arguments
+/// * `s` - The size to parse
+///
+/// # Returns
+/// * `Result<u64>` - The size in bytes
+pub fn parse_size(s: &str) -> Result<u64> {
+    parse_bytes(s).ok_or_else(|| anyhow!("Invalid size {:?}, expected bytes such as 500KB or 64MiB", s))
+}
+
+/// Parse a number of bytes with an optional decimal or binary unit
+fn parse_bytes(s: &str) -> Option<u64> {
+    let trimmed = s.trim();
+    let unit_start = trimmed.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(unit_start);
+    let number: f64 = number.trim().parse().ok()?;
+
+    let unit = unit.trim().trim_end_matches(['B', 'b']).to_ascii_lowercase();
+    let multiplier = match unit.as_str() {
+        "" => 1.0,
+        "k" => 1e3,
+        "m" => 1e6,
+        "g" => 1e9,
+        "ki" => 1024.0,
+        "mi" => 1024.0 * 1024.0,
+        "gi" => 1024.0 * 1024.0 * 1024.0,
+        _ => return None,
+    };
+    let bytes = number * multiplier;
+    (bytes.is_finite() && bytes >= 0.0).then_some(bytes as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("500KB").unwrap(), 500_000);
+        assert_eq!(parse_rate("1MiB").unwrap(), 1024 * 1024);
+        assert_eq!(parse_rate("2.5M/s").unwrap(), 2_500_000);
+        assert_eq!(parse_rate(" 100 kb/s ").unwrap(), 100_000);
+        assert_eq!(parse_rate("0").unwrap(), 0);
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("1048576").unwrap(), 1_048_576);
+        assert_eq!(parse_size("64MiB").unwrap(), 64 * 1024 * 1024);
+        assert_eq!(parse_size("1GB").unwrap(), 1_000_000_000);
+    }
+
+    #[test]
+    fn rejects_invalid_amounts() {
+        for invalid in ["-1", "1XB", "", "KB", "fast", "1e400"] {
+            assert!(parse_rate(invalid).is_err(), "{:?} parsed as a rate", invalid);
+            assert!(parse_size(invalid).is_err(), "{:?} parsed as a size", invalid);
+        }
+        assert!(parse_size("1MB/s").is_err());
+    }
+}
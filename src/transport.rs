@@ -0,0 +1,164 @@
+//! Transports of a node
+//!
+//! Connections run over QUIC, or over TCP secured with TLS 1.3 or Noise and
+//! multiplexed with Yamux, the same stack as Kubo's default. In a private
+//! network, TCP connections start with a handshake on the pre-shared key
+//! instead, and QUIC isn't used. Every connection is counted by the
+//! [`bandwidth`] accounting.
+
+use anyhow::Result;
+use either::Either;
+use futures::{future, TryFutureExt};
+use libp2p::{
+    core::{
+        muxing::StreamMuxerBox,
+        transport::Boxed,
+        upgrade::{self, InboundConnectionUpgrade, OutboundConnectionUpgrade, UpgradeInfo},
+    },
+    identity, noise,
+    pnet::{PnetConfig, PreSharedKey},
+    quic, tcp, tls, yamux, PeerId, Transport,
+};
+
+use crate::bandwidth::{self, Bandwidth};
+
+/// Build the transport of a node
+///
+/// # Arguments
+/// * `keypair` - The node's identity keypair, which secures its connections
+/// * `swarm_key` - Pre-shared key of the private network to join, if any
+/// * `bandwidth` - Where the bytes of every connection are counted
+///
+/// # Returns
+/// * `Result<Boxed<(PeerId, StreamMuxerBox)>>` - The transport, before DNS resolution is added
+pub(crate) fn build(
+    keypair: &identity::Keypair,
+    swarm_key: Option<PreSharedKey>,
+    bandwidth: Bandwidth,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    let tcp = tcp::tokio::Transport::new(tcp::Config::default());
+    let transport = match swarm_key {
+        None => {
+            // TLS is offered first, like Kubo does
+            let security = SelectSecurity(tls::Config::new(keypair)?, noise::Config::new(keypair)?);
+            let tcp = tcp
+                .upgrade(upgrade::Version::V1Lazy)
+                .authenticate(security)
+                .multiplex(yamux::Config::default())
+                .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)));
+            let quic = quic::tokio::Transport::new(quic::Config::new(keypair))
+                .map(|(peer, connection), _| (peer, StreamMuxerBox::new(connection)));
+            quic.or_transport(tcp).map(|either, _| either.into_inner()).boxed()
+        }
+        // QUIC has no place for the handshake on the key, and the key already
+        // keeps other implementations out, so Noise over TCP suffices
+        Some(psk) => tcp
+            .and_then(move |socket, _| PnetConfig::new(psk).handshake(socket))
+            .upgrade(upgrade::Version::V1Lazy)
+            .authenticate(noise::Config::new(keypair)?)
+            .multiplex(yamux::Config::default())
+            .map(|(peer, muxer), _| (peer, StreamMuxerBox::new(muxer)))
+            .boxed(),
+    };
+
+    Ok(transport
+        .map(move |(peer, muxer), _| {
+            let counted = bandwidth::Muxer::new(muxer, peer, bandwidth.clone());
+            (peer, StreamMuxerBox::new(counted))
+        })
+        .boxed())
+}
+
+/// Security upgrade offering two protocols, the first one preferred
+///
+/// The peer picks the first of them it supports, and the connection is
+/// secured with that one.
+#[derive(Debug, Clone)]
+struct SelectSecurity<A, B>(A, B);
+
+impl<A: UpgradeInfo, B: UpgradeInfo> UpgradeInfo for SelectSecurity<A, B> {
+    type Info = Either<A::Info, B::Info>;
+    type InfoIter = Vec<Self::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        let first = self.0.protocol_info().into_iter().map(Either::Left);
+        let second = self.1.protocol_info().into_iter().map(Either::Right);
+        first.chain(second).collect()
+    }
+}
+
+/// Future of a connection being secured with the first protocol
+type SecuringFirst<F, TA, TB, EA, EB> = future::MapOk<
+    future::MapErr<F, fn(EA) -> Either<EA, EB>>,
+    fn((PeerId, TA)) -> (PeerId, future::Either<TA, TB>),
+>;
+
+/// Future of a connection being secured with the second protocol
+type SecuringSecond<F, TA, TB, EA, EB> = future::MapOk<
+    future::MapErr<F, fn(EB) -> Either<EA, EB>>,
+    fn((PeerId, TB)) -> (PeerId, future::Either<TA, TB>),
+>;
+
+impl<C, A, B, TA, TB, EA, EB> InboundConnectionUpgrade<C> for SelectSecurity<A, B>
+where
+    A: InboundConnectionUpgrade<C, Output = (PeerId, TA), Error = EA>,
+    B: InboundConnectionUpgrade<C, Output = (PeerId, TB), Error = EB>,
+{
+    type Output = (PeerId, future::Either<TA, TB>);
+    type Error = Either<EA, EB>;
+    type Future = future::Either<SecuringFirst<A::Future, TA, TB, EA, EB>, SecuringSecond<B::Future, TA, TB, EA, EB>>;
+
+    fn upgrade_inbound(self, socket: C, info: Self::Info) -> Self::Future {
+        match info {
+            Either::Left(info) => future::Either::Left(
+                self.0
+                    .upgrade_inbound(socket, info)
+                    .map_err(Either::Left as fn(EA) -> Either<EA, EB>)
+                    .map_ok(first::<TA, TB> as fn((PeerId, TA)) -> (PeerId, future::Either<TA, TB>)),
+            ),
+            Either::Right(info) => future::Either::Right(
+                self.1
+                    .upgrade_inbound(socket, info)
+                    .map_err(Either::Right as fn(EB) -> Either<EA, EB>)
+                    .map_ok(second::<TA, TB> as fn((PeerId, TB)) -> (PeerId, future::Either<TA, TB>)),
+            ),
+        }
+    }
+}
+
+impl<C, A, B, TA, TB, EA, EB> OutboundConnectionUpgrade<C> for SelectSecurity<A, B>
+where
+    A: OutboundConnectionUpgrade<C, Output = (PeerId, TA), Error = EA>,
+    B: OutboundConnectionUpgrade<C, Output = (PeerId, TB), Error = EB>,
+{
+    type Output = (PeerId, future::Either<TA, TB>);
+    type Error = Either<EA, EB>;
+    type Future = future::Either<SecuringFirst<A::Future, TA, TB, EA, EB>, SecuringSecond<B::Future, TA, TB, EA, EB>>;
+
+    fn upgrade_outbound(self, socket: C, info: Self::Info) -> Self::Future {
+        match info {
+            Either::Left(info) => future::Either::Left(
+                self.0
+                    .upgrade_outbound(socket, info)
+                    .map_err(Either::Left as fn(EA) -> Either<EA, EB>)
+                    .map_ok(first::<TA, TB> as fn((PeerId, TA)) -> (PeerId, future::Either<TA, TB>)),
+            ),
+            Either::Right(info) => future::Either::Right(
+                self.1
+                    .upgrade_outbound(socket, info)
+                    .map_err(Either::Right as fn(EB) -> Either<EA, EB>)
+                    .map_ok(second::<TA, TB> as fn((PeerId, TB)) -> (PeerId, future::Either<TA, TB>)),
+            ),
+        }
+    }
+}
+
+/// A connection secured with the first protocol
+fn first<TA, TB>((peer, connection): (PeerId, TA)) -> (PeerId, future::Either<TA, TB>) {
+    (peer, future::Either::Left(connection))
+}
+
+/// A connection secured with the second protocol
+fn second<TA, TB>((peer, connection): (PeerId, TB)) -> (PeerId, future::Either<TA, TB>) {
+    (peer, future::Either::Right(connection))
+}
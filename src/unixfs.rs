@@ -0,0 +1,604 @@
+//! UnixFS files
+//!
+//! UnixFS is the format IPFS uses to represent files as DAGs of dag-pb nodes.
+//! A file is split into fixed-size chunks stored in leaf nodes, which are joined
+//! by a balanced tree of intermediate nodes. The parameters used here match
+//! Kubo's defaults (256 KiB chunks, 174 links per node, CIDv0), so adding the
+//! same file produces the same CID as `ipfs add`. [`AddOptions`] select
+//! CIDv1, raw leaves and other hash functions, for the CIDs of
+//! `ipfs add --cid-version 1` and of web3 uploaders.
+
+use anyhow::{anyhow, Context, Result};
+use cid::{Cid, Version};
+use multihash_codetable::MultihashDigest;
+use std::{
+    fs,
+    io::{ErrorKind, Read},
+    path::Path,
+};
+
+use crate::{
+    blockstore::{self, Blockstore, RAW_CODEC},
+    dag::{PbLink, PbNode, DAG_PB_CODEC},
+    multiformats::HashFunction,
+    protobuf,
+};
+
+/// Size of the chunks a file is split into
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Maximum number of links in an intermediate node
+const MAX_LINKS: usize = 174;
+
+/// How content is split into a UnixFS DAG
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddOptions {
+    /// Version of the CIDs of the dag-pb nodes
+    pub cid_version: Version,
+    /// Store the chunks as raw blocks, with CIDv1, instead of wrapping them in dag-pb nodes
+    pub raw_leaves: bool,
+    /// Hash function of every block; CIDv0 only allows sha2-256
+    pub hash: HashFunction,
+}
+
+impl Default for AddOptions {
+    /// The defaults of `ipfs add`: CIDv0 without raw leaves
+    fn default() -> Self {
+        Self::kubo(Version::V0)
+    }
+}
+
+impl AddOptions {
+    /// The options of `ipfs add --cid-version <version>`, which uses raw leaves with CIDv1
+    pub fn kubo(cid_version: Version) -> Self {
+        Self {
+            cid_version,
+            raw_leaves: cid_version == Version::V1,
+            hash: HashFunction::Sha2_256,
+        }
+    }
+
+    /// Options from the flags of `ipfs add`
+    ///
+    /// # Arguments
+    /// * `cid_version` - Version of the CIDs, 0 or 1 (default: 0, or 1 for hash functions other than sha2-256)
+    /// * `raw_leaves` - Whether to use raw leaves (default: with CIDv1)
+    /// * `hash` - Hash function of the blocks
+    ///
+    /// # Returns
+    /// * `Result<AddOptions>` - The options, or Err for an unknown CID version or CIDv0 with another hash function
+    pub fn from_flags(cid_version: Option<u64>, raw_leaves: Option<bool>, hash: HashFunction) -> Result<Self> {
+        // Like Kubo, other hash functions imply CIDv1, as CIDv0 can't name them
+        let cid_version = match cid_version {
+            None if hash != HashFunction::Sha2_256 => Version::V1,
+            None | Some(0) => Version::V0,
+            Some(1) => Version::V1,
+            Some(version) => return Err(anyhow!("Unknown CID version {}, only 0 and 1 exist", version)),
+        };
+        if cid_version == Version::V0 && hash != HashFunction::Sha2_256 {
+            return Err(anyhow!("CIDv0 only allows sha2-256, use CIDv1 for other hash functions"));
+        }
+        let defaults = Self::kubo(cid_version);
+        Ok(Self {
+            cid_version,
+            raw_leaves: raw_leaves.unwrap_or(defaults.raw_leaves),
+            hash,
+        })
+    }
+}
+
+/// Type of a UnixFS node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    /// Raw file content (legacy leaf type)
+    Raw = 0,
+    /// A directory listing its entries as named links
+    Directory = 1,
+    /// A file or a part of a file
+    File = 2,
+    /// File metadata (unused)
+    Metadata = 3,
+    /// A symbolic link
+    Symlink = 4,
+    /// A shard of a large directory
+    HamtShard = 5,
+}
+
+impl TryFrom<u64> for DataType {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u64) -> Result<Self> {
+        match value {
+            0 => Ok(DataType::Raw),
+            1 => Ok(DataType::Directory),
+            2 => Ok(DataType::File),
+            3 => Ok(DataType::Metadata),
+            4 => Ok(DataType::Symlink),
+            5 => Ok(DataType::HamtShard),
+            _ => Err(anyhow!("Unknown UnixFS data type {}", value)),
+        }
+    }
+}
+
+/// UnixFS metadata, stored in the data field of a dag-pb node
+#[derive(Debug, Clone)]
+pub struct UnixfsData {
+    /// Type of the node
+    pub data_type: DataType,
+    /// File content stored directly in this node
+    pub data: Option<Vec<u8>>,
+    /// Total size of the file content below this node
+    pub filesize: Option<u64>,
+    /// Size of the file content below each link of this node
+    pub blocksizes: Vec<u64>,
+}
+
+impl UnixfsData {
+    /// Encode the metadata as a UnixFS `Data` protobuf message
+    pub fn encode(&self) -> Vec<u8> {
+        let mut writer = protobuf::Writer::new();
+        writer.varint(1, self.data_type as u64);
+        if let Some(data) = &self.data {
+            writer.bytes(2, data);
+        }
+        if let Some(filesize) = self.filesize {
+            writer.varint(3, filesize);
+        }
+        for blocksize in &self.blocksizes {
+            writer.varint(4, *blocksize);
+        }
+        writer.finish()
+    }
+
+    /// Decode a UnixFS `Data` protobuf message
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let mut data_type = None;
+        let mut data = None;
+        let mut filesize = None;
+        let mut blocksizes = Vec::new();
+
+        let mut reader = protobuf::Reader::new(bytes);
+        while let Some((number, field)) = reader.next_field()? {
+            match number {
+                1 => data_type = Some(DataType::try_from(field.as_varint()?)?),
+                2 => data = Some(field.as_bytes()?.to_vec()),
+                3 => filesize = Some(field.as_varint()?),
+                4 => blocksizes.push(field.as_varint()?),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            data_type: data_type.ok_or_else(|| anyhow!("UnixFS node without a type"))?,
+            data,
+            filesize,
+            blocksizes,
+        })
+    }
+}
+
+/// The part of a file stored in one block
+#[derive(Debug)]
+pub struct FileBlock {
+    /// File content stored directly in the block
+    pub data: Vec<u8>,
+    /// Blocks holding the rest of the content below this block, in order
+    pub children: Vec<Cid>,
+    /// Size of the file content below each child, if the block records it
+    pub child_sizes: Vec<u64>,
+    /// Total size of the file content in and below this block, if known
+    pub filesize: Option<u64>,
+}
+
+/// Decode a block of a UnixFS file
+///
+/// # Arguments
+/// * `cid` - CID of the block, used to determine its codec
+/// * `block` - The block data
+///
+/// # Returns
+/// * `Result<FileBlock>` - The file content in the block and the links to the rest of the file
+pub fn decode_file_block(cid: &Cid, block: &[u8]) -> Result<FileBlock> {
+    match cid.codec() {
+        // Raw leaves hold file content directly
+        RAW_CODEC => Ok(FileBlock {
+            data: block.to_vec(),
+            children: Vec::new(),
+            child_sizes: Vec::new(),
+            filesize: Some(block.len() as u64),
+        }),
+        DAG_PB_CODEC => {
+            let node = PbNode::decode(block)?;
+            let unixfs = UnixfsData::decode(node.data.as_deref().unwrap_or_default())
+                .with_context(|| format!("Invalid UnixFS node {}", cid))?;
+            match unixfs.data_type {
+                DataType::File | DataType::Raw => Ok(FileBlock {
+                    data: unixfs.data.unwrap_or_default(),
+                    children: node.links.into_iter().map(|link| link.cid).collect(),
+                    child_sizes: unixfs.blocksizes,
+                    filesize: unixfs.filesize,
+                }),
+                DataType::Directory | DataType::HamtShard => Err(anyhow!("{} is a directory, not a file", cid)),
+                other => Err(anyhow!("{} is a UnixFS {:?} node, not a file", cid, other)),
+            }
+        }
+        codec => Err(anyhow!("Unsupported codec 0x{:x} in CID {}", codec, cid)),
+    }
+}
+
+/// An entry of a UnixFS directory
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    /// Name of the entry within the directory
+    pub name: String,
+    /// CID of the entry
+    pub cid: Cid,
+    /// Size of the entry's DAG, blocks included, if the directory records it
+    pub size: Option<u64>,
+}
+
+/// List the entries of a UnixFS directory
+///
+/// # Arguments
+/// * `cid` - CID of the directory block
+/// * `block` - The block data
+///
+/// # Returns
+/// * `Result<Option<Vec<DirEntry>>>` - The entries in the order the directory lists them, or None if the block isn't a directory
+pub fn list_directory(cid: &Cid, block: &[u8]) -> Result<Option<Vec<DirEntry>>> {
+    // Raw blocks are file content, which has no entries
+    if cid.codec() != DAG_PB_CODEC {
+        return Ok(None);
+    }
+    let node = PbNode::decode(block)?;
+    let unixfs = UnixfsData::decode(node.data.as_deref().unwrap_or_default())
+        .with_context(|| format!("Invalid UnixFS node {}", cid))?;
+    match unixfs.data_type {
+        DataType::Directory => Ok(Some(
+            node.links
+                .into_iter()
+                .map(|link| DirEntry {
+                    name: link.name.unwrap_or_default(),
+                    cid: link.cid,
+                    size: link.tsize,
+                })
+                .collect(),
+        )),
+        DataType::HamtShard => Err(anyhow!("{} is a sharded directory, which is not supported", cid)),
+        _ => Ok(None),
+    }
+}
+
+/// Look up an entry of a UnixFS directory by name
+///
+/// # Arguments
+/// * `cid` - CID of the directory block
+/// * `block` - The block data
+/// * `name` - Name of the entry
+///
+/// # Returns
+/// * `Result<Option<Cid>>` - CID of the entry, or None if there is no entry of that name or the block isn't a directory
+pub fn find_entry(cid: &Cid, block: &[u8], name: &str) -> Result<Option<Cid>> {
+    let entries = list_directory(cid, block)?.unwrap_or_default();
+    Ok(entries.into_iter().find(|entry| entry.name == name).map(|entry| entry.cid))
+}
+
+/// Result of adding a file to the blockstore
+#[derive(Debug)]
+pub struct AddedFile {
+    /// CID of the root of the file's DAG
+    pub cid: Cid,
+    /// Size of the file content in bytes
+    pub size: u64,
+    /// Number of blocks the file was split into
+    pub blocks: usize,
+}
+
+/// A node written to the blockstore, waiting to be linked from its parent
+struct BuiltNode {
+    /// CID of the node
+    cid: Cid,
+    /// Size of the file content below the node
+    filesize: u64,
+    /// Size of the encoded node plus all nodes below it
+    tsize: u64,
+}
+
+/// Add a file to the blockstore as a UnixFS DAG
+///
+/// # Arguments
+/// * `blockstore` - The blockstore to write the blocks to
+/// * `path` - Path of the file to add
+/// * `options` - How the file is split into a DAG
+///
+/// # Returns
+/// * `Result<AddedFile>` - CID and size of the added file
+pub fn add_file(blockstore: &Blockstore, path: &Path, options: AddOptions) -> Result<AddedFile> {
+    let file = fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    if file.metadata()?.is_dir() {
+        return Err(anyhow!("{:?} is a directory, only files can be added", path));
+    }
+    add_reader(blockstore, file, options)
+}
+
+/// Add content read from a reader to the blockstore as a UnixFS DAG
+///
+/// # Arguments
+/// * `blockstore` - The blockstore to write the blocks to
+/// * `reader` - Source of the file content, read until its end
+/// * `options` - How the content is split into a DAG
+///
+/// # Returns
+/// * `Result<AddedFile>` - CID and size of the added content
+pub fn add_reader(blockstore: &Blockstore, reader: impl Read, options: AddOptions) -> Result<AddedFile> {
+    build(reader, options, &mut |cid, bytes| blockstore.put(cid, bytes))
+}
+
+/// Compute the CID a file would get if it was added, without storing anything
+///
+/// # Arguments
+/// * `path` - Path of the file
+/// * `options` - How the file would be split into a DAG
+///
+/// # Returns
+/// * `Result<AddedFile>` - CID and size the file would be added with
+pub fn hash_file(path: &Path, options: AddOptions) -> Result<AddedFile> {
+    let file = fs::File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    if file.metadata()?.is_dir() {
+        return Err(anyhow!("{:?} is a directory, only files can be hashed", path));
+    }
+    build(file, options, &mut |_, _| Ok(()))
+}
+
+/// Split content into a UnixFS DAG, handing every encoded node to `put`
+///
+/// # Arguments
+/// * `reader` - Source of the file content, read until its end
+/// * `options` - How the content is split into a DAG
+/// * `put` - Called with the CID and bytes of every node, children before their parents
+///
+/// # Returns
+/// * `Result<AddedFile>` - CID and size of the content
+fn build(
+    mut reader: impl Read,
+    options: AddOptions,
+    put: &mut impl FnMut(&Cid, &[u8]) -> Result<()>,
+) -> Result<AddedFile> {
+    // Split the content into chunks, each stored in its own leaf node
+    let mut blocks = 0;
+    let mut level = Vec::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let len = read_chunk(&mut reader, &mut buf)?;
+        // An empty file still gets a (single, empty) leaf
+        if len == 0 && !level.is_empty() {
+            break;
+        }
+
+        if options.raw_leaves {
+            // Raw leaves are the chunk itself, so their size is that of the chunk
+            let cid = options.hash.cid(RAW_CODEC, &buf[..len]);
+            put(&cid, &buf[..len])?;
+            level.push(BuiltNode {
+                cid,
+                filesize: len as u64,
+                tsize: len as u64,
+            });
+        } else {
+            let unixfs = UnixfsData {
+                data_type: DataType::File,
+                data: (len > 0).then(|| buf[..len].to_vec()),
+                filesize: Some(len as u64),
+                blocksizes: Vec::new(),
+            };
+            let node = PbNode {
+                links: Vec::new(),
+                data: Some(unixfs.encode()),
+            };
+            level.push(put_node(put, options, &node, len as u64, 0)?);
+        }
+        blocks += 1;
+
+        if len < CHUNK_SIZE {
+            break;
+        }
+    }
+
+    // Join the nodes into a balanced tree, one level at a time
+    while level.len() > 1 {
+        let mut parents = Vec::with_capacity(level.len().div_ceil(MAX_LINKS));
+        for children in level.chunks(MAX_LINKS) {
+            parents.push(put_parent(put, options, children)?);
+            blocks += 1;
+        }
+        level = parents;
+    }
+
+    let root = level.pop().expect("a file always has at least one leaf");
+    Ok(AddedFile {
+        cid: root.cid,
+        size: root.filesize,
+        blocks,
+    })
+}
+
+/// Write an intermediate node linking to the given children
+fn put_parent(
+    put: &mut impl FnMut(&Cid, &[u8]) -> Result<()>,
+    options: AddOptions,
+    children: &[BuiltNode],
+) -> Result<BuiltNode> {
+    let filesize = children.iter().map(|child| child.filesize).sum();
+    let unixfs = UnixfsData {
+        data_type: DataType::File,
+        data: None,
+        filesize: Some(filesize),
+        blocksizes: children.iter().map(|child| child.filesize).collect(),
+    };
+    let node = PbNode {
+        links: children
+            .iter()
+            .map(|child| PbLink {
+                cid: child.cid,
+                name: Some(String::new()),
+                tsize: Some(child.tsize),
+            })
+            .collect(),
+        data: Some(unixfs.encode()),
+    };
+    let children_tsize = children.iter().map(|child| child.tsize).sum();
+    put_node(put, options, &node, filesize, children_tsize)
+}
+
+/// Encode a node, hand it to `put` and return its CID and sizes
+fn put_node(
+    put: &mut impl FnMut(&Cid, &[u8]) -> Result<()>,
+    options: AddOptions,
+    node: &PbNode,
+    filesize: u64,
+    children_tsize: u64,
+) -> Result<BuiltNode> {
+    let bytes = node.encode();
+    let hash = options.hash.code().digest(&bytes);
+    let cid = match options.cid_version {
+        Version::V0 => Cid::new_v0(hash)?,
+        Version::V1 => Cid::new_v1(DAG_PB_CODEC, hash),
+    };
+    put(&cid, &bytes)?;
+    Ok(BuiltNode {
+        cid,
+        filesize,
+        tsize: bytes.len() as u64 + children_tsize,
+    })
+}
+
+/// Fill the buffer from the reader, stopping early only at the end of the input
+///
+/// # Returns
+/// * `Result<usize>` - Number of bytes read
+fn read_chunk(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
+}
+
+/// Compare content with a file DAG stored in the blockstore
+///
+/// The blocks are read in file order and checked against their CIDs, so a
+/// corrupted block is reported as such rather than as a difference.
+///
+/// # Arguments
+/// * `blockstore` - The blockstore holding the DAG
+/// * `root` - CID of the root of the file DAG
+/// * `reader` - Source of the content to compare, read until its end or the first difference
+///
+/// # Returns
+/// * `Result<Option<u64>>` - Offset of the first byte that differs, or None if the content matches
+pub fn compare_stored(blockstore: &Blockstore, root: &Cid, mut reader: impl Read) -> Result<Option<u64>> {
+    let mut offset = 0;
+    let mut buf = Vec::new();
+    // Blocks still to be compared, the next one last
+    let mut stack = vec![*root];
+    while let Some(cid) = stack.pop() {
+        let block = blockstore
+            .get(&cid)?
+            .ok_or_else(|| anyhow!("Block {} of {} is not stored locally", cid, root))?;
+        blockstore::verify(&cid, &block)?;
+        let file_block = decode_file_block(&cid, &block)?;
+
+        buf.resize(file_block.data.len(), 0);
+        let len = read_chunk(&mut reader, &mut buf)?;
+        if let Some(i) = (0..len).find(|&i| buf[i] != file_block.data[i]) {
+            return Ok(Some(offset + i as u64));
+        }
+        if len < file_block.data.len() {
+            return Ok(Some(offset + len as u64));
+        }
+        offset += len as u64;
+        stack.extend(file_block.children.into_iter().rev());
+    }
+    // Content beyond the end of the DAG differs as well
+    let len = read_chunk(&mut reader, &mut [0])?;
+    Ok((len > 0).then_some(offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Split content into a DAG, keeping its blocks in memory
+    fn build_in_memory(content: &[u8], options: AddOptions) -> (AddedFile, HashMap<Cid, Vec<u8>>) {
+        let mut blocks = HashMap::new();
+        let added = build(content, options, &mut |cid, bytes| {
+            blocks.insert(*cid, bytes.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        (added, blocks)
+    }
+
+    /// Read the content of a file DAG back, in file order
+    fn read_back(root: &Cid, blocks: &HashMap<Cid, Vec<u8>>) -> Vec<u8> {
+        let file = decode_file_block(root, &blocks[root]).unwrap();
+        let mut content = file.data;
+        for child in &file.children {
+            content.extend(read_back(child, blocks));
+        }
+        content
+    }
+
+    #[test]
+    fn matches_ipfs_add() {
+        let (added, _) = build_in_memory(b"hello world\n", AddOptions::default());
+        assert_eq!(added.cid.to_string(), "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o");
+        assert_eq!(added.size, 12);
+    }
+
+    #[test]
+    fn matches_ipfs_add_of_an_empty_file() {
+        let (added, _) = build_in_memory(b"", AddOptions::default());
+        assert_eq!(added.cid.to_string(), "QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH");
+        assert_eq!(added.size, 0);
+    }
+
+    #[test]
+    fn matches_ipfs_add_with_cid_version_1() {
+        let (added, _) = build_in_memory(b"hello world\n", AddOptions::kubo(Version::V1));
+        assert_eq!(added.cid.to_string(), "bafkreifjjcie6lypi6ny7amxnfftagclbuxndqonfipmb64f2km2devei4");
+    }
+
+    #[test]
+    fn joins_many_chunks_into_a_balanced_tree() {
+        // One chunk more than an intermediate node links to, plus a partial one
+        let content: Vec<u8> = (0..(MAX_LINKS + 1) * CHUNK_SIZE + 10).map(|i| (i / 7 % 251) as u8).collect();
+        let (added, blocks) = build_in_memory(&content, AddOptions::default());
+
+        // 176 leaves, two intermediate nodes and the root
+        assert_eq!(added.blocks, MAX_LINKS + 2 + 3);
+        assert_eq!(added.size, content.len() as u64);
+        let root = decode_file_block(&added.cid, &blocks[&added.cid]).unwrap();
+        assert_eq!(root.children.len(), 2);
+        assert_eq!(root.child_sizes, vec![(MAX_LINKS * CHUNK_SIZE) as u64, (CHUNK_SIZE + 10) as u64]);
+        assert_eq!(root.filesize, Some(content.len() as u64));
+        assert!(read_back(&added.cid, &blocks) == content);
+    }
+
+    #[test]
+    fn options_from_flags() {
+        assert_eq!(AddOptions::from_flags(None, None, HashFunction::Sha2_256).unwrap(), AddOptions::default());
+        assert_eq!(AddOptions::from_flags(Some(1), None, HashFunction::Sha2_256).unwrap(), AddOptions::kubo(Version::V1));
+        let options = AddOptions::from_flags(None, Some(false), HashFunction::Sha2_512).unwrap();
+        assert_eq!(options.cid_version, Version::V1);
+        assert!(!options.raw_leaves);
+        assert!(AddOptions::from_flags(Some(2), None, HashFunction::Sha2_256).is_err());
+        assert!(AddOptions::from_flags(Some(0), None, HashFunction::Sha2_512).is_err());
+    }
+}